@@ -0,0 +1,87 @@
+//! Compares splitting a line into its parts as the server used to, copying every argument into a
+//! `String` of its own, with slicing it into a borrowed `MessageRef`. The full parse into the
+//! owned `Message`, which now starts from a `MessageRef`, is measured alongside.
+//! Run with `cargo bench`.
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use iris_lib::types::{MessageRef, Nick, ParsedMessage, UnparsedMessage, SERVER_NAME};
+
+const LINES: [&str; 4] = [
+    "PRIVMSG #haku :Hello everyone, how is the assignment going?\r\n",
+    "@label=abc :tom!tom@127.0.0.1 MODE #haku +ov tom ann\r\n",
+    "CHATHISTORY BEFORE #haku timestamp=2023-11-14T22:13:20.000Z 50\r\n",
+    "PING :iris-server\r\n",
+];
+
+/// The prefix and the command with its arguments, split as lines were before `MessageRef`:
+/// tags and prefix taken off the front, then each part of the rest copied out.
+/// Kept here as the baseline the borrowed parser is measured against.
+fn split_allocating(line: &str) -> (Option<String>, Vec<String>) {
+    let rest = match line.strip_prefix('@') {
+        Some(tagged) => match tagged.split_once(' ') {
+            Some((_, rest)) => rest.trim_start_matches(' '),
+            None => "",
+        },
+        None => line,
+    };
+    let (prefix, rest) = match rest.strip_prefix(':') {
+        Some(prefixed) => match prefixed.split_once(' ') {
+            Some((prefix, rest)) => (Some(prefix), rest.trim_start_matches(' ')),
+            None => (Some(prefixed), ""),
+        },
+        None => (None, rest),
+    };
+    let stripped = rest.strip_suffix("\r\n").unwrap_or(rest);
+    let (middle, trailing) = match stripped.split_once(" :") {
+        Some((before, after)) => (before, Some(after)),
+        None => (stripped, None),
+    };
+    let mut command = middle
+        .split(' ')
+        .filter(|arg| !arg.is_empty())
+        .collect::<Vec<_>>();
+    command.extend(trailing);
+    (
+        prefix.map(str::to_string),
+        command.into_iter().map(str::to_string).collect(),
+    )
+}
+
+fn parse(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse");
+    group.bench_function("allocating", |b| {
+        b.iter(|| {
+            for line in LINES {
+                let (prefix, command) = split_allocating(black_box(line));
+                black_box((prefix, command.first(), command.get(1), command.last()));
+            }
+        })
+    });
+    group.bench_function("borrowed", |b| {
+        b.iter(|| {
+            for line in LINES {
+                let message = MessageRef::parse(black_box(line));
+                black_box((
+                    message.prefix,
+                    message.command,
+                    message.param(0),
+                    message.params().last(),
+                ));
+            }
+        })
+    });
+    group.bench_function("message", |b| {
+        b.iter(|| {
+            for line in LINES {
+                let _ = black_box(ParsedMessage::try_from(UnparsedMessage {
+                    sender_nick: Nick("tom".to_string()),
+                    server_name: SERVER_NAME,
+                    message: black_box(line),
+                }));
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, parse);
+criterion_main!(benches);
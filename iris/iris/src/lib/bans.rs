@@ -0,0 +1,359 @@
+//! # Bans
+//! Server bans set by IRC operators.
+//!
+//! A D-line bans an IP address or CIDR range, and its connections are refused before they can
+//! register. A K-line bans a `user@host` mask, and is checked when a client registers. Either
+//! kind may expire, and they are persisted to a plain text file so they survive restarts.
+use std::{
+    collections::BTreeMap,
+    fmt::{self, Display},
+    io,
+    net::IpAddr,
+    path::PathBuf,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use log::error;
+
+use crate::{store, types::NameRules};
+
+/// Which connections a ban refuses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum BanKind {
+    /// By `user@host` mask, at registration.
+    KLine,
+    /// By IP address or CIDR range, as soon as they connect.
+    DLine,
+}
+
+impl Display for BanKind {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanKind::KLine => write!(fmt, "K-line"),
+            BanKind::DLine => write!(fmt, "D-line"),
+        }
+    }
+}
+
+/// A single server ban.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Ban {
+    pub kind: BanKind,
+    /// A `user@host` mask for a K-line, or an address like `10.0.0.0/8` for a D-line.
+    pub mask: String,
+    pub reason: String,
+    /// When the ban stops applying, or `None` if it never does.
+    pub expires: Option<SystemTime>,
+}
+
+impl Ban {
+    /// Why a user refused by this ban is disconnected, for example `K-lined: Spamming`.
+    pub fn disconnect_reason(&self) -> String {
+        match self.kind {
+            BanKind::KLine => format!("K-lined: {}", self.reason),
+            BanKind::DLine => format!("D-lined: {}", self.reason),
+        }
+    }
+
+    /// Whether the ban has stopped applying by `now`.
+    pub fn expired(&self, now: SystemTime) -> bool {
+        self.expires.is_some_and(|expires| expires <= now)
+    }
+}
+
+/// Everything that can go wrong when changing the bans.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BanError {
+    /// A K-line mask without an `@`, or a D-line that isn't an address or CIDR range.
+    InvalidMask(BanKind, String),
+    NotFound(BanKind, String),
+}
+
+impl Display for BanError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BanError::InvalidMask(BanKind::KLine, mask) => {
+                write!(fmt, "{mask} is not a valid user@host mask.")
+            }
+            BanError::InvalidMask(BanKind::DLine, mask) => {
+                write!(fmt, "{mask} is not a valid IP address or CIDR range.")
+            }
+            BanError::NotFound(kind, mask) => write!(fmt, "There is no {kind} for {mask}."),
+        }
+    }
+}
+
+/// Parses an IP address, or a CIDR range like `10.0.0.0/8`, into its address and prefix length.
+pub fn parse_cidr(mask: &str) -> Option<(IpAddr, u8)> {
+    let (address, len) = match mask.split_once('/') {
+        Some((address, len)) => (address.parse::<IpAddr>().ok()?, len.parse::<u8>().ok()?),
+        None => {
+            let address = mask.parse::<IpAddr>().ok()?;
+            (address, if address.is_ipv4() { 32 } else { 128 })
+        }
+    };
+    let max = if address.is_ipv4() { 32 } else { 128 };
+    (len <= max).then_some((address, len))
+}
+
+/// Whether `ip` is within the `len` bit prefix of `network`.
+/// Addresses of different families are never in the same network.
+pub fn in_network(ip: IpAddr, network: IpAddr, len: u8) -> bool {
+    match (ip, network) {
+        (IpAddr::V4(ip), IpAddr::V4(network)) => {
+            let mask = u32::MAX
+                .checked_shl(32 - u32::from(len.min(32)))
+                .unwrap_or(0);
+            u32::from(ip) & mask == u32::from(network) & mask
+        }
+        (IpAddr::V6(ip), IpAddr::V6(network)) => {
+            let mask = u128::MAX
+                .checked_shl(128 - u32::from(len.min(128)))
+                .unwrap_or(0);
+            u128::from(ip) & mask == u128::from(network) & mask
+        }
+        _ => false,
+    }
+}
+
+/// The server ban store.
+#[derive(Debug, Default)]
+pub struct Bans {
+    /// Bans by their kind and casefolded mask.
+    bans: BTreeMap<(BanKind, String), Ban>,
+    rules: NameRules,
+    path: Option<PathBuf>,
+}
+
+impl Bans {
+    /// Loads the bans stored at `path`, which will also be used to save any changes.
+    /// A missing file is treated as an empty store. Masks are compared under `rules`.
+    ///
+    /// Each ban is a `K` or `D` line with its mask, its expiry in seconds since the Unix
+    /// epoch (0 for never), and its reason.
+    pub fn load(path: impl Into<PathBuf>, rules: NameRules) -> io::Result<Self> {
+        let path = path.into();
+        let mut bans = BTreeMap::new();
+        for line in store::read_lines(&path)? {
+            let fields = line.splitn(4, '\t').collect::<Vec<_>>();
+            let (kind, mask, expires, reason) = match fields[..] {
+                ["K", mask, expires, reason] => (BanKind::KLine, mask, expires, reason),
+                ["D", mask, expires, reason] => (BanKind::DLine, mask, expires, reason),
+                _ => {
+                    error!("Skipping malformed ban entry in {}", path.display());
+                    continue;
+                }
+            };
+            let Ok(expires) = expires.parse::<u64>() else {
+                error!("Skipping malformed ban entry in {}", path.display());
+                continue;
+            };
+            let ban = Ban {
+                kind,
+                mask: mask.to_string(),
+                reason: reason.to_string(),
+                expires: (expires > 0).then(|| UNIX_EPOCH + Duration::from_secs(expires)),
+            };
+            bans.insert((kind, rules.fold(mask)), ban);
+        }
+        Ok(Bans {
+            bans,
+            rules,
+            path: Some(path),
+        })
+    }
+
+    /// A ban store that is never written to disk.
+    pub fn in_memory(rules: NameRules) -> Self {
+        Bans {
+            rules,
+            ..Bans::default()
+        }
+    }
+
+    /// Bans `mask` for `reason`, for `duration` from `now` or for good.
+    /// A ban already set on the same mask is replaced.
+    pub fn add(
+        &mut self,
+        kind: BanKind,
+        mask: &str,
+        reason: &str,
+        duration: Option<Duration>,
+        now: SystemTime,
+    ) -> Result<&Ban, BanError> {
+        let valid = match kind {
+            BanKind::KLine => mask.split_once('@').is_some_and(|(user, host)| {
+                !user.is_empty() && !host.is_empty() && !mask.contains(char::is_whitespace)
+            }),
+            BanKind::DLine => parse_cidr(mask).is_some(),
+        };
+        if !valid {
+            return Err(BanError::InvalidMask(kind, mask.to_string()));
+        }
+        let key = (kind, self.rules.fold(mask));
+        self.bans.insert(
+            key.clone(),
+            Ban {
+                kind,
+                mask: mask.to_string(),
+                reason: reason.to_string(),
+                expires: duration.map(|duration| now + duration),
+            },
+        );
+        self.save();
+        Ok(&self.bans[&key])
+    }
+
+    /// Lifts the ban on `mask`.
+    pub fn remove(&mut self, kind: BanKind, mask: &str) -> Result<Ban, BanError> {
+        let ban = self
+            .bans
+            .remove(&(kind, self.rules.fold(mask)))
+            .ok_or_else(|| BanError::NotFound(kind, mask.to_string()))?;
+        self.save();
+        Ok(ban)
+    }
+
+    /// The bans of `kind` still in force at `now`, ordered by mask.
+    pub fn list(&self, kind: BanKind, now: SystemTime) -> impl Iterator<Item = &Ban> {
+        self.bans
+            .values()
+            .filter(move |ban| ban.kind == kind && !ban.expired(now))
+    }
+
+    /// The D-line refusing connections from `ip` at `now`, if there is one.
+    pub fn dline(&self, ip: IpAddr, now: SystemTime) -> Option<&Ban> {
+        self.list(BanKind::DLine, now).find(|ban| {
+            parse_cidr(&ban.mask).is_some_and(|(network, len)| in_network(ip, network, len))
+        })
+    }
+
+    /// The K-line refusing `username` from any of `hosts` at `now`, if there is one.
+    pub fn kline<'a>(
+        &self,
+        username: &str,
+        hosts: impl IntoIterator<Item = &'a str> + Clone,
+        now: SystemTime,
+    ) -> Option<&Ban> {
+        self.list(BanKind::KLine, now).find(|ban| {
+            hosts.clone().into_iter().any(|host| {
+                self.rules
+                    .mask_matches(&ban.mask, &format!("{username}@{host}"))
+            })
+        })
+    }
+
+    /// Forgets the bans that have expired by `now`.
+    pub fn expire(&mut self, now: SystemTime) {
+        let before = self.bans.len();
+        self.bans.retain(|_, ban| !ban.expired(now));
+        if self.bans.len() != before {
+            self.save();
+        }
+    }
+
+    /// Writes every ban to the file the store was loaded from.
+    /// Changes are saved as they are made, so this is only needed after a save failed,
+    /// or to be sure of it as the server stops.
+    pub fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let lines = self.bans.values().map(|ban| {
+            let kind = match ban.kind {
+                BanKind::KLine => "K",
+                BanKind::DLine => "D",
+            };
+            let expires = ban
+                .expires
+                .and_then(|expires| expires.duration_since(UNIX_EPOCH).ok())
+                .map_or(0, |expires| expires.as_secs().max(1));
+            format!("{kind}\t{}\t{expires}\t{}", ban.mask, ban.reason)
+        });
+        if let Err(e) = store::write_lines(path, lines) {
+            error!("Failed to save bans to {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        assert_eq!(parse_cidr("10.0.0.0/8"), Some((ip("10.0.0.0"), 8)));
+        assert_eq!(parse_cidr("::1"), Some((ip("::1"), 128)));
+        assert_eq!(parse_cidr("10.0.0.0/33"), None);
+        assert_eq!(parse_cidr("*@host"), None);
+        assert!(in_network(ip("10.1.2.3"), ip("10.0.0.0"), 8));
+        assert!(!in_network(ip("11.1.2.3"), ip("10.0.0.0"), 8));
+        assert!(in_network(ip("11.1.2.3"), ip("10.0.0.0"), 0));
+        assert!(in_network(ip("2001:db8::5"), ip("2001:db8::"), 64));
+        assert!(!in_network(ip("10.0.0.1"), ip("::"), 0));
+    }
+
+    #[test]
+    fn test_bans() {
+        let now = SystemTime::now();
+        let mut bans = Bans::in_memory(NameRules::default());
+        bans.add(BanKind::DLine, "10.0.0.0/8", "spam", None, now)
+            .unwrap();
+        bans.add(
+            BanKind::KLine,
+            "*bot@127.0.0.1",
+            "bots",
+            Some(Duration::from_secs(60)),
+            now,
+        )
+        .unwrap();
+        assert_eq!(
+            bans.add(BanKind::KLine, "nobody", "", None, now),
+            Err(BanError::InvalidMask(BanKind::KLine, "nobody".to_string()))
+        );
+        assert_eq!(bans.dline(ip("10.9.9.9"), now).unwrap().reason, "spam");
+        assert!(bans.dline(ip("127.0.0.1"), now).is_none());
+        assert!(bans.kline("SpamBot", ["127.0.0.1"], now).is_some());
+        assert!(bans.kline("tom", ["127.0.0.1"], now).is_none());
+        let later = now + Duration::from_secs(60);
+        assert!(bans.kline("SpamBot", ["127.0.0.1"], later).is_none());
+        bans.expire(later);
+        assert_eq!(bans.list(BanKind::KLine, now).count(), 0);
+        assert!(bans.remove(BanKind::DLine, "10.0.0.0/8").is_ok());
+        assert_eq!(
+            bans.remove(BanKind::DLine, "10.0.0.0/8"),
+            Err(BanError::NotFound(BanKind::DLine, "10.0.0.0/8".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_persistence() {
+        let path = std::env::temp_dir().join(format!("iris-bans-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let now = SystemTime::now();
+        {
+            let mut bans = Bans::load(&path, NameRules::default()).unwrap();
+            bans.add(BanKind::DLine, "192.168.0.0/16", "lan party", None, now)
+                .unwrap();
+            bans.add(
+                BanKind::KLine,
+                "*@example.com",
+                "go away",
+                Some(Duration::from_secs(3600)),
+                now,
+            )
+            .unwrap();
+        }
+        let bans = Bans::load(&path, NameRules::default()).unwrap();
+        let klines = bans.list(BanKind::KLine, now).collect::<Vec<_>>();
+        assert_eq!(klines.len(), 1);
+        assert_eq!(klines[0].reason, "go away");
+        assert!(klines[0].expires.is_some());
+        assert!(bans.dline(ip("192.168.1.1"), now).is_some());
+        std::fs::remove_file(&path).unwrap();
+    }
+}
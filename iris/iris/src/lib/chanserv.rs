@@ -0,0 +1,491 @@
+//! # ChanServ
+//! A channel registration service, reachable by users as the pseudo-user `ChanServ`.
+//!
+//! A registered channel belongs to the NickServ account of its founder, and keeps an
+//! access list of accounts along with the channel's topic and modes. These settings are
+//! persisted to a plain text file, and re-applied by the sever thread whenever the
+//! channel is recreated after everybody has left.
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::{self, Display},
+    io,
+    path::PathBuf,
+};
+
+use log::error;
+
+use crate::{
+    store,
+    types::{Channel, NameRules},
+};
+
+/// The nickname the service answers to.
+pub const CHANSERV: &str = "ChanServ";
+
+/// How much a user is trusted in a registered channel.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum AccessLevel {
+    /// Voiced on join.
+    Voice,
+    /// Opped on join, and may use OP/DEOP.
+    Op,
+    /// Owns the channel, and may change its access list.
+    Founder,
+}
+
+impl Display for AccessLevel {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AccessLevel::Voice => write!(fmt, "voice"),
+            AccessLevel::Op => write!(fmt, "op"),
+            AccessLevel::Founder => write!(fmt, "founder"),
+        }
+    }
+}
+
+impl TryFrom<&str> for AccessLevel {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.to_ascii_lowercase().as_str() {
+            "voice" => Ok(AccessLevel::Voice),
+            "op" => Ok(AccessLevel::Op),
+            "founder" => Ok(AccessLevel::Founder),
+            _ => Err(()),
+        }
+    }
+}
+
+/// The settings kept for a registered channel.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RegisteredChannel {
+    /// The channel name, in the casing it was registered with.
+    pub name: Channel,
+    /// Accounts (lowercased) and their access level. The founder is always present.
+    pub access: BTreeMap<String, AccessLevel>,
+    pub topic: Option<String>,
+    pub modes: BTreeSet<char>,
+}
+
+/// Everything that can go wrong when operating on registered channels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChanServError {
+    AlreadyRegistered,
+    NotRegistered,
+    /// The founder's access can't be changed, only the channel dropped.
+    Founder,
+}
+
+impl Display for ChanServError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ChanServError::AlreadyRegistered => write!(fmt, "This channel is already registered."),
+            ChanServError::NotRegistered => write!(fmt, "This channel is not registered."),
+            ChanServError::Founder => write!(fmt, "The founder's access can't be changed."),
+        }
+    }
+}
+
+/// The registered channel store.
+#[derive(Debug, Default)]
+pub struct ChanServ {
+    /// Channels by their casefolded name.
+    channels: HashMap<String, RegisteredChannel>,
+    rules: NameRules,
+    path: Option<PathBuf>,
+}
+
+impl ChanServ {
+    /// Loads the channels stored at `path`, which will also be used to save any changes.
+    /// A missing file is treated as an empty store. Names are compared under `rules`.
+    ///
+    /// Each channel is a `C` line with its modes and topic, followed by an `A` line per access entry.
+    pub fn load(path: impl Into<PathBuf>, rules: NameRules) -> io::Result<Self> {
+        let path = path.into();
+        let mut channels: HashMap<String, RegisteredChannel> = HashMap::new();
+        for line in store::read_lines(&path)? {
+            let fields = line.splitn(4, '\t').collect::<Vec<_>>();
+            match fields[..] {
+                ["C", name, modes, topic] => {
+                    channels.insert(
+                        rules.fold(name),
+                        RegisteredChannel {
+                            name: Channel(name.to_string()),
+                            access: BTreeMap::new(),
+                            topic: Some(topic.to_string()).filter(|t| !t.is_empty()),
+                            modes: modes.chars().filter(|m| *m != '+').collect(),
+                        },
+                    );
+                }
+                ["A", name, account, level] => {
+                    match (
+                        channels.get_mut(&rules.fold(name)),
+                        AccessLevel::try_from(level),
+                    ) {
+                        (Some(channel), Ok(level)) => {
+                            channel.access.insert(account.to_string(), level);
+                        }
+                        _ => error!("Skipping malformed access entry in {}", path.display()),
+                    }
+                }
+                _ => error!("Skipping malformed channel entry in {}", path.display()),
+            }
+        }
+        Ok(ChanServ {
+            channels,
+            rules,
+            path: Some(path),
+        })
+    }
+
+    /// A channel store that is never written to disk.
+    pub fn in_memory(rules: NameRules) -> Self {
+        ChanServ {
+            rules,
+            ..ChanServ::default()
+        }
+    }
+
+    /// The settings of `channel`, if it is registered.
+    pub fn channel(&self, channel: &Channel) -> Option<&RegisteredChannel> {
+        self.channels.get(&self.rules.fold(&channel.0))
+    }
+
+    /// The access `account` has to `channel`.
+    pub fn access(&self, channel: &Channel, account: Option<&str>) -> Option<AccessLevel> {
+        self.channel(channel)?
+            .access
+            .get(&self.rules.fold(account?))
+            .copied()
+    }
+
+    /// Registers `channel` to `founder`, keeping its current topic and modes.
+    pub fn register(
+        &mut self,
+        channel: &Channel,
+        founder: &str,
+        topic: Option<String>,
+        modes: BTreeSet<char>,
+    ) -> Result<(), ChanServError> {
+        let key = self.rules.fold(&channel.0);
+        if self.channels.contains_key(&key) {
+            return Err(ChanServError::AlreadyRegistered);
+        }
+        self.channels.insert(
+            key,
+            RegisteredChannel {
+                name: channel.clone(),
+                access: BTreeMap::from([(self.rules.fold(founder), AccessLevel::Founder)]),
+                topic,
+                modes,
+            },
+        );
+        self.save();
+        Ok(())
+    }
+
+    /// Forgets everything about `channel`.
+    pub fn drop_channel(&mut self, channel: &Channel) -> Result<(), ChanServError> {
+        self.channels
+            .remove(&self.rules.fold(&channel.0))
+            .ok_or(ChanServError::NotRegistered)?;
+        self.save();
+        Ok(())
+    }
+
+    /// Gives `account` the `level` of access to `channel`, or removes its access if `level` is `None`.
+    pub fn set_access(
+        &mut self,
+        channel: &Channel,
+        account: &str,
+        level: Option<AccessLevel>,
+    ) -> Result<(), ChanServError> {
+        let registered = self
+            .channels
+            .get_mut(&self.rules.fold(&channel.0))
+            .ok_or(ChanServError::NotRegistered)?;
+        let account = self.rules.fold(account);
+        if level == Some(AccessLevel::Founder)
+            || registered.access.get(&account) == Some(&AccessLevel::Founder)
+        {
+            return Err(ChanServError::Founder);
+        }
+        match level {
+            Some(level) => registered.access.insert(account, level),
+            None => registered.access.remove(&account),
+        };
+        self.save();
+        Ok(())
+    }
+
+    /// Takes `account` off every access list and unregisters the channels it founded, so that
+    /// whoever registers the name next doesn't inherit its access.
+    pub fn forget_account(&mut self, account: &str) {
+        let account = self.rules.fold(account);
+        let mut changed = false;
+        self.channels
+            .retain(|_, registered| match registered.access.remove(&account) {
+                Some(level) => {
+                    changed = true;
+                    level != AccessLevel::Founder
+                }
+                None => true,
+            });
+        if changed {
+            self.save();
+        }
+    }
+
+    /// Remembers the topic of `channel`, if it is registered.
+    pub fn set_topic(&mut self, channel: &Channel, topic: Option<String>) {
+        if let Some(registered) = self.channels.get_mut(&self.rules.fold(&channel.0)) {
+            registered.topic = topic;
+            self.save();
+        }
+    }
+
+    /// Remembers the modes of `channel`, if it is registered.
+    pub fn set_modes(&mut self, channel: &Channel, modes: BTreeSet<char>) {
+        if let Some(registered) = self.channels.get_mut(&self.rules.fold(&channel.0)) {
+            registered.modes = modes;
+            self.save();
+        }
+    }
+
+    /// Writes every registered channel to the file the store was loaded from.
+    /// Changes are saved as they are made, so this is only needed after a save failed,
+    /// or to be sure of it as the server stops.
+    pub fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let lines = self.channels.values().flat_map(|channel| {
+            let modes = channel.modes.iter().collect::<String>();
+            let topic = channel.topic.as_deref().unwrap_or_default();
+            std::iter::once(format!("C\t{}\t+{modes}\t{topic}", channel.name)).chain(
+                channel
+                    .access
+                    .iter()
+                    .map(|(account, level)| format!("A\t{}\t{account}\t{level}", channel.name)),
+            )
+        });
+        if let Err(e) = store::write_lines(path, lines) {
+            error!("Failed to save channels to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// A command sent to ChanServ, for example `PRIVMSG ChanServ :OP #channel`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ChanServCommand {
+    /// `REGISTER <#channel>`: register a channel you are an operator of.
+    Register { channel: Channel },
+    /// `OP <#channel> [nick]`: give operator status, by default to yourself.
+    Op {
+        channel: Channel,
+        nick: Option<String>,
+    },
+    /// `DEOP <#channel> [nick]`: take operator status, by default from yourself.
+    Deop {
+        channel: Channel,
+        nick: Option<String>,
+    },
+    /// `ACCESS <#channel> LIST`: show the access list.
+    AccessList { channel: Channel },
+    /// `ACCESS <#channel> ADD <account> <voice|op>`: add to or change the access list.
+    AccessAdd {
+        channel: Channel,
+        account: String,
+        level: AccessLevel,
+    },
+    /// `ACCESS <#channel> DEL <account>`: remove from the access list.
+    AccessDel { channel: Channel, account: String },
+    /// `DROP <#channel>`: unregister a channel you founded.
+    Drop { channel: Channel },
+    /// `HELP`: list the commands above.
+    Help,
+}
+
+impl TryFrom<&str> for ChanServCommand {
+    /// A usage message to show the user.
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let args = value.split_whitespace().collect::<Vec<_>>();
+        let command = args
+            .first()
+            .map(|c| c.to_ascii_uppercase())
+            .unwrap_or_default();
+        let rest = args.get(1..).unwrap_or_default();
+        let channel = match rest.first() {
+            Some(channel) => Some(
+                Channel::try_from(channel.to_string())
+                    .map_err(|_| format!("{channel} is not a valid channel."))?,
+            ),
+            None => None,
+        };
+        let sub = rest.get(1).map(|s| s.to_ascii_uppercase());
+        match (command.as_str(), channel, sub.as_deref(), rest.get(1..)) {
+            ("REGISTER", Some(channel), None, _) => Ok(ChanServCommand::Register { channel }),
+            ("OP", Some(channel), _, Some([] | [_])) => Ok(ChanServCommand::Op {
+                channel,
+                nick: rest.get(1).map(|n| n.to_string()),
+            }),
+            ("DEOP", Some(channel), _, Some([] | [_])) => Ok(ChanServCommand::Deop {
+                channel,
+                nick: rest.get(1).map(|n| n.to_string()),
+            }),
+            ("ACCESS", Some(channel), Some("LIST"), Some([_])) => {
+                Ok(ChanServCommand::AccessList { channel })
+            }
+            ("ACCESS", Some(channel), Some("ADD"), Some([_, account, level])) => {
+                match AccessLevel::try_from(*level) {
+                    Ok(level) if level != AccessLevel::Founder => Ok(ChanServCommand::AccessAdd {
+                        channel,
+                        account: account.to_string(),
+                        level,
+                    }),
+                    _ => Err("Access level must be one of: voice, op".to_string()),
+                }
+            }
+            ("ACCESS", Some(channel), Some("DEL"), Some([_, account])) => {
+                Ok(ChanServCommand::AccessDel {
+                    channel,
+                    account: account.to_string(),
+                })
+            }
+            ("DROP", Some(channel), None, _) => Ok(ChanServCommand::Drop { channel }),
+            ("HELP", _, _, _) => Ok(ChanServCommand::Help),
+            ("REGISTER", _, _, _) => Err("Syntax: REGISTER <#channel>".to_string()),
+            ("OP", _, _, _) => Err("Syntax: OP <#channel> [nick]".to_string()),
+            ("DEOP", _, _, _) => Err("Syntax: DEOP <#channel> [nick]".to_string()),
+            ("ACCESS", _, _, _) => Err(
+                "Syntax: ACCESS <#channel> LIST | ADD <account> <voice|op> | DEL <account>"
+                    .to_string(),
+            ),
+            ("DROP", _, _, _) => Err("Syntax: DROP <#channel>".to_string()),
+            _ => Err(format!(
+                "Unknown command {}. Use HELP for a list of commands.",
+                args.first().unwrap_or(&"")
+            )),
+        }
+    }
+}
+
+/// The lines sent in response to `HELP`.
+pub const HELP: [&str; 8] = [
+    "REGISTER <#channel>                       - Register a channel you are an operator of",
+    "OP <#channel> [nick]                      - Give operator status",
+    "DEOP <#channel> [nick]                    - Take operator status",
+    "ACCESS <#channel> LIST                    - Show the access list",
+    "ACCESS <#channel> ADD <account> <level>   - Give an account voice or op access",
+    "ACCESS <#channel> DEL <account>           - Remove an account from the access list",
+    "DROP <#channel>                           - Unregister a channel you founded",
+    "HELP                                      - Show this list",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn haku() -> Channel {
+        Channel("#haku".to_string())
+    }
+
+    #[test]
+    fn test_register_and_access() {
+        let mut chanserv = ChanServ::in_memory(NameRules::default());
+        chanserv
+            .register(&haku(), "Alice", None, BTreeSet::new())
+            .unwrap();
+        assert_eq!(
+            chanserv.register(&Channel("#HAKU".to_string()), "bob", None, BTreeSet::new()),
+            Err(ChanServError::AlreadyRegistered)
+        );
+        assert_eq!(
+            chanserv.access(&haku(), Some("alice")),
+            Some(AccessLevel::Founder)
+        );
+        chanserv
+            .set_access(&haku(), "Bob", Some(AccessLevel::Voice))
+            .unwrap();
+        assert_eq!(
+            chanserv.access(&haku(), Some("bob")),
+            Some(AccessLevel::Voice)
+        );
+        assert_eq!(chanserv.access(&haku(), None), None);
+        assert_eq!(
+            chanserv.set_access(&haku(), "alice", None),
+            Err(ChanServError::Founder)
+        );
+        chanserv.set_access(&haku(), "bob", None).unwrap();
+        assert_eq!(chanserv.access(&haku(), Some("bob")), None);
+    }
+
+    #[test]
+    fn test_forget_account() {
+        let mut chanserv = ChanServ::in_memory(NameRules::default());
+        let team = Channel("#team".to_string());
+        chanserv
+            .register(&haku(), "alice", None, BTreeSet::new())
+            .unwrap();
+        chanserv
+            .register(&team, "bob", None, BTreeSet::new())
+            .unwrap();
+        chanserv
+            .set_access(&team, "Alice", Some(AccessLevel::Op))
+            .unwrap();
+        chanserv.forget_account("ALICE");
+        assert!(chanserv.channel(&haku()).is_none());
+        assert_eq!(chanserv.access(&team, Some("alice")), None);
+        assert_eq!(
+            chanserv.access(&team, Some("bob")),
+            Some(AccessLevel::Founder)
+        );
+    }
+
+    #[test]
+    fn test_persistence() {
+        let path = std::env::temp_dir().join(format!("iris-chanserv-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut chanserv = ChanServ::load(&path, NameRules::default()).unwrap();
+            chanserv
+                .register(&haku(), "alice", None, BTreeSet::from(['n']))
+                .unwrap();
+            chanserv
+                .set_access(&haku(), "bob", Some(AccessLevel::Op))
+                .unwrap();
+            chanserv.set_topic(&haku(), Some("Welcome\tto haku".to_string()));
+            chanserv.set_modes(&haku(), BTreeSet::from(['n', 't']));
+        }
+        let chanserv = ChanServ::load(&path, NameRules::default()).unwrap();
+        let registered = chanserv.channel(&haku()).unwrap();
+        assert_eq!(registered.topic.as_deref(), Some("Welcome\tto haku"));
+        assert_eq!(registered.modes, BTreeSet::from(['n', 't']));
+        assert_eq!(chanserv.access(&haku(), Some("bob")), Some(AccessLevel::Op));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(
+            ChanServCommand::try_from("access #haku add bob OP"),
+            Ok(ChanServCommand::AccessAdd {
+                channel: haku(),
+                account: "bob".to_string(),
+                level: AccessLevel::Op
+            })
+        );
+        assert_eq!(
+            ChanServCommand::try_from("OP #haku"),
+            Ok(ChanServCommand::Op {
+                channel: haku(),
+                nick: None
+            })
+        );
+        assert!(ChanServCommand::try_from("ACCESS #haku ADD bob founder").is_err());
+        assert!(ChanServCommand::try_from("REGISTER haku").is_err());
+        assert!(ChanServCommand::try_from("").is_err());
+    }
+}
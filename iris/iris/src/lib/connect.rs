@@ -1,42 +1,82 @@
 use std::{
     error::Error,
     fmt::{self, Debug, Display},
-    io::{Read, Write},
-    net::{IpAddr, SocketAddr, TcpListener, TcpStream},
+    io::{self, Write},
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    sync::{mpsc, watch},
+};
+use tokio_rustls::TlsAcceptor;
+
+use crate::tls::{fingerprint, TlsConfig};
+
+/// How long a client has to finish its TLS handshake before it is dropped.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The reading half of a client's socket, which may be wrapped in TLS.
+type SocketRead = Box<dyn AsyncRead + Send + Unpin>;
+/// The writing half of a client's socket, which may be wrapped in TLS.
+type SocketWrite = Box<dyn AsyncWrite + Send + Unpin>;
 
 pub struct ConnectionManager {
     listener: TcpListener,
+    /// Set for listeners that only take TLS connections.
+    tls: Option<TlsConfig>,
+    stats: Arc<QueueStats>,
+    /// Every writer task holds a clone, so `closed` only ends once they have all finished.
+    open: mpsc::Sender<()>,
+    closed: mpsc::Receiver<()>,
 }
 
 impl ConnectionManager {
-    pub fn launch(address: impl Into<IpAddr>, port: u16) -> Self {
-        let address = address.into();
-        let listener = TcpListener::bind((address, port))
-            .unwrap_or_else(|_| panic!("failed to bind to {address}:{port}"));
+    /// Listens on `address`; port 0 picks any free port, see `local_addr`.
+    /// With `tls`, every client must start with a TLS handshake.
+    /// The connections accepted keep their send queue depths in `stats`.
+    /// Must be called from within a tokio runtime.
+    pub fn bind(
+        address: impl Into<SocketAddr>,
+        tls: Option<TlsConfig>,
+        stats: Arc<QueueStats>,
+    ) -> io::Result<Self> {
+        let listener = std::net::TcpListener::bind(address.into())?;
+        listener.set_nonblocking(true)?;
+        let (open, closed) = mpsc::channel(1);
 
-        Self { listener }
+        Ok(Self {
+            listener: TcpListener::from_std(listener)?,
+            tls,
+            stats,
+            open,
+            closed,
+        })
     }
 
-    pub fn accept_new_connection(&mut self) -> (ConnectionRead, ConnectionWrite) {
+    /// The address actually being listened on.
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.listener.local_addr()
+    }
+
+    /// Waits for the next client. Any TLS handshake is left to [`Accepted::establish`],
+    /// so a slow client doesn't hold up the ones after it.
+    pub async fn accept_new_connection(&mut self) -> Accepted {
         loop {
-            match self.listener.accept() {
+            match self.listener.accept().await {
                 Ok((socket, addr)) => {
-                    let (socket_read, socket_write) = (
-                        match socket.try_clone() {
-                            Ok(socket) => socket,
-                            Err(err) => {
-                                eprintln!("[WARN] Failed to clone socket: {err}");
-                                continue;
-                            }
-                        },
+                    return Accepted {
                         socket,
-                    );
-
-                    return (
-                        ConnectionRead::from_socket(socket_read, addr),
-                        ConnectionWrite::from_socket(socket_write, addr),
-                    );
+                        addr,
+                        tls: self.tls.as_ref().map(TlsConfig::acceptor),
+                        stats: self.stats.clone(),
+                        open: self.open.clone(),
+                    };
                 }
                 Err(err) => {
                     eprintln!("[WARN] failed to connect to client: {err}");
@@ -44,18 +84,318 @@ impl ConnectionManager {
             }
         }
     }
+
+    /// Stops listening, then waits until every connection accepted has finished writing.
+    pub async fn close(self) {
+        let ConnectionManager {
+            listener,
+            open,
+            tls: _,
+            stats: _,
+            mut closed,
+        } = self;
+        drop((listener, open));
+        let _ = closed.recv().await;
+    }
 }
 
-pub struct ConnectionRead {
+/// A client whose connection has been accepted, but may not have finished its TLS handshake.
+pub struct Accepted {
     socket: TcpStream,
+    addr: SocketAddr,
+    tls: Option<TlsAcceptor>,
+    stats: Arc<QueueStats>,
+    open: mpsc::Sender<()>,
+}
+
+impl Accepted {
+    pub fn id(&self) -> String {
+        self.addr.to_string()
+    }
+
+    /// Finishes the TLS handshake if the client connected to a TLS listener,
+    /// then splits the connection into the halves it is read and written through.
+    pub async fn establish(self) -> io::Result<(ConnectionRead, ConnectionWrite)> {
+        let Accepted {
+            socket,
+            addr,
+            tls,
+            stats,
+            open,
+        } = self;
+        let (socket_read, socket_write, secure): (SocketRead, SocketWrite, _) = match tls {
+            None => {
+                let (socket_read, socket_write) = socket.into_split();
+                (Box::new(socket_read), Box::new(socket_write), None)
+            }
+            Some(acceptor) => {
+                let stream = tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(socket))
+                    .await
+                    .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+                let certfp = stream
+                    .get_ref()
+                    .1
+                    .peer_certificates()
+                    .and_then(<[_]>::first)
+                    .map(|cert| fingerprint(cert));
+                let (socket_read, socket_write) = tokio::io::split(stream);
+                (Box::new(socket_read), Box::new(socket_write), Some(certfp))
+            }
+        };
+        let (closing, closed) = watch::channel(false);
+
+        let sink = TcpSink::spawn(socket_write, closing, stats.clone(), open);
+        let mut conn_write = ConnectionWrite::new(addr.to_string(), sink);
+        conn_write.stats = Some(stats);
+        if let Some(certfp) = secure {
+            conn_write.set_secure(certfp);
+        }
+        let conn_read =
+            ConnectionRead::from_socket(socket_read, addr, closed, conn_write.flood_exempt.clone());
+
+        Ok((conn_read, conn_write))
+    }
+}
+
+pub struct ConnectionRead {
+    socket: SocketRead,
     socket_addr: SocketAddr,
+    /// Becomes true once the writing side has closed the connection.
+    closed: watch::Receiver<bool>,
+    /// Shared with the `ConnectionWrite`, see `ConnectionWrite::set_flood_exempt`.
+    flood_exempt: Arc<AtomicBool>,
     buffer: Box<[u8; 512]>,
     buflen: usize,
 }
 
 pub struct ConnectionWrite {
-    socket: TcpStream,
-    socket_addr: SocketAddr,
+    sink: Box<dyn Sink>,
+    id: String,
+    held: Option<Vec<String>>,
+    /// The bytes of the messages in `held`, which count towards the SendQ too.
+    held_bytes: usize,
+    /// The most bytes allowed to wait in the sink before the connection is dropped.
+    sendq: usize,
+    /// Why writing stopped, once the connection went over its SendQ or its sink failed.
+    failure: Option<&'static str>,
+    /// Where the connection's id is pushed when it fails, see `report_failures`.
+    failed: Option<Arc<Mutex<Vec<String>>>>,
+    stats: Option<Arc<QueueStats>>,
+    flood_exempt: Arc<AtomicBool>,
+    /// Whether the connection is over TLS.
+    secure: bool,
+    /// The SHA-256 fingerprint of the client's certificate, if it presented one.
+    certfp: Option<String>,
+}
+
+/// Where the messages written to a connection end up: its socket, or memory when testing.
+pub trait Sink: Write + Send {
+    /// Closes the connection, which also ends the reading side of a socket.
+    fn close(&mut self);
+
+    /// Bytes written but not sent yet.
+    fn queued(&self) -> usize {
+        0
+    }
+}
+
+/// How much is waiting to be sent, across every connection sharing these stats.
+#[derive(Debug, Default)]
+pub struct QueueStats {
+    queued: AtomicUsize,
+    peak: AtomicUsize,
+    exceeded: AtomicUsize,
+}
+
+impl QueueStats {
+    /// Bytes written to connections but not sent yet.
+    pub fn queued_bytes(&self) -> usize {
+        self.queued.load(Ordering::Relaxed)
+    }
+
+    /// The most bytes that have been waiting for any single connection.
+    pub fn peak_bytes(&self) -> usize {
+        self.peak.load(Ordering::Relaxed)
+    }
+
+    /// How many connections were dropped for exceeding their SendQ.
+    pub fn sendq_exceeded(&self) -> usize {
+        self.exceeded.load(Ordering::Relaxed)
+    }
+}
+
+/// How closely the connections from some hosts are watched for going quiet, and how many of
+/// them are let in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ConnectionClass {
+    /// A wildcard mask for the hosts in this class, for example `192.168.*`.
+    pub hosts: String,
+    /// How long a registered client may send nothing before the server sends it a PING.
+    pub ping_frequency: Duration,
+    /// How long the client then has to answer, before it is dropped with `Ping timeout`.
+    pub ping_timeout: Duration,
+    /// How long a client has to register, before it is dropped with `Registration timeout`.
+    pub registration_timeout: Duration,
+    /// The most connections allowed at once from a single IP address.
+    pub max_per_ip: usize,
+    /// The most connections allowed at once from a single network, see `cidr_ipv4` and `cidr_ipv6`.
+    pub max_per_cidr: usize,
+    /// How many leading bits of an IPv4 address make up its network.
+    pub cidr_ipv4: u8,
+    /// How many leading bits of an IPv6 address make up its network.
+    pub cidr_ipv6: u8,
+    /// The most times a single IP address may connect within `throttle_window`.
+    pub throttle: usize,
+    pub throttle_window: Duration,
+}
+
+impl Default for ConnectionClass {
+    /// Every host, pinged after two minutes of quiet, with up to 10 connections from an address
+    /// and 50 from a /24 (or /64) network, and 10 reconnects every 10 seconds.
+    fn default() -> Self {
+        ConnectionClass {
+            hosts: "*".to_string(),
+            ping_frequency: Duration::from_secs(120),
+            ping_timeout: Duration::from_secs(60),
+            registration_timeout: Duration::from_secs(30),
+            max_per_ip: 10,
+            max_per_cidr: 50,
+            cidr_ipv4: 24,
+            cidr_ipv6: 64,
+            throttle: 10,
+            throttle_window: Duration::from_secs(10),
+        }
+    }
+}
+
+/// Hands what is written to a task that sends it down the socket, so writing never blocks.
+struct TcpSink {
+    queue: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    /// Bytes handed to the task that it hasn't sent yet.
+    depth: Arc<AtomicUsize>,
+    stats: Arc<QueueStats>,
+}
+
+impl TcpSink {
+    fn spawn(
+        mut socket: SocketWrite,
+        closing: watch::Sender<bool>,
+        stats: Arc<QueueStats>,
+        open: mpsc::Sender<()>,
+    ) -> Self {
+        let (queue, mut queued) = mpsc::unbounded_channel::<Vec<u8>>();
+        let depth = Arc::new(AtomicUsize::new(0));
+        let sink = TcpSink {
+            queue: Some(queue),
+            depth: depth.clone(),
+            stats: stats.clone(),
+        };
+        let sent = move |bytes: &[u8]| {
+            depth.fetch_sub(bytes.len(), Ordering::Relaxed);
+            stats.queued.fetch_sub(bytes.len(), Ordering::Relaxed);
+        };
+        tokio::spawn(async move {
+            while let Some(bytes) = queued.recv().await {
+                let written = socket.write_all(&bytes).await;
+                sent(&bytes);
+                if written.is_err() {
+                    break;
+                }
+            }
+            // Whatever is left can't be sent any more.
+            queued.close();
+            while let Ok(bytes) = queued.try_recv() {
+                sent(&bytes);
+            }
+            let _ = socket.shutdown().await;
+            let _ = closing.send(true);
+            drop(open);
+        });
+        sink
+    }
+}
+
+impl Write for TcpSink {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let Some(queue) = &self.queue else {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        };
+        // Counted before sending, so the task never takes away bytes that haven't been added.
+        let depth = self.depth.fetch_add(buf.len(), Ordering::Relaxed) + buf.len();
+        self.stats.queued.fetch_add(buf.len(), Ordering::Relaxed);
+        if queue.send(buf.to_vec()).is_err() {
+            self.depth.fetch_sub(buf.len(), Ordering::Relaxed);
+            self.stats.queued.fetch_sub(buf.len(), Ordering::Relaxed);
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.stats.peak.fetch_max(depth, Ordering::Relaxed);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink for TcpSink {
+    fn close(&mut self) {
+        // The task sends whatever is still queued before shutting the socket.
+        self.queue = None;
+    }
+
+    fn queued(&self) -> usize {
+        self.depth.load(Ordering::Relaxed)
+    }
+}
+
+/// A sink that keeps what is written to it, so it can be read back.
+/// Clones share the same contents.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink {
+    written: Arc<Mutex<Vec<u8>>>,
+    closed: Arc<AtomicBool>,
+}
+
+impl MemorySink {
+    /// Takes the lines written since the last call, without their line endings.
+    pub fn take_lines(&self) -> Vec<String> {
+        let written = std::mem::take(&mut *self.written.lock().unwrap());
+        String::from_utf8_lossy(&written)
+            .lines()
+            .map(str::to_string)
+            .collect()
+    }
+
+    /// Whether the connection has been closed.
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::Relaxed)
+    }
+}
+
+impl Write for MemorySink {
+    /// Fails once the sink is closed, as writing to a closed socket would.
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.is_closed() {
+            return Err(io::ErrorKind::BrokenPipe.into());
+        }
+        self.written.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Sink for MemorySink {
+    fn close(&mut self) {
+        self.closed.store(true, Ordering::Relaxed);
+    }
+
+    /// Everything not yet taken with `take_lines`.
+    fn queued(&self) -> usize {
+        self.written.lock().unwrap().len()
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -75,10 +415,17 @@ impl Display for ConnectionError {
 impl Error for ConnectionError {}
 
 impl ConnectionRead {
-    fn from_socket(socket: TcpStream, socket_addr: SocketAddr) -> Self {
+    fn from_socket(
+        socket: SocketRead,
+        socket_addr: SocketAddr,
+        closed: watch::Receiver<bool>,
+        flood_exempt: Arc<AtomicBool>,
+    ) -> Self {
         Self {
             socket,
             socket_addr,
+            closed,
+            flood_exempt,
             buffer: Box::from([0; 512]),
             buflen: 0,
         }
@@ -92,12 +439,18 @@ impl ConnectionRead {
             .map(|(index, _)| index)
     }
 
-    pub fn read_message(&mut self) -> Result<String, ConnectionError> {
+    pub async fn read_message(&mut self) -> Result<String, ConnectionError> {
         use std::io::ErrorKind;
 
         if self.buffer_crlf().is_none() {
             let n_bytes = loop {
-                break match self.socket.read(&mut self.buffer[self.buflen..]) {
+                let read = tokio::select! {
+                    read = self.socket.read(&mut self.buffer[self.buflen..]) => read,
+                    _ = self.closed.wait_for(|closed| *closed) => {
+                        return Err(ConnectionError::ConnectionClosed)
+                    }
+                };
+                break match read {
                     Ok(0) => return Err(ConnectionError::ConnectionClosed),
                     Ok(n_bytes) => n_bytes,
                     Err(err) => {
@@ -125,7 +478,7 @@ impl ConnectionRead {
         let after_crlf = end + 2;
 
         self.buffer.copy_within(after_crlf..self.buflen, 0);
-        self.buflen = self.buflen - after_crlf;
+        self.buflen -= after_crlf;
 
         let message = String::from_utf8(bytes).map_err(|_| ConnectionError::MessageInvalidUtf8)?;
 
@@ -135,27 +488,139 @@ impl ConnectionRead {
     pub fn id(&self) -> String {
         self.socket_addr.to_string()
     }
+
+    /// Whether the messages read may be handled as fast as they arrive.
+    pub fn flood_exempt(&self) -> bool {
+        self.flood_exempt.load(Ordering::Relaxed)
+    }
 }
 
 impl ConnectionWrite {
-    fn from_socket(socket: TcpStream, socket_addr: SocketAddr) -> Self {
+    /// A connection known as `id` (usually its IP Address + Port), written to `sink`.
+    pub fn new(id: impl Into<String>, sink: impl Sink + 'static) -> Self {
         Self {
-            socket,
-            socket_addr,
+            sink: Box::new(sink),
+            id: id.into(),
+            held: None,
+            held_bytes: 0,
+            sendq: usize::MAX,
+            failure: None,
+            failed: None,
+            stats: None,
+            flood_exempt: Arc::default(),
+            secure: false,
+            certfp: None,
+        }
+    }
+
+    /// Limits how many bytes may wait to be sent. A message that would go over the limit
+    /// closes the connection with `ERROR :SendQ exceeded` instead, see `failure`.
+    pub fn set_sendq(&mut self, bytes: usize) {
+        self.sendq = bytes;
+    }
+
+    /// Why the connection can no longer be written to, if it can't:
+    /// `SendQ exceeded` when it went over its SendQ, `Connection reset` when its sink failed.
+    /// Anything written since is discarded.
+    pub fn failure(&self) -> Option<&'static str> {
+        self.failure
+    }
+
+    /// Pushes the connection's id to `failed` when it fails, so failed connections can be found
+    /// without checking every one.
+    pub fn report_failures(&mut self, failed: Arc<Mutex<Vec<String>>>) {
+        if self.failure.is_some() {
+            failed.lock().unwrap().push(self.id.clone());
         }
+        self.failed = Some(failed);
+    }
+
+    /// Lets the client send messages as fast as it likes, or stops letting it.
+    /// The reading side of the connection sees the change straight away.
+    pub fn set_flood_exempt(&self, exempt: bool) {
+        self.flood_exempt.store(exempt, Ordering::Relaxed);
+    }
+
+    /// Marks the connection as made over TLS, with a client certificate fingerprinted `certfp` if any.
+    pub fn set_secure(&mut self, certfp: Option<String>) {
+        self.secure = true;
+        self.certfp = certfp;
+    }
+
+    /// Whether the connection is over TLS.
+    pub fn secure(&self) -> bool {
+        self.secure
+    }
+
+    /// The SHA-256 fingerprint of the client's certificate, as lowercase hex, if it presented one.
+    pub fn certfp(&self) -> Option<&str> {
+        self.certfp.as_deref()
+    }
+
+    /// Bytes written but not sent yet.
+    pub fn queued(&self) -> usize {
+        self.sink.queued()
     }
 
     pub fn write_message(&mut self, message: &str) -> Result<(), ConnectionError> {
-        self.socket
-            .write_all(message.as_bytes())
-            .map_err(|_| ConnectionError::ConnectionClosed)?;
-        let _ = self.socket.flush();
+        if self.failure.is_some() {
+            return Err(ConnectionError::ConnectionClosed);
+        }
+        let queued = self.sink.queued().saturating_add(self.held_bytes);
+        if queued.saturating_add(message.len()) > self.sendq {
+            if let Some(stats) = &self.stats {
+                stats.exceeded.fetch_add(1, Ordering::Relaxed);
+            }
+            self.release_messages();
+            let _ = self.sink.write_all(b"ERROR :SendQ exceeded\r\n");
+            return Err(self.fail("SendQ exceeded"));
+        }
+        if let Some(held) = &mut self.held {
+            held.push(message.to_string());
+            self.held_bytes += message.len();
+            return Ok(());
+        }
+        if self.sink.write_all(message.as_bytes()).is_err() {
+            return Err(self.fail("Connection reset"));
+        }
+        let _ = self.sink.flush();
 
         Ok(())
     }
 
+    /// Stops writing for `failure`, closing the connection and reporting it.
+    fn fail(&mut self, failure: &'static str) -> ConnectionError {
+        self.failure = Some(failure);
+        self.sink.close();
+        if let Some(failed) = &self.failed {
+            failed.lock().unwrap().push(self.id.clone());
+        }
+        ConnectionError::ConnectionClosed
+    }
+
+    /// Holds back every message written from now on, until `release_messages` is called.
+    /// Held messages count towards the SendQ, and going over it discards them.
+    pub fn hold_messages(&mut self) {
+        self.held.get_or_insert_with(Vec::new);
+    }
+
+    /// Stops holding messages back, returning the ones written since `hold_messages`.
+    pub fn release_messages(&mut self) -> Vec<String> {
+        self.held_bytes = 0;
+        self.held.take().unwrap_or_default()
+    }
+
+    /// Closes both halves of the connection, which also ends the matching `ConnectionRead`.
+    /// Any messages being held back are sent first.
+    pub fn shutdown(&mut self) {
+        for message in self.release_messages() {
+            let _ = self.write_message(&message);
+        }
+        self.sink.close();
+    }
+
     pub fn id(&self) -> String {
-        self.socket_addr.to_string()
+        self.id.clone()
     }
 }
 
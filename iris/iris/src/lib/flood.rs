@@ -0,0 +1,136 @@
+//! # Flood
+//! Keeps a client from sending commands faster than the server is willing to handle them.
+//!
+//! Every command costs some points, and a client may spend up to its burst of points at once.
+//! After that its commands are held back ("fakelag"), each point taking the penalty to earn
+//! back, until the client falls so far behind that it is disconnected with `Excess Flood`.
+use std::time::{Duration, Instant};
+
+use crate::{
+    nickserv::{NickServCommand, NICKSERV},
+    types::MessageRef,
+};
+
+/// How fast a client may send commands before being slowed down, and then disconnected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FloodPolicy {
+    /// The points a client may spend at once before its commands are held back.
+    pub burst: u32,
+    /// How long each point spent takes to earn back.
+    pub penalty: Duration,
+    /// How many bytes of commands may be held back before the client is disconnected.
+    pub recvq: usize,
+}
+
+impl Default for FloodPolicy {
+    /// Ten points at once, then two a second, with up to 8 KiB held back.
+    fn default() -> Self {
+        FloodPolicy {
+            burst: 10,
+            penalty: Duration::from_millis(500),
+            recvq: 8192,
+        }
+    }
+}
+
+/// The points a command costs: commands with long replies cost more, and the ones that keep a
+/// connection alive or close it cost nothing, so they are never held back by their own price.
+/// NickServ commands with a password cost a whole default burst, as hashing it takes a while.
+pub fn command_cost(line: &str) -> u32 {
+    let message = MessageRef::parse(line);
+    match message.command.to_ascii_uppercase().as_str() {
+        "PONG" | "QUIT" => 0,
+        "PRIVMSG"
+            if message
+                .param(0)
+                .is_some_and(|target| target.eq_ignore_ascii_case(NICKSERV))
+                && message
+                    .param(1)
+                    .and_then(|text| NickServCommand::try_from(text).ok())
+                    .is_some_and(|command| command.password().is_some()) =>
+        {
+            10
+        }
+        "NICK" | "JOIN" | "PART" | "PLUGIN" => 2,
+        "NAMES" | "WHO" | "CHATHISTORY" => 3,
+        _ => 1,
+    }
+}
+
+/// One client's standing under a [`FloodPolicy`].
+#[derive(Debug, Clone)]
+pub struct FloodLimiter {
+    policy: FloodPolicy,
+    /// When the client will have earned back every point it has spent.
+    since: Instant,
+}
+
+impl FloodLimiter {
+    /// A client that hasn't spent anything yet.
+    pub fn new(policy: FloodPolicy) -> Self {
+        FloodLimiter {
+            policy,
+            since: Instant::now(),
+        }
+    }
+
+    /// When a command costing `cost` may be handled, which is `now` unless that would take
+    /// the client over its burst. One costing more than the whole burst waits until the client
+    /// has earned back every point.
+    pub fn ready_at(&self, cost: u32, now: Instant) -> Instant {
+        let since = self.since.max(now) + self.policy.penalty * cost.min(self.policy.burst);
+        let allowance = self.policy.penalty * self.policy.burst;
+        since
+            .checked_sub(allowance)
+            .map_or(now, |ready| ready.max(now))
+    }
+
+    /// Spends `cost` points on a command handled at `now`.
+    pub fn charge(&mut self, cost: u32, now: Instant) {
+        self.since = self.since.max(now) + self.policy.penalty * cost;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_command_cost() {
+        assert_eq!(command_cost("PRIVMSG #haku :hi"), 1);
+        assert_eq!(command_cost("@label=a join #haku"), 2);
+        assert_eq!(command_cost(":tom WHO #haku"), 3);
+        assert_eq!(command_cost("PONG :iris-server"), 0);
+        assert_eq!(command_cost("PRIVMSG nickserv :IDENTIFY hunter2"), 10);
+        assert_eq!(command_cost("PRIVMSG NickServ :GHOST tom"), 1);
+    }
+
+    #[test]
+    fn test_burst_then_penalty() {
+        let policy = FloodPolicy {
+            burst: 3,
+            penalty: Duration::from_secs(1),
+            recvq: 512,
+        };
+        let mut limiter = FloodLimiter::new(policy);
+        let start = Instant::now();
+        for _ in 0..3 {
+            assert_eq!(limiter.ready_at(1, start), start);
+            limiter.charge(1, start);
+        }
+        let next = limiter.ready_at(1, start);
+        assert_eq!(next, start + Duration::from_secs(1));
+        assert_eq!(limiter.ready_at(2, start), start + Duration::from_secs(2));
+        assert_eq!(limiter.ready_at(0, start), start);
+        limiter.charge(1, next);
+        // Points are earned back while the client is quiet.
+        let later = start + Duration::from_secs(10);
+        assert_eq!(limiter.ready_at(3, later), later);
+        // A command costing more than the burst only waits for every point to be earned back,
+        // instead of forever, and what it costs past the burst holds back the next ones.
+        assert_eq!(limiter.ready_at(10, later), later);
+        limiter.charge(10, later);
+        assert_eq!(limiter.ready_at(1, later), later + Duration::from_secs(8));
+        assert_eq!(limiter.ready_at(10, later), later + Duration::from_secs(10));
+    }
+}
@@ -0,0 +1,1095 @@
+//! # History
+//! A store of recent channel and private messages, replayed to users through the IRCv3
+//! `draft/chathistory` extension.
+//!
+//! Every conversation (a channel, or the messages between two accounts) keeps only its most
+//! recent messages, and is persisted to its own file in the history directory so it survives
+//! restarts. Files are named by a hash of the conversation, never by the names in it.
+//! Each message is appended to its file, which is only rewritten without the messages
+//! that have expired once it holds twice as many as are kept. The files are written by a thread
+//! of their own, which takes the changes queued since it last wrote and makes them together,
+//! so relaying a message never waits for the disk.
+//! Deciding who may read a conversation is left to the server thread.
+use std::{
+    collections::{HashMap, HashSet, VecDeque},
+    fmt::{self, Display, Write as _},
+    fs, io,
+    path::PathBuf,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::error;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    store,
+    types::{
+        unique_id, Channel, ChatHistoryMsg, NameRules, Nick, NoticeMsg, NoticeReply, Prefix,
+        PrivMsg, PrivReply, Reply, Target,
+    },
+};
+
+/// The most messages (or targets) a single `CHATHISTORY` request may return.
+pub const MAX_LIMIT: usize = 100;
+
+/// Which command a stored message was sent with.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MessageKind {
+    PrivMsg,
+    Notice,
+}
+
+impl Display for MessageKind {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageKind::PrivMsg => write!(fmt, "PRIVMSG"),
+            MessageKind::Notice => write!(fmt, "NOTICE"),
+        }
+    }
+}
+
+/// A single message, as it was relayed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HistoryEntry {
+    pub msgid: String,
+    /// Milliseconds since the unix epoch at which the message was sent.
+    pub time: u64,
+    pub sender: Prefix,
+    pub kind: MessageKind,
+    pub target: Target,
+    pub message: String,
+}
+
+impl HistoryEntry {
+    /// The message as it is relayed to its recipients.
+    pub fn reply(&self) -> Reply {
+        match self.kind {
+            MessageKind::PrivMsg => Reply::PrivMsg(PrivReply {
+                message: PrivMsg {
+                    target: self.target.clone(),
+                    message: self.message.clone(),
+                },
+                sender: self.sender.clone(),
+            }),
+            MessageKind::Notice => Reply::Notice(NoticeReply {
+                message: NoticeMsg {
+                    target: self.target.clone(),
+                    message: self.message.clone(),
+                },
+                sender: self.sender.clone(),
+            }),
+        }
+    }
+
+    fn to_line(&self) -> String {
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}",
+            self.msgid, self.time, self.sender, self.kind, self.target, self.message
+        )
+    }
+
+    /// Reads an entry back from its line, for a server called `server_name`.
+    fn from_line(line: &str, server_name: &str) -> Option<Self> {
+        let fields = line.splitn(6, '\t').collect::<Vec<_>>();
+        let [msgid, time, sender, kind, target, message] = fields[..] else {
+            return None;
+        };
+        Some(HistoryEntry {
+            msgid: msgid.to_string(),
+            time: time.parse().ok()?,
+            sender: Prefix::parse(sender, server_name),
+            kind: match kind {
+                "PRIVMSG" => MessageKind::PrivMsg,
+                "NOTICE" => MessageKind::Notice,
+                _ => return None,
+            },
+            target: Target::from(target.to_string()),
+            message: message.to_string(),
+        })
+    }
+}
+
+/// How many messages each conversation keeps; 0 keeps none.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Retention {
+    pub channel: usize,
+    pub direct: usize,
+}
+
+/// A point in a conversation, for example `msgid=1a2b` or `timestamp=2023-01-01T00:00:00.000Z`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MessageRef {
+    MsgId(String),
+    Timestamp(u64),
+}
+
+impl TryFrom<&str> for MessageRef {
+    type Error = HistoryError;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.split_once('=') {
+            Some(("msgid", msgid)) if !msgid.is_empty() => Ok(MessageRef::MsgId(msgid.to_string())),
+            Some(("timestamp", timestamp)) => parse_timestamp(timestamp)
+                .map(MessageRef::Timestamp)
+                .ok_or(HistoryError::InvalidParams),
+            _ => Err(HistoryError::InvalidParams),
+        }
+    }
+}
+
+/// A `CHATHISTORY` request, with its limit already capped at [`MAX_LIMIT`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum HistoryQuery {
+    /// The most recent messages, optionally only those after a point.
+    Latest {
+        target: String,
+        after: Option<MessageRef>,
+        limit: usize,
+    },
+    Before {
+        target: String,
+        anchor: MessageRef,
+        limit: usize,
+    },
+    After {
+        target: String,
+        anchor: MessageRef,
+        limit: usize,
+    },
+    /// Messages on both sides of a point, including the point itself.
+    Around {
+        target: String,
+        anchor: MessageRef,
+        limit: usize,
+    },
+    /// Messages strictly between two points, counted from `start`.
+    Between {
+        target: String,
+        start: MessageRef,
+        end: MessageRef,
+        limit: usize,
+    },
+    /// The conversations with a message between two times.
+    Targets { start: u64, end: u64, limit: usize },
+}
+
+impl HistoryQuery {
+    /// The channel or nickname whose messages are asked for, if any.
+    pub fn target(&self) -> Option<&str> {
+        match self {
+            HistoryQuery::Latest { target, .. }
+            | HistoryQuery::Before { target, .. }
+            | HistoryQuery::After { target, .. }
+            | HistoryQuery::Around { target, .. }
+            | HistoryQuery::Between { target, .. } => Some(target),
+            HistoryQuery::Targets { .. } => None,
+        }
+    }
+}
+
+impl TryFrom<&ChatHistoryMsg> for HistoryQuery {
+    type Error = HistoryError;
+
+    fn try_from(value: &ChatHistoryMsg) -> Result<Self, Self::Error> {
+        let limit = |limit: &String| match limit.parse::<usize>() {
+            Ok(0) | Err(_) => Err(HistoryError::InvalidParams),
+            Ok(limit) => Ok(limit.min(MAX_LIMIT)),
+        };
+        let target = |target: &String| target.to_string();
+        let args = value.args.as_slice();
+        match (value.subcommand.as_str(), args) {
+            ("LATEST", [t, after, l]) => Ok(HistoryQuery::Latest {
+                target: target(t),
+                after: match after.as_str() {
+                    "*" => None,
+                    after => Some(MessageRef::try_from(after)?),
+                },
+                limit: limit(l)?,
+            }),
+            ("BEFORE", [t, anchor, l]) => Ok(HistoryQuery::Before {
+                target: target(t),
+                anchor: MessageRef::try_from(anchor.as_str())?,
+                limit: limit(l)?,
+            }),
+            ("AFTER", [t, anchor, l]) => Ok(HistoryQuery::After {
+                target: target(t),
+                anchor: MessageRef::try_from(anchor.as_str())?,
+                limit: limit(l)?,
+            }),
+            ("AROUND", [t, anchor, l]) => Ok(HistoryQuery::Around {
+                target: target(t),
+                anchor: MessageRef::try_from(anchor.as_str())?,
+                limit: limit(l)?,
+            }),
+            ("BETWEEN", [t, start, end, l]) => Ok(HistoryQuery::Between {
+                target: target(t),
+                start: MessageRef::try_from(start.as_str())?,
+                end: MessageRef::try_from(end.as_str())?,
+                limit: limit(l)?,
+            }),
+            ("TARGETS", [start, end, l]) => {
+                match (
+                    MessageRef::try_from(start.as_str())?,
+                    MessageRef::try_from(end.as_str())?,
+                ) {
+                    (MessageRef::Timestamp(start), MessageRef::Timestamp(end)) => {
+                        Ok(HistoryQuery::Targets {
+                            start,
+                            end,
+                            limit: limit(l)?,
+                        })
+                    }
+                    _ => Err(HistoryError::InvalidParams),
+                }
+            }
+            ("LATEST" | "BEFORE" | "AFTER" | "AROUND" | "BETWEEN" | "TARGETS", _) => {
+                Err(HistoryError::InvalidParams)
+            }
+            _ => Err(HistoryError::UnknownCommand),
+        }
+    }
+}
+
+/// Everything that can be wrong with a `CHATHISTORY` request.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HistoryError {
+    UnknownCommand,
+    InvalidParams,
+    InvalidTarget,
+}
+
+impl HistoryError {
+    /// The code sent in the `FAIL` reply.
+    pub fn code(&self) -> &'static str {
+        match self {
+            HistoryError::UnknownCommand => "UNKNOWN_COMMAND",
+            HistoryError::InvalidParams => "INVALID_PARAMS",
+            HistoryError::InvalidTarget => "INVALID_TARGET",
+        }
+    }
+}
+
+impl Display for HistoryError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HistoryError::UnknownCommand => write!(fmt, "Unknown subcommand"),
+            HistoryError::InvalidParams => write!(fmt, "Invalid parameters"),
+            HistoryError::InvalidTarget => write!(fmt, "Messages could not be retrieved"),
+        }
+    }
+}
+
+/// The message store.
+#[derive(Debug)]
+pub struct History {
+    conversations: HashMap<Conversation, VecDeque<HistoryEntry>>,
+    /// How many messages each conversation's file holds, including expired ones.
+    written: HashMap<Conversation, usize>,
+    /// The conversations with messages appended since their file was last written whole.
+    appended: HashSet<Conversation>,
+    retention: Retention,
+    rules: NameRules,
+    dir: Option<PathBuf>,
+    writer: Option<Writer>,
+}
+
+/// Whose messages are kept together: a channel's, or those between two accounts.
+/// Private messages are kept by account rather than nickname, since nicknames change hands.
+/// Names are kept casefolded; see [`History::channel`] and [`History::direct`].
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum Conversation {
+    Channel(String),
+    Direct(String, String),
+}
+
+impl Conversation {
+    /// The first line of the conversation's file, naming it.
+    fn to_line(&self) -> String {
+        match self {
+            Conversation::Channel(channel) => format!("channel\t{channel}"),
+            Conversation::Direct(first, second) => format!("direct\t{first}\t{second}"),
+        }
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        match line.split('\t').collect::<Vec<_>>()[..] {
+            ["channel", channel] => Some(Conversation::Channel(channel.to_string())),
+            ["direct", first, second] => {
+                Some(Conversation::Direct(first.to_string(), second.to_string()))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// The file a conversation is saved to, named by the SHA-256 of its key so that
+/// nothing a user chose ends up in the path.
+fn file_name(conversation: &Conversation) -> String {
+    let mut name = Sha256::digest(conversation.to_line().as_bytes())
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        });
+    name.push_str(".log");
+    name
+}
+
+impl History {
+    /// Loads the conversations stored in `dir`, which will also be used to save any changes.
+    /// A missing directory is treated as an empty store. Names are compared under `rules`,
+    /// and messages sent as `server_name` are read back as coming from the server.
+    pub fn load(
+        dir: impl Into<PathBuf>,
+        retention: Retention,
+        rules: NameRules,
+        server_name: &str,
+    ) -> io::Result<Self> {
+        let dir = dir.into();
+        let mut history = History {
+            conversations: HashMap::new(),
+            written: HashMap::new(),
+            appended: HashSet::new(),
+            retention,
+            rules,
+            dir: Some(dir.clone()),
+            writer: Some(Writer::spawn()),
+        };
+        let files = match fs::read_dir(&dir) {
+            Ok(files) => files,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(history),
+            Err(e) => return Err(e),
+        };
+        for file in files {
+            let path = file?.path();
+            if path.extension().is_none_or(|extension| extension != "log") {
+                continue;
+            }
+            let lines = store::read_lines(&path)?;
+            let Some(conversation) = lines.first().and_then(|line| Conversation::from_line(line))
+            else {
+                error!(
+                    "Skipping history file {} with no conversation",
+                    path.display()
+                );
+                continue;
+            };
+            let mut entries = VecDeque::new();
+            for line in &lines[1..] {
+                match HistoryEntry::from_line(line, server_name) {
+                    Some(entry) => entries.push_back(entry),
+                    None => error!("Skipping malformed history entry in {}", path.display()),
+                }
+            }
+            // The retention may have been lowered since the file was written.
+            let limit = history.limit(&conversation);
+            while entries.len() > limit {
+                entries.pop_front();
+            }
+            if !entries.is_empty() {
+                history
+                    .written
+                    .insert(conversation.clone(), lines.len() - 1);
+                history.conversations.insert(conversation, entries);
+            }
+        }
+        Ok(history)
+    }
+
+    /// A message store that is never written to disk.
+    pub fn in_memory(retention: Retention, rules: NameRules) -> Self {
+        History {
+            conversations: HashMap::new(),
+            written: HashMap::new(),
+            appended: HashSet::new(),
+            retention,
+            rules,
+            dir: None,
+            writer: None,
+        }
+    }
+
+    /// The messages sent to a channel.
+    pub fn channel(&self, channel: &Channel) -> Conversation {
+        Conversation::Channel(self.rules.fold(&channel.0))
+    }
+
+    /// The private messages between two accounts, whichever of them sent each one.
+    pub fn direct(&self, account: &str, other: &str) -> Conversation {
+        let mut accounts = [self.rules.fold(account), self.rules.fold(other)];
+        accounts.sort();
+        let [first, second] = accounts;
+        Conversation::Direct(first, second)
+    }
+
+    fn limit(&self, conversation: &Conversation) -> usize {
+        match conversation {
+            Conversation::Channel(_) => self.retention.channel,
+            Conversation::Direct(..) => self.retention.direct,
+        }
+    }
+
+    /// Stamps a message with an id and the current time, keeping it in `conversation` if that has any retention.
+    /// A message with no conversation, such as one between users who aren't logged in, is never kept.
+    pub fn record(
+        &mut self,
+        conversation: Option<Conversation>,
+        sender: &Prefix,
+        kind: MessageKind,
+        target: &Target,
+        message: &str,
+    ) -> HistoryEntry {
+        let entry = HistoryEntry {
+            msgid: unique_id(),
+            time: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_millis() as u64)
+                .unwrap_or(0),
+            sender: sender.clone(),
+            kind,
+            target: target.clone(),
+            message: message.to_string(),
+        };
+        let Some(conversation) = conversation else {
+            return entry;
+        };
+        let limit = self.limit(&conversation);
+        if limit > 0 {
+            let entries = self.conversations.entry(conversation.clone()).or_default();
+            entries.push_back(entry.clone());
+            while entries.len() > limit {
+                entries.pop_front();
+            }
+            self.save(&conversation, &entry, limit);
+        }
+        entry
+    }
+
+    /// The messages of `conversation` asked for, oldest first.
+    /// A `TARGETS` query is answered by [`History::targets`] instead, and has no messages.
+    pub fn query(&self, conversation: &Conversation, query: &HistoryQuery) -> Vec<&HistoryEntry> {
+        let Some(entries) = self.conversations.get(conversation) else {
+            return vec![];
+        };
+        // Where a point falls: the messages before it end at the first index, and those after start at the second.
+        let bounds = |anchor: &MessageRef| match anchor {
+            MessageRef::MsgId(msgid) => entries
+                .iter()
+                .position(|e| &e.msgid == msgid)
+                .map(|i| (i, i + 1)),
+            MessageRef::Timestamp(time) => Some((
+                entries.partition_point(|e| e.time < *time),
+                entries.partition_point(|e| e.time <= *time),
+            )),
+        };
+        let first = |start: usize, end: usize, limit: usize| {
+            entries
+                .range(start..end.max(start))
+                .take(limit)
+                .collect::<Vec<_>>()
+        };
+        let last = |start: usize, end: usize, limit: usize| {
+            let end = end.max(start);
+            entries
+                .range(end.saturating_sub(limit).max(start)..end)
+                .collect::<Vec<_>>()
+        };
+        match query {
+            HistoryQuery::Latest {
+                after: None, limit, ..
+            } => last(0, entries.len(), *limit),
+            HistoryQuery::Latest {
+                after: Some(after),
+                limit,
+                ..
+            } => bounds(after).map_or(vec![], |(_, start)| last(start, entries.len(), *limit)),
+            HistoryQuery::Before { anchor, limit, .. } => {
+                bounds(anchor).map_or(vec![], |(end, _)| last(0, end, *limit))
+            }
+            HistoryQuery::After { anchor, limit, .. } => {
+                bounds(anchor).map_or(vec![], |(_, start)| first(start, entries.len(), *limit))
+            }
+            HistoryQuery::Around { anchor, limit, .. } => {
+                bounds(anchor).map_or(vec![], |(middle, _)| {
+                    let mut around = last(0, middle, limit / 2);
+                    around.extend(first(middle, entries.len(), limit - around.len()));
+                    around
+                })
+            }
+            HistoryQuery::Between {
+                start, end, limit, ..
+            } => match (bounds(start), bounds(end)) {
+                (Some((_, after_start)), Some((before_end, _))) if after_start <= before_end => {
+                    first(after_start, before_end, *limit)
+                }
+                // The points were given newest first, so count back from `start`.
+                (Some((before_start, _)), Some((_, after_end))) => {
+                    last(after_end, before_start, *limit)
+                }
+                _ => vec![],
+            },
+            HistoryQuery::Targets { .. } => vec![],
+        }
+    }
+
+    /// The conversations whose latest message was sent between `start` and `end`,
+    /// with the time of that message, oldest first.
+    /// Of the channels, only those in `joined` are included, and of the private messages,
+    /// only those of `account`. The other side of a private conversation is named by its account.
+    pub fn targets(
+        &self,
+        account: Option<&str>,
+        joined: &[Channel],
+        start: u64,
+        end: u64,
+        limit: usize,
+    ) -> Vec<(Target, u64)> {
+        let (start, end) = (start.min(end), start.max(end));
+        let account = account.map(|account| self.rules.fold(account));
+        let mut targets = self
+            .conversations
+            .iter()
+            .filter_map(|(conversation, entries)| Some((conversation, entries.back()?)))
+            .filter(|(_, latest)| (start..=end).contains(&latest.time))
+            .filter_map(|(conversation, latest)| match conversation {
+                Conversation::Channel(_) => joined
+                    .iter()
+                    .any(|channel| self.channel(channel) == *conversation)
+                    .then(|| (latest.target.clone(), latest.time)),
+                Conversation::Direct(first, second) => {
+                    let other = match account.as_ref() {
+                        Some(account) if account == first => second,
+                        Some(account) if account == second => first,
+                        _ => return None,
+                    };
+                    Some((Target::User(Nick(other.clone())), latest.time))
+                }
+            })
+            .collect::<Vec<_>>();
+        targets.sort_by_key(|(_, time)| *time);
+        targets.truncate(limit);
+        targets
+    }
+
+    /// Appends `entry` to the file of `conversation`, or rewrites the file with only the messages
+    /// still kept if it's new, or holds twice the `limit`.
+    fn save(&mut self, conversation: &Conversation, entry: &HistoryEntry, limit: usize) {
+        let (Some(dir), Some(writer)) = (&self.dir, &self.writer) else {
+            return;
+        };
+        // Start the files that couldn't be written over, in case they were lost.
+        for failed in writer.take_failed() {
+            self.written.remove(&failed);
+        }
+        let Some(entries) = self.conversations.get(conversation) else {
+            return;
+        };
+        let path = dir.join(file_name(conversation));
+        let written = self.written.entry(conversation.clone()).or_default();
+        if *written > 0 && *written < limit * 2 {
+            self.appended.insert(conversation.clone());
+            *written += 1;
+            writer.send(Change::Append(conversation.clone(), path, entry.to_line()));
+        } else {
+            self.appended.remove(conversation);
+            *written = entries.len();
+            let lines = conversation_lines(conversation, entries);
+            writer.send(Change::Replace(conversation.clone(), path, lines));
+        }
+    }
+
+    /// Writes out again every conversation that had messages appended, trimmed to its
+    /// retention and synced to disk, as appending doesn't wait for the disk.
+    /// Returns once everything saved so far is written.
+    pub fn flush(&mut self) {
+        let (Some(dir), Some(writer)) = (&self.dir, &self.writer) else {
+            return;
+        };
+        for conversation in self.appended.drain() {
+            let Some(entries) = self.conversations.get(&conversation) else {
+                continue;
+            };
+            let path = dir.join(file_name(&conversation));
+            let lines = conversation_lines(&conversation, entries);
+            self.written.insert(conversation.clone(), entries.len());
+            writer.send(Change::Replace(conversation, path, lines));
+        }
+        self.settle();
+    }
+
+    /// Waits for the writer to catch up with every change sent to it.
+    fn settle(&self) {
+        if let Some(writer) = &self.writer {
+            writer.settle();
+        }
+    }
+}
+
+/// The header naming `conversation`, then its `entries`, as they're saved to its file.
+fn conversation_lines(
+    conversation: &Conversation,
+    entries: &VecDeque<HistoryEntry>,
+) -> Vec<String> {
+    std::iter::once(conversation.to_line())
+        .chain(entries.iter().map(HistoryEntry::to_line))
+        .collect()
+}
+
+/// A change to a conversation's file, made by the [`Writer`].
+#[derive(Debug)]
+enum Change {
+    /// Replace the file with these lines.
+    Replace(Conversation, PathBuf, Vec<String>),
+    /// Add this line to the end of the file.
+    Append(Conversation, PathBuf, String),
+    /// Signals once every change sent before it has been made.
+    Settle(mpsc::Sender<()>),
+}
+
+/// The thread that writes history files, so the server thread never waits on the disk.
+#[derive(Debug)]
+struct Writer {
+    changes: Option<mpsc::Sender<Change>>,
+    thread: Option<thread::JoinHandle<()>>,
+    /// The conversations whose files couldn't be written since they were last taken.
+    failed: Arc<Mutex<HashSet<Conversation>>>,
+}
+
+impl Writer {
+    fn spawn() -> Self {
+        let (changes, receiver) = mpsc::channel();
+        let failed = Arc::new(Mutex::new(HashSet::new()));
+        let thread = {
+            let failed = failed.clone();
+            thread::spawn(move || write_changes(receiver, &failed))
+        };
+        Writer {
+            changes: Some(changes),
+            thread: Some(thread),
+            failed,
+        }
+    }
+
+    fn send(&self, change: Change) {
+        if let Some(changes) = &self.changes {
+            let _ = changes.send(change);
+        }
+    }
+
+    fn settle(&self) {
+        let (done, settled) = mpsc::channel();
+        self.send(Change::Settle(done));
+        let _ = settled.recv();
+    }
+
+    fn take_failed(&self) -> HashSet<Conversation> {
+        std::mem::take(&mut *self.failed.lock().unwrap())
+    }
+}
+
+impl Drop for Writer {
+    /// Lets the thread finish the changes already sent before it's gone.
+    fn drop(&mut self) {
+        self.changes = None;
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Makes the `changes` sent to the writer until its sender is dropped. Whatever has queued up
+/// while a batch was being written is made as the next batch, with each file opened only once.
+fn write_changes(changes: mpsc::Receiver<Change>, failed: &Mutex<HashSet<Conversation>>) {
+    while let Ok(first) = changes.recv() {
+        // Each file's replacement lines, if it's being replaced, and the lines appended after.
+        let mut files = HashMap::<PathBuf, (Conversation, Option<Vec<String>>, Vec<String>)>::new();
+        let mut settled = vec![];
+        for change in std::iter::once(first).chain(changes.try_iter()) {
+            match change {
+                Change::Replace(conversation, path, lines) => {
+                    files.insert(path, (conversation, Some(lines), vec![]));
+                }
+                Change::Append(conversation, path, line) => files
+                    .entry(path)
+                    .or_insert_with(|| (conversation, None, vec![]))
+                    .2
+                    .push(line),
+                Change::Settle(done) => settled.push(done),
+            }
+        }
+        for (path, (conversation, replacement, appended)) in files {
+            let result = match replacement {
+                Some(lines) => store::write_lines(&path, lines.into_iter().chain(appended)),
+                None => store::append_lines(&path, appended),
+            };
+            if let Err(e) = result {
+                error!("Failed to save history to {}: {}", path.display(), e);
+                failed.lock().unwrap().insert(conversation);
+            }
+        }
+        for done in settled {
+            let _ = done.send(());
+        }
+    }
+}
+
+/// Formats milliseconds since the unix epoch as an IRCv3 `server-time`, for example `2023-11-14T22:13:20.123Z`.
+pub fn format_timestamp(millis: u64) -> String {
+    let secs = millis / 1000;
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    format!(
+        "{year:04}-{month:02}-{day:02}T{:02}:{:02}:{:02}.{:03}Z",
+        secs % 86400 / 3600,
+        secs % 3600 / 60,
+        secs % 60,
+        millis % 1000
+    )
+}
+
+/// Parses a timestamp in the format [`format_timestamp`] produces, where the fraction of a second is optional.
+pub fn parse_timestamp(timestamp: &str) -> Option<u64> {
+    let (date, time) = timestamp.strip_suffix('Z')?.split_once('T')?;
+    let number = |field: &str| {
+        field
+            .chars()
+            .all(|c| c.is_ascii_digit())
+            .then(|| field.parse::<u64>().ok())
+            .flatten()
+    };
+    let [year, month, day] = date.split('-').map(number).collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let (time, fraction) = time.split_once('.').unwrap_or((time, "0"));
+    let [hour, minute, second] = time.split(':').map(number).collect::<Vec<_>>()[..] else {
+        return None;
+    };
+    let (year, month, day) = (year?, month?, day?);
+    let (hour, minute, second) = (hour?, minute?, second?);
+    if year < 1970 || !(1..=12).contains(&month) || !(1..=31).contains(&day) {
+        return None;
+    }
+    if hour > 23 || minute > 59 || second > 60 || fraction.is_empty() {
+        return None;
+    }
+    let millis = number(&format!("{:0<3}", &fraction[..fraction.len().min(3)]))?;
+    let days = days_from_civil(year as i64, month, day) as u64;
+    Some((((days * 24 + hour) * 60 + minute) * 60 + second) * 1000 + millis)
+}
+
+// Conversions between days since the unix epoch and the proleptic Gregorian calendar,
+// from Howard Hinnant's "chrono-Compatible Low-Level Date Algorithms".
+fn civil_from_days(days: i64) -> (i64, u64, u64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+    (year, month as u64, day as u64)
+}
+
+fn days_from_civil(year: i64, month: u64, day: u64) -> i64 {
+    let year = year - i64::from(month <= 2);
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let shifted_month = (month as i64 + 9) % 12;
+    let day_of_year = (153 * shifted_month + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::types::SERVER_NAME;
+
+    fn history_msg(subcommand: &str, args: &[&str]) -> ChatHistoryMsg {
+        ChatHistoryMsg {
+            subcommand: subcommand.to_string(),
+            args: args.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+
+    fn messages(entries: Vec<&HistoryEntry>) -> Vec<&str> {
+        entries.into_iter().map(|e| e.message.as_str()).collect()
+    }
+
+    #[test]
+    fn test_timestamps() {
+        assert_eq!(format_timestamp(0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(format_timestamp(1700000000123), "2023-11-14T22:13:20.123Z");
+        assert_eq!(format_timestamp(951782400000), "2000-02-29T00:00:00.000Z");
+        assert_eq!(
+            parse_timestamp("2023-11-14T22:13:20.123Z"),
+            Some(1700000000123)
+        );
+        assert_eq!(parse_timestamp("2023-11-14T22:13:20Z"), Some(1700000000000));
+        assert_eq!(
+            parse_timestamp("2023-11-14T22:13:20.5Z"),
+            Some(1700000000500)
+        );
+        assert_eq!(parse_timestamp("2023-13-14T22:13:20Z"), None);
+        assert_eq!(parse_timestamp("yesterday"), None);
+    }
+
+    #[test]
+    fn test_retention() {
+        let mut history = History::in_memory(
+            Retention {
+                channel: 2,
+                direct: 0,
+            },
+            NameRules::default(),
+        );
+        let sender = Prefix::from("alice!al@127.0.0.1");
+        let haku = Channel("#haku".to_string());
+        let channel = Target::Channel(haku.clone());
+        for message in ["one", "two", "three"] {
+            let conversation = Some(history.channel(&haku));
+            history.record(
+                conversation,
+                &sender,
+                MessageKind::PrivMsg,
+                &channel,
+                message,
+            );
+        }
+        let bob = Target::User(Nick("bob".to_string()));
+        let direct = history.direct("alice", "bob");
+        let entry = history.record(
+            Some(direct.clone()),
+            &sender,
+            MessageKind::Notice,
+            &bob,
+            "psst",
+        );
+        assert_eq!(
+            entry.reply().to_string(),
+            ":alice!al@127.0.0.1 NOTICE bob :psst\r\n"
+        );
+
+        let latest = HistoryQuery::try_from(&history_msg("LATEST", &["#HAKU", "*", "10"])).unwrap();
+        let upper = history.channel(&Channel("#HAKU".to_string()));
+        assert_eq!(messages(history.query(&upper, &latest)), ["two", "three"]);
+        let latest = HistoryQuery::try_from(&history_msg("LATEST", &["bob", "*", "10"])).unwrap();
+        assert!(history.query(&direct, &latest).is_empty());
+    }
+
+    #[test]
+    fn test_selectors() {
+        let mut history = History::in_memory(
+            Retention {
+                channel: 10,
+                direct: 10,
+            },
+            NameRules::default(),
+        );
+        let bob = Nick("bob".to_string());
+        // Alice is logged in as "alice", and Bob as "Robert".
+        let direct = history.direct("alice", "Robert");
+        let ids = ["a", "b", "c", "d", "e"]
+            .map(|message| {
+                history
+                    .record(
+                        Some(direct.clone()),
+                        &Prefix::from("alice!al@127.0.0.1"),
+                        MessageKind::PrivMsg,
+                        &Target::User(bob.clone()),
+                        message,
+                    )
+                    .msgid
+            })
+            .map(|msgid| format!("msgid={msgid}"));
+        let query = |subcommand: &str, args: &[&str]| {
+            let query = HistoryQuery::try_from(&history_msg(subcommand, args)).unwrap();
+            messages(history.query(&history.direct("robert", "ALICE"), &query))
+        };
+        assert_eq!(query("LATEST", &["alice", "*", "2"]), ["d", "e"]);
+        assert_eq!(query("LATEST", &["alice", &ids[2], "5"]), ["d", "e"]);
+        assert_eq!(query("BEFORE", &["alice", &ids[2], "5"]), ["a", "b"]);
+        assert_eq!(query("AFTER", &["alice", &ids[2], "1"]), ["d"]);
+        assert_eq!(query("AROUND", &["alice", &ids[2], "3"]), ["b", "c", "d"]);
+        assert_eq!(
+            query("BETWEEN", &["alice", &ids[0], &ids[4], "2"]),
+            ["b", "c"]
+        );
+        assert_eq!(
+            query("BETWEEN", &["alice", &ids[4], &ids[0], "2"]),
+            ["c", "d"]
+        );
+        assert!(query("BEFORE", &["alice", "msgid=unknown", "5"]).is_empty());
+
+        let targets = history.targets(Some("Robert"), &[], 0, u64::MAX, 10);
+        assert_eq!(
+            targets,
+            [(Target::User(Nick("alice".to_string())), targets[0].1)]
+        );
+        let targets = history.targets(Some("alice"), &[], 0, u64::MAX, 10);
+        assert_eq!(targets[0].0, Target::User(Nick("robert".to_string())));
+        assert!(history
+            .targets(Some("carol"), &[], 0, u64::MAX, 10)
+            .is_empty());
+        assert!(history.targets(None, &[], 0, u64::MAX, 10).is_empty());
+    }
+
+    #[test]
+    fn test_parse_queries() {
+        assert_eq!(
+            HistoryQuery::try_from(&history_msg(
+                "TARGETS",
+                &[
+                    "timestamp=2023-11-14T22:13:20.123Z",
+                    "timestamp=1970-01-01T00:00:00Z",
+                    "500"
+                ]
+            )),
+            Ok(HistoryQuery::Targets {
+                start: 1700000000123,
+                end: 0,
+                limit: MAX_LIMIT
+            })
+        );
+        assert_eq!(
+            HistoryQuery::try_from(&history_msg("BEFORE", &["#haku", "*", "5"])),
+            Err(HistoryError::InvalidParams)
+        );
+        assert_eq!(
+            HistoryQuery::try_from(&history_msg("LATEST", &["#haku", "*", "0"])),
+            Err(HistoryError::InvalidParams)
+        );
+        assert_eq!(
+            HistoryQuery::try_from(&history_msg("FORGET", &[])),
+            Err(HistoryError::UnknownCommand)
+        );
+    }
+
+    #[test]
+    fn test_persistence() {
+        let dir = std::env::temp_dir().join(format!("iris-history-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let retention = Retention {
+            channel: 5,
+            direct: 5,
+        };
+        let recorded = {
+            let mut history =
+                History::load(&dir, retention, NameRules::default(), SERVER_NAME).unwrap();
+            let haku = Channel("#haku".to_string());
+            history.record(
+                Some(history.direct("alice", "bob")),
+                &Prefix::from("alice!al@127.0.0.1"),
+                MessageKind::PrivMsg,
+                &Target::User(Nick("bob".to_string())),
+                "psst",
+            );
+            history.record(
+                Some(history.channel(&haku)),
+                &Prefix::from("alice!al@127.0.0.1"),
+                MessageKind::PrivMsg,
+                &Target::Channel(haku),
+                "tabs\tand :colons survive",
+            )
+        };
+        let history = History::load(&dir, retention, NameRules::default(), SERVER_NAME).unwrap();
+        let latest = HistoryQuery::try_from(&history_msg("LATEST", &["#haku", "*", "5"])).unwrap();
+        let haku = history.channel(&Channel("#haku".to_string()));
+        assert_eq!(history.query(&haku, &latest), [&recorded]);
+        let direct = history.direct("bob", "alice");
+        assert_eq!(messages(history.query(&direct, &latest)), ["psst"]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_appends() {
+        let dir = std::env::temp_dir().join(format!("iris-history-appends-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let retention = Retention {
+            channel: 2,
+            direct: 2,
+        };
+        let haku = Channel("#haku".to_string());
+        let mut history =
+            History::load(&dir, retention, NameRules::default(), SERVER_NAME).unwrap();
+        let path = dir.join(file_name(&history.channel(&haku)));
+        let mut file_lines = vec![];
+        for message in ["one", "two", "three", "four", "five"] {
+            history.record(
+                Some(history.channel(&haku)),
+                &Prefix::from("alice!al@127.0.0.1"),
+                MessageKind::PrivMsg,
+                &Target::Channel(haku.clone()),
+                message,
+            );
+            history.settle();
+            file_lines.push(store::read_lines(&path).unwrap().len());
+        }
+        // Messages are appended until the file holds twice the retention, then it's trimmed.
+        assert_eq!(file_lines, [2, 3, 4, 5, 3]);
+        history.record(
+            Some(history.channel(&haku)),
+            &Prefix::from("alice!al@127.0.0.1"),
+            MessageKind::PrivMsg,
+            &Target::Channel(haku.clone()),
+            "six",
+        );
+        history.settle();
+        assert_eq!(store::read_lines(&path).unwrap().len(), 4);
+        history.flush();
+        assert_eq!(store::read_lines(&path).unwrap().len(), 3);
+        let history = History::load(&dir, retention, NameRules::default(), SERVER_NAME).unwrap();
+        let latest = HistoryQuery::try_from(&history_msg("LATEST", &["#haku", "*", "5"])).unwrap();
+        let conversation = history.channel(&haku);
+        assert_eq!(
+            messages(history.query(&conversation, &latest)),
+            ["five", "six"]
+        );
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_file_names() {
+        let dir = std::env::temp_dir().join(format!("iris-history-names-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        let retention = Retention {
+            channel: 5,
+            direct: 5,
+        };
+        let escape = Channel("#../../escape".to_string());
+        let channel = Target::Channel(escape.clone());
+        let mut history =
+            History::load(&dir, retention, NameRules::default(), SERVER_NAME).unwrap();
+        history.record(
+            Some(history.channel(&escape)),
+            &Prefix::from("alice!al@127.0.0.1"),
+            MessageKind::PrivMsg,
+            &channel,
+            "hello",
+        );
+        history.settle();
+        let files = fs::read_dir(&dir)
+            .unwrap()
+            .map(|file| file.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(files, [file_name(&history.channel(&escape))]);
+        assert!(files[0]
+            .trim_end_matches(".log")
+            .chars()
+            .all(|c| c.is_ascii_hexdigit()));
+        let history = History::load(&dir, retention, NameRules::default(), SERVER_NAME).unwrap();
+        let latest =
+            HistoryQuery::try_from(&history_msg("LATEST", &["#../../escape", "*", "5"])).unwrap();
+        let conversation = history.channel(&escape);
+        assert_eq!(history.query(&conversation, &latest).len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
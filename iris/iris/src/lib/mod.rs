@@ -1,3 +1,12 @@
+pub mod bans;
+pub mod chanserv;
 pub mod connect;
+pub mod flood;
+pub mod history;
+pub mod nickserv;
+pub mod numeric;
 pub mod plugin;
+pub mod server;
+pub mod store;
+pub mod tls;
 pub mod types;
@@ -0,0 +1,609 @@
+//! # NickServ
+//! A nickname registration service, reachable by users as the pseudo-user `NickServ`.
+//!
+//! Accounts are keyed by the (case-insensitive) nickname they were registered with,
+//! and are persisted to a plain text file so they survive restarts.
+//! The routing itself (sending notices, renaming and disconnecting users) is done by
+//! the sever thread; this module only owns the account store and command parsing.
+use std::{
+    collections::HashMap,
+    fmt::{self, Display},
+    io,
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use log::error;
+use pbkdf2::pbkdf2_hmac;
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    store,
+    types::{NameRules, Nick},
+};
+
+/// The nickname the service answers to.
+pub const NICKSERV: &str = "NickServ";
+
+/// How many random bytes are used to salt each password.
+const SALT_LEN: usize = 16;
+
+/// How many PBKDF2 rounds new password hashes take.
+/// Hashes stored with fewer are redone the next time their owner identifies.
+const ROUNDS: u32 = 600_000;
+
+/// What hashes made with PBKDF2 start with, before their rounds and the key itself.
+const PBKDF2_PREFIX: &str = "pbkdf2-sha256$";
+
+/// A password, salted and hashed as it is stored.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Credentials {
+    salt: String,
+    hash: String,
+}
+
+impl Credentials {
+    /// Salts `password` afresh and hashes it with `rounds` of PBKDF2.
+    /// This is slow on purpose, so the server does it off the thread handling messages.
+    fn new(password: &str, rounds: u32) -> Self {
+        let salt = new_salt();
+        let hash = hash_password(&salt, password, rounds);
+        Credentials { salt, hash }
+    }
+
+    /// Checks a password against the stored salted hash, as slowly as it was made.
+    /// The comparison takes as long wherever the hashes differ, so timing it gives nothing away.
+    pub fn verify(&self, password: &str) -> bool {
+        let hash = match self.rounds() {
+            Some(rounds) => hash_password(&self.salt, password, rounds),
+            None => legacy_hash(&self.salt, password),
+        };
+        hash.as_bytes().ct_eq(self.hash.as_bytes()).into()
+    }
+
+    /// The PBKDF2 rounds the stored hash took, or `None` for a hash from before PBKDF2 was used.
+    fn rounds(&self) -> Option<u32> {
+        let (rounds, _) = self.hash.strip_prefix(PBKDF2_PREFIX)?.split_once('$')?;
+        rounds.parse().ok().filter(|&rounds| rounds > 0)
+    }
+}
+
+/// A registered account.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Account {
+    /// The account name, in the casing it was registered with.
+    pub name: String,
+    /// Seconds since the unix epoch at which the account was registered.
+    pub registered: u64,
+    credentials: Credentials,
+}
+
+impl Account {
+    fn new(name: String, credentials: Credentials) -> Self {
+        Account {
+            name,
+            registered: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            credentials,
+        }
+    }
+}
+
+/// The slow half of a NickServ command that takes a password: checking it against an
+/// account, or hashing it to store. Made by [`NickServ::check`] or [`NickServ::hash`].
+#[derive(Debug, Clone)]
+pub struct PasswordWork {
+    /// The credentials to check the password against, or `None` to hash it.
+    against: Option<Credentials>,
+    rounds: u32,
+}
+
+impl PasswordWork {
+    /// Checks or hashes `password`. This takes a while, so the server runs it on a blocking
+    /// thread and hands the result back to the store afterwards.
+    pub fn run(self, password: &str) -> PasswordResult {
+        match self.against {
+            Some(against) => {
+                let matched = against.verify(password);
+                let rehashed = (matched && against.rounds() != Some(self.rounds))
+                    .then(|| Credentials::new(password, self.rounds));
+                PasswordResult::Checked {
+                    against,
+                    matched,
+                    rehashed,
+                }
+            }
+            None => PasswordResult::Hashed(Credentials::new(password, self.rounds)),
+        }
+    }
+}
+
+/// What [`PasswordWork::run`] found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PasswordResult {
+    /// A new password, hashed to store.
+    Hashed(Credentials),
+    /// A password checked against the credentials an account had when the command arrived.
+    Checked {
+        against: Credentials,
+        matched: bool,
+        /// The password hashed again as new ones are, if it matched an outdated hash.
+        rehashed: Option<Credentials>,
+    },
+}
+
+fn new_salt() -> String {
+    let mut bytes = [0u8; SALT_LEN];
+    getrandom::getrandom(&mut bytes).expect("failed to gather randomness for password salt");
+    to_hex(&bytes)
+}
+
+/// Derives a key from `password` with PBKDF2-HMAC-SHA256, stored along with its `rounds`.
+fn hash_password(salt: &str, password: &str, rounds: u32) -> String {
+    let mut key = [0u8; 32];
+    pbkdf2_hmac::<Sha256>(password.as_bytes(), salt.as_bytes(), rounds, &mut key);
+    format!("{PBKDF2_PREFIX}{rounds}${}", to_hex(&key))
+}
+
+/// The single salted SHA-256 that passwords were once stored as, kept to check old accounts.
+fn legacy_hash(salt: &str, password: &str) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(salt.as_bytes());
+    hasher.update(password.as_bytes());
+    to_hex(&hasher.finalize())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
+}
+
+/// Everything that can go wrong when operating on accounts.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NickServError {
+    AlreadyRegistered,
+    NotRegistered,
+    BadPassword,
+}
+
+impl Display for NickServError {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NickServError::AlreadyRegistered => write!(fmt, "This nickname is already registered."),
+            NickServError::NotRegistered => write!(fmt, "This nickname is not registered."),
+            NickServError::BadPassword => write!(fmt, "Invalid password."),
+        }
+    }
+}
+
+/// The account store.
+#[derive(Debug)]
+pub struct NickServ {
+    /// Accounts by their casefolded name.
+    accounts: HashMap<String, Account>,
+    rules: NameRules,
+    /// How many PBKDF2 rounds new password hashes take.
+    rounds: u32,
+    path: Option<PathBuf>,
+}
+
+impl NickServ {
+    /// Loads the accounts stored at `path`, which will also be used to save any changes.
+    /// A missing file is treated as an empty store. Names are compared under `rules`.
+    pub fn load(path: impl Into<PathBuf>, rules: NameRules) -> io::Result<Self> {
+        let path = path.into();
+        let mut accounts = HashMap::new();
+        for line in store::read_lines(&path)? {
+            let fields = line.split('\t').collect::<Vec<_>>();
+            if let [name, registered, salt, hash] = fields[..] {
+                accounts.insert(
+                    rules.fold(name),
+                    Account {
+                        name: name.to_string(),
+                        registered: registered.parse().unwrap_or(0),
+                        credentials: Credentials {
+                            salt: salt.to_string(),
+                            hash: hash.to_string(),
+                        },
+                    },
+                );
+            } else {
+                error!("Skipping malformed account entry in {}", path.display());
+            }
+        }
+        Ok(NickServ {
+            accounts,
+            rules,
+            rounds: ROUNDS,
+            path: Some(path),
+        })
+    }
+
+    /// An account store that is never written to disk.
+    pub fn in_memory(rules: NameRules) -> Self {
+        NickServ {
+            accounts: HashMap::new(),
+            rules,
+            rounds: ROUNDS,
+            path: None,
+        }
+    }
+
+    /// Hashes new passwords with `rounds` of PBKDF2 instead of the default 600,000.
+    /// Hashes stored with a different number are redone the next time their owner identifies.
+    pub fn with_rounds(mut self, rounds: u32) -> Self {
+        self.rounds = rounds.max(1);
+        self
+    }
+
+    /// The account owning `nick`, if it is registered.
+    pub fn account(&self, nick: &str) -> Option<&Account> {
+        self.accounts.get(&self.rules.fold(nick))
+    }
+
+    /// Whether `account` is the owner of `nick`.
+    pub fn owns(&self, account: Option<&str>, nick: &Nick) -> bool {
+        match (account, self.account(&nick.0)) {
+            (Some(account), Some(owner)) => self.rules.same(&owner.name, account),
+            _ => false,
+        }
+    }
+
+    /// The work of checking a password for `account`, if it is registered.
+    pub fn check(&self, account: &str) -> Option<PasswordWork> {
+        self.account(account).map(|account| PasswordWork {
+            against: Some(account.credentials.clone()),
+            rounds: self.rounds,
+        })
+    }
+
+    /// The work of hashing a new password.
+    pub fn hash(&self) -> PasswordWork {
+        PasswordWork {
+            against: None,
+            rounds: self.rounds,
+        }
+    }
+
+    /// Registers `nick` as a new account protected by the password `credentials` were hashed from.
+    pub fn register(
+        &mut self,
+        nick: &Nick,
+        credentials: Credentials,
+    ) -> Result<&Account, NickServError> {
+        let key = self.rules.fold(&nick.0);
+        if self.accounts.contains_key(&key) {
+            return Err(NickServError::AlreadyRegistered);
+        }
+        self.accounts
+            .insert(key.clone(), Account::new(nick.0.clone(), credentials));
+        self.save();
+        Ok(&self.accounts[&key])
+    }
+
+    /// Returns `account` if `result` is its password checked against its current credentials,
+    /// so a password changed while the check ran doesn't count.
+    /// A password stored under an older hash is replaced by the one made during the check.
+    pub fn identify(
+        &mut self,
+        account: &str,
+        result: &PasswordResult,
+    ) -> Result<&Account, NickServError> {
+        let key = self.rules.fold(account);
+        let account = self
+            .accounts
+            .get_mut(&key)
+            .ok_or(NickServError::NotRegistered)?;
+        let PasswordResult::Checked {
+            against,
+            matched: true,
+            rehashed,
+        } = result
+        else {
+            return Err(NickServError::BadPassword);
+        };
+        if *against != account.credentials {
+            return Err(NickServError::BadPassword);
+        }
+        if let Some(rehashed) = rehashed {
+            account.credentials = rehashed.clone();
+            self.save();
+        }
+        Ok(&self.accounts[&key])
+    }
+
+    /// Deletes `account`, if `result` is its password as for [`NickServ::identify`].
+    pub fn drop_account(
+        &mut self,
+        account: &str,
+        result: &PasswordResult,
+    ) -> Result<Account, NickServError> {
+        self.identify(account, result)?;
+        let dropped = self
+            .accounts
+            .remove(&self.rules.fold(account))
+            .ok_or(NickServError::NotRegistered)?;
+        self.save();
+        Ok(dropped)
+    }
+
+    /// Replaces the password of `account` with the one `credentials` were hashed from.
+    pub fn set_password(
+        &mut self,
+        account: &str,
+        credentials: Credentials,
+    ) -> Result<(), NickServError> {
+        self.accounts
+            .get_mut(&self.rules.fold(account))
+            .ok_or(NickServError::NotRegistered)?
+            .credentials = credentials;
+        self.save();
+        Ok(())
+    }
+
+    /// Writes every account to the file the store was loaded from.
+    /// Changes are saved as they are made, so this is only needed after a save failed,
+    /// or to be sure of it as the server stops.
+    pub fn save(&self) {
+        let Some(path) = &self.path else {
+            return;
+        };
+        let lines = self.accounts.values().map(|account| {
+            format!(
+                "{}\t{}\t{}\t{}",
+                account.name,
+                account.registered,
+                account.credentials.salt,
+                account.credentials.hash
+            )
+        });
+        if let Err(e) = store::write_lines(path, lines) {
+            error!("Failed to save accounts to {}: {}", path.display(), e);
+        }
+    }
+}
+
+/// A command sent to NickServ, for example `PRIVMSG NickServ :IDENTIFY hunter2`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum NickServCommand {
+    /// `REGISTER <password>`: register the current nickname.
+    Register { password: String },
+    /// `IDENTIFY [account] <password>`: log in to an account, by default the current nickname.
+    Identify {
+        account: Option<String>,
+        password: String,
+    },
+    /// `GHOST <nick> [password]`: disconnect a session using a nickname you own.
+    Ghost {
+        nick: String,
+        password: Option<String>,
+    },
+    /// `DROP <password>`: delete the account you are identified to.
+    Drop { password: String },
+    /// `SET PASSWORD <password>`: change the password of the account you are identified to.
+    SetPassword { password: String },
+    /// `HELP`: list the commands above.
+    Help,
+}
+
+impl NickServCommand {
+    /// The password the command gives, which needs checking or hashing before it is carried out.
+    pub fn password(&self) -> Option<&str> {
+        match self {
+            NickServCommand::Register { password }
+            | NickServCommand::Identify { password, .. }
+            | NickServCommand::Drop { password }
+            | NickServCommand::SetPassword { password } => Some(password),
+            NickServCommand::Ghost { password, .. } => password.as_deref(),
+            NickServCommand::Help => None,
+        }
+    }
+}
+
+impl TryFrom<&str> for NickServCommand {
+    /// A usage message to show the user.
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        let args = value.split_whitespace().collect::<Vec<_>>();
+        let command = args
+            .first()
+            .map(|c| c.to_ascii_uppercase())
+            .unwrap_or_default();
+        match (command.as_str(), args.get(1..).unwrap_or_default()) {
+            ("REGISTER", [password, ..]) => Ok(NickServCommand::Register {
+                password: password.to_string(),
+            }),
+            ("IDENTIFY", [password]) => Ok(NickServCommand::Identify {
+                account: None,
+                password: password.to_string(),
+            }),
+            ("IDENTIFY", [account, password]) => Ok(NickServCommand::Identify {
+                account: Some(account.to_string()),
+                password: password.to_string(),
+            }),
+            ("GHOST", [nick]) => Ok(NickServCommand::Ghost {
+                nick: nick.to_string(),
+                password: None,
+            }),
+            ("GHOST", [nick, password]) => Ok(NickServCommand::Ghost {
+                nick: nick.to_string(),
+                password: Some(password.to_string()),
+            }),
+            ("DROP", [password]) => Ok(NickServCommand::Drop {
+                password: password.to_string(),
+            }),
+            ("SET", [option, password]) if option.eq_ignore_ascii_case("PASSWORD") => {
+                Ok(NickServCommand::SetPassword {
+                    password: password.to_string(),
+                })
+            }
+            ("HELP", _) => Ok(NickServCommand::Help),
+            ("REGISTER", _) => Err("Syntax: REGISTER <password>".to_string()),
+            ("IDENTIFY", _) => Err("Syntax: IDENTIFY [account] <password>".to_string()),
+            ("GHOST", _) => Err("Syntax: GHOST <nick> [password]".to_string()),
+            ("DROP", _) => Err("Syntax: DROP <password>".to_string()),
+            ("SET", _) => Err("Syntax: SET PASSWORD <password>".to_string()),
+            _ => Err(format!(
+                "Unknown command {}. Use HELP for a list of commands.",
+                args.first().unwrap_or(&"")
+            )),
+        }
+    }
+}
+
+/// The lines sent in response to `HELP`.
+pub const HELP: [&str; 6] = [
+    "REGISTER <password>            - Register your current nickname",
+    "IDENTIFY [account] <password>  - Identify to an account",
+    "GHOST <nick> [password]        - Disconnect a session using your nickname",
+    "DROP <password>                - Delete your account",
+    "SET PASSWORD <password>        - Change your password",
+    "HELP                           - Show this list",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Few enough rounds that the tests don't wait on them.
+    const TEST_ROUNDS: u32 = 1000;
+
+    fn register(nickserv: &mut NickServ, nick: &str, password: &str) -> Result<(), NickServError> {
+        let PasswordResult::Hashed(credentials) = nickserv.hash().run(password) else {
+            unreachable!("hashing a password doesn't check it");
+        };
+        nickserv
+            .register(&Nick(nick.to_string()), credentials)
+            .map(|_| ())
+    }
+
+    fn check(nickserv: &NickServ, account: &str, password: &str) -> PasswordResult {
+        nickserv.check(account).unwrap().run(password)
+    }
+
+    fn identify(
+        nickserv: &mut NickServ,
+        account: &str,
+        password: &str,
+    ) -> Result<String, NickServError> {
+        let result = check(nickserv, account, password);
+        nickserv
+            .identify(account, &result)
+            .map(|account| account.name.clone())
+    }
+
+    #[test]
+    fn test_register_identify() {
+        let mut nickserv = NickServ::in_memory(NameRules::default()).with_rounds(TEST_ROUNDS);
+        let nick = Nick("Alice".to_string());
+        register(&mut nickserv, "Alice", "hunter2").unwrap();
+        assert_eq!(
+            register(&mut nickserv, "alice", "other"),
+            Err(NickServError::AlreadyRegistered)
+        );
+        assert_eq!(
+            identify(&mut nickserv, "ALICE", "hunter2").unwrap(),
+            "Alice"
+        );
+        assert_eq!(
+            identify(&mut nickserv, "alice", "hunter3"),
+            Err(NickServError::BadPassword)
+        );
+        assert!(nickserv.check("bob").is_none());
+        assert!(nickserv.owns(Some("alice"), &nick));
+        assert!(!nickserv.owns(None, &nick));
+    }
+
+    #[test]
+    fn test_set_password_and_drop() {
+        let mut nickserv = NickServ::in_memory(NameRules::default()).with_rounds(TEST_ROUNDS);
+        register(&mut nickserv, "bob", "first").unwrap();
+        // A check that was running while the password changed doesn't count.
+        let stale = check(&nickserv, "bob", "first");
+        let PasswordResult::Hashed(credentials) = nickserv.hash().run("second") else {
+            unreachable!("hashing a password doesn't check it");
+        };
+        nickserv.set_password("bob", credentials).unwrap();
+        assert_eq!(
+            nickserv.identify("bob", &stale),
+            Err(NickServError::BadPassword)
+        );
+        assert!(identify(&mut nickserv, "bob", "first").is_err());
+        let wrong = check(&nickserv, "bob", "first");
+        assert_eq!(
+            nickserv.drop_account("bob", &wrong),
+            Err(NickServError::BadPassword)
+        );
+        let right = check(&nickserv, "bob", "second");
+        nickserv.drop_account("bob", &right).unwrap();
+        assert!(nickserv.account("bob").is_none());
+    }
+
+    #[test]
+    fn test_persistence() {
+        let path = std::env::temp_dir().join(format!("iris-nickserv-{}.db", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        {
+            let mut nickserv = NickServ::load(&path, NameRules::default())
+                .unwrap()
+                .with_rounds(TEST_ROUNDS);
+            register(&mut nickserv, "carol", "secret").unwrap();
+        }
+        let mut nickserv = NickServ::load(&path, NameRules::default())
+            .unwrap()
+            .with_rounds(TEST_ROUNDS);
+        assert!(identify(&mut nickserv, "carol", "secret").is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_legacy_hashes() {
+        let path = std::env::temp_dir().join(format!("iris-legacy-{}.db", std::process::id()));
+        let hash = legacy_hash("pepper", "secret");
+        store::write_lines(&path, [format!("dave\t0\tpepper\t{hash}")]).unwrap();
+        let mut nickserv = NickServ::load(&path, NameRules::default())
+            .unwrap()
+            .with_rounds(TEST_ROUNDS);
+        assert_eq!(
+            identify(&mut nickserv, "dave", "wrong"),
+            Err(NickServError::BadPassword)
+        );
+        assert_eq!(nickserv.account("dave").unwrap().credentials.hash, hash);
+        // Identifying upgrades the hash, and saves it.
+        assert!(identify(&mut nickserv, "dave", "secret").is_ok());
+        let mut nickserv = NickServ::load(&path, NameRules::default())
+            .unwrap()
+            .with_rounds(TEST_ROUNDS);
+        let credentials = &nickserv.account("dave").unwrap().credentials;
+        assert_eq!(credentials.rounds(), Some(TEST_ROUNDS));
+        assert_ne!(credentials.salt, "pepper");
+        assert!(identify(&mut nickserv, "dave", "secret").is_ok());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_commands() {
+        assert_eq!(
+            NickServCommand::try_from("identify alice hunter2"),
+            Ok(NickServCommand::Identify {
+                account: Some("alice".to_string()),
+                password: "hunter2".to_string()
+            })
+        );
+        assert_eq!(
+            NickServCommand::try_from("SET password new"),
+            Ok(NickServCommand::SetPassword {
+                password: "new".to_string()
+            })
+        );
+        assert_eq!(
+            NickServCommand::try_from("GHOST bob").map(|c| c.password().is_some()),
+            Ok(false)
+        );
+        assert!(NickServCommand::try_from("GHOST").is_err());
+        assert!(NickServCommand::try_from("").is_err());
+    }
+}
@@ -0,0 +1,351 @@
+//! # Numerics
+//! The catalog of numeric replies from RFC 2812 and the Modern IRC client protocol,
+//! and a uniform way to render them.
+//!
+//! Every numeric is sent as `:<server> <code> <target> <params>...`, where the target is the
+//! nick of the user it is for (or `*` before they have one). Each entry in the catalog
+//! records the parameters that follow the target the way the specifications write them,
+//! for example `<channel> :<topic>`, and a parameter written with a leading `:` is always
+//! sent as the final, trailing parameter. When that trailing parameter is fixed text it is
+//! the numeric's default text, which [`NumericReply::new`] fills in.
+use std::fmt::{self, Display};
+
+use crate::types::{write_command, SERVER_NAME};
+
+macro_rules! numerics {
+    ($($variant:ident = $code:literal, $name:literal, $params:literal;)*) => {
+        /// A numeric reply or error.
+        #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+        pub enum Numeric {
+            $(
+                #[doc = concat!("`", stringify!($code), "` ", $name, ": `", $params, "`")]
+                $variant,
+            )*
+        }
+
+        impl Numeric {
+            /// Every numeric in the catalog, in order of their codes.
+            pub const ALL: &'static [Numeric] = &[$(Numeric::$variant),*];
+
+            /// The three digit code, for example `401` for `ERR_NOSUCHNICK`.
+            pub fn code(self) -> u16 {
+                match self {
+                    $(Numeric::$variant => $code,)*
+                }
+            }
+
+            /// The name the specifications use, for example `ERR_NOSUCHNICK`.
+            pub fn name(self) -> &'static str {
+                match self {
+                    $(Numeric::$variant => $name,)*
+                }
+            }
+
+            /// The parameters after the target, for example `<nickname> :No such nick/channel`.
+            pub fn params(self) -> &'static str {
+                match self {
+                    $(Numeric::$variant => $params,)*
+                }
+            }
+        }
+    };
+}
+
+numerics! {
+    RplWelcome = 1, "RPL_WELCOME", ":Welcome to the Internet Relay Network <nick>!<user>@<host>";
+    RplYourHost = 2, "RPL_YOURHOST", ":Your host is <servername>, running version <ver>";
+    RplCreated = 3, "RPL_CREATED", ":This server was created <date>";
+    RplMyInfo = 4, "RPL_MYINFO", "<servername> <version> <available user modes> <available channel modes>";
+    RplISupport = 5, "RPL_ISUPPORT", "<token>... :are supported by this server";
+    RplBounce = 10, "RPL_BOUNCE", "<hostname> <port> :<info>";
+    RplTraceLink = 200, "RPL_TRACELINK", "Link <version & debug level> <destination> <next server> <protocol version> <link uptime in seconds> <backstream sendq> <upstream sendq>";
+    RplTraceConnecting = 201, "RPL_TRACECONNECTING", "Try. <class> <server>";
+    RplTraceHandshake = 202, "RPL_TRACEHANDSHAKE", "H.S. <class> <server>";
+    RplTraceUnknown = 203, "RPL_TRACEUNKNOWN", "???? <class> <client IP address in dot form>";
+    RplTraceOperator = 204, "RPL_TRACEOPERATOR", "Oper <class> <nick>";
+    RplTraceUser = 205, "RPL_TRACEUSER", "User <class> <nick>";
+    RplTraceServer = 206, "RPL_TRACESERVER", "Serv <class> <int>S <int>C <server> <nick!user|*!*>@<host|server> <protocol version>";
+    RplTraceService = 207, "RPL_TRACESERVICE", "Service <class> <name> <type> <active type>";
+    RplTraceNewType = 208, "RPL_TRACENEWTYPE", "<newtype> 0 <client name>";
+    RplTraceClass = 209, "RPL_TRACECLASS", "Class <class> <count>";
+    RplStatsLinkInfo = 211, "RPL_STATSLINKINFO", "<linkname> <sendq> <sent messages> <sent Kbytes> <received messages> <received Kbytes> <time open>";
+    RplStatsCommands = 212, "RPL_STATSCOMMANDS", "<command> <count> <byte count> <remote count>";
+    RplStatsKLine = 216, "RPL_STATSKLINE", "K <user@host> <expires> :<reason>";
+    RplEndOfStats = 219, "RPL_ENDOFSTATS", "<stats letter> :End of STATS report";
+    RplUModeIs = 221, "RPL_UMODEIS", "<user mode string>";
+    RplStatsDLine = 225, "RPL_STATSDLINE", "D <address> <expires> :<reason>";
+    RplServList = 234, "RPL_SERVLIST", "<name> <server> <mask> <type> <hopcount> <info>";
+    RplServListEnd = 235, "RPL_SERVLISTEND", "<mask> <type> :End of service listing";
+    RplStatsUptime = 242, "RPL_STATSUPTIME", ":Server Up <days> days <hours>:<minutes>:<seconds>";
+    RplStatsOLine = 243, "RPL_STATSOLINE", "O <hostmask> * <name>";
+    RplLUserClient = 251, "RPL_LUSERCLIENT", ":There are <users> users and <services> services on <servers> servers";
+    RplLUserOp = 252, "RPL_LUSEROP", "<ops> :operator(s) online";
+    RplLUserUnknown = 253, "RPL_LUSERUNKNOWN", "<connections> :unknown connection(s)";
+    RplLUserChannels = 254, "RPL_LUSERCHANNELS", "<channels> :channels formed";
+    RplLUserMe = 255, "RPL_LUSERME", ":I have <clients> clients and <servers> servers";
+    RplAdminMe = 256, "RPL_ADMINME", "<server> :Administrative info";
+    RplAdminLoc1 = 257, "RPL_ADMINLOC1", ":<admin info>";
+    RplAdminLoc2 = 258, "RPL_ADMINLOC2", ":<admin info>";
+    RplAdminEmail = 259, "RPL_ADMINEMAIL", ":<admin info>";
+    RplTraceLog = 261, "RPL_TRACELOG", "File <logfile> <debug level>";
+    RplTraceEnd = 262, "RPL_TRACEEND", "<server name> <version & debug level> :End of TRACE";
+    RplTryAgain = 263, "RPL_TRYAGAIN", "<command> :Please wait a while and try again.";
+    RplLocalUsers = 265, "RPL_LOCALUSERS", "<users> <max> :Current local users <users>, max <max>";
+    RplGlobalUsers = 266, "RPL_GLOBALUSERS", "<users> <max> :Current global users <users>, max <max>";
+    RplWhoisCertFp = 276, "RPL_WHOISCERTFP", "<nick> :has client certificate fingerprint <fingerprint>";
+    RplAway = 301, "RPL_AWAY", "<nick> :<away message>";
+    RplUserHost = 302, "RPL_USERHOST", ":<reply>...";
+    RplIsOn = 303, "RPL_ISON", ":<nick>...";
+    RplUnAway = 305, "RPL_UNAWAY", ":You are no longer marked as being away";
+    RplNowAway = 306, "RPL_NOWAWAY", ":You have been marked as being away";
+    RplWhoisRegNick = 307, "RPL_WHOISREGNICK", "<nick> :has identified for this nick";
+    RplWhoisUser = 311, "RPL_WHOISUSER", "<nick> <user> <host> * :<real name>";
+    RplWhoisServer = 312, "RPL_WHOISSERVER", "<nick> <server> :<server info>";
+    RplWhoisOperator = 313, "RPL_WHOISOPERATOR", "<nick> :is an IRC operator";
+    RplWhowasUser = 314, "RPL_WHOWASUSER", "<nick> <user> <host> * :<real name>";
+    RplEndOfWho = 315, "RPL_ENDOFWHO", "<mask> :End of WHO list";
+    RplWhoisIdle = 317, "RPL_WHOISIDLE", "<nick> <secs> <signon> :seconds idle, signon time";
+    RplEndOfWhois = 318, "RPL_ENDOFWHOIS", "<nick> :End of /WHOIS list";
+    RplWhoisChannels = 319, "RPL_WHOISCHANNELS", "<nick> :<channel>...";
+    RplWhoisSpecial = 320, "RPL_WHOISSPECIAL", "<nick> :<message>";
+    RplListStart = 321, "RPL_LISTSTART", "Channel :Users  Name";
+    RplList = 322, "RPL_LIST", "<channel> <client count> :<topic>";
+    RplListEnd = 323, "RPL_LISTEND", ":End of /LIST";
+    RplChannelModeIs = 324, "RPL_CHANNELMODEIS", "<channel> <modestring> <mode arguments>...";
+    RplUniqOpIs = 325, "RPL_UNIQOPIS", "<channel> <nickname>";
+    RplCreationTime = 329, "RPL_CREATIONTIME", "<channel> <creationtime>";
+    RplWhoisAccount = 330, "RPL_WHOISACCOUNT", "<nick> <account> :is logged in as";
+    RplNoTopic = 331, "RPL_NOTOPIC", "<channel> :No topic is set";
+    RplTopic = 332, "RPL_TOPIC", "<channel> :<topic>";
+    RplTopicWhoTime = 333, "RPL_TOPICWHOTIME", "<channel> <nick> <setat>";
+    RplInviteList = 336, "RPL_INVITELIST", "<channel>";
+    RplEndOfInviteList = 337, "RPL_ENDOFINVITELIST", ":End of /INVITE list";
+    RplWhoisActually = 338, "RPL_WHOISACTUALLY", "<nick> <host> :is actually using host";
+    RplInviting = 341, "RPL_INVITING", "<nick> <channel>";
+    RplSummoning = 342, "RPL_SUMMONING", "<user> :Summoning user to IRC";
+    RplInvExList = 346, "RPL_INVEXLIST", "<channel> <mask>";
+    RplEndOfInvExList = 347, "RPL_ENDOFINVEXLIST", "<channel> :End of channel invite list";
+    RplExceptList = 348, "RPL_EXCEPTLIST", "<channel> <mask>";
+    RplEndOfExceptList = 349, "RPL_ENDOFEXCEPTLIST", "<channel> :End of channel exception list";
+    RplVersion = 351, "RPL_VERSION", "<version> <server> :<comments>";
+    RplWhoReply = 352, "RPL_WHOREPLY", "<channel> <user> <host> <server> <nick> <flags> :<hopcount> <real name>";
+    RplNamReply = 353, "RPL_NAMREPLY", "<symbol> <channel> :<nick>...";
+    RplLinks = 364, "RPL_LINKS", "<mask> <server> :<hopcount> <server info>";
+    RplEndOfLinks = 365, "RPL_ENDOFLINKS", "<mask> :End of /LINKS list";
+    RplEndOfNames = 366, "RPL_ENDOFNAMES", "<channel> :End of /NAMES list";
+    RplBanList = 367, "RPL_BANLIST", "<channel> <mask>";
+    RplEndOfBanList = 368, "RPL_ENDOFBANLIST", "<channel> :End of channel ban list";
+    RplEndOfWhowas = 369, "RPL_ENDOFWHOWAS", "<nick> :End of WHOWAS";
+    RplInfo = 371, "RPL_INFO", ":<string>";
+    RplMotd = 372, "RPL_MOTD", ":- <text>";
+    RplEndOfInfo = 374, "RPL_ENDOFINFO", ":End of INFO list";
+    RplMotdStart = 375, "RPL_MOTDSTART", ":- <server> Message of the day - ";
+    RplEndOfMotd = 376, "RPL_ENDOFMOTD", ":End of MOTD command";
+    RplWhoisHost = 378, "RPL_WHOISHOST", "<nick> :is connecting from <username>@<host> <ip>";
+    RplWhoisModes = 379, "RPL_WHOISMODES", "<nick> :is using modes <modes>";
+    RplYoureOper = 381, "RPL_YOUREOPER", ":You are now an IRC operator";
+    RplRehashing = 382, "RPL_REHASHING", "<config file> :Rehashing";
+    RplYoureService = 383, "RPL_YOURESERVICE", ":You are service <servicename>";
+    RplTime = 391, "RPL_TIME", "<server> :<local time>";
+    RplUsersStart = 392, "RPL_USERSSTART", ":UserID   Terminal  Host";
+    RplUsers = 393, "RPL_USERS", ":<username> <ttyline> <hostname>";
+    RplEndOfUsers = 394, "RPL_ENDOFUSERS", ":End of users";
+    RplNoUsers = 395, "RPL_NOUSERS", ":Nobody logged in";
+    ErrUnknownError = 400, "ERR_UNKNOWNERROR", "<command> :<info>";
+    ErrNoSuchNick = 401, "ERR_NOSUCHNICK", "<nickname> :No such nick/channel";
+    ErrNoSuchServer = 402, "ERR_NOSUCHSERVER", "<server name> :No such server";
+    ErrNoSuchChannel = 403, "ERR_NOSUCHCHANNEL", "<channel name> :No such channel";
+    ErrCannotSendToChan = 404, "ERR_CANNOTSENDTOCHAN", "<channel name> :Cannot send to channel";
+    ErrTooManyChannels = 405, "ERR_TOOMANYCHANNELS", "<channel name> :You have joined too many channels";
+    ErrWasNoSuchNick = 406, "ERR_WASNOSUCHNICK", "<nickname> :There was no such nickname";
+    ErrTooManyTargets = 407, "ERR_TOOMANYTARGETS", "<target> :<error code> recipients. <abort message>";
+    ErrNoSuchService = 408, "ERR_NOSUCHSERVICE", "<service name> :No such service";
+    ErrNoOrigin = 409, "ERR_NOORIGIN", ":No origin specified";
+    ErrInvalidCapCmd = 410, "ERR_INVALIDCAPCMD", "<command> :Invalid CAP command";
+    ErrNoRecipient = 411, "ERR_NORECIPIENT", ":No recipient given";
+    ErrNoTextToSend = 412, "ERR_NOTEXTTOSEND", ":No text to send";
+    ErrNoTopLevel = 413, "ERR_NOTOPLEVEL", "<mask> :No toplevel domain specified";
+    ErrWildTopLevel = 414, "ERR_WILDTOPLEVEL", "<mask> :Wildcard in toplevel domain";
+    ErrBadMask = 415, "ERR_BADMASK", "<mask> :Bad Server/host mask";
+    ErrInputTooLong = 417, "ERR_INPUTTOOLONG", ":Input line was too long";
+    ErrUnknownCommand = 421, "ERR_UNKNOWNCOMMAND", "<command> :Unknown command";
+    ErrNoMotd = 422, "ERR_NOMOTD", ":MOTD File is missing";
+    ErrNoAdminInfo = 423, "ERR_NOADMININFO", "<server> :No administrative info available";
+    ErrFileError = 424, "ERR_FILEERROR", ":File error doing <file op> on <file>";
+    ErrNoNicknameGiven = 431, "ERR_NONICKNAMEGIVEN", ":No nickname given";
+    // The typo is the same as in RFC 1459.
+    ErrErroneusNickname = 432, "ERR_ERRONEUSNICKNAME", "<nick> :Erroneus nickname";
+    ErrNicknameInUse = 433, "ERR_NICKNAMEINUSE", "<nick> :Nickname is already in use";
+    ErrNickCollision = 436, "ERR_NICKCOLLISION", "<nick> :Nickname collision";
+    ErrUnavailResource = 437, "ERR_UNAVAILRESOURCE", "<nick/channel> :Nick/channel is temporarily unavailable";
+    ErrUserNotInChannel = 441, "ERR_USERNOTINCHANNEL", "<nick> <channel> :They aren't on that channel";
+    ErrNotOnChannel = 442, "ERR_NOTONCHANNEL", "<channel> :You're not on that channel";
+    ErrUserOnChannel = 443, "ERR_USERONCHANNEL", "<user> <channel> :is already on channel";
+    ErrNoLogin = 444, "ERR_NOLOGIN", "<user> :User not logged in";
+    ErrSummonDisabled = 445, "ERR_SUMMONDISABLED", ":SUMMON has been disabled";
+    ErrUsersDisabled = 446, "ERR_USERSDISABLED", ":USERS has been disabled";
+    ErrNotRegistered = 451, "ERR_NOTREGISTERED", ":You have not registered";
+    ErrNeedMoreParams = 461, "ERR_NEEDMOREPARAMS", "<command> :Not enough parameters";
+    ErrAlreadyRegistered = 462, "ERR_ALREADYREGISTERED", ":You may not reregister";
+    ErrNoPermForHost = 463, "ERR_NOPERMFORHOST", ":Your host isn't among the privileged";
+    ErrPasswdMismatch = 464, "ERR_PASSWDMISMATCH", ":Password incorrect";
+    ErrYoureBannedCreep = 465, "ERR_YOUREBANNEDCREEP", ":You are banned from this server";
+    ErrYouWillBeBanned = 466, "ERR_YOUWILLBEBANNED", ":You will be banned from this server";
+    ErrKeySet = 467, "ERR_KEYSET", "<channel> :Channel key already set";
+    ErrInvalidUsername = 468, "ERR_INVALIDUSERNAME", "<username> :Malformed username";
+    ErrChannelIsFull = 471, "ERR_CHANNELISFULL", "<channel> :Cannot join channel (+l)";
+    ErrUnknownMode = 472, "ERR_UNKNOWNMODE", "<char> :is unknown mode char to me";
+    ErrInviteOnlyChan = 473, "ERR_INVITEONLYCHAN", "<channel> :Cannot join channel (+i)";
+    ErrBannedFromChan = 474, "ERR_BANNEDFROMCHAN", "<channel> :Cannot join channel (+b)";
+    ErrBadChannelKey = 475, "ERR_BADCHANNELKEY", "<channel> :Cannot join channel (+k)";
+    ErrBadChanMask = 476, "ERR_BADCHANMASK", "<channel> :Bad Channel Mask";
+    ErrNoChanModes = 477, "ERR_NOCHANMODES", "<channel> :Channel doesn't support modes";
+    ErrBanListFull = 478, "ERR_BANLISTFULL", "<channel> <char> :Channel list is full";
+    ErrBadChanName = 479, "ERR_BADCHANNAME", "<channel> :Illegal channel name";
+    ErrNoPrivileges = 481, "ERR_NOPRIVILEGES", ":Permission Denied- You're not an IRC operator";
+    ErrChanOPrivsNeeded = 482, "ERR_CHANOPRIVSNEEDED", "<channel> :You're not channel operator";
+    ErrCantKillServer = 483, "ERR_CANTKILLSERVER", ":You can't kill a server!";
+    ErrRestricted = 484, "ERR_RESTRICTED", ":Your connection is restricted!";
+    ErrUniqOpPrivsNeeded = 485, "ERR_UNIQOPPRIVSNEEDED", ":You're not the original channel operator";
+    ErrNoOperHost = 491, "ERR_NOOPERHOST", ":No O-lines for your host";
+    ErrUModeUnknownFlag = 501, "ERR_UMODEUNKNOWNFLAG", ":Unknown MODE flag";
+    ErrUsersDontMatch = 502, "ERR_USERSDONTMATCH", ":Cannot change mode for other users";
+    ErrHelpNotFound = 524, "ERR_HELPNOTFOUND", "<subject> :No help available on this topic";
+    ErrInvalidKey = 525, "ERR_INVALIDKEY", "<channel> :Key is not well-formed";
+    RplStartTls = 670, "RPL_STARTTLS", ":STARTTLS successful, proceed with TLS handshake";
+    RplWhoisSecure = 671, "RPL_WHOISSECURE", "<nick> :is using a secure connection";
+    ErrStartTls = 691, "ERR_STARTTLS", ":STARTTLS failed";
+    ErrInvalidModeParam = 696, "ERR_INVALIDMODEPARAM", "<target> <mode char> <parameter> :<description>";
+    RplHelpStart = 704, "RPL_HELPSTART", "<subject> :<first line of help section>";
+    RplHelpTxt = 705, "RPL_HELPTXT", "<subject> :<line of help text>";
+    RplEndOfHelp = 706, "RPL_ENDOFHELP", "<subject> :<last line of help text>";
+    ErrNoPrivs = 723, "ERR_NOPRIVS", "<priv> :Insufficient oper privileges.";
+    RplLoggedIn = 900, "RPL_LOGGEDIN", "<nick>!<user>@<host> <account> :You are now logged in as <account>";
+    RplLoggedOut = 901, "RPL_LOGGEDOUT", "<nick>!<user>@<host> :You are now logged out";
+    ErrNickLocked = 902, "ERR_NICKLOCKED", ":You must use a nick assigned to you";
+    RplSaslSuccess = 903, "RPL_SASLSUCCESS", ":SASL authentication successful";
+    ErrSaslFail = 904, "ERR_SASLFAIL", ":SASL authentication failed";
+    ErrSaslTooLong = 905, "ERR_SASLTOOLONG", ":SASL message too long";
+    ErrSaslAborted = 906, "ERR_SASLABORTED", ":SASL authentication aborted";
+    ErrSaslAlready = 907, "ERR_SASLALREADY", ":You have already authenticated using SASL";
+    RplSaslMechs = 908, "RPL_SASLMECHS", "<mechanisms> :are available SASL mechanisms";
+}
+
+impl Numeric {
+    /// Whether the last parameter is always sent after a `:`, because it is free text.
+    pub fn has_text(self) -> bool {
+        self.params().starts_with(':') || self.params().contains(" :")
+    }
+
+    /// The fixed text of the last parameter, for example `No such nick/channel`.
+    /// Numerics whose text is filled in, like the topic of `RPL_TOPIC`, have none.
+    pub fn default_text(self) -> Option<&'static str> {
+        let params = self.params();
+        let text = match params.split_once(" :") {
+            Some((_, text)) => text,
+            None => params.strip_prefix(':')?,
+        };
+        (!text.contains('<')).then_some(text)
+    }
+}
+
+impl TryFrom<u16> for Numeric {
+    type Error = ();
+
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        Numeric::ALL
+            .iter()
+            .find(|numeric| numeric.code() == value)
+            .copied()
+            .ok_or(())
+    }
+}
+
+impl Display for Numeric {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(fmt, "{:03}", self.code())
+    }
+}
+
+/// A numeric sent to a single user, for example `:iris-server 401 tom ann :No such nick/channel`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NumericReply {
+    pub numeric: Numeric,
+    /// The nick of the user the reply is for, or `*` if they haven't chosen one yet.
+    pub target: String,
+    /// The parameters between the target and the text.
+    pub params: Vec<String>,
+    /// The last parameter, for numerics that have one.
+    pub text: Option<String>,
+}
+
+impl NumericReply {
+    /// A reply with the numeric's default text, if it has one.
+    pub fn new(numeric: Numeric, target: impl Display, params: Vec<String>) -> Self {
+        NumericReply {
+            numeric,
+            target: target.to_string(),
+            params,
+            text: numeric.default_text().map(str::to_string),
+        }
+    }
+
+    /// This reply with `text` in place of the default.
+    pub fn with_text(mut self, text: impl Into<String>) -> Self {
+        self.text = Some(text.into());
+        self
+    }
+}
+
+impl Display for NumericReply {
+    fn fmt(&self, fmt: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let args = std::iter::once(self.target.as_str())
+            .chain(self.params.iter().map(String::as_str))
+            .collect::<Vec<_>>();
+        write!(fmt, ":{SERVER_NAME} ")?;
+        write_command(fmt, &self.numeric.to_string(), &args, self.text.as_deref())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catalog() {
+        for (i, numeric) in Numeric::ALL.iter().enumerate() {
+            assert_eq!(Numeric::try_from(numeric.code()), Ok(*numeric));
+            assert!(Numeric::ALL[i + 1..]
+                .iter()
+                .all(|other| other.code() > numeric.code()));
+        }
+        assert_eq!(Numeric::ErrNoSuchNick.name(), "ERR_NOSUCHNICK");
+        assert_eq!(Numeric::RplNoTopic.default_text(), Some("No topic is set"));
+        assert_eq!(Numeric::RplTopic.default_text(), None);
+        assert!(Numeric::RplTopic.has_text());
+        assert!(!Numeric::RplChannelModeIs.has_text());
+    }
+
+    #[test]
+    fn test_render() {
+        assert_eq!(
+            NumericReply::new(Numeric::ErrNoSuchNick, "tom", vec!["ann".to_string()]).to_string(),
+            ":iris-server 401 tom ann :No such nick/channel\r\n"
+        );
+        assert_eq!(
+            NumericReply::new(Numeric::RplWelcome, "*", vec![])
+                .with_text("Hi")
+                .to_string(),
+            ":iris-server 001 * :Hi\r\n"
+        );
+        assert_eq!(
+            NumericReply::new(
+                Numeric::RplChannelModeIs,
+                "tom",
+                vec!["#haku".to_string(), "+t".to_string()]
+            )
+            .to_string(),
+            ":iris-server 324 tom #haku +t\r\n"
+        );
+    }
+}
@@ -8,16 +8,16 @@
 //! - add a variant to Plugin enum
 //! - match the variant in create_plugin function
 //! - add parsing (string -> your plugin) to parse_plugin function
-//! - create the actual function to be ran in the sever thread  
+//! - create the actual function to be ran in the sever thread
+//!
 //! Please check out how Listing plugin is implemented.
-use std::{
-    collections::{HashMap, HashSet},
-    sync::mpsc::Sender,
-    thread::{self, sleep},
-    time::Duration,
-};
+use std::{collections::HashMap, time::Duration};
+use tokio::sync::mpsc::UnboundedSender;
 
-use crate::types::{Channel, MyMessage, ThreadInfo};
+use crate::{
+    numeric::{Numeric, NumericReply},
+    types::{Channel, ChannelInfo, Channels, MyMessage, ThreadInfo},
+};
 
 /// Plugin enum can be used to create plugin functions that are ran in the sever thread, by calling create_plugin
 pub enum Plugin {
@@ -35,17 +35,18 @@ pub enum Plugin {
 /// In addition to the Plugin enum and its associated parameters, we provide more parameters for the plugin designer.
 /// In particular, we provide
 /// - ip: ip address of the client
-/// - sender: channel to send messages to client threads,  Sender<(String, MyMessage)>: String for IP address + port
+/// - sender: channel to send messages to client threads,  UnboundedSender<(String, MyMessage)>: String for IP address + port
 /// - channels: all channel information
 /// - my_map: all user information, and their coonnection_writes
+///
 /// It's expected that the plugin function will be FnOnce, and should not block.
 /// If the plugin blocks, the sever thread will also be blocked !!! This is because we have only 1 sever thread.
-/// If blocking operation is required, for example in reminder, it's expected to perform the blocking operation in a new thread.
+/// If waiting is required, for example in reminder, it's expected to spawn a tokio task that waits; the sever thread runs inside the server's tokio runtime.
 pub fn create_plugin<'a>(
     plugin: Plugin,
     ip: String,
-    sender: Sender<(String, MyMessage)>,
-    channels: &'a mut HashMap<Channel, HashSet<String>>,
+    sender: UnboundedSender<(String, MyMessage)>,
+    channels: &'a mut Channels,
     my_map: &'a mut HashMap<String, ThreadInfo>,
 ) -> Box<dyn FnOnce() + 'a> {
     match plugin {
@@ -78,21 +79,28 @@ pub fn parse_plugin(command: String) -> Option<Plugin> {
 
 /// Doesn't need to be pub, added pub here for documentation
 /// This is the function to be ran in the sever thread for the Listing Plugin
-pub fn listing(
-    ip: String,
-    channels: &mut HashMap<Channel, HashSet<String>>,
-    my_map: &mut HashMap<String, ThreadInfo>,
-) {
-    let channels: Vec<&Channel> = channels.keys().collect();
-    let output = channels
+pub fn listing(ip: String, channels: &mut Channels, my_map: &mut HashMap<String, ThreadInfo>) {
+    let Some(user) = my_map.get_mut(&ip) else {
+        return;
+    };
+    let target = user.nick.as_ref().map_or("*".to_string(), |n| n.0.clone());
+    let mut listed: Vec<(&Channel, &ChannelInfo)> = channels
         .iter()
-        .map(|x| x.to_string())
-        .collect::<Vec<String>>()
-        .join(", ");
-    let conn_write = &mut my_map.get_mut(&ip).unwrap().conn_write;
-    conn_write
-        .write_message(&format!("Available channels are [{}]\n", output))
-        .unwrap();
+        .filter(|(_, info)| !info.modes.contains(&'s') || info.members.contains(&ip))
+        .collect();
+    listed.sort_by(|a, b| a.0 .0.cmp(&b.0 .0));
+    let mut output = String::new();
+    for (channel, info) in listed {
+        let reply = NumericReply::new(
+            Numeric::RplList,
+            &target,
+            vec![channel.to_string(), info.members.len().to_string()],
+        )
+        .with_text(info.topic.clone().unwrap_or_default());
+        output.push_str(&reply.to_string());
+    }
+    output.push_str(&NumericReply::new(Numeric::RplListEnd, &target, vec![]).to_string());
+    let _ = user.conn_write.write_message(&output);
 }
 
 /// Doesn't need to be pub
@@ -101,12 +109,12 @@ pub fn reminder(
     duration: Duration,
     nickname: String,
     message: String,
-    sender: Sender<(String, MyMessage)>,
+    sender: UnboundedSender<(String, MyMessage)>,
     ip: String,
 ) {
-    thread::spawn(move || {
+    tokio::spawn(async move {
         let command = format!("PRIVMSG {} :{}", nickname, message);
-        sleep(duration);
-        sender.send((ip, MyMessage::Request(command))).unwrap();
+        tokio::time::sleep(duration).await;
+        let _ = sender.send((ip, MyMessage::Request(command)));
     });
 }
@@ -0,0 +1,3670 @@
+//! The state of a running server, and how it responds to its clients.
+//!
+//! [`ServerState`] owns every user and channel and is driven one [`MyMessage`] at a time,
+//! writing its replies to each user's [`ConnectionWrite`]. It never touches a socket itself,
+//! so it can be exercised with in-memory connections.
+//!
+//! [`Server`] puts a [`ServerState`] behind real listeners, for the `iris` binary or anything
+//! else that wants to embed one:
+//!
+//! ```no_run
+//! use iris_lib::server::Server;
+//!
+//! let server = Server::builder().listen(([127, 0, 0, 1], 0)).build().unwrap();
+//! let handle = server.spawn();
+//! println!("listening on {}", handle.local_addr());
+//! handle.shutdown();
+//! handle.join();
+//! ```
+use std::{
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+    sync::{Arc, Mutex},
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+use tokio::{
+    runtime::{self, Runtime},
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        watch,
+    },
+    task,
+    time::MissedTickBehavior,
+};
+
+use log::{debug, error, info};
+use subtle::ConstantTimeEq;
+
+use crate::{
+    bans::{in_network, BanKind, Bans},
+    chanserv::{self, AccessLevel, ChanServ, ChanServCommand, CHANSERV},
+    connect::{
+        Accepted, ConnectionClass, ConnectionError, ConnectionManager, ConnectionRead,
+        ConnectionWrite, QueueStats,
+    },
+    flood::{command_cost, FloodLimiter, FloodPolicy},
+    history::{
+        self, format_timestamp, Conversation, History, HistoryEntry, HistoryError, HistoryQuery,
+        MessageKind, Retention,
+    },
+    nickserv::{self, NickServ, NickServCommand, NickServError, PasswordResult, NICKSERV},
+    numeric::{Numeric, NumericReply},
+    plugin::{create_plugin, parse_plugin, Plugin},
+    tls::TlsConfig,
+    types::{
+        labeled_response, message_label, unique_id, AccountReply, AwayMsg, AwayReply,
+        AwayStatusReply, BanMsg, BatchStartReply, CapMsg, CapReply, Capability, CaseMapping,
+        Channel, ChannelInfo, ChannelModeIsReply, Channels, ChatHistoryMsg,
+        ChatHistoryTargetsReply, ChgHostReply, CommandError, EndOfNamesReply, EndOfWhoReply,
+        ErrorType, ExtendedJoinReply, FailReply, ISupportReply, IsAwayReply, JoinMsg, JoinReply,
+        Message, MessageRef, ModeChange, ModeReply, MyMessage, NamePolicy, NameRules, NamesReply,
+        Nick, NickMsg, NickReply, Nicks, NoticeMsg, NoticeReply, ParsedMessage, PartMsg, PartReply,
+        Prefix, QuitMsg, QuitReply, Reply, Target, ThreadInfo, TopicIsReply, TopicMsg, TopicReply,
+        UnparsedMessage, UserMsg, WelcomeReply, WhoReply, SERVER_NAME,
+    },
+};
+
+/// Whether `nick` belongs to one of the services pseudo-users.
+fn is_service(nick: &Nick, rules: &NameRules) -> bool {
+    [NICKSERV, CHANSERV]
+        .iter()
+        .any(|service| rules.confusable(&nick.0, service))
+}
+
+/// Sends each of `recipients` the reply `reply_for` picks for them, if any.
+fn send_each<'a>(
+    recipients: impl IntoIterator<Item = &'a String>,
+    my_map: &mut HashMap<String, ThreadInfo>,
+    reply_for: impl Fn(&ThreadInfo) -> Option<Reply>,
+) {
+    for recipient in recipients {
+        let Some(user) = my_map.get_mut(recipient) else {
+            continue;
+        };
+        if let Some(reply) = reply_for(user) {
+            let _ = user.conn_write.write_message(&reply.to_string());
+        }
+    }
+}
+
+/// Sends `reply` to the user at `address`, if they are still connected.
+/// A failed write is dealt with once the current message has been handled.
+fn send_to(address: &str, reply: &Reply, my_map: &mut HashMap<String, ThreadInfo>) {
+    if let Some(user) = my_map.get_mut(address) {
+        let _ = user.conn_write.write_message(&reply.to_string());
+    }
+}
+
+/// Sends `reply` to every member of a channel.
+fn channel_broadcast(info: &ChannelInfo, reply: &Reply, my_map: &mut HashMap<String, ThreadInfo>) {
+    send_each(&info.members, my_map, |_| Some(reply.clone()));
+}
+
+/// A stored message as `user` is shown it, with the tags they asked for.
+fn history_reply(user: &ThreadInfo, entry: &HistoryEntry) -> Reply {
+    let mut tags = vec![];
+    if user.caps.contains(&Capability::ServerTime) {
+        tags.push(("time".to_string(), format_timestamp(entry.time)));
+    }
+    if user.caps.contains(&Capability::MessageTags) {
+        tags.push(("msgid".to_string(), entry.msgid.clone()));
+    }
+    entry.reply().with_tags(tags)
+}
+
+/// Relays a PRIVMSG or NOTICE from the user at `address` to the users at `recipients`, keeping it in the history.
+/// Private messages are only kept when both users are logged in.
+fn relay<'a>(
+    address: &str,
+    kind: MessageKind,
+    target: &Target,
+    message: &str,
+    recipients: impl IntoIterator<Item = &'a String>,
+    my_map: &mut HashMap<String, ThreadInfo>,
+    history: &mut History,
+) {
+    let Some(sender) = my_map.get(address).map(ThreadInfo::prefix) else {
+        return;
+    };
+    let mut recipients = recipients.into_iter().peekable();
+    let conversation = match target {
+        Target::Channel(channel) => Some(history.channel(channel)),
+        Target::User(_) => recipients
+            .peek()
+            .and_then(|recipient| direct_conversation(address, recipient, my_map, history)),
+    };
+    let entry = history.record(conversation, &sender, kind, target, message);
+    send_each(recipients, my_map, |user| Some(history_reply(user, &entry)));
+}
+
+/// Wraps `replies` in a batch of type `kind`, if `user` supports batches.
+fn batched(user: &ThreadInfo, kind: &str, params: Vec<String>, replies: Vec<Reply>) -> Vec<Reply> {
+    if !user.caps.contains(&Capability::Batch) {
+        return replies;
+    }
+    let id = unique_id();
+    let mut batch = vec![Reply::BatchStart(BatchStartReply {
+        id: id.clone(),
+        kind: kind.to_string(),
+        params,
+    })];
+    batch.extend(
+        replies
+            .into_iter()
+            .map(|reply| reply.with_tags(vec![("batch".to_string(), id.clone())])),
+    );
+    batch.push(Reply::BatchEnd(id));
+    batch
+}
+
+/// The conversation a private message between the users at `address` and `other` is kept in,
+/// if both are logged in.
+fn direct_conversation(
+    address: &str,
+    other: &str,
+    my_map: &HashMap<String, ThreadInfo>,
+    history: &History,
+) -> Option<Conversation> {
+    let account = |address| my_map.get(address)?.account.as_deref();
+    Some(history.direct(account(address)?, account(other)?))
+}
+
+/// Runs a CHATHISTORY subcommand for the user at `address`.
+/// Users may read the history of channels they are in, and, once logged in, of their own private messages.
+fn chathistory_command(
+    address: &str,
+    msg: ChatHistoryMsg,
+    history: &History,
+    nickserv: &NickServ,
+    my_map: &mut HashMap<String, ThreadInfo>,
+    nicks: &Nicks,
+    channels: &Channels,
+) {
+    let Some(user) = my_map.get(address) else {
+        return;
+    };
+    let fail = |error: HistoryError, mut context: Vec<String>| {
+        context.insert(0, msg.subcommand.clone());
+        vec![Reply::Fail(FailReply {
+            command: "CHATHISTORY".to_string(),
+            code: error.code().to_string(),
+            context,
+            description: error.to_string(),
+        })]
+    };
+    let replies = match HistoryQuery::try_from(&msg) {
+        Err(e) => fail(e, vec![]),
+        Ok(HistoryQuery::Targets { start, end, limit }) => {
+            let joined = channels
+                .iter()
+                .filter(|(_, info)| info.members.contains(address))
+                .map(|(channel, _)| channel.clone())
+                .collect::<Vec<_>>();
+            let targets = history
+                .targets(user.account.as_deref(), &joined, start, end, limit)
+                .into_iter()
+                .map(|(target, time)| {
+                    Reply::ChatHistoryTargets(ChatHistoryTargetsReply { target, time })
+                })
+                .collect();
+            batched(user, "draft/chathistory-targets", vec![], targets)
+        }
+        Ok(query) => {
+            let target = Target::from(query.target().unwrap_or_default().to_string());
+            // Private messages are only shown to the accounts they were between, the other
+            // found from whoever uses the nickname now, or else whoever registered it.
+            let conversation = match &target {
+                Target::Channel(channel) => channels
+                    .get(channel)
+                    .is_some_and(|info| info.members.contains(address))
+                    .then(|| history.channel(channel)),
+                Target::User(nick) => user.account.as_deref().and_then(|account| {
+                    let other = nicks
+                        .address_of(&nick.0)
+                        .and_then(|other| my_map.get(other)?.account.clone())
+                        .or_else(|| Some(nickserv.account(&nick.0)?.name.clone()))?;
+                    Some(history.direct(account, &other))
+                }),
+            };
+            if let Some(conversation) = conversation {
+                let entries = history
+                    .query(&conversation, &query)
+                    .into_iter()
+                    .map(|entry| history_reply(user, entry))
+                    .collect();
+                batched(user, "chathistory", vec![target.to_string()], entries)
+            } else {
+                fail(HistoryError::InvalidTarget, vec![target.to_string()])
+            }
+        }
+    };
+    for reply in replies {
+        send_to(address, &reply, my_map);
+    }
+}
+
+/// The user at `address`, and everyone they share a channel with.
+fn shared_members(address: &str, channels: &Channels) -> HashSet<String> {
+    let mut members = channels
+        .values()
+        .filter(|info| info.members.contains(address))
+        .flat_map(|info| info.members.iter().cloned())
+        .collect::<HashSet<_>>();
+    members.insert(address.to_string());
+    members
+}
+
+/// The host a connection comes from, taken from its IP Address + Port.
+fn host_of(address: &str) -> String {
+    address
+        .parse::<SocketAddr>()
+        .map(|a| a.ip().to_string())
+        .unwrap_or_else(|_| address.to_string())
+}
+
+/// The IP address a connection comes from, taken from its IP Address + Port.
+fn ip_of(address: &str) -> Option<IpAddr> {
+    address.parse::<SocketAddr>().ok().map(|a| a.ip())
+}
+
+/// Logs the user at `address` in to (or out of) `account`, telling everyone who asked to know.
+/// Identified users have their host cloaked as `user/<account>`.
+fn set_account(
+    address: &str,
+    account: Option<String>,
+    my_map: &mut HashMap<String, ThreadInfo>,
+    channels: &Channels,
+) {
+    let Some(user) = my_map.get_mut(address) else {
+        return;
+    };
+    if user.account == account {
+        return;
+    }
+    user.account = account.clone();
+    let Some(nick) = user.nick.clone() else {
+        return;
+    };
+    let host = match &account {
+        Some(account) => format!("user/{account}"),
+        None => host_of(address),
+    };
+    let sender = user.prefix();
+    let old_host = std::mem::replace(&mut user.host, host.clone());
+    let username = user.username.clone().unwrap_or_else(|| nick.0.clone());
+    let recipients = shared_members(address, channels);
+    let reply = Reply::Account(AccountReply {
+        account,
+        sender: sender.clone(),
+    });
+    send_each(&recipients, my_map, |member| {
+        member
+            .caps
+            .contains(&Capability::AccountNotify)
+            .then(|| reply.clone())
+    });
+    if old_host != host {
+        let reply = Reply::ChgHost(ChgHostReply {
+            username,
+            host,
+            sender,
+        });
+        send_each(&recipients, my_map, |member| {
+            member
+                .caps
+                .contains(&Capability::ChgHost)
+                .then(|| reply.clone())
+        });
+    }
+}
+
+/// How long the names in a single NAMES reply may get before the rest go in another.
+const NAMES_LINE_LIMIT: usize = 400;
+
+/// Whether the user at `address` may see who is in a channel.
+fn can_see(info: &ChannelInfo, address: &str) -> bool {
+    !info.modes.contains(&'s') || info.members.contains(address)
+}
+
+/// The NAMES replies for `channel`, as seen by the user at `viewer`.
+/// With `multi-prefix` every status is shown, and with `userhost-in-names` full hostmasks are.
+fn names_replies(
+    viewer: &str,
+    channel: &Channel,
+    info: &ChannelInfo,
+    my_map: &HashMap<String, ThreadInfo>,
+) -> Vec<Reply> {
+    let Some(user) = my_map.get(viewer) else {
+        return Vec::new();
+    };
+    let Some(target_nick) = user.nick.clone() else {
+        return Vec::new();
+    };
+    let multi_prefix = user.caps.contains(&Capability::MultiPrefix);
+    let userhost = user.caps.contains(&Capability::UserhostInNames);
+    let mut names = info
+        .members
+        .iter()
+        .filter_map(|member| {
+            let member_info = my_map.get(member)?;
+            let nick = member_info.nick.as_ref()?;
+            let prefixes = info.prefixes(member, multi_prefix);
+            Some(if userhost {
+                let username = member_info.username.as_ref().unwrap_or(&nick.0);
+                format!("{prefixes}{nick}!{username}@{}", member_info.host)
+            } else {
+                format!("{prefixes}{nick}")
+            })
+        })
+        .collect::<Vec<_>>();
+    names.sort();
+    let mut replies = vec![];
+    let mut line: Vec<String> = vec![];
+    for name in names {
+        if line.iter().map(|n| n.len() + 1).sum::<usize>() + name.len() > NAMES_LINE_LIMIT {
+            replies.push(std::mem::take(&mut line));
+        }
+        line.push(name);
+    }
+    if !line.is_empty() {
+        replies.push(line);
+    }
+    replies
+        .into_iter()
+        .map(|names| {
+            Reply::Names(NamesReply {
+                target_nick: target_nick.clone(),
+                channel: channel.clone(),
+                secret: info.modes.contains(&'s'),
+                names,
+            })
+        })
+        .collect()
+}
+
+/// The WHO replies for `mask`, which is either a channel or a nickname mask, as seen by the user at `viewer`.
+fn who_replies(
+    viewer: &str,
+    mask: &str,
+    my_map: &HashMap<String, ThreadInfo>,
+    channels: &Channels,
+    rules: &NameRules,
+) -> Vec<Reply> {
+    let Some(user) = my_map.get(viewer) else {
+        return Vec::new();
+    };
+    let Some(target_nick) = user.nick.clone() else {
+        return Vec::new();
+    };
+    let multi_prefix = user.caps.contains(&Capability::MultiPrefix);
+    let who = |address: &String, channel: Option<(&Channel, &ChannelInfo)>| {
+        let member = my_map.get(address)?;
+        let nick = member.nick.clone()?;
+        Some(Reply::Who(WhoReply {
+            target_nick: target_nick.clone(),
+            channel: channel.map(|(channel, _)| channel.clone()),
+            username: member.username.clone().unwrap_or_else(|| nick.0.clone()),
+            host: member.host.clone(),
+            away: member.away.is_some(),
+            prefixes: channel.map_or(String::new(), |(_, info)| {
+                info.prefixes(address, multi_prefix)
+            }),
+            real_name: member.full_name.clone().unwrap_or_default(),
+            nick,
+        }))
+    };
+    let mut replies = if mask.starts_with('#') {
+        let channel = Channel(mask.to_string());
+        match channels.get_key_value(&channel) {
+            Some((channel, info)) if can_see(info, viewer) => {
+                let mut members = info.members.iter().collect::<Vec<_>>();
+                members.sort_by_key(|member| {
+                    my_map
+                        .get(*member)
+                        .and_then(|m| m.nick.as_ref())
+                        .map(|n| n.0.clone())
+                });
+                members
+                    .into_iter()
+                    .filter_map(|member| who(member, Some((channel, info))))
+                    .collect()
+            }
+            _ => vec![],
+        }
+    } else {
+        let mut users = my_map
+            .iter()
+            .filter(|(_, user)| user.full_name.is_some())
+            .filter(|(_, user)| {
+                user.nick
+                    .as_ref()
+                    .is_some_and(|nick| rules.mask_matches(mask, &nick.0))
+            })
+            .collect::<Vec<_>>();
+        users.sort_by_key(|(_, user)| user.nick.as_ref().map(|n| n.0.clone()));
+        users
+            .into_iter()
+            .filter_map(|(address, _)| who(address, None))
+            .collect::<Vec<_>>()
+    };
+    replies.push(Reply::EndOfWho(EndOfWhoReply {
+        target_nick,
+        mask: mask.to_string(),
+    }));
+    replies
+}
+
+/// The WHOIS replies about `nick`, as seen by the user at `viewer`.
+/// A client certificate fingerprint is only shown to its owner and to IRC operators.
+fn whois_replies(
+    viewer: &str,
+    nick: &Nick,
+    my_map: &HashMap<String, ThreadInfo>,
+    nicks: &Nicks,
+    channels: &Channels,
+) -> Vec<Reply> {
+    let Some(own_nick) = my_map.get(viewer).and_then(|user| user.nick.clone()) else {
+        return Vec::new();
+    };
+    let found = nicks
+        .address_of(&nick.0)
+        .and_then(|address| Some((my_map.get(address)?, address.clone())))
+        .filter(|(user, _)| user.full_name.is_some());
+    let Some((user, address)) = found else {
+        return vec![
+            Reply::error(&own_nick, ErrorType::NoSuchNick.about([nick])),
+            Reply::Numeric(NumericReply::new(
+                Numeric::RplEndOfWhois,
+                &own_nick,
+                vec![nick.to_string()],
+            )),
+        ];
+    };
+    // Replies name the user the way they spell their nick, not the way it was asked for.
+    let nick = user.nick.as_ref().unwrap_or(nick);
+    let numeric = |numeric, params: &[&str]| {
+        let params = [nick.0.as_str()]
+            .iter()
+            .chain(params)
+            .map(|param| param.to_string())
+            .collect();
+        NumericReply::new(numeric, &own_nick, params)
+    };
+    let mut replies = vec![numeric(
+        Numeric::RplWhoisUser,
+        &[user.username.as_deref().unwrap_or(&nick.0), &user.host, "*"],
+    )
+    .with_text(user.full_name.clone().unwrap_or_default())];
+    let mut listed = channels
+        .iter()
+        .filter(|(_, info)| info.members.contains(&address) && can_see(info, viewer))
+        .map(|(channel, info)| format!("{}{channel}", info.prefixes(&address, false)))
+        .collect::<Vec<_>>();
+    if !listed.is_empty() {
+        listed.sort();
+        replies.push(numeric(Numeric::RplWhoisChannels, &[]).with_text(listed.join(" ")));
+    }
+    replies.push(numeric(Numeric::RplWhoisServer, &[SERVER_NAME]).with_text("The iris IRC server"));
+    if let Some(away) = &user.away {
+        replies.push(numeric(Numeric::RplAway, &[]).with_text(away.clone()));
+    }
+    if user.oper.is_some() {
+        replies.push(numeric(Numeric::RplWhoisOperator, &[]));
+    }
+    if let Some(account) = &user.account {
+        replies.push(numeric(Numeric::RplWhoisAccount, &[account]));
+    }
+    if user.conn_write.secure() {
+        replies.push(numeric(Numeric::RplWhoisSecure, &[]));
+    }
+    let privileged = address == viewer || my_map.get(viewer).is_some_and(|u| u.oper.is_some());
+    if let Some(certfp) = user.conn_write.certfp().filter(|_| privileged) {
+        replies.push(
+            numeric(Numeric::RplWhoisCertFp, &[])
+                .with_text(format!("has client certificate fingerprint {certfp}")),
+        );
+    }
+    replies.push(numeric(Numeric::RplEndOfWhois, &[]));
+    replies.into_iter().map(Reply::Numeric).collect()
+}
+
+/// Runs a CAP subcommand for the user at `address`.
+/// Returns the USER message that was put on hold if this ended negotiation and registration can finish.
+fn cap_command(
+    address: &str,
+    msg: CapMsg,
+    my_map: &mut HashMap<String, ThreadInfo>,
+) -> Option<UserMsg> {
+    let user = my_map.get_mut(address)?;
+    let registered = user.full_name.is_some();
+    let target = user.nick.as_ref().map_or("*".to_string(), |n| n.0.clone());
+    let reply = |subcommand: &str, caps: String| {
+        Reply::Cap(CapReply {
+            target: target.clone(),
+            subcommand: subcommand.to_string(),
+            caps,
+        })
+    };
+    let reply = match msg.subcommand.as_str() {
+        "LS" => {
+            let caps = Capability::ALL.map(|cap| cap.to_string()).join(" ");
+            reply("LS", caps)
+        }
+        "LIST" => {
+            let mut caps = user.caps.iter().collect::<Vec<_>>();
+            caps.sort();
+            let caps = caps.iter().map(|cap| cap.to_string()).collect::<Vec<_>>();
+            reply("LIST", caps.join(" "))
+        }
+        "REQ" => {
+            let requested = msg.args.unwrap_or_default();
+            // A request is all-or-nothing, and `-cap` asks for a capability to be removed.
+            let changes = requested
+                .split_whitespace()
+                .map(|cap| match cap.strip_prefix('-') {
+                    Some(cap) => Capability::try_from(cap).map(|cap| (false, cap)),
+                    None => Capability::try_from(cap).map(|cap| (true, cap)),
+                })
+                .collect::<Result<Vec<_>, _>>();
+            match changes {
+                Ok(changes) => {
+                    for (enable, cap) in changes {
+                        if enable {
+                            user.caps.insert(cap);
+                        } else {
+                            user.caps.remove(&cap);
+                        }
+                    }
+                    reply("ACK", requested)
+                }
+                Err(()) => reply("NAK", requested),
+            }
+        }
+        "END" => {
+            user.negotiating = false;
+            user.nick.as_ref()?;
+            return user.pending_user.take();
+        }
+        _ => Reply::error(&target, ErrorType::InvalidCapCmd.about([&msg.subcommand])),
+    };
+    // Negotiation puts registration on hold until CAP END.
+    if !registered && msg.subcommand != "LIST" && !matches!(reply, Reply::Error(_)) {
+        user.negotiating = true;
+    }
+    let _ = user.conn_write.write_message(&reply.to_string());
+    None
+}
+
+/// Finishes registering the user at `address`, welcoming them to the server.
+#[allow(clippy::too_many_arguments)]
+fn complete_registration(
+    address: &str,
+    name: UserMsg,
+    my_map: &mut HashMap<String, ThreadInfo>,
+    nicks: &mut Nicks,
+    connections: &mut HashMap<IpAddr, usize>,
+    nickserv: &NickServ,
+    bans: &Bans,
+    rules: &NameRules,
+    sender: UnboundedSender<(String, MyMessage)>,
+    grace: Duration,
+) {
+    let Some(user) = my_map.get_mut(address) else {
+        return;
+    };
+    let Some(nick) = user.nick.clone() else {
+        return;
+    };
+    let real_host = host_of(address);
+    let hosts = [real_host.as_str(), user.host.as_str()];
+    if let Some(ban) = bans.kline(&name.username, hosts, SystemTime::now()) {
+        let reason = ban.disconnect_reason();
+        let reply = Reply::error(&nick, ErrorType::YoureBannedCreep);
+        let _ = user.conn_write.write_message(&reply.to_string());
+        let _ = user
+            .conn_write
+            .write_message(&format!("ERROR :Closing Link ({reason})\r\n"));
+        user.conn_write.shutdown();
+        remove_user(address, my_map, nicks, connections);
+        return;
+    }
+    let reply = Reply::Welcome(WelcomeReply {
+        target_nick: nick.clone(),
+        message: format!("Hi {}, welcome to IRC", name.real_name),
+    });
+    let _ = user.conn_write.write_message(&reply.to_string());
+    let reply = Reply::ISupport(ISupportReply {
+        target_nick: nick.clone(),
+        tokens: vec![
+            format!("CASEMAPPING={}", rules.casemapping),
+            format!("CHANNELLEN={}", rules.channel_len),
+            format!("CHATHISTORY={}", history::MAX_LIMIT),
+            format!("NICKLEN={}", rules.nick_len),
+        ],
+    });
+    let _ = user.conn_write.write_message(&reply.to_string());
+    user.full_name = Some(name.real_name);
+    user.username = Some(name.username);
+    if nickserv.account(&nick.0).is_some() {
+        protect_nick(
+            &mut user.conn_write,
+            &nick,
+            address.to_string(),
+            sender,
+            grace,
+        );
+    }
+}
+
+/// The prefix of messages from the service called `service`, for example `NickServ!NickServ@iris-server`.
+fn service_prefix(service: &str) -> Prefix {
+    Prefix::User {
+        nick: Nick(service.to_string()),
+        username: Some(service.to_string()),
+        host: Some(SERVER_NAME.to_string()),
+    }
+}
+
+/// Sends a NOTICE to `target` on behalf of the service called `service`.
+fn service_notice(conn_write: &mut ConnectionWrite, service: &str, target: &Nick, message: &str) {
+    let reply = Reply::Notice(NoticeReply {
+        message: NoticeMsg {
+            target: Target::User(target.clone()),
+            message: message.to_string(),
+        },
+        sender: service_prefix(service),
+    });
+    let _ = conn_write.write_message(&reply.to_string());
+}
+
+/// Changes the nick of the user at `address`, telling them and everyone they share a channel with.
+fn change_nick(
+    address: &str,
+    new_nick: Nick,
+    my_map: &mut HashMap<String, ThreadInfo>,
+    nicks: &mut Nicks,
+    channels: &Channels,
+) {
+    let Some(user) = my_map.get_mut(address).filter(|user| user.nick.is_some()) else {
+        return;
+    };
+    let sender = user.prefix();
+    nicks.insert(address, user.nick.as_ref(), &new_nick);
+    user.nick = Some(new_nick.clone());
+    let reply = Reply::Nick(NickReply {
+        message: NickMsg { nick: new_nick },
+        sender,
+    });
+    send_each(&shared_members(address, channels), my_map, |_| {
+        Some(reply.clone())
+    });
+}
+
+/// Removes the user at `address` from the server, sending `reply` to everyone they shared a channel with.
+/// If `reason` is given, the server is closing the link itself, so the user is told why.
+fn disconnect(
+    address: &str,
+    reply: Reply,
+    reason: Option<&str>,
+    my_map: &mut HashMap<String, ThreadInfo>,
+    nicks: &mut Nicks,
+    channels: &mut Channels,
+    connections: &mut HashMap<IpAddr, usize>,
+) {
+    for info in channels.values_mut() {
+        if info.remove(address) {
+            channel_broadcast(info, &reply, my_map);
+        }
+    }
+    channels.retain(|_, info| !info.members.is_empty());
+    if let Some(mut user) = remove_user(address, my_map, nicks, connections) {
+        if let Some(reason) = reason {
+            let _ = user
+                .conn_write
+                .write_message(&format!("ERROR :Closing Link ({reason})\r\n"));
+            user.conn_write.shutdown();
+        }
+    }
+}
+
+/// Removes the user at `address` from `my_map` and `nicks`, no longer counting them in `connections`.
+fn remove_user(
+    address: &str,
+    my_map: &mut HashMap<String, ThreadInfo>,
+    nicks: &mut Nicks,
+    connections: &mut HashMap<IpAddr, usize>,
+) -> Option<ThreadInfo> {
+    let user = my_map.remove(address)?;
+    if let Some(nick) = &user.nick {
+        nicks.remove(address, nick);
+    }
+    if let Some(ip) = ip_of(address) {
+        if let Entry::Occupied(mut count) = connections.entry(ip) {
+            *count.get_mut() -= 1;
+            if *count.get() == 0 {
+                count.remove();
+            }
+        }
+    }
+    Some(user)
+}
+
+/// Warns the user about to hold a registered nickname, and renames them if they don't identify in time.
+fn protect_nick(
+    conn_write: &mut ConnectionWrite,
+    nick: &Nick,
+    address: String,
+    sender: UnboundedSender<(String, MyMessage)>,
+    grace: Duration,
+) {
+    service_notice(
+        conn_write,
+        NICKSERV,
+        nick,
+        &format!(
+            "This nickname is registered. Please identify via /msg {NICKSERV} IDENTIFY <password> within {} seconds, or your nickname will be changed.",
+            grace.as_secs()
+        ),
+    );
+    let nick = nick.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(grace).await;
+        let _ = sender.send((address, MyMessage::NickEnforce(nick)));
+    });
+}
+
+/// Drops the clients that took too long to register or to answer a PING, and pings the
+/// registered users who have gone quiet, each as often as their connection class says.
+fn check_timers(
+    now: Instant,
+    my_map: &mut HashMap<String, ThreadInfo>,
+    nicks: &mut Nicks,
+    channels: &mut Channels,
+    connections: &mut HashMap<IpAddr, usize>,
+) {
+    let mut expired = Vec::new();
+    for (address, user) in my_map.iter_mut() {
+        let class = &user.class;
+        if user.full_name.is_none() {
+            if now.saturating_duration_since(user.connected) >= class.registration_timeout {
+                expired.push((address.clone(), "Registration timeout"));
+            }
+        } else if let Some(sent) = user.ping {
+            if now.saturating_duration_since(sent) >= class.ping_timeout {
+                expired.push((address.clone(), "Ping timeout"));
+            }
+        } else if now.saturating_duration_since(user.last_active) >= class.ping_frequency {
+            user.ping = Some(now);
+            let reply = Reply::Ping(SERVER_NAME.to_string());
+            let _ = user.conn_write.write_message(&reply.to_string());
+        }
+    }
+    for (address, reason) in expired {
+        let Some(user) = my_map.get(&address) else {
+            continue;
+        };
+        let reply = Reply::Quit(QuitReply {
+            message: QuitMsg {
+                message: Some(reason.to_string()),
+            },
+            sender: user.prefix(),
+        });
+        disconnect(
+            &address,
+            reply,
+            Some(reason),
+            my_map,
+            nicks,
+            channels,
+            connections,
+        );
+    }
+}
+
+/// Why the connection from `address` should be refused, if it should: it is D-lined, or
+/// reconnecting too often, or there are already too many connections from its host or
+/// network, as its connection `class` allows. The attempt is remembered in `recent`.
+fn refuse_connection(
+    address: &str,
+    class: &ConnectionClass,
+    now: Instant,
+    bans: &Bans,
+    recent: &mut HashMap<IpAddr, VecDeque<Instant>>,
+    connections: &HashMap<IpAddr, usize>,
+) -> Option<String> {
+    let ip = ip_of(address)?;
+    if let Some(ban) = bans.dline(ip, SystemTime::now()) {
+        return Some(ban.disconnect_reason());
+    }
+    let attempts = recent.entry(ip).or_default();
+    while attempts
+        .front()
+        .is_some_and(|at| now.saturating_duration_since(*at) >= class.throttle_window)
+    {
+        attempts.pop_front();
+    }
+    attempts.push_back(now);
+    if attempts.len() > class.throttle {
+        return Some("Throttled: reconnecting too fast".to_string());
+    }
+    let len = match ip {
+        IpAddr::V4(_) => class.cidr_ipv4,
+        IpAddr::V6(_) => class.cidr_ipv6,
+    };
+    let same_host = connections.get(&ip).copied().unwrap_or(0);
+    let same_network = connections
+        .iter()
+        .filter(|(other, _)| in_network(**other, ip, len))
+        .map(|(_, count)| count)
+        .sum::<usize>();
+    if same_host >= class.max_per_ip {
+        Some("Too many connections from your host".to_string())
+    } else if same_network >= class.max_per_cidr {
+        Some("Too many connections from your network".to_string())
+    } else {
+        None
+    }
+}
+
+/// Disconnects every user that one of `bans` now refuses.
+fn enforce_bans(
+    bans: &Bans,
+    my_map: &mut HashMap<String, ThreadInfo>,
+    nicks: &mut Nicks,
+    channels: &mut Channels,
+    connections: &mut HashMap<IpAddr, usize>,
+) {
+    let now = SystemTime::now();
+    let banned = my_map
+        .iter()
+        .filter_map(|(address, user)| {
+            let real_host = host_of(address);
+            let dline = real_host
+                .parse::<IpAddr>()
+                .ok()
+                .and_then(|ip| bans.dline(ip, now));
+            let kline = user.username.as_ref().and_then(|username| {
+                bans.kline(username, [real_host.as_str(), user.host.as_str()], now)
+            });
+            let ban = dline.or(kline)?;
+            Some((address.clone(), user.prefix(), ban.disconnect_reason()))
+        })
+        .collect::<Vec<_>>();
+    for (address, prefix, reason) in banned {
+        let reply = Reply::Quit(QuitReply {
+            message: QuitMsg {
+                message: Some(reason.clone()),
+            },
+            sender: prefix,
+        });
+        disconnect(
+            &address,
+            reply,
+            Some(&reason),
+            my_map,
+            nicks,
+            channels,
+            connections,
+        );
+    }
+}
+
+/// Sends a NOTICE from the server itself, called `server_name`, to `target`.
+fn server_notice(conn_write: &mut ConnectionWrite, server_name: &str, target: &str, message: &str) {
+    let reply = Reply::Notice(NoticeReply {
+        message: NoticeMsg {
+            target: Target::User(Nick(target.to_string())),
+            message: message.to_string(),
+        },
+        sender: Prefix::server(server_name),
+    });
+    let _ = conn_write.write_message(&reply.to_string());
+}
+
+/// Handles KLINE, DLINE, UNKLINE and UNDLINE from the user at `address`, who must be an IRC operator.
+/// A new ban disconnects everyone it refuses straight away.
+#[allow(clippy::too_many_arguments)]
+fn ban_command(
+    address: &str,
+    message: Message,
+    server_name: &str,
+    bans: &mut Bans,
+    my_map: &mut HashMap<String, ThreadInfo>,
+    nicks: &mut Nicks,
+    channels: &mut Channels,
+    connections: &mut HashMap<IpAddr, usize>,
+) {
+    let Some(user) = my_map.get_mut(address) else {
+        return;
+    };
+    let own_nick = user.nick.as_ref().map_or("*".to_string(), |n| n.0.clone());
+    if user.oper.is_none() {
+        let reply = Reply::error(&own_nick, ErrorType::NoPrivileges);
+        let _ = user.conn_write.write_message(&reply.to_string());
+        return;
+    }
+    let (notice, added) = match message {
+        Message::KLine(msg) => add_ban(bans, BanKind::KLine, msg),
+        Message::DLine(msg) => add_ban(bans, BanKind::DLine, msg),
+        Message::UnKLine(mask) => (remove_ban(bans, BanKind::KLine, &mask), false),
+        Message::UnDLine(mask) => (remove_ban(bans, BanKind::DLine, &mask), false),
+        _ => return,
+    };
+    server_notice(&mut user.conn_write, server_name, &own_nick, &notice);
+    if added {
+        enforce_bans(bans, my_map, nicks, channels, connections);
+    }
+}
+
+/// Adds the ban an operator asked for, returning what to tell them and whether it was added.
+/// Bans without a reason get a generic one, and bans without a duration never expire.
+fn add_ban(bans: &mut Bans, kind: BanKind, msg: BanMsg) -> (String, bool) {
+    let reason = msg.reason.as_deref().unwrap_or("No reason");
+    let duration = msg
+        .minutes
+        .filter(|minutes| *minutes > 0)
+        .map(|minutes| Duration::from_secs(minutes * 60));
+    match bans.add(kind, &msg.mask, reason, duration, SystemTime::now()) {
+        Ok(ban) => (format!("Added {kind} for {}.", ban.mask), true),
+        Err(e) => (e.to_string(), false),
+    }
+}
+
+/// Lifts the ban an operator asked for, returning what to tell them.
+fn remove_ban(bans: &mut Bans, kind: BanKind, mask: &str) -> String {
+    match bans.remove(kind, mask) {
+        Ok(ban) => format!("Removed {kind} for {}.", ban.mask),
+        Err(e) => e.to_string(),
+    }
+}
+
+/// The replies to `STATS query` from `nick`: the K-lines for `k` and the D-lines for `d`,
+/// which only IRC operators may list. Other queries only get the end of the report.
+fn stats_replies(nick: &str, query: &str, oper: bool, bans: &Bans) -> Vec<Reply> {
+    let mut replies = Vec::new();
+    let listed = match query {
+        "k" | "K" => Some((BanKind::KLine, Numeric::RplStatsKLine, "K")),
+        "d" | "D" => Some((BanKind::DLine, Numeric::RplStatsDLine, "D")),
+        _ => None,
+    };
+    if let Some((kind, numeric, letter)) = listed {
+        if !oper {
+            return vec![Reply::error(nick, ErrorType::NoPrivileges)];
+        }
+        let now = SystemTime::now();
+        for ban in bans.list(kind, now) {
+            let expires = ban.expires.map_or("never".to_string(), |expires| {
+                let left = expires.duration_since(now).unwrap_or_default();
+                format!("{}m", left.as_secs().div_ceil(60))
+            });
+            let params = vec![letter.to_string(), ban.mask.clone(), expires];
+            let reply = NumericReply::new(numeric, nick, params).with_text(ban.reason.clone());
+            replies.push(Reply::Numeric(reply));
+        }
+    }
+    replies.push(Reply::Numeric(NumericReply::new(
+        Numeric::RplEndOfStats,
+        nick,
+        vec![query.to_string()],
+    )));
+    replies
+}
+
+/// Picks an unused `GuestNNNN` nickname.
+fn guest_nick(nicks: &Nicks) -> Nick {
+    loop {
+        let mut bytes = [0u8; 2];
+        getrandom::getrandom(&mut bytes).expect("failed to gather randomness for guest nick");
+        let guest = Nick(format!("Guest{}", u16::from_le_bytes(bytes) % 10000));
+        if nicks.confusable(&guest.0).is_none() {
+            return guest;
+        }
+    }
+}
+
+/// Starts a command sent by the user at `address` to NickServ. A password the command gives is
+/// checked or hashed on a blocking thread, after which the command comes back as
+/// [`MyMessage::NickServ`]; any other command is returned to be finished straight away.
+fn nickserv_command(
+    address: &str,
+    command: &str,
+    nickserv: &NickServ,
+    my_map: &mut HashMap<String, ThreadInfo>,
+    sender: &UnboundedSender<(String, MyMessage)>,
+) -> Option<NickServCommand> {
+    let ThreadInfo {
+        nick: Some(nick),
+        account,
+        conn_write,
+        ..
+    } = my_map.get_mut(address)?
+    else {
+        return None;
+    };
+    let command = match NickServCommand::try_from(command) {
+        Ok(command) => command,
+        Err(usage) => {
+            service_notice(conn_write, NICKSERV, nick, &usage);
+            return None;
+        }
+    };
+    let work = match &command {
+        NickServCommand::Register { .. }
+            if account.is_none() && nickserv.account(&nick.0).is_none() =>
+        {
+            Some(nickserv.hash())
+        }
+        NickServCommand::Identify { account: name, .. } => {
+            nickserv.check(name.as_deref().unwrap_or(&nick.0))
+        }
+        NickServCommand::Ghost {
+            nick: ghost,
+            password: Some(_),
+        } => nickserv.check(ghost),
+        NickServCommand::Drop { .. } => account.as_deref().and_then(|name| nickserv.check(name)),
+        NickServCommand::SetPassword { .. } if account.is_some() => Some(nickserv.hash()),
+        _ => None,
+    };
+    let Some(work) = work else {
+        return Some(command);
+    };
+    let (address, sender) = (address.to_string(), sender.clone());
+    tokio::task::spawn_blocking(move || {
+        let result = work.run(command.password().unwrap_or_default());
+        let _ = sender.send((address, MyMessage::NickServ(command, result)));
+    });
+    None
+}
+
+/// Finishes a NickServ command from the user at `address`, given what checking or hashing its
+/// password found, and replies to them with notices.
+#[allow(clippy::too_many_arguments)]
+fn finish_nickserv_command(
+    address: &str,
+    command: NickServCommand,
+    result: Option<PasswordResult>,
+    nickserv: &mut NickServ,
+    chanserv: &mut ChanServ,
+    my_map: &mut HashMap<String, ThreadInfo>,
+    nicks: &mut Nicks,
+    channels: &mut Channels,
+    connections: &mut HashMap<IpAddr, usize>,
+) {
+    let Some(ThreadInfo {
+        nick: Some(nick),
+        account,
+        ..
+    }) = my_map.get(address)
+    else {
+        return;
+    };
+    let (nick, account) = (nick.clone(), account.clone());
+    let mut identified_as = None;
+    let replies = match command {
+        NickServCommand::Help => nickserv::HELP.iter().map(|line| line.to_string()).collect(),
+        NickServCommand::Register { .. } if account.is_some() => {
+            vec!["You are already identified to an account.".to_string()]
+        }
+        NickServCommand::Register { .. } => match result {
+            Some(PasswordResult::Hashed(credentials)) => {
+                match nickserv.register(&nick, credentials) {
+                    Ok(registered) => {
+                        identified_as = Some(registered.name.clone());
+                        vec![format!(
+                            "Nickname {} registered. You are now identified.",
+                            registered.name
+                        )]
+                    }
+                    Err(e) => vec![e.to_string()],
+                }
+            }
+            _ => vec![NickServError::AlreadyRegistered.to_string()],
+        },
+        NickServCommand::Identify { account: name, .. } => match result
+            .as_ref()
+            .map_or(Err(NickServError::NotRegistered), |result| {
+                nickserv.identify(name.as_deref().unwrap_or(&nick.0), result)
+            }) {
+            Ok(identified) => {
+                identified_as = Some(identified.name.clone());
+                vec![format!("You are now identified for {}.", identified.name)]
+            }
+            Err(e) => vec![e.to_string()],
+        },
+        NickServCommand::Ghost {
+            nick: ghost,
+            password,
+        } => {
+            let ghost_user = nicks
+                .address_of(&ghost)
+                .and_then(|a| Some((a.clone(), my_map.get(a)?.prefix())));
+            let allowed = match password {
+                Some(_) => result
+                    .as_ref()
+                    .is_some_and(|result| nickserv.identify(&ghost, result).is_ok()),
+                None => nickserv.owns(account.as_deref(), &Nick(ghost.clone())),
+            };
+            match ghost_user {
+                None => vec![format!("{ghost} is not online.")],
+                Some((ghost_address, _)) if ghost_address == address => {
+                    vec!["You may not ghost yourself.".to_string()]
+                }
+                Some(_) if !allowed => vec!["Access denied.".to_string()],
+                Some((ghost_address, ghost_prefix)) => {
+                    let reason = format!("GHOST command used by {nick}");
+                    let reply = Reply::Quit(QuitReply {
+                        message: QuitMsg {
+                            message: Some(reason.clone()),
+                        },
+                        sender: ghost_prefix,
+                    });
+                    disconnect(
+                        &ghost_address,
+                        reply,
+                        Some(&reason),
+                        my_map,
+                        nicks,
+                        channels,
+                        connections,
+                    );
+                    vec![format!("{ghost} has been ghosted.")]
+                }
+            }
+        }
+        NickServCommand::Drop { .. } => match &account {
+            None => vec!["You are not identified to an account.".to_string()],
+            Some(name) => match result
+                .as_ref()
+                .map_or(Err(NickServError::NotRegistered), |result| {
+                    nickserv.drop_account(name, result)
+                }) {
+                Ok(dropped) => {
+                    chanserv.forget_account(&dropped.name);
+                    let logged_in = my_map
+                        .iter()
+                        .filter(|(_, user)| user.account.as_ref() == Some(&dropped.name))
+                        .map(|(a, _)| a.clone())
+                        .collect::<Vec<_>>();
+                    for logged_in in logged_in {
+                        set_account(&logged_in, None, my_map, channels);
+                    }
+                    vec![format!("Account {} has been dropped.", dropped.name)]
+                }
+                Err(e) => vec![e.to_string()],
+            },
+        },
+        NickServCommand::SetPassword { .. } => match &account {
+            None => vec!["You are not identified to an account.".to_string()],
+            Some(name) => {
+                let changed = match result {
+                    Some(PasswordResult::Hashed(credentials)) => {
+                        nickserv.set_password(name, credentials)
+                    }
+                    _ => Err(NickServError::NotRegistered),
+                };
+                match changed {
+                    Ok(()) => vec!["Your password has been changed.".to_string()],
+                    Err(e) => vec![e.to_string()],
+                }
+            }
+        },
+    };
+    if let Some(user) = my_map.get_mut(address) {
+        for reply in replies {
+            service_notice(&mut user.conn_write, NICKSERV, &nick, &reply);
+        }
+    }
+    if identified_as.is_some() {
+        set_account(address, identified_as, my_map, channels);
+    }
+}
+
+/// Applies `changes` to the modes of `channel`, telling its members about the ones that took effect.
+/// Returns an error for every change that could not be applied.
+fn apply_channel_modes(
+    channel: &Channel,
+    changes: Vec<ModeChange>,
+    sender: Prefix,
+    chanserv: &mut ChanServ,
+    my_map: &mut HashMap<String, ThreadInfo>,
+    nicks: &Nicks,
+    channels: &mut Channels,
+) -> Vec<CommandError> {
+    let Some(info) = channels.get_mut(channel) else {
+        return vec![ErrorType::NoSuchChannel.about([channel])];
+    };
+    let mut applied = vec![];
+    let mut errors = vec![];
+    for change in changes {
+        let changed = match &change.arg {
+            Some(target) => {
+                let Some(member) = nicks.address_of(target).cloned() else {
+                    errors.push(ErrorType::NoSuchNick.about([target]));
+                    continue;
+                };
+                if !info.members.contains(&member) {
+                    errors.push(ErrorType::UserNotInChannel.about([target, &channel.0]));
+                    continue;
+                }
+                let status = if change.mode == 'o' {
+                    &mut info.ops
+                } else {
+                    &mut info.voices
+                };
+                if change.add {
+                    status.insert(member)
+                } else {
+                    status.remove(&member)
+                }
+            }
+            None if change.add => info.modes.insert(change.mode),
+            None => info.modes.remove(&change.mode),
+        };
+        if changed {
+            applied.push(change);
+        }
+    }
+    if applied.iter().any(|change| change.arg.is_none()) {
+        chanserv.set_modes(channel, info.modes.clone());
+    }
+    if !applied.is_empty() {
+        let reply = Reply::Mode(ModeReply::from_changes(
+            Target::Channel(channel.clone()),
+            &applied,
+            sender,
+        ));
+        channel_broadcast(info, &reply, my_map);
+    }
+    errors
+}
+
+/// Runs a command sent by the user at `address` to ChanServ, replying to them with notices.
+fn chanserv_command(
+    address: &str,
+    command: &str,
+    chanserv: &mut ChanServ,
+    my_map: &mut HashMap<String, ThreadInfo>,
+    nicks: &Nicks,
+    channels: &mut Channels,
+) {
+    let Some(ThreadInfo {
+        nick: Some(nick),
+        account,
+        ..
+    }) = my_map.get(address)
+    else {
+        return;
+    };
+    let (nick, account) = (nick.clone(), account.clone());
+    let command = ChanServCommand::try_from(command);
+    let op = matches!(command, Ok(ChanServCommand::Op { .. }));
+    // Every command apart from HELP acts on a channel the user must have some access to.
+    let required = |chanserv: &ChanServ, channel: &Channel, level: AccessLevel| match chanserv
+        .channel(channel)
+    {
+        None => Err(chanserv::ChanServError::NotRegistered.to_string()),
+        Some(_) if chanserv.access(channel, account.as_deref()) >= Some(level) => Ok(()),
+        Some(_) => Err("Access denied.".to_string()),
+    };
+    let replies = match command {
+        Err(usage) => vec![usage],
+        Ok(ChanServCommand::Help) => chanserv::HELP.iter().map(|line| line.to_string()).collect(),
+        Ok(_) if account.is_none() => {
+            vec![format!(
+                "You must be identified to {NICKSERV} to use {CHANSERV}."
+            )]
+        }
+        Ok(ChanServCommand::Register { channel }) => match (channels.get(&channel), &account) {
+            (Some(info), Some(account)) if info.ops.contains(address) => {
+                match chanserv.register(&channel, account, info.topic.clone(), info.modes.clone()) {
+                    Ok(()) => vec![format!("Channel {channel} is now registered to {account}.")],
+                    Err(e) => vec![e.to_string()],
+                }
+            }
+            _ => vec![format!(
+                "You must be a channel operator in {channel} to register it."
+            )],
+        },
+        Ok(ChanServCommand::Op {
+            channel,
+            nick: target,
+        })
+        | Ok(ChanServCommand::Deop {
+            channel,
+            nick: target,
+        }) => match required(chanserv, &channel, AccessLevel::Op) {
+            Err(e) => vec![e],
+            Ok(()) => {
+                let change = ModeChange {
+                    add: op,
+                    mode: 'o',
+                    arg: Some(target.unwrap_or_else(|| nick.0.clone())),
+                };
+                apply_channel_modes(
+                    &channel,
+                    vec![change],
+                    service_prefix(CHANSERV),
+                    chanserv,
+                    my_map,
+                    nicks,
+                    channels,
+                )
+                .into_iter()
+                .map(|e| e.error.to_string())
+                .collect()
+            }
+        },
+        Ok(ChanServCommand::AccessList { channel }) => {
+            match required(chanserv, &channel, AccessLevel::Op) {
+                Err(e) => vec![e],
+                Ok(()) => std::iter::once(format!("Access list for {channel}:"))
+                    .chain(
+                        chanserv
+                            .channel(&channel)
+                            .into_iter()
+                            .flat_map(|info| &info.access)
+                            .map(|(account, level)| format!("  {account} {level}")),
+                    )
+                    .collect(),
+            }
+        }
+        Ok(ChanServCommand::AccessAdd {
+            channel,
+            account: entry,
+            level,
+        }) => match required(chanserv, &channel, AccessLevel::Founder).and_then(|()| {
+            chanserv
+                .set_access(&channel, &entry, Some(level))
+                .map_err(|e| e.to_string())
+        }) {
+            Err(e) => vec![e],
+            Ok(()) => vec![format!("{entry} now has {level} access to {channel}.")],
+        },
+        Ok(ChanServCommand::AccessDel {
+            channel,
+            account: entry,
+        }) => match required(chanserv, &channel, AccessLevel::Founder).and_then(|()| {
+            chanserv
+                .set_access(&channel, &entry, None)
+                .map_err(|e| e.to_string())
+        }) {
+            Err(e) => vec![e],
+            Ok(()) => vec![format!("{entry} no longer has access to {channel}.")],
+        },
+        Ok(ChanServCommand::Drop { channel }) => {
+            match required(chanserv, &channel, AccessLevel::Founder)
+                .and_then(|()| chanserv.drop_channel(&channel).map_err(|e| e.to_string()))
+            {
+                Err(e) => vec![e],
+                Ok(()) => vec![format!("Channel {channel} has been dropped.")],
+            }
+        }
+    };
+    if let Some(user) = my_map.get_mut(address) {
+        for reply in replies {
+            service_notice(&mut user.conn_write, CHANSERV, &nick, &reply);
+        }
+    }
+}
+
+/// Every user and channel on the server, along with the services and message history.
+pub struct ServerState {
+    /// Users by the IP Address + Port of their connection.
+    pub users: HashMap<String, ThreadInfo>,
+    /// The addresses of users by their nickname.
+    nicks: Nicks,
+    pub channels: Channels,
+    nickserv: NickServ,
+    chanserv: ChanServ,
+    history: History,
+    /// How long an unidentified user may hold a registered nickname.
+    grace: Duration,
+    /// Where timers and plugins send the messages they raise later.
+    sender: UnboundedSender<(String, MyMessage)>,
+    /// How nicknames and channel names are compared and checked.
+    rules: NameRules,
+    /// The classes connections are sorted into by host, see [`ServerState::with_classes`].
+    classes: Vec<ConnectionClass>,
+    /// The accounts whose users are never held back for flooding.
+    flood_exempt: Vec<String>,
+    bans: Bans,
+    /// The names and passwords the OPER command accepts.
+    opers: Vec<(String, String)>,
+    /// The names the OPER command accepts from clients with a certificate of these fingerprints.
+    oper_certfps: Vec<(String, String)>,
+    /// When each IP address recently connected, for reconnect throttling.
+    recent: HashMap<IpAddr, VecDeque<Instant>>,
+    /// How many users are connected from each IP address.
+    connections: HashMap<IpAddr, usize>,
+    /// The addresses of the users whose connections failed and haven't been dropped yet.
+    failed: Arc<Mutex<Vec<String>>>,
+    /// The name the server goes by in the prefixes of its own messages.
+    server_name: String,
+}
+
+impl ServerState {
+    /// A server with no users or channels yet.
+    pub fn new(
+        nickserv: NickServ,
+        chanserv: ChanServ,
+        history: History,
+        bans: Bans,
+        rules: NameRules,
+        grace: Duration,
+        sender: UnboundedSender<(String, MyMessage)>,
+    ) -> Self {
+        ServerState {
+            users: HashMap::new(),
+            nicks: Nicks::new(rules),
+            channels: Channels::new(rules),
+            nickserv,
+            chanserv,
+            history,
+            grace,
+            sender,
+            rules,
+            classes: Vec::new(),
+            flood_exempt: Vec::new(),
+            bans,
+            opers: Vec::new(),
+            oper_certfps: Vec::new(),
+            recent: HashMap::new(),
+            connections: HashMap::new(),
+            failed: Arc::default(),
+            server_name: SERVER_NAME.to_string(),
+        }
+    }
+
+    /// Goes by `name` instead of [`SERVER_NAME`].
+    pub fn with_server_name(mut self, name: impl Into<String>) -> Self {
+        self.server_name = name.into();
+        self
+    }
+
+    /// Puts each new connection in the first of `classes` whose hosts it matches,
+    /// or in the default [`ConnectionClass`] if it matches none.
+    pub fn with_classes(mut self, classes: Vec<ConnectionClass>) -> Self {
+        self.classes = classes;
+        self
+    }
+
+    /// Exempts the users identified to any of `accounts` from flood protection.
+    pub fn with_flood_exempt(mut self, accounts: Vec<String>) -> Self {
+        self.flood_exempt = accounts;
+        self
+    }
+
+    /// Lets the OPER command make users IRC operators with any of these names and passwords.
+    pub fn with_opers(mut self, opers: Vec<(String, String)>) -> Self {
+        self.opers = opers;
+        self
+    }
+
+    /// Lets the OPER command make users IRC operators with any of these names, whatever password
+    /// they give, when their client certificate has the SHA-256 fingerprint paired with the name.
+    pub fn with_oper_certfps(mut self, certfps: Vec<(String, String)>) -> Self {
+        self.oper_certfps = certfps;
+        self
+    }
+
+    /// Handles one message from (or about) the client at `address`, writing any replies to the users concerned.
+    pub fn handle(&mut self, address: String, request: MyMessage) {
+        self.dispatch(address.clone(), request);
+        self.drop_failed_connections();
+        self.update_flood_exemption(&address);
+    }
+
+    /// Exempts the user at `address` from flood protection if they are an IRC operator or their
+    /// account is exempt, or stops exempting them if not. Done after each of their messages, as
+    /// any may change their account.
+    fn update_flood_exemption(&self, address: &str) {
+        if let Some(user) = self.users.get(address) {
+            let exempt = user.oper.is_some()
+                || user.account.as_ref().is_some_and(|account| {
+                    self.flood_exempt
+                        .iter()
+                        .any(|exempt| self.rules.same(exempt, account))
+                });
+            user.conn_write.set_flood_exempt(exempt);
+        }
+    }
+
+    /// Removes the users whose connections failed while the message was handled,
+    /// either by going over their SendQ or by a write to their socket failing.
+    /// Telling others they left can fail more connections, so this repeats until none do.
+    fn drop_failed_connections(&mut self) {
+        loop {
+            let failed = std::mem::take(&mut *self.failed.lock().unwrap());
+            if failed.is_empty() {
+                return;
+            }
+            for address in failed {
+                self.drop_failed_connection(&address);
+            }
+        }
+    }
+
+    /// Removes the user at `address`, if their connection failed and they are still here.
+    fn drop_failed_connection(&mut self, address: &str) {
+        let Some(failure) = self
+            .users
+            .get(address)
+            .and_then(|user| user.conn_write.failure())
+        else {
+            return;
+        };
+        let reply = Reply::Quit(QuitReply {
+            message: QuitMsg {
+                message: Some(failure.to_string()),
+            },
+            sender: self.users[address].prefix(),
+        });
+        disconnect(
+            address,
+            reply,
+            None,
+            &mut self.users,
+            &mut self.nicks,
+            &mut self.channels,
+            &mut self.connections,
+        );
+    }
+
+    fn dispatch(&mut self, address: String, request: MyMessage) {
+        let ServerState {
+            users: my_map,
+            nicks,
+            channels,
+            nickserv,
+            chanserv,
+            history,
+            grace,
+            sender,
+            rules,
+            classes,
+            flood_exempt: _,
+            bans,
+            opers,
+            oper_certfps,
+            recent,
+            connections,
+            failed,
+            server_name,
+        } = self;
+        let grace = *grace;
+        debug!("User Info: {:?}", my_map);
+        debug!("Channels: {:?}", channels);
+        match request {
+            MyMessage::Request(request) => {
+                if let Some(user) = my_map.get_mut(&address) {
+                    // Anything the client sends shows that its connection is still alive.
+                    user.last_active = Instant::now();
+                    user.ping = None;
+                }
+                // Replies to a labeled request are held back, so they can be labeled together.
+                let label = my_map.get_mut(&address).and_then(|user| {
+                    if !user.caps.contains(&Capability::LabeledResponse) {
+                        return None;
+                    }
+                    let label = message_label(&request)?;
+                    user.conn_write.hold_messages();
+                    Some(label)
+                });
+                // The user may already be gone, e.g. if their session was ghosted.
+                let Some(source) = my_map.get(&address).map(ThreadInfo::prefix) else {
+                    return;
+                };
+                let Some(ThreadInfo {
+                    conn_write,
+                    nick,
+                    full_name,
+                    account,
+                    ..
+                }) = my_map.get_mut(&address)
+                else {
+                    return;
+                };
+                let own_nick = nick.as_ref().map_or("*".to_string(), |n| n.0.clone());
+                let request = UnparsedMessage {
+                    sender_nick: match &nick {
+                        Some(nick) => nick.clone(),
+                        None => Nick("".to_string()),
+                    },
+                    message: &request,
+                    server_name,
+                };
+                if full_name.is_none() {
+                    let parsed_message = ParsedMessage::try_from(request);
+                    match parsed_message {
+                        Ok(request) => match request.message {
+                            Message::Nick(name) => {
+                                let nick = match rules.nick(&name.nick) {
+                                    Ok(nick) => nick,
+                                    Err(e) => {
+                                        let reply = Reply::error(&own_nick, e.about([&name.nick]));
+                                        send_to(&address, &reply, my_map);
+                                        return;
+                                    }
+                                };
+                                if is_service(&nick, rules)
+                                    || nicks.confusable(&nick.0).is_some_and(|a| a != &address)
+                                {
+                                    let reply = Reply::error(
+                                        &own_nick,
+                                        ErrorType::NickCollision.about([&nick]),
+                                    );
+                                    send_to(&address, &reply, my_map);
+                                } else if let Some(user) = my_map.get_mut(&address) {
+                                    nicks.insert(&address, user.nick.as_ref(), &nick);
+                                    user.nick = Some(nick);
+                                    // USER may have come first.
+                                    let pending = if user.negotiating {
+                                        None
+                                    } else {
+                                        user.pending_user.take()
+                                    };
+                                    if let Some(name) = pending {
+                                        complete_registration(
+                                            &address,
+                                            name,
+                                            my_map,
+                                            nicks,
+                                            connections,
+                                            nickserv,
+                                            bans,
+                                            rules,
+                                            sender.clone(),
+                                            grace,
+                                        );
+                                    }
+                                }
+                            }
+                            Message::User(name) => {
+                                let Some(user) = my_map.get_mut(&address) else {
+                                    return;
+                                };
+                                if user.negotiating || user.nick.is_none() {
+                                    user.pending_user = Some(name);
+                                } else {
+                                    complete_registration(
+                                        &address,
+                                        name,
+                                        my_map,
+                                        nicks,
+                                        connections,
+                                        nickserv,
+                                        bans,
+                                        rules,
+                                        sender.clone(),
+                                        grace,
+                                    );
+                                }
+                            }
+                            Message::Cap(msg) => {
+                                if let Some(name) = cap_command(&address, msg, my_map) {
+                                    complete_registration(
+                                        &address,
+                                        name,
+                                        my_map,
+                                        nicks,
+                                        connections,
+                                        nickserv,
+                                        bans,
+                                        rules,
+                                        sender.clone(),
+                                        grace,
+                                    );
+                                }
+                            }
+                            Message::Quit(_) => {
+                                remove_user(&address, my_map, nicks, connections);
+                            }
+                            Message::Pong(_) => {}
+                            _ => {
+                                let reply = Reply::error(&own_nick, ErrorType::NotRegistered);
+                                send_to(&address, &reply, my_map);
+                            }
+                        },
+                        // Commands this server doesn't know are refused like any other until registration.
+                        Err(e) if e.error == ErrorType::UnknownCommand => {
+                            let reply = Reply::error(&own_nick, ErrorType::NotRegistered);
+                            let _ = conn_write.write_message(&reply.to_string());
+                        }
+                        Err(e) => {
+                            let _ =
+                                conn_write.write_message(&Reply::error(&own_nick, e).to_string());
+                        }
+                    }
+                } else {
+                    let parsed_message = ParsedMessage::try_from(request);
+                    match parsed_message {
+                        Ok(request) => match request.message {
+                            Message::PrivMsg(msg) => match msg.target.clone() {
+                                Target::Channel(target) => match channels.get(&target) {
+                                    None => {
+                                        let _ = conn_write.write_message(
+                                            &Reply::error(
+                                                &own_nick,
+                                                ErrorType::NoSuchChannel.about([&target]),
+                                            )
+                                            .to_string(),
+                                        );
+                                    }
+                                    Some(info) if !info.can_speak(&address) => {
+                                        let _ = conn_write.write_message(
+                                            &Reply::error(
+                                                &own_nick,
+                                                ErrorType::CannotSendToChan.about([&target]),
+                                            )
+                                            .to_string(),
+                                        );
+                                    }
+                                    Some(info) => {
+                                        relay(
+                                            &address,
+                                            MessageKind::PrivMsg,
+                                            &msg.target,
+                                            &msg.message,
+                                            &info.members,
+                                            my_map,
+                                            history,
+                                        );
+                                    }
+                                },
+                                Target::User(target) if rules.same(&target.0, CHANSERV) => {
+                                    chanserv_command(
+                                        &address,
+                                        &msg.message,
+                                        chanserv,
+                                        my_map,
+                                        nicks,
+                                        channels,
+                                    );
+                                }
+                                Target::User(target) if rules.same(&target.0, NICKSERV) => {
+                                    if let Some(command) = nickserv_command(
+                                        &address,
+                                        &msg.message,
+                                        nickserv,
+                                        my_map,
+                                        sender,
+                                    ) {
+                                        finish_nickserv_command(
+                                            &address,
+                                            command,
+                                            None,
+                                            nickserv,
+                                            chanserv,
+                                            my_map,
+                                            nicks,
+                                            channels,
+                                            connections,
+                                        );
+                                    }
+                                }
+                                Target::User(target) => {
+                                    let nick = Nick(own_nick);
+                                    if let Some(recipient) = nicks.address_of(&target.0).cloned() {
+                                        relay(
+                                            &address,
+                                            MessageKind::PrivMsg,
+                                            &msg.target,
+                                            &msg.message,
+                                            [&recipient],
+                                            my_map,
+                                            history,
+                                        );
+                                        let away = my_map
+                                            .get(&recipient)
+                                            .and_then(|user| user.away.clone());
+                                        if let Some(away) = away {
+                                            let reply = Reply::IsAway(IsAwayReply {
+                                                target_nick: nick,
+                                                away_nick: target,
+                                                message: away,
+                                            });
+                                            send_to(&address, &reply, my_map);
+                                        }
+                                    } else {
+                                        let reply = Reply::error(
+                                            &nick,
+                                            ErrorType::NoSuchNick.about([&target]),
+                                        );
+                                        send_to(&address, &reply, my_map);
+                                    }
+                                }
+                            },
+                            // Notices are relayed like messages, but never answered with an error.
+                            Message::Notice(msg) => {
+                                let recipients = match &msg.target {
+                                    Target::Channel(target) => channels
+                                        .get(target)
+                                        .filter(|info| info.can_speak(&address))
+                                        .map(|info| info.members.clone()),
+                                    Target::User(target) if is_service(target, rules) => None,
+                                    Target::User(target) => nicks
+                                        .address_of(&target.0)
+                                        .map(|recipient| HashSet::from([recipient.clone()])),
+                                };
+                                if let Some(recipients) = recipients {
+                                    relay(
+                                        &address,
+                                        MessageKind::Notice,
+                                        &msg.target,
+                                        &msg.message,
+                                        &recipients,
+                                        my_map,
+                                        history,
+                                    );
+                                }
+                            }
+                            Message::Nick(msg) => {
+                                let current = nick.clone();
+                                let new_nick = match rules.nick(&msg.nick) {
+                                    Ok(nick) => nick,
+                                    Err(e) => {
+                                        let reply = Reply::error(&own_nick, e.about([&msg.nick]));
+                                        send_to(&address, &reply, my_map);
+                                        return;
+                                    }
+                                };
+                                if is_service(&new_nick, rules)
+                                    || nicks.confusable(&new_nick.0).is_some_and(|a| a != &address)
+                                {
+                                    let reply = Reply::error(
+                                        &own_nick,
+                                        ErrorType::NickCollision.about([&new_nick]),
+                                    );
+                                    send_to(&address, &reply, my_map);
+                                } else if current != Some(new_nick.clone()) {
+                                    change_nick(
+                                        &address,
+                                        new_nick.clone(),
+                                        my_map,
+                                        nicks,
+                                        channels,
+                                    );
+                                    let Some(user) = my_map.get_mut(&address) else {
+                                        return;
+                                    };
+                                    if nickserv.account(&new_nick.0).is_some()
+                                        && !nickserv.owns(user.account.as_deref(), &new_nick)
+                                    {
+                                        protect_nick(
+                                            &mut user.conn_write,
+                                            &new_nick,
+                                            address.clone(),
+                                            sender.clone(),
+                                            grace,
+                                        );
+                                    }
+                                }
+                            }
+                            Message::User(_) => {
+                                let _ = conn_write.write_message(
+                                    &Reply::error(&own_nick, ErrorType::AlreadyRegistered)
+                                        .to_string(),
+                                );
+                            }
+                            Message::Ping(msg) => {
+                                let reply = Reply::Pong(msg);
+                                let _ = conn_write.write_message(&reply.to_string());
+                            }
+                            // Already counted as a sign of life above.
+                            Message::Pong(_) => {}
+                            Message::Join(mut msg) => {
+                                if !channels.contains(&msg.channel) {
+                                    match rules.channel(&msg.channel) {
+                                        Ok(channel) => msg.channel = channel,
+                                        Err(e) => {
+                                            let reply =
+                                                Reply::error(&own_nick, e.about([&msg.channel]));
+                                            let _ = conn_write.write_message(&reply.to_string());
+                                            return;
+                                        }
+                                    }
+                                }
+                                // A new channel may not pass for one that already exists.
+                                if !channels.contains(&msg.channel)
+                                    && channels
+                                        .keys()
+                                        .any(|c| rules.confusable(&c.0, &msg.channel.0))
+                                {
+                                    let reply = Reply::error(
+                                        &own_nick,
+                                        ErrorType::BadChanName.about([&msg.channel]),
+                                    );
+                                    let _ = conn_write.write_message(&reply.to_string());
+                                    return;
+                                }
+                                let nick = Nick(own_nick.clone());
+                                let account = account.clone();
+                                let reply = Reply::Join(JoinReply {
+                                    message: JoinMsg {
+                                        channel: msg.channel.clone(),
+                                    },
+                                    sender: source.clone(),
+                                });
+                                let extended_reply = Reply::ExtendedJoin(ExtendedJoinReply {
+                                    message: JoinMsg {
+                                        channel: msg.channel.clone(),
+                                    },
+                                    account: account.clone(),
+                                    real_name: full_name.clone().unwrap_or_default(),
+                                    sender: source.clone(),
+                                });
+                                let away = my_map.get(&address).and_then(|user| user.away.clone());
+                                let away = away.map(|away| {
+                                    Reply::Away(AwayReply {
+                                        message: AwayMsg {
+                                            message: Some(away),
+                                        },
+                                        sender: source.clone(),
+                                    })
+                                });
+                                let created = !channels.contains(&msg.channel);
+                                let registered = chanserv.channel(&msg.channel);
+                                let info = channels.get_or_create(&msg.channel);
+                                if let (true, Some(registered)) = (created, registered) {
+                                    // Bring back the settings the channel had before it emptied.
+                                    info.topic = registered.topic.clone();
+                                    info.modes = registered.modes.clone();
+                                } else if created {
+                                    info.ops.insert(address.clone());
+                                }
+                                info.members.insert(address.clone());
+                                send_each(&info.members, my_map, |member| {
+                                    if member.caps.contains(&Capability::ExtendedJoin) {
+                                        Some(extended_reply.clone())
+                                    } else {
+                                        Some(reply.clone())
+                                    }
+                                });
+                                if let Some(away) = away {
+                                    let others = info.members.iter().filter(|m| *m != &address);
+                                    send_each(others, my_map, |member| {
+                                        member
+                                            .caps
+                                            .contains(&Capability::AwayNotify)
+                                            .then(|| away.clone())
+                                    });
+                                }
+                                if let Some(topic) = &info.topic {
+                                    let reply = Reply::TopicIs(TopicIsReply {
+                                        target_nick: nick.clone(),
+                                        channel: msg.channel.clone(),
+                                        topic: Some(topic.clone()),
+                                    });
+                                    send_to(&address, &reply, my_map);
+                                }
+                                let mode = match chanserv.access(&msg.channel, account.as_deref()) {
+                                    Some(AccessLevel::Voice) => Some('v'),
+                                    Some(_) => Some('o'),
+                                    None => None,
+                                };
+                                if let Some(mode) = mode {
+                                    apply_channel_modes(
+                                        &msg.channel,
+                                        vec![ModeChange {
+                                            add: true,
+                                            mode,
+                                            arg: Some(nick.0),
+                                        }],
+                                        service_prefix(CHANSERV),
+                                        chanserv,
+                                        my_map,
+                                        nicks,
+                                        channels,
+                                    );
+                                }
+                            }
+                            Message::Part(msg) => match channels.get_mut(&msg.channel) {
+                                None => {
+                                    let _ = conn_write.write_message(
+                                        &Reply::error(
+                                            &own_nick,
+                                            ErrorType::NoSuchChannel.about([&msg.channel]),
+                                        )
+                                        .to_string(),
+                                    );
+                                }
+                                Some(info) => {
+                                    if info.remove(&address) {
+                                        let reply = Reply::Part(PartReply {
+                                            message: PartMsg {
+                                                channel: msg.channel.clone(),
+                                            },
+                                            sender: source.clone(),
+                                        });
+                                        channel_broadcast(info, &reply, my_map);
+                                        if info.members.is_empty() {
+                                            channels.remove(&msg.channel);
+                                        }
+                                    }
+                                }
+                            },
+                            Message::Topic(msg) => {
+                                let nick = Nick(own_nick.clone());
+                                let error = match channels.get_mut(&msg.channel) {
+                                    None => Some(ErrorType::NoSuchChannel),
+                                    // Secret channels are hidden from everyone outside them.
+                                    Some(info) if !can_see(info, &address) => {
+                                        Some(ErrorType::NoSuchChannel)
+                                    }
+                                    Some(info) => match msg.topic {
+                                        None => {
+                                            let reply = Reply::TopicIs(TopicIsReply {
+                                                target_nick: nick,
+                                                channel: msg.channel.clone(),
+                                                topic: info.topic.clone(),
+                                            });
+                                            let _ = conn_write.write_message(&reply.to_string());
+                                            None
+                                        }
+                                        Some(_) if !info.members.contains(&address) => {
+                                            Some(ErrorType::NotOnChannel)
+                                        }
+                                        Some(_)
+                                            if info.modes.contains(&'t')
+                                                && !info.ops.contains(&address) =>
+                                        {
+                                            Some(ErrorType::ChanOPrivsNeeded)
+                                        }
+                                        Some(topic) => {
+                                            // An empty topic clears it.
+                                            let topic = Some(topic).filter(|t| !t.is_empty());
+                                            info.topic = topic.clone();
+                                            chanserv.set_topic(&msg.channel, topic.clone());
+                                            let reply = Reply::Topic(TopicReply {
+                                                message: TopicMsg {
+                                                    channel: msg.channel.clone(),
+                                                    topic,
+                                                },
+                                                sender: source.clone(),
+                                            });
+                                            channel_broadcast(info, &reply, my_map);
+                                            None
+                                        }
+                                    },
+                                };
+                                if let Some(e) = error {
+                                    let reply = Reply::error(&own_nick, e.about([&msg.channel]));
+                                    send_to(&address, &reply, my_map);
+                                }
+                            }
+                            Message::Mode(msg) => {
+                                let nick = Nick(own_nick.clone());
+                                // User modes are only set by the server, so users may just look at their own.
+                                let errors = match &msg.target {
+                                    Target::User(target) if !rules.same(&target.0, &own_nick) => {
+                                        vec![ErrorType::UsersDontMatch.into()]
+                                    }
+                                    Target::User(_) if msg.modes.is_none() => {
+                                        let modes = my_map
+                                            .get(&address)
+                                            .map(ThreadInfo::mode_string)
+                                            .unwrap_or_default();
+                                        let reply = NumericReply::new(
+                                            Numeric::RplUModeIs,
+                                            &own_nick,
+                                            vec![modes],
+                                        );
+                                        send_to(&address, &Reply::Numeric(reply), my_map);
+                                        vec![]
+                                    }
+                                    Target::User(_) => vec![],
+                                    Target::Channel(channel) => match channels.get(channel) {
+                                        None => {
+                                            vec![ErrorType::NoSuchChannel.about([channel])]
+                                        }
+                                        Some(info) if msg.modes.is_none() => {
+                                            let reply = Reply::ChannelModeIs(ChannelModeIsReply {
+                                                target_nick: nick,
+                                                channel: channel.clone(),
+                                                modes: info.mode_string(),
+                                            });
+                                            let _ = conn_write.write_message(&reply.to_string());
+                                            vec![]
+                                        }
+                                        Some(info) if !info.ops.contains(&address) => {
+                                            vec![ErrorType::ChanOPrivsNeeded.about([channel])]
+                                        }
+                                        Some(_) => match msg.changes() {
+                                            Err(e) => vec![e],
+                                            Ok(changes) => apply_channel_modes(
+                                                channel,
+                                                changes,
+                                                source.clone(),
+                                                chanserv,
+                                                my_map,
+                                                nicks,
+                                                channels,
+                                            ),
+                                        },
+                                    },
+                                };
+                                for e in errors {
+                                    send_to(&address, &Reply::error(&own_nick, e), my_map);
+                                }
+                            }
+                            Message::Away(msg) => {
+                                let nick = Nick(own_nick.clone());
+                                let reply = Reply::AwayStatus(AwayStatusReply {
+                                    target_nick: nick.clone(),
+                                    away: msg.message.is_some(),
+                                });
+                                let _ = conn_write.write_message(&reply.to_string());
+                                if let Some(user) = my_map.get_mut(&address) {
+                                    user.away = msg.message.clone();
+                                }
+                                let reply = Reply::Away(AwayReply {
+                                    message: msg,
+                                    sender: source.clone(),
+                                });
+                                let mut others = shared_members(&address, channels);
+                                others.remove(&address);
+                                send_each(&others, my_map, |member| {
+                                    member
+                                        .caps
+                                        .contains(&Capability::AwayNotify)
+                                        .then(|| reply.clone())
+                                });
+                            }
+                            Message::Cap(msg) => {
+                                cap_command(&address, msg, my_map);
+                            }
+                            Message::Names(msg) => {
+                                let nick = Nick(own_nick.clone());
+                                let listed = match &msg.channel {
+                                    Some(channel) => channels
+                                        .get_key_value(channel)
+                                        .into_iter()
+                                        .collect::<Vec<_>>(),
+                                    None => channels.iter().collect(),
+                                };
+                                let mut replies = listed
+                                    .into_iter()
+                                    .filter(|(_, info)| can_see(info, &address))
+                                    .flat_map(|(channel, info)| {
+                                        names_replies(&address, channel, info, my_map)
+                                    })
+                                    .collect::<Vec<_>>();
+                                replies.push(Reply::EndOfNames(EndOfNamesReply {
+                                    target_nick: nick,
+                                    channel: msg.channel.map_or("*".to_string(), |c| c.to_string()),
+                                }));
+                                for reply in replies {
+                                    send_to(&address, &reply, my_map);
+                                }
+                            }
+                            Message::ChatHistory(msg) => {
+                                chathistory_command(
+                                    &address, msg, history, nickserv, my_map, nicks, channels,
+                                );
+                            }
+                            Message::Who(msg) => {
+                                let replies =
+                                    who_replies(&address, &msg.mask, my_map, channels, rules);
+                                for reply in replies {
+                                    send_to(&address, &reply, my_map);
+                                }
+                            }
+                            Message::Oper(msg) => {
+                                let certfp = conn_write.certfp();
+                                // Compared in constant time, so timing it doesn't give the password away.
+                                let valid = opers.iter().any(|(name, password)| {
+                                    *name == msg.name
+                                        && bool::from(
+                                            password.as_bytes().ct_eq(msg.password.as_bytes()),
+                                        )
+                                }) || oper_certfps.iter().any(|(name, fingerprint)| {
+                                    *name == msg.name
+                                        && certfp
+                                            .is_some_and(|c| c.eq_ignore_ascii_case(fingerprint))
+                                });
+                                if !valid {
+                                    let reply = Reply::error(&own_nick, ErrorType::PasswdMismatch);
+                                    let _ = conn_write.write_message(&reply.to_string());
+                                } else if let Some(user) = my_map.get_mut(&address) {
+                                    info!("{} is now an IRC operator as {}", address, msg.name);
+                                    user.oper = Some(msg.name);
+                                    let reply =
+                                        NumericReply::new(Numeric::RplYoureOper, &own_nick, vec![]);
+                                    let _ = user.conn_write.write_message(&reply.to_string());
+                                }
+                            }
+                            message @ (Message::KLine(_)
+                            | Message::DLine(_)
+                            | Message::UnKLine(_)
+                            | Message::UnDLine(_)) => {
+                                ban_command(
+                                    &address,
+                                    message,
+                                    server_name,
+                                    bans,
+                                    my_map,
+                                    nicks,
+                                    channels,
+                                    connections,
+                                );
+                            }
+                            Message::Whois(nick) => {
+                                for reply in whois_replies(&address, &nick, my_map, nicks, channels)
+                                {
+                                    send_to(&address, &reply, my_map);
+                                }
+                            }
+                            Message::Stats(query) => {
+                                let oper = my_map.get(&address).is_some_and(|u| u.oper.is_some());
+                                for reply in stats_replies(&own_nick, &query, oper, bans) {
+                                    send_to(&address, &reply, my_map);
+                                }
+                            }
+                            Message::Quit(msg) => {
+                                let reply = Reply::Quit(QuitReply {
+                                    message: msg,
+                                    sender: source.clone(),
+                                });
+                                disconnect(
+                                    &address,
+                                    reply,
+                                    None,
+                                    my_map,
+                                    nicks,
+                                    channels,
+                                    connections,
+                                );
+                            }
+                        },
+                        Err(e) => {
+                            let _ =
+                                conn_write.write_message(&Reply::error(&own_nick, e).to_string());
+                        }
+                    }
+                }
+                if let (Some(label), Some(user)) = (label, my_map.get_mut(&address)) {
+                    let replies = user.conn_write.release_messages();
+                    let batch = user.caps.contains(&Capability::Batch);
+                    for line in labeled_response(&label, replies, batch) {
+                        let _ = user.conn_write.write_message(&line);
+                    }
+                }
+            }
+            MyMessage::Est(ip, mut conn_write) => {
+                let host = host_of(&ip);
+                let class = classes
+                    .iter()
+                    .find(|class| rules.mask_matches(&class.hosts, &host))
+                    .cloned()
+                    .unwrap_or_default();
+                let now = Instant::now();
+                if let Some(reason) = refuse_connection(&ip, &class, now, bans, recent, connections)
+                {
+                    info!("Refusing connection from {}: {}", ip, reason);
+                    let _ =
+                        conn_write.write_message(&format!("ERROR :Closing Link ({reason})\r\n"));
+                    conn_write.shutdown();
+                    return;
+                }
+                conn_write.report_failures(failed.clone());
+                if let Some(host_ip) = ip_of(&ip) {
+                    *connections.entry(host_ip).or_default() += 1;
+                }
+                my_map.insert(
+                    ip,
+                    ThreadInfo {
+                        conn_write,
+                        nick: None,
+                        full_name: None,
+                        account: None,
+                        username: None,
+                        host,
+                        away: None,
+                        caps: HashSet::new(),
+                        negotiating: false,
+                        pending_user: None,
+                        class,
+                        connected: now,
+                        last_active: now,
+                        ping: None,
+                        oper: None,
+                    },
+                );
+            }
+            MyMessage::Plugin(plugin) => {
+                let plugin_function =
+                    create_plugin(plugin, address, sender.clone(), channels, my_map);
+                plugin_function();
+            }
+            MyMessage::NickServ(command, result) => {
+                finish_nickserv_command(
+                    &address,
+                    command,
+                    Some(result),
+                    nickserv,
+                    chanserv,
+                    my_map,
+                    nicks,
+                    channels,
+                    connections,
+                );
+            }
+            MyMessage::NickEnforce(enforced) => {
+                let Some(user) = my_map.get(&address) else {
+                    return;
+                };
+                if user
+                    .nick
+                    .as_ref()
+                    .is_some_and(|nick| rules.same(&nick.0, &enforced.0))
+                    && nickserv.account(&enforced.0).is_some()
+                    && !nickserv.owns(user.account.as_deref(), &enforced)
+                {
+                    let guest = guest_nick(nicks);
+                    change_nick(&address, guest.clone(), my_map, nicks, channels);
+                    let Some(user) = my_map.get_mut(&address) else {
+                        return;
+                    };
+                    service_notice(
+                        &mut user.conn_write,
+                        NICKSERV,
+                        &guest,
+                        &format!("You did not identify in time, your nickname has been changed to {guest}."),
+                    );
+                }
+            }
+            MyMessage::Shutdown => {
+                for (_, mut user) in my_map.drain() {
+                    let _ = user
+                        .conn_write
+                        .write_message("ERROR :Closing Link (server shutting down)\r\n");
+                    user.conn_write.shutdown();
+                }
+                channels.clear();
+                nicks.clear();
+                connections.clear();
+                nickserv.save();
+                chanserv.save();
+                bans.save();
+                history.flush();
+            }
+            MyMessage::Tick(now) => {
+                check_timers(now, my_map, nicks, channels, connections);
+                bans.expire(SystemTime::now());
+                let window = classes
+                    .iter()
+                    .map(|class| class.throttle_window)
+                    .chain([ConnectionClass::default().throttle_window])
+                    .max()
+                    .unwrap_or_default();
+                recent.retain(|_, attempts| {
+                    attempts
+                        .back()
+                        .is_some_and(|at| now.saturating_duration_since(*at) < window)
+                });
+            }
+            MyMessage::ExcessFlood => {
+                let Some(user) = my_map.get(&address) else {
+                    return;
+                };
+                let reply = Reply::Quit(QuitReply {
+                    message: QuitMsg {
+                        message: Some("Excess Flood".to_string()),
+                    },
+                    sender: user.prefix(),
+                });
+                disconnect(
+                    &address,
+                    reply,
+                    Some("Excess Flood"),
+                    my_map,
+                    nicks,
+                    channels,
+                    connections,
+                );
+            }
+        }
+    }
+}
+
+/// Turns the rest of a `PLUGIN` line into a [`Plugin`], like [`parse_plugin`] does.
+pub type PluginParser = fn(String) -> Option<Plugin>;
+
+/// Configures a [`Server`] before it starts listening; see [`Server::builder`].
+pub struct ServerBuilder {
+    /// Each address to listen on, and whether it takes TLS connections.
+    listeners: Vec<(SocketAddr, bool)>,
+    /// The PEM certificate chain and private key for TLS listeners.
+    tls: Option<(PathBuf, PathBuf)>,
+    data_dir: Option<PathBuf>,
+    nick_grace: Duration,
+    rules: NameRules,
+    retention: Retention,
+    plugins: Option<PluginParser>,
+    worker_threads: Option<usize>,
+    sendq: usize,
+    classes: Vec<ConnectionClass>,
+    flood: FloodPolicy,
+    flood_exempt: Vec<String>,
+    opers: Vec<(String, String)>,
+    oper_certfps: Vec<(String, String)>,
+    password_rounds: Option<u32>,
+    server_name: String,
+}
+
+impl Default for ServerBuilder {
+    fn default() -> Self {
+        ServerBuilder {
+            listeners: Vec::new(),
+            tls: None,
+            data_dir: None,
+            nick_grace: Duration::from_secs(30),
+            rules: NameRules::default(),
+            retention: Retention {
+                channel: 500,
+                direct: 100,
+            },
+            plugins: Some(parse_plugin),
+            worker_threads: None,
+            sendq: 1 << 20,
+            classes: Vec::new(),
+            flood: FloodPolicy::default(),
+            flood_exempt: Vec::new(),
+            opers: Vec::new(),
+            oper_certfps: Vec::new(),
+            password_rounds: None,
+            server_name: SERVER_NAME.to_string(),
+        }
+    }
+}
+
+impl ServerBuilder {
+    /// Also accepts clients on `address`. Port 0 picks any free port, see [`Server::local_addrs`].
+    /// With no listeners at all the server listens on `127.0.0.1:6991`.
+    pub fn listen(mut self, address: impl Into<SocketAddr>) -> Self {
+        self.listeners.push((address.into(), false));
+        self
+    }
+
+    /// Also accepts clients over TLS on `address`, which needs [`ServerBuilder::tls`].
+    pub fn listen_tls(mut self, address: impl Into<SocketAddr>) -> Self {
+        self.listeners.push((address.into(), true));
+        self
+    }
+
+    /// The PEM certificate chain, leaf first, and private key TLS listeners present.
+    /// They can be read again while the server runs, see [`ServerHandle::reload_tls`].
+    pub fn tls(mut self, cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> Self {
+        self.tls = Some((cert.into(), key.into()));
+        self
+    }
+
+    /// Keeps services accounts, channels and history under `dir`; without one they only live in memory.
+    pub fn data_dir(mut self, dir: impl Into<PathBuf>) -> Self {
+        self.data_dir = Some(dir.into());
+        self
+    }
+
+    /// How long an unidentified user may hold a registered nickname before being renamed.
+    pub fn nick_grace(mut self, grace: Duration) -> Self {
+        self.nick_grace = grace;
+        self
+    }
+
+    pub fn casemapping(mut self, casemapping: CaseMapping) -> Self {
+        self.rules.casemapping = casemapping;
+        self
+    }
+
+    pub fn names(mut self, names: NamePolicy) -> Self {
+        self.rules.policy = names;
+        self
+    }
+
+    /// The longest nickname and channel name allowed, in characters.
+    pub fn name_lengths(mut self, nick_len: usize, channel_len: usize) -> Self {
+        self.rules.nick_len = nick_len;
+        self.rules.channel_len = channel_len;
+        self
+    }
+
+    /// How many messages CHATHISTORY keeps for each conversation.
+    pub fn retention(mut self, retention: Retention) -> Self {
+        self.retention = retention;
+        self
+    }
+
+    /// Reads `PLUGIN` lines with `parser` instead of [`parse_plugin`].
+    pub fn plugins(mut self, parser: PluginParser) -> Self {
+        self.plugins = Some(parser);
+        self
+    }
+
+    /// Treats `PLUGIN` like any other unknown command.
+    pub fn without_plugins(mut self) -> Self {
+        self.plugins = None;
+        self
+    }
+
+    /// How many threads serve client connections; by default one per CPU core.
+    /// Handling messages always takes one more thread.
+    pub fn worker_threads(mut self, threads: usize) -> Self {
+        self.worker_threads = Some(threads);
+        self
+    }
+
+    /// How many bytes may wait to be sent to a client before it is disconnected with
+    /// `ERROR :SendQ exceeded`; 1 MiB by default.
+    pub fn sendq(mut self, bytes: usize) -> Self {
+        self.sendq = bytes;
+        self
+    }
+
+    /// Pings and times out the clients from hosts matching `class.hosts` as `class` says.
+    /// Classes are tried in the order they were added; clients matching none get
+    /// [`ConnectionClass::default`].
+    pub fn connection_class(mut self, class: ConnectionClass) -> Self {
+        self.classes.push(class);
+        self
+    }
+
+    /// How fast clients may send commands; see [`FloodPolicy`].
+    pub fn flood(mut self, policy: FloodPolicy) -> Self {
+        self.flood = policy;
+        self
+    }
+
+    /// Lets users identified to the NickServ `account` send commands as fast as they like,
+    /// for example a trusted bot.
+    pub fn flood_exempt(mut self, account: impl Into<String>) -> Self {
+        self.flood_exempt.push(account.into());
+        self
+    }
+
+    /// Lets a user become an IRC operator with `OPER name password`, allowing them to set bans.
+    pub fn oper(mut self, name: impl Into<String>, password: impl Into<String>) -> Self {
+        self.opers.push((name.into(), password.into()));
+        self
+    }
+
+    /// Lets a user become an IRC operator as `name` with a client certificate of the SHA-256
+    /// `fingerprint` alone, whatever password they give OPER.
+    pub fn oper_certfp(mut self, name: impl Into<String>, fingerprint: impl Into<String>) -> Self {
+        self.oper_certfps.push((name.into(), fingerprint.into()));
+        self
+    }
+
+    /// How many PBKDF2 rounds NickServ hashes passwords with; 600,000 by default.
+    /// Hashes are made on blocking threads, so this only slows down the commands taking a password.
+    pub fn password_rounds(mut self, rounds: u32) -> Self {
+        self.password_rounds = Some(rounds);
+        self
+    }
+
+    /// The name the server goes by in the prefixes of its own messages; [`SERVER_NAME`] by default.
+    pub fn server_name(mut self, name: impl Into<String>) -> Self {
+        self.server_name = name.into();
+        self
+    }
+
+    /// Loads the services and binds every listener, ready to [`Server::spawn`].
+    pub fn build(self) -> io::Result<Server> {
+        let rules = self.rules;
+        let (mut nickserv, chanserv, history, bans) = match &self.data_dir {
+            Some(dir) => (
+                NickServ::load(dir.join("nickserv.db"), rules)?,
+                ChanServ::load(dir.join("chanserv.db"), rules)?,
+                History::load(
+                    dir.join("history"),
+                    self.retention,
+                    rules,
+                    &self.server_name,
+                )?,
+                Bans::load(dir.join("bans.db"), rules)?,
+            ),
+            None => (
+                NickServ::in_memory(rules),
+                ChanServ::in_memory(rules),
+                History::in_memory(self.retention, rules),
+                Bans::in_memory(rules),
+            ),
+        };
+        if let Some(rounds) = self.password_rounds {
+            nickserv = nickserv.with_rounds(rounds);
+        }
+        let mut runtime = runtime::Builder::new_multi_thread();
+        if let Some(threads) = self.worker_threads {
+            runtime.worker_threads(threads);
+        }
+        let runtime = runtime.thread_name("iris-worker").enable_all().build()?;
+        let tls = match &self.tls {
+            Some((cert, key)) => Some(TlsConfig::load(cert, key)?),
+            None => None,
+        };
+        let mut addresses = self.listeners;
+        if addresses.is_empty() {
+            addresses.push((SocketAddr::from(([127, 0, 0, 1], 6991)), false));
+        }
+        if addresses.iter().any(|(_, secure)| *secure) && tls.is_none() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "TLS listeners need a certificate and key",
+            ));
+        }
+        let stats = Arc::new(QueueStats::default());
+        let listeners = {
+            let _runtime = runtime.enter();
+            addresses
+                .into_iter()
+                .map(|(address, secure)| {
+                    let tls = tls.clone().filter(|_| secure);
+                    ConnectionManager::bind(address, tls, stats.clone())
+                })
+                .collect::<io::Result<Vec<_>>>()?
+        };
+        let (sender, receiver) = mpsc::unbounded_channel::<(String, MyMessage)>(); //String for IP address + port
+        Ok(Server {
+            state: ServerState::new(
+                nickserv,
+                chanserv,
+                history,
+                bans,
+                rules,
+                self.nick_grace,
+                sender.clone(),
+            )
+            .with_classes(self.classes)
+            .with_flood_exempt(self.flood_exempt)
+            .with_opers(self.opers)
+            .with_oper_certfps(self.oper_certfps)
+            .with_server_name(self.server_name),
+            runtime,
+            listeners,
+            sender,
+            receiver,
+            plugins: self.plugins,
+            sendq: self.sendq,
+            flood: self.flood,
+            stats,
+            tls,
+        })
+    }
+}
+
+/// An IRC server whose listeners are bound but which isn't accepting clients yet.
+///
+/// Connections are served by a pool of async tasks, while every message is handled in turn by
+/// a single thread that owns the [`ServerState`].
+pub struct Server {
+    state: ServerState,
+    runtime: Runtime,
+    listeners: Vec<ConnectionManager>,
+    sender: UnboundedSender<(String, MyMessage)>,
+    receiver: UnboundedReceiver<(String, MyMessage)>,
+    plugins: Option<PluginParser>,
+    sendq: usize,
+    flood: FloodPolicy,
+    stats: Arc<QueueStats>,
+    tls: Option<TlsConfig>,
+}
+
+impl Server {
+    pub fn builder() -> ServerBuilder {
+        ServerBuilder::default()
+    }
+
+    /// The addresses being listened on, with any port 0 replaced by the port picked.
+    pub fn local_addrs(&self) -> Vec<SocketAddr> {
+        self.listeners
+            .iter()
+            .filter_map(|listener| listener.local_addr().ok())
+            .collect()
+    }
+
+    /// Starts serving clients in the background.
+    pub fn spawn(self) -> ServerHandle {
+        let addresses = self.local_addrs();
+        let (stop, stopping) = watch::channel(false);
+        let Server {
+            mut state,
+            runtime,
+            listeners,
+            sender,
+            mut receiver,
+            plugins,
+            sendq,
+            flood,
+            stats,
+            tls,
+        } = self;
+        let handle = runtime.handle().clone();
+        let server_thread = thread::Builder::new()
+            .name("iris-server".to_string())
+            .spawn(move || {
+                // Timers are spawned onto the runtime from here.
+                let _runtime = handle.enter();
+                while let Some((address, message)) = receiver.blocking_recv() {
+                    let stop = matches!(message, MyMessage::Shutdown);
+                    state.handle(address, message);
+                    if stop {
+                        break;
+                    }
+                }
+                // Connections established as the server stopped are closed too, so that
+                // their writer tasks finish and `join` isn't left waiting on them.
+                receiver.close();
+                while let Ok((_, message)) = receiver.try_recv() {
+                    if let MyMessage::Est(_, mut conn_write) = message {
+                        let _ = conn_write
+                            .write_message("ERROR :Closing Link (server shutting down)\r\n");
+                        conn_write.shutdown();
+                    }
+                }
+            })
+            .expect("failed to spawn the server thread");
+        let listeners = listeners
+            .into_iter()
+            .map(|listener| {
+                runtime.spawn(accept(
+                    listener,
+                    sender.clone(),
+                    stopping.clone(),
+                    plugins,
+                    sendq,
+                    flood,
+                ))
+            })
+            .collect();
+        runtime.spawn(tick(sender.clone(), stopping));
+        ServerHandle {
+            addresses,
+            sender,
+            stop,
+            server_thread,
+            listeners,
+            runtime,
+            stats,
+            tls,
+        }
+    }
+
+    /// Serves clients until the server is shut down.
+    pub fn run(self) {
+        self.spawn().join();
+    }
+}
+
+/// Accepts clients until the server stops, then waits for their connections to be closed.
+async fn accept(
+    mut listener: ConnectionManager,
+    sender: UnboundedSender<(String, MyMessage)>,
+    mut stopping: watch::Receiver<bool>,
+    plugins: Option<PluginParser>,
+    sendq: usize,
+    flood: FloodPolicy,
+) {
+    loop {
+        let connection = tokio::select! {
+            connection = listener.accept_new_connection() => connection,
+            _ = stopping.wait_for(|stopping| *stopping) => break,
+        };
+        if sender.is_closed() {
+            break;
+        }
+        tokio::spawn(establish(connection, sender.clone(), plugins, sendq, flood));
+    }
+    drop(sender);
+    listener.close().await;
+}
+
+/// Finishes setting up a client's connection, including any TLS handshake,
+/// then hands it to the server thread and reads its requests.
+async fn establish(
+    connection: Accepted,
+    sender: UnboundedSender<(String, MyMessage)>,
+    plugins: Option<PluginParser>,
+    sendq: usize,
+    flood: FloodPolicy,
+) {
+    let id = connection.id();
+    let (conn_read, mut conn_write) = match connection.establish().await {
+        Ok(connection) => connection,
+        Err(e) => {
+            info!("Failed to establish a connection with {}: {}", id, e);
+            return;
+        }
+    };
+    info!("New connection from {}", id);
+    conn_write.set_sendq(sendq);
+    if sender
+        .send((id.clone(), MyMessage::Est(id, conn_write)))
+        .is_err()
+    {
+        return;
+    }
+    read_requests(conn_read, sender, plugins, flood).await;
+}
+
+/// Has the server thread check every connection's timers once a second, until the server stops.
+async fn tick(sender: UnboundedSender<(String, MyMessage)>, mut stopping: watch::Receiver<bool>) {
+    let mut interval = tokio::time::interval(Duration::from_secs(1));
+    interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = stopping.wait_for(|stopping| *stopping) => break,
+        }
+        if sender
+            .send((String::new(), MyMessage::Tick(Instant::now())))
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+/// Forwards each line a client sends to the server thread, until the client goes away.
+/// Lines sent faster than `flood` allows are held back, and a client that gets too far ahead
+/// of its limit is disconnected for `Excess Flood`.
+async fn read_requests(
+    mut conn_read: ConnectionRead,
+    sender: UnboundedSender<(String, MyMessage)>,
+    plugins: Option<PluginParser>,
+    flood: FloodPolicy,
+) {
+    let mut limiter = FloodLimiter::new(flood);
+    let mut waiting = VecDeque::<String>::new();
+    let mut waiting_bytes = 0;
+    loop {
+        // Hand over every line the client may send by now, holding back the rest.
+        let now = Instant::now();
+        let mut ready = None;
+        while let Some(message) = waiting.pop_front() {
+            let cost = command_cost(&message);
+            if !conn_read.flood_exempt() {
+                let at = limiter.ready_at(cost, now);
+                if at > now {
+                    waiting.push_front(message);
+                    ready = Some(at);
+                    break;
+                }
+                limiter.charge(cost, now);
+            }
+            waiting_bytes -= message.len();
+            if !forward(conn_read.id(), message, &sender, plugins) {
+                return;
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::sleep_until(ready.unwrap_or(now).into()), if ready.is_some() => {}
+            read = conn_read.read_message() => match read {
+                Ok(message) => {
+                    waiting_bytes += message.len();
+                    waiting.push_back(message);
+                    if waiting_bytes > flood.recvq {
+                        info!("Excess flood from {}", conn_read.id());
+                        let _ = sender.send((conn_read.id(), MyMessage::ExcessFlood));
+                        break;
+                    }
+                }
+                Err(ConnectionError::ConnectionLost | ConnectionError::ConnectionClosed) => {
+                    let _ = sender.send((conn_read.id(), MyMessage::Request("QUIT".to_string())));
+                    debug!("Lost connection from {}.", conn_read.id());
+                    break;
+                }
+                Err(e) => {
+                    error!("{}", e);
+                    error!("Invalid message received... ignoring message.");
+                }
+            }
+        }
+    }
+}
+
+/// Hands a line read from the client at `id` to the server thread, returning whether to keep reading.
+fn forward(
+    id: String,
+    message: String,
+    sender: &UnboundedSender<(String, MyMessage)>,
+    plugins: Option<PluginParser>,
+) -> bool {
+    let line = MessageRef::parse(&message);
+    let quit = line.command.eq_ignore_ascii_case("QUIT");
+    let request = match plugins {
+        Some(parse) if line.command.eq_ignore_ascii_case("PLUGIN") => {
+            match parse(line.args.to_string()) {
+                Some(plugin) => MyMessage::Plugin(plugin),
+                None => return true,
+            }
+        }
+        _ => MyMessage::Request(message),
+    };
+    sender.send((id, request)).is_ok() && !quit
+}
+
+/// Controls a running [`Server`].
+pub struct ServerHandle {
+    addresses: Vec<SocketAddr>,
+    sender: UnboundedSender<(String, MyMessage)>,
+    stop: watch::Sender<bool>,
+    server_thread: thread::JoinHandle<()>,
+    listeners: Vec<task::JoinHandle<()>>,
+    runtime: Runtime,
+    stats: Arc<QueueStats>,
+    tls: Option<TlsConfig>,
+}
+
+impl ServerHandle {
+    /// The first address being listened on.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addresses[0]
+    }
+
+    /// Every address being listened on, in the order they were added to the builder.
+    pub fn local_addrs(&self) -> &[SocketAddr] {
+        &self.addresses
+    }
+
+    /// How much is waiting to be sent to clients.
+    pub fn queue_stats(&self) -> &QueueStats {
+        &self.stats
+    }
+
+    /// Reads the TLS certificate and key again, for the clients that connect from now on.
+    /// If they can't be read, TLS listeners keep presenting the ones they had.
+    pub fn reload_tls(&self) -> io::Result<()> {
+        match &self.tls {
+            Some(tls) => tls.reload(),
+            None => Ok(()),
+        }
+    }
+
+    /// Stops listening and disconnects every client with an `ERROR`, once the messages already
+    /// received have been handled. Use [`ServerHandle::join`] to wait until it has.
+    pub fn shutdown(&self) {
+        if self.stop.send_replace(true) {
+            return;
+        }
+        let _ = self.sender.send((String::new(), MyMessage::Shutdown));
+    }
+
+    /// Waits for the server to stop, including sending everything still queued for its clients.
+    pub fn join(self) {
+        let _ = self.server_thread.join();
+        self.runtime.block_on(async {
+            for listener in self.listeners {
+                let _ = listener.await;
+            }
+        });
+    }
+
+    /// Waits up to `timeout` for the server to stop, returning whether it did.
+    pub fn join_timeout(self, timeout: Duration) -> bool {
+        let (done, finished) = std::sync::mpsc::channel();
+        thread::spawn(move || {
+            self.join();
+            let _ = done.send(());
+        });
+        finished.recv_timeout(timeout).is_ok()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::connect::{ConnectionClass, MemorySink, Sink};
+
+    fn server() -> ServerState {
+        services_server().0
+    }
+
+    /// A server along with the receiver NickServ commands come back on once their password has
+    /// been checked or hashed; see [`finish_password`].
+    fn services_server() -> (ServerState, UnboundedReceiver<(String, MyMessage)>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let rules = NameRules::default();
+        let server = ServerState::new(
+            NickServ::in_memory(rules).with_rounds(1000),
+            ChanServ::in_memory(rules),
+            History::in_memory(
+                Retention {
+                    channel: 10,
+                    direct: 10,
+                },
+                rules,
+            ),
+            Bans::in_memory(rules),
+            rules,
+            Duration::from_secs(60),
+            sender,
+        );
+        (server, receiver)
+    }
+
+    /// Waits for the next NickServ command whose password was checked or hashed, and finishes it.
+    async fn finish_password(
+        server: &mut ServerState,
+        receiver: &mut UnboundedReceiver<(String, MyMessage)>,
+    ) {
+        let (address, message) = receiver.recv().await.unwrap();
+        assert!(matches!(message, MyMessage::NickServ(..)));
+        server.handle(address, message);
+    }
+
+    fn connect(server: &mut ServerState, address: &str) -> MemorySink {
+        let sink = MemorySink::default();
+        server.handle(
+            address.to_string(),
+            MyMessage::Est(
+                address.to_string(),
+                ConnectionWrite::new(address, sink.clone()),
+            ),
+        );
+        sink
+    }
+
+    fn send(server: &mut ServerState, address: &str, line: &str) {
+        server.handle(address.to_string(), MyMessage::Request(line.to_string()));
+    }
+
+    /// Connects and registers `nick`, discarding the welcome burst.
+    fn register(server: &mut ServerState, address: &str, nick: &str) -> MemorySink {
+        let sink = connect(server, address);
+        send(server, address, &format!("NICK {nick}"));
+        send(server, address, &format!("USER ignored 0 * {nick}"));
+        sink.take_lines();
+        sink
+    }
+
+    #[test]
+    fn test_registration() {
+        let mut server = server();
+        let sink = connect(&mut server, "127.0.0.1:5000");
+        send(&mut server, "127.0.0.1:5000", "JOIN #haku");
+        assert_eq!(
+            sink.take_lines(),
+            [":iris-server 451 * :You have not registered"]
+        );
+        send(&mut server, "127.0.0.1:5000", "NICK tfpkasdfasdfasdf");
+        assert_eq!(
+            sink.take_lines(),
+            [":iris-server 432 * tfpkasdfasdfasdf :Erroneus nickname"]
+        );
+        send(&mut server, "127.0.0.1:5000", "NICK tom");
+        assert!(sink.take_lines().is_empty());
+        send(&mut server, "127.0.0.1:5000", "USER ignored 0 * :Tom T");
+        let lines = sink.take_lines();
+        assert_eq!(lines[0], ":iris-server 001 tom :Hi Tom T, welcome to IRC");
+        assert!(lines[1].starts_with(":iris-server 005 tom "));
+        assert_eq!(
+            server.users["127.0.0.1:5000"].nick,
+            Some(Nick("tom".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_privmsg() {
+        let mut server = server();
+        let tom = register(&mut server, "127.0.0.1:5000", "tom");
+        let ann = register(&mut server, "127.0.0.1:5001", "ann");
+        send(&mut server, "127.0.0.1:5000", "PRIVMSG ann :How are you?");
+        assert_eq!(
+            ann.take_lines(),
+            [":tom!ignored@127.0.0.1 PRIVMSG ann :How are you?"]
+        );
+        assert!(tom.take_lines().is_empty());
+        send(&mut server, "127.0.0.1:5000", "PRIVMSG ANN :Hello?");
+        assert_eq!(
+            ann.take_lines(),
+            [":tom!ignored@127.0.0.1 PRIVMSG ANN :Hello?"]
+        );
+        send(&mut server, "127.0.0.1:5000", "PRIVMSG bob :Anyone there?");
+        assert_eq!(
+            tom.take_lines(),
+            [":iris-server 401 tom bob :No such nick/channel"]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_private_history() {
+        let (mut server, mut receiver) = services_server();
+        let tom = register(&mut server, "127.0.0.1:5000", "tom");
+        let ann = register(&mut server, "127.0.0.1:5001", "ann");
+        send(
+            &mut server,
+            "127.0.0.1:5001",
+            "PRIVMSG tom :Not logged in yet",
+        );
+        send(
+            &mut server,
+            "127.0.0.1:5000",
+            "PRIVMSG NickServ :REGISTER hunter2",
+        );
+        send(
+            &mut server,
+            "127.0.0.1:5001",
+            "PRIVMSG NickServ :REGISTER hunter2",
+        );
+        // Nobody is identified until their password has been hashed.
+        assert!(server.users["127.0.0.1:5000"].account.is_none());
+        finish_password(&mut server, &mut receiver).await;
+        finish_password(&mut server, &mut receiver).await;
+        send(&mut server, "127.0.0.1:5001", "PRIVMSG tom :Psst");
+        tom.take_lines();
+        ann.take_lines();
+        send(&mut server, "127.0.0.1:5000", "CHATHISTORY LATEST ann * 10");
+        assert_eq!(
+            tom.take_lines(),
+            [":ann!ignored@user/ann PRIVMSG tom :Psst"]
+        );
+
+        // Others can't read the conversation, logged in or not.
+        let eve = register(&mut server, "127.0.0.1:5002", "eve");
+        send(&mut server, "127.0.0.1:5002", "CHATHISTORY LATEST ann * 10");
+        assert_eq!(
+            eve.take_lines(),
+            [":iris-server FAIL CHATHISTORY INVALID_TARGET LATEST ann :Messages could not be retrieved"]
+        );
+        send(
+            &mut server,
+            "127.0.0.1:5002",
+            "PRIVMSG NickServ :REGISTER hunter2",
+        );
+        finish_password(&mut server, &mut receiver).await;
+        eve.take_lines();
+        send(&mut server, "127.0.0.1:5002", "CHATHISTORY LATEST ann * 10");
+        assert!(eve.take_lines().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_drop_account() {
+        let (mut server, mut receiver) = services_server();
+        let tom = register(&mut server, "127.0.0.1:5000", "tom");
+        let ann = register(&mut server, "127.0.0.1:5001", "ann");
+        for address in ["127.0.0.1:5000", "127.0.0.1:5001"] {
+            send(&mut server, address, "PRIVMSG NickServ :REGISTER hunter2");
+            finish_password(&mut server, &mut receiver).await;
+        }
+        send(&mut server, "127.0.0.1:5000", "JOIN #haku");
+        send(
+            &mut server,
+            "127.0.0.1:5000",
+            "PRIVMSG ChanServ :REGISTER #haku",
+        );
+        send(
+            &mut server,
+            "127.0.0.1:5000",
+            "PRIVMSG ChanServ :ACCESS #haku ADD ann op",
+        );
+        // Whoever registers the name after ann dropped it doesn't get ann's access.
+        send(
+            &mut server,
+            "127.0.0.1:5001",
+            "PRIVMSG NickServ :DROP hunter2",
+        );
+        finish_password(&mut server, &mut receiver).await;
+        send(
+            &mut server,
+            "127.0.0.1:5001",
+            "PRIVMSG NickServ :REGISTER other",
+        );
+        finish_password(&mut server, &mut receiver).await;
+        tom.take_lines();
+        ann.take_lines();
+        send(&mut server, "127.0.0.1:5001", "JOIN #haku");
+        assert_eq!(tom.take_lines(), [":ann!ignored@user/ann JOIN #haku"]);
+        assert!(!ann.take_lines().iter().any(|line| line.contains("MODE")));
+    }
+
+    #[test]
+    fn test_labeled_response_without_batch() {
+        let mut server = server();
+        let tom = connect(&mut server, "127.0.0.1:5000");
+        send(&mut server, "127.0.0.1:5000", "CAP REQ :labeled-response");
+        send(&mut server, "127.0.0.1:5000", "NICK tom");
+        send(&mut server, "127.0.0.1:5000", "USER tom 0 * :Tom");
+        send(&mut server, "127.0.0.1:5000", "CAP END");
+        send(&mut server, "127.0.0.1:5000", "JOIN #haku");
+        tom.take_lines();
+        send(&mut server, "127.0.0.1:5000", "@label=n1 NAMES #haku");
+        assert_eq!(
+            tom.take_lines(),
+            [
+                ":iris-server 353 tom = #haku :@tom",
+                ":iris-server 366 tom #haku :End of /NAMES list"
+            ]
+        );
+        send(&mut server, "127.0.0.1:5000", "@label=p1 PING :x");
+        assert_eq!(tom.take_lines(), ["@label=p1 PONG :x"]);
+    }
+
+    #[test]
+    fn test_secret_topic() {
+        let mut server = server();
+        let tom = register(&mut server, "127.0.0.1:5000", "tom");
+        let ann = register(&mut server, "127.0.0.1:5001", "ann");
+        send(&mut server, "127.0.0.1:5000", "JOIN #haku");
+        send(&mut server, "127.0.0.1:5000", "TOPIC #haku :Hidden away");
+        send(&mut server, "127.0.0.1:5001", "TOPIC #haku");
+        assert_eq!(
+            ann.take_lines(),
+            [":iris-server 332 ann #haku :Hidden away"]
+        );
+        send(&mut server, "127.0.0.1:5000", "MODE #haku +s");
+        tom.take_lines();
+        send(&mut server, "127.0.0.1:5001", "TOPIC #haku");
+        send(&mut server, "127.0.0.1:5001", "TOPIC #haku :Found it");
+        let lines = ann.take_lines();
+        assert_eq!(lines.len(), 2);
+        assert!(lines
+            .iter()
+            .all(|line| line.starts_with(":iris-server 403 ann #haku ")));
+        send(&mut server, "127.0.0.1:5000", "TOPIC #haku");
+        assert_eq!(
+            tom.take_lines(),
+            [":iris-server 332 tom #haku :Hidden away"]
+        );
+    }
+
+    #[test]
+    fn test_join_quit() {
+        let mut server = server();
+        let tom = register(&mut server, "127.0.0.1:5000", "tom");
+        let ann = register(&mut server, "127.0.0.1:5001", "ann");
+        send(&mut server, "127.0.0.1:5000", "JOIN #haku");
+        assert_eq!(tom.take_lines()[0], ":tom!ignored@127.0.0.1 JOIN #haku");
+        send(&mut server, "127.0.0.1:5001", "JOIN #haku");
+        assert_eq!(ann.take_lines()[0], ":ann!ignored@127.0.0.1 JOIN #haku");
+        assert_eq!(tom.take_lines(), [":ann!ignored@127.0.0.1 JOIN #haku"]);
+        send(&mut server, "127.0.0.1:5001", "QUIT :bye");
+        assert_eq!(tom.take_lines(), [":ann!ignored@127.0.0.1 QUIT :bye"]);
+        assert!(!server.users.contains_key("127.0.0.1:5001"));
+        assert_eq!(server.nicks.address_of("ann"), None);
+    }
+
+    #[test]
+    fn test_sendq() {
+        let mut server = server();
+        let tom = register(&mut server, "127.0.0.1:5000", "tom");
+        let ann = register(&mut server, "127.0.0.1:5001", "ann");
+        send(&mut server, "127.0.0.1:5000", "JOIN #haku");
+        send(&mut server, "127.0.0.1:5001", "JOIN #haku");
+        tom.take_lines();
+        ann.take_lines();
+        let conn_write = &mut server.users.get_mut("127.0.0.1:5001").unwrap().conn_write;
+        conn_write.set_sendq(100);
+        send(&mut server, "127.0.0.1:5000", "PRIVMSG ann :Are you there?");
+        assert_eq!(52, server.users["127.0.0.1:5001"].conn_write.queued());
+        send(&mut server, "127.0.0.1:5000", "PRIVMSG ann :Hello?");
+        send(&mut server, "127.0.0.1:5000", "PRIVMSG ann :Anyone?");
+        assert_eq!(
+            ann.take_lines(),
+            [
+                ":tom!ignored@127.0.0.1 PRIVMSG ann :Are you there?",
+                ":tom!ignored@127.0.0.1 PRIVMSG ann :Hello?",
+                "ERROR :SendQ exceeded",
+            ]
+        );
+        assert!(ann.is_closed());
+        assert!(!server.users.contains_key("127.0.0.1:5001"));
+        assert_eq!(
+            tom.take_lines(),
+            [":ann!ignored@127.0.0.1 QUIT :SendQ exceeded"]
+        );
+    }
+
+    #[test]
+    fn test_held_sendq() {
+        let sink = MemorySink::default();
+        let mut conn_write = ConnectionWrite::new("127.0.0.1:5000", sink.clone());
+        conn_write.set_sendq(20);
+        // Replies held back for a label count towards the SendQ while they wait.
+        conn_write.hold_messages();
+        assert!(conn_write.write_message("PING :a\r\n").is_ok());
+        assert!(conn_write.write_message("PING :b\r\n").is_ok());
+        assert!(conn_write.write_message("PING :c\r\n").is_err());
+        assert_eq!(conn_write.failure(), Some("SendQ exceeded"));
+        assert!(conn_write.release_messages().is_empty());
+        assert_eq!(sink.take_lines(), ["ERROR :SendQ exceeded"]);
+        assert!(sink.is_closed());
+    }
+
+    #[test]
+    fn test_connection_reset() {
+        let mut server = server();
+        let tom = register(&mut server, "127.0.0.1:5000", "tom");
+        let ann = register(&mut server, "127.0.0.1:5001", "ann");
+        let bob = register(&mut server, "127.0.0.1:5002", "bob");
+        for address in ["127.0.0.1:5000", "127.0.0.1:5001", "127.0.0.1:5002"] {
+            send(&mut server, address, "JOIN #haku");
+        }
+        tom.take_lines();
+        ann.take_lines();
+        bob.take_lines();
+        // Both sockets die while tom's message is broadcast to the channel.
+        let mut dead = ann.clone();
+        dead.close();
+        let mut dead = bob.clone();
+        dead.close();
+        send(&mut server, "127.0.0.1:5000", "PRIVMSG #haku :Still here?");
+        assert!(!server.users.contains_key("127.0.0.1:5001"));
+        assert!(!server.users.contains_key("127.0.0.1:5002"));
+        let mut lines = tom.take_lines();
+        lines.sort();
+        assert_eq!(
+            lines,
+            [
+                ":ann!ignored@127.0.0.1 QUIT :Connection reset",
+                ":bob!ignored@127.0.0.1 QUIT :Connection reset",
+                ":tom!ignored@127.0.0.1 PRIVMSG #haku :Still here?",
+            ]
+        );
+        let members = &server
+            .channels
+            .get(&Channel("#haku".to_string()))
+            .unwrap()
+            .members;
+        assert_eq!(Vec::from_iter(members), ["127.0.0.1:5000"]);
+        // The socket also reports the close, after its user was already dropped.
+        send(&mut server, "127.0.0.1:5001", "QUIT");
+        assert!(tom.take_lines().is_empty());
+    }
+
+    #[test]
+    fn test_ping_timeout() {
+        let mut server = server();
+        let start = Instant::now();
+        let tom = register(&mut server, "127.0.0.1:5000", "tom");
+        let ann = register(&mut server, "127.0.0.1:5001", "ann");
+        send(&mut server, "127.0.0.1:5000", "JOIN #haku");
+        send(&mut server, "127.0.0.1:5001", "JOIN #haku");
+        tom.take_lines();
+        ann.take_lines();
+        let tick = |server: &mut ServerState, secs| {
+            let now = start + Duration::from_secs(secs);
+            server.handle(String::new(), MyMessage::Tick(now));
+        };
+        tick(&mut server, 119);
+        assert!(tom.take_lines().is_empty());
+        tick(&mut server, 121);
+        assert_eq!(tom.take_lines(), ["PING :iris-server"]);
+        assert_eq!(ann.take_lines(), ["PING :iris-server"]);
+        send(&mut server, "127.0.0.1:5001", "PONG :iris-server");
+        tick(&mut server, 180);
+        assert!(tom.take_lines().is_empty());
+        tick(&mut server, 181);
+        assert_eq!(tom.take_lines(), ["ERROR :Closing Link (Ping timeout)"]);
+        assert!(tom.is_closed());
+        assert!(!server.users.contains_key("127.0.0.1:5000"));
+        // ann answered the first PING; this one is for going quiet since.
+        assert_eq!(
+            ann.take_lines(),
+            [
+                "PING :iris-server",
+                ":tom!ignored@127.0.0.1 QUIT :Ping timeout"
+            ]
+        );
+        assert!(server.users.contains_key("127.0.0.1:5001"));
+    }
+
+    #[test]
+    fn test_registration_timeout() {
+        let lan = ConnectionClass {
+            hosts: "10.*".to_string(),
+            registration_timeout: Duration::from_secs(5),
+            ..ConnectionClass::default()
+        };
+        let mut server = server().with_classes(vec![lan]);
+        let start = Instant::now();
+        let local = connect(&mut server, "127.0.0.1:5000");
+        let remote = connect(&mut server, "10.0.0.1:5000");
+        send(&mut server, "10.0.0.1:5000", "NICK tom");
+        let tick = |server: &mut ServerState, secs| {
+            let now = start + Duration::from_secs(secs);
+            server.handle(String::new(), MyMessage::Tick(now));
+        };
+        tick(&mut server, 6);
+        assert_eq!(
+            remote.take_lines(),
+            ["ERROR :Closing Link (Registration timeout)"]
+        );
+        assert!(remote.is_closed());
+        assert!(!server.users.contains_key("10.0.0.1:5000"));
+        assert!(local.take_lines().is_empty());
+        tick(&mut server, 31);
+        assert_eq!(
+            local.take_lines(),
+            ["ERROR :Closing Link (Registration timeout)"]
+        );
+        assert!(server.users.is_empty());
+    }
+
+    #[test]
+    fn test_connection_limits() {
+        let class = ConnectionClass {
+            max_per_ip: 2,
+            max_per_cidr: 3,
+            throttle: 3,
+            ..ConnectionClass::default()
+        };
+        let mut server = server().with_classes(vec![class]);
+        connect(&mut server, "10.0.0.1:5000");
+        connect(&mut server, "10.0.0.1:5001");
+        let third = connect(&mut server, "10.0.0.1:5002");
+        assert_eq!(
+            third.take_lines(),
+            ["ERROR :Closing Link (Too many connections from your host)"]
+        );
+        assert!(third.is_closed());
+        connect(&mut server, "10.0.0.2:5000");
+        let neighbour = connect(&mut server, "10.0.0.3:5000");
+        assert_eq!(
+            neighbour.take_lines(),
+            ["ERROR :Closing Link (Too many connections from your network)"]
+        );
+        assert!(connect(&mut server, "10.0.1.1:5000")
+            .take_lines()
+            .is_empty());
+        assert_eq!(server.users.len(), 4);
+        // Refused attempts still count towards the throttle.
+        send(&mut server, "10.0.0.1:5000", "QUIT");
+        send(&mut server, "10.0.0.1:5001", "QUIT");
+        let ip = |ip: &str| ip.parse::<IpAddr>().unwrap();
+        assert_eq!(
+            server.connections,
+            HashMap::from([(ip("10.0.0.2"), 1), (ip("10.0.1.1"), 1)])
+        );
+        let again = connect(&mut server, "10.0.0.1:5003");
+        assert_eq!(
+            again.take_lines(),
+            ["ERROR :Closing Link (Throttled: reconnecting too fast)"]
+        );
+        server.handle(
+            String::new(),
+            MyMessage::Tick(Instant::now() + Duration::from_secs(11)),
+        );
+        assert!(server.recent.is_empty());
+    }
+
+    #[test]
+    fn test_bans() {
+        let mut server = server().with_opers(vec![("admin".to_string(), "hunter2".to_string())]);
+        let tom = register(&mut server, "127.0.0.1:5000", "tom");
+        let ann = register(&mut server, "10.0.0.1:5000", "ann");
+        send(&mut server, "127.0.0.1:5000", "KLINE *@10.0.0.1 :spam");
+        send(&mut server, "127.0.0.1:5000", "OPER admin hunter3");
+        assert_eq!(
+            tom.take_lines(),
+            [
+                ":iris-server 481 tom :Permission Denied- You're not an IRC operator",
+                ":iris-server 464 tom :Password incorrect",
+            ]
+        );
+        send(&mut server, "127.0.0.1:5000", "OPER admin hunter2");
+        send(&mut server, "127.0.0.1:5000", "KLINE 5 *@10.0.0.1 :spam");
+        assert_eq!(
+            tom.take_lines(),
+            [
+                ":iris-server 381 tom :You are now an IRC operator",
+                ":iris-server NOTICE tom :Added K-line for *@10.0.0.1.",
+            ]
+        );
+        assert_eq!(ann.take_lines(), ["ERROR :Closing Link (K-lined: spam)"]);
+        assert!(!server.users.contains_key("10.0.0.1:5000"));
+        // K-lines are checked again at registration.
+        let ann = connect(&mut server, "10.0.0.1:5001");
+        send(&mut server, "10.0.0.1:5001", "NICK ann");
+        send(&mut server, "10.0.0.1:5001", "USER ignored 0 * ann");
+        assert_eq!(
+            ann.take_lines(),
+            [
+                ":iris-server 465 ann :You are banned from this server",
+                "ERROR :Closing Link (K-lined: spam)",
+            ]
+        );
+        assert_eq!(server.users.len(), 1);
+        send(&mut server, "127.0.0.1:5000", "DLINE 10.0.0.0/8");
+        send(&mut server, "127.0.0.1:5000", "STATS k");
+        assert_eq!(
+            tom.take_lines(),
+            [
+                ":iris-server NOTICE tom :Added D-line for 10.0.0.0/8.",
+                ":iris-server 216 tom K *@10.0.0.1 5m :spam",
+                ":iris-server 219 tom k :End of STATS report",
+            ]
+        );
+        let ann = connect(&mut server, "10.0.0.1:5002");
+        assert_eq!(
+            ann.take_lines(),
+            ["ERROR :Closing Link (D-lined: No reason)"]
+        );
+        send(&mut server, "127.0.0.1:5000", "UNDLINE 10.0.0.0/8");
+        send(&mut server, "127.0.0.1:5000", "UNDLINE 10.0.0.0/8");
+        assert_eq!(
+            tom.take_lines(),
+            [
+                ":iris-server NOTICE tom :Removed D-line for 10.0.0.0/8.",
+                ":iris-server NOTICE tom :There is no D-line for 10.0.0.0/8.",
+            ]
+        );
+        assert!(connect(&mut server, "10.0.0.1:5003")
+            .take_lines()
+            .is_empty());
+    }
+
+    #[test]
+    fn test_server_name() {
+        let mut server = server()
+            .with_opers(vec![("admin".to_string(), "hunter2".to_string())])
+            .with_server_name("haku");
+        let tom = register(&mut server, "127.0.0.1:5000", "tom");
+        send(&mut server, "127.0.0.1:5000", "OPER admin hunter2");
+        tom.take_lines();
+        send(&mut server, "127.0.0.1:5000", "KLINE *@10.0.0.1 :spam");
+        assert_eq!(
+            tom.take_lines(),
+            [":haku NOTICE tom :Added K-line for *@10.0.0.1."]
+        );
+    }
+
+    #[test]
+    fn test_forward() {
+        let (sender, mut receiver) = mpsc::unbounded_channel();
+        let id = || "127.0.0.1:5000".to_string();
+        assert!(!forward(
+            id(),
+            "quit :bye".to_string(),
+            &sender,
+            Some(parse_plugin)
+        ));
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok((_, MyMessage::Request(line))) if line == "quit :bye"
+        ));
+        assert!(forward(id(), "QUITTER".to_string(), &sender, None));
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok((_, MyMessage::Request(_)))
+        ));
+        assert!(forward(
+            id(),
+            "plugin LISTING".to_string(),
+            &sender,
+            Some(parse_plugin)
+        ));
+        assert!(matches!(
+            receiver.try_recv(),
+            Ok((_, MyMessage::Plugin(Plugin::ListingChannels)))
+        ));
+        assert!(forward(
+            id(),
+            "PLUGIN nothing".to_string(),
+            &sender,
+            Some(parse_plugin)
+        ));
+        assert!(receiver.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_secure_connections() {
+        let mut server =
+            server().with_oper_certfps(vec![("admin".to_string(), "ab12".to_string())]);
+        let tom = MemorySink::default();
+        let mut conn_write = ConnectionWrite::new("127.0.0.1:5000", tom.clone());
+        conn_write.set_secure(Some("AB12".to_string()));
+        server.handle(
+            "127.0.0.1:5000".to_string(),
+            MyMessage::Est("127.0.0.1:5000".to_string(), conn_write),
+        );
+        send(&mut server, "127.0.0.1:5000", "NICK tom");
+        send(&mut server, "127.0.0.1:5000", "USER ignored 0 * :Tom T");
+        tom.take_lines();
+        let ann = register(&mut server, "127.0.0.1:5001", "ann");
+        send(&mut server, "127.0.0.1:5000", "MODE tom");
+        send(&mut server, "127.0.0.1:5000", "MODE ann");
+        send(&mut server, "127.0.0.1:5000", "WHOIS tom");
+        assert_eq!(
+            tom.take_lines(),
+            [
+                ":iris-server 221 tom +Z",
+                ":iris-server 502 tom :Cannot change mode for other users",
+                ":iris-server 311 tom tom ignored 127.0.0.1 * :Tom T",
+                ":iris-server 312 tom tom iris-server :The iris IRC server",
+                ":iris-server 671 tom tom :is using a secure connection",
+                ":iris-server 276 tom tom :has client certificate fingerprint AB12",
+                ":iris-server 318 tom tom :End of /WHOIS list",
+            ]
+        );
+        // Only its owner and IRC operators see the fingerprint.
+        send(&mut server, "127.0.0.1:5001", "WHOIS TOM");
+        send(&mut server, "127.0.0.1:5001", "WHOIS bob");
+        assert_eq!(
+            ann.take_lines(),
+            [
+                ":iris-server 311 ann tom ignored 127.0.0.1 * :Tom T",
+                ":iris-server 312 ann tom iris-server :The iris IRC server",
+                ":iris-server 671 ann tom :is using a secure connection",
+                ":iris-server 318 ann tom :End of /WHOIS list",
+                ":iris-server 401 ann bob :No such nick/channel",
+                ":iris-server 318 ann bob :End of /WHOIS list",
+            ]
+        );
+        send(&mut server, "127.0.0.1:5001", "OPER admin anything");
+        send(&mut server, "127.0.0.1:5000", "OPER admin anything");
+        send(&mut server, "127.0.0.1:5000", "MODE tom");
+        assert_eq!(
+            ann.take_lines(),
+            [":iris-server 464 ann :Password incorrect"]
+        );
+        assert_eq!(
+            tom.take_lines(),
+            [
+                ":iris-server 381 tom :You are now an IRC operator",
+                ":iris-server 221 tom +oZ",
+            ]
+        );
+    }
+}
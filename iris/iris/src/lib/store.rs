@@ -0,0 +1,45 @@
+//! Helpers for the small line-based files services persist their state in.
+use std::{
+    fs::{self, OpenOptions},
+    io::{self, Write},
+    path::Path,
+};
+
+/// Replaces the file at `path` with `lines`, one per line.
+/// The lines are written to a temporary file first so a crash never leaves a half-written store.
+pub fn write_lines(path: &Path, lines: impl IntoIterator<Item = String>) -> io::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp)?;
+    for line in lines {
+        writeln!(file, "{line}")?;
+    }
+    file.sync_all()?;
+    fs::rename(&tmp, path)
+}
+
+/// Adds `lines` to the end of the file at `path`.
+/// Unlike [`write_lines`] the file isn't synced, so this is cheap enough to do for every change.
+pub fn append_lines(path: &Path, lines: impl IntoIterator<Item = String>) -> io::Result<()> {
+    let mut file = OpenOptions::new().append(true).open(path)?;
+    for line in lines {
+        writeln!(file, "{line}")?;
+    }
+    Ok(())
+}
+
+/// Reads the lines of the file at `path`, skipping blank ones.
+/// A missing file has no lines.
+pub fn read_lines(path: &Path) -> io::Result<Vec<String>> {
+    match fs::read_to_string(path) {
+        Ok(contents) => Ok(contents
+            .lines()
+            .filter(|line| !line.is_empty())
+            .map(str::to_string)
+            .collect()),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+        Err(e) => Err(e),
+    }
+}
@@ -0,0 +1,183 @@
+//! # TLS
+//! Lets clients connect over TLS, on listeners of their own next to the plaintext ones.
+//!
+//! The server's certificate chain and key are read from PEM files, and can be read again
+//! while the server runs, so a renewed certificate is picked up without dropping anyone.
+//! Clients may present a certificate of their own, which is never checked against any
+//! authority: it only identifies them by its SHA-256 fingerprint, for example to OPER.
+use std::{
+    fmt::Write as _,
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+
+use rustls::{
+    client::danger::HandshakeSignatureValid,
+    crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+    pki_types::{pem::PemObject, CertificateDer, PrivateKeyDer, UnixTime},
+    server::danger::{ClientCertVerified, ClientCertVerifier},
+    DigitallySignedStruct, DistinguishedName, ServerConfig, SignatureScheme,
+};
+use sha2::{Digest, Sha256};
+use tokio_rustls::TlsAcceptor;
+
+/// The certificate and key TLS listeners present, shared by all of them.
+/// Clones share the same files, so reloading one reloads them all.
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    cert: PathBuf,
+    key: PathBuf,
+    current: Arc<RwLock<Arc<ServerConfig>>>,
+}
+
+impl TlsConfig {
+    /// Reads the PEM certificate chain at `cert`, leaf first, and the PEM private key at `key`.
+    pub fn load(cert: impl Into<PathBuf>, key: impl Into<PathBuf>) -> io::Result<Self> {
+        let (cert, key) = (cert.into(), key.into());
+        let config = server_config(&cert, &key)?;
+        Ok(TlsConfig {
+            cert,
+            key,
+            current: Arc::new(RwLock::new(Arc::new(config))),
+        })
+    }
+
+    /// Reads the certificate and key again, for the connections accepted from now on.
+    /// If either can't be read, the ones already loaded are kept.
+    pub fn reload(&self) -> io::Result<()> {
+        let config = server_config(&self.cert, &self.key)?;
+        *self.current.write().unwrap() = Arc::new(config);
+        Ok(())
+    }
+
+    /// Performs the server side of the handshake with the certificate loaded now.
+    pub fn acceptor(&self) -> TlsAcceptor {
+        TlsAcceptor::from(self.current.read().unwrap().clone())
+    }
+}
+
+/// The SHA-256 fingerprint of a DER encoded certificate, as lowercase hex.
+pub fn fingerprint(cert: &[u8]) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+fn server_config(cert: &Path, key: &Path) -> io::Result<ServerConfig> {
+    let invalid = |path: &Path, e: &dyn std::fmt::Display| {
+        io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("{}: {e}", path.display()),
+        )
+    };
+    let chain = CertificateDer::pem_file_iter(cert)
+        .and_then(Iterator::collect::<Result<Vec<_>, _>>)
+        .map_err(|e| invalid(cert, &e))?;
+    if chain.is_empty() {
+        return Err(invalid(cert, &"no certificates found"));
+    }
+    let private_key = PrivateKeyDer::from_pem_file(key).map_err(|e| invalid(key, &e))?;
+    let provider = Arc::new(ring::default_provider());
+    ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()
+        .map_err(|e| invalid(cert, &e))?
+        .with_client_cert_verifier(Arc::new(AnyClientCert { provider }))
+        .with_single_cert(chain, private_key)
+        .map_err(|e| invalid(cert, &e))
+}
+
+/// Asks clients for a certificate, and takes whichever one they present, or none at all.
+/// Only the signature proving the client holds the certificate's key is checked.
+#[derive(Debug)]
+struct AnyClientCert {
+    provider: Arc<CryptoProvider>,
+}
+
+impl ClientCertVerifier for AnyClientCert {
+    fn client_auth_mandatory(&self) -> bool {
+        false
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        &[]
+    }
+
+    fn verify_client_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _now: UnixTime,
+    ) -> Result<ClientCertVerified, rustls::Error> {
+        Ok(ClientCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fingerprint() {
+        assert_eq!(
+            fingerprint(b"abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+
+    #[test]
+    fn test_load_and_reload() {
+        let dir = std::env::temp_dir().join(format!("iris-tls-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let (cert, key) = (dir.join("cert.pem"), dir.join("key.pem"));
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(&cert, generated.cert.pem()).unwrap();
+        std::fs::write(&key, generated.key_pair.serialize_pem()).unwrap();
+        let config = TlsConfig::load(&cert, &key).unwrap();
+        // A broken renewal leaves the certificate already loaded in place.
+        std::fs::write(&cert, "not a certificate").unwrap();
+        assert!(config.reload().is_err());
+        let _ = config.acceptor();
+        std::fs::write(&cert, generated.cert.pem()).unwrap();
+        assert!(config.reload().is_ok());
+        assert!(TlsConfig::load(dir.join("missing.pem"), &key).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,4 +1,19 @@
-use crate::{connect::ConnectionWrite, plugin::Plugin};
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    str::FromStr,
+    time::Instant,
+};
+
+use unicode_normalization::UnicodeNormalization;
+use unicode_security::{GeneralSecurityProfile, RestrictionLevel, RestrictionLevelDetection};
+
+use crate::{
+    connect::{ConnectionClass, ConnectionWrite},
+    history::{format_timestamp, parse_timestamp},
+    nickserv::{NickServCommand, PasswordResult},
+    numeric::{Numeric, NumericReply},
+    plugin::Plugin,
+};
 
 /// All relevant IRC errors are listed here.
 /// See the assignment documentation for more information.
@@ -7,6 +22,7 @@ pub enum ErrorType {
     NoNickNameGiven = 431,
     ErroneousNickname = 432,
     NickCollision = 436,
+    InvalidCapCmd = 410,
     NoRecipient = 411,
     NoTextToSend = 412,
     NoOrigin = 409,
@@ -14,80 +30,585 @@ pub enum ErrorType {
     NeedMoreParams = 461,
     NoSuchNick = 401,
     NoSuchChannel = 403,
+    CannotSendToChan = 404,
+    UserNotInChannel = 441,
+    NotOnChannel = 442,
+    UnknownMode = 472,
+    ChanOPrivsNeeded = 482,
+    NotRegistered = 451,
+    AlreadyRegistered = 462,
+    InvalidUsername = 468,
+    BadChanName = 479,
+    PasswdMismatch = 464,
+    YoureBannedCreep = 465,
+    NoPrivileges = 481,
+    UsersDontMatch = 502,
 }
 
 pub enum MyMessage {
     Request(String),              // String: What Client threads got from the IRC clients
     Est(String, ConnectionWrite), // String: IP Address + Port
     Plugin(Plugin), //Plugin Message Type, should be able to create a plugin function from plugin type to be ran in sever thread
+    NickEnforce(Nick), // Nick: a registered nickname whose grace period for identifying has run out
+    Shutdown,       // The server is stopping: every connection gets closed
+    Tick(Instant), // Instant: when the timer fired, to check connections for ping and registration timeouts
+    ExcessFlood,   // The client fell too far behind its flood limit, and is disconnected
+    NickServ(NickServCommand, PasswordResult), // A NickServ command whose password was checked or hashed on a blocking thread
 }
 #[derive(Debug)]
 pub struct ThreadInfo {
     pub conn_write: ConnectionWrite,
     pub nick: Option<Nick>,
     pub full_name: Option<String>,
+    pub account: Option<String>, // The NickServ account this user has identified to
+    pub username: Option<String>,
+    pub host: String,
+    pub away: Option<String>,
+    pub caps: HashSet<Capability>,
+    pub negotiating: bool, // Registration is on hold until the client sends CAP END
+    pub pending_user: Option<UserMsg>, // A USER message received while negotiating
+    pub class: ConnectionClass,
+    pub connected: Instant,
+    pub last_active: Instant,  // When the client last sent anything
+    pub ping: Option<Instant>, // When the server sent a PING that hasn't been answered yet
+    pub oper: Option<String>,  // The name this user became an IRC operator with
+}
+
+impl ThreadInfo {
+    /// The prefix of messages relayed from this user, for example `tom!tom@127.0.0.1`.
+    pub fn prefix(&self) -> Prefix {
+        Prefix::User {
+            nick: self.nick.clone().unwrap_or_else(|| Nick("*".to_string())),
+            username: self.username.clone(),
+            host: Some(self.host.clone()),
+        }
+    }
+
+    /// The user's modes, for example `+Z`. They are set by the server, never by the user.
+    /// - `o`: an IRC operator
+    /// - `Z`: connected over TLS
+    pub fn mode_string(&self) -> String {
+        let modes = [('o', self.oper.is_some()), ('Z', self.conn_write.secure())];
+        let set = modes.iter().filter(|(_, set)| *set).map(|(mode, _)| *mode);
+        format!("+{}", set.collect::<String>())
+    }
+}
+
+/// Everything the sever thread knows about a channel.
+/// Members are identified by the IP Address + Port of their connection.
+#[derive(Debug, Clone, Default)]
+pub struct ChannelInfo {
+    pub members: HashSet<String>,
+    pub ops: HashSet<String>,
+    pub voices: HashSet<String>,
+    pub topic: Option<String>,
+    pub modes: BTreeSet<char>,
+}
+
+impl ChannelInfo {
+    /// Flag modes a channel may have set.
+    /// - `t`: only operators may change the topic
+    /// - `n`: no messages from users outside the channel
+    /// - `m`: only operators and voiced users may speak
+    /// - `s`: the channel is secret
+    pub const FLAG_MODES: [char; 4] = ['t', 'n', 'm', 's'];
+
+    /// Removes a member, along with any status they had.
+    pub fn remove(&mut self, address: &str) -> bool {
+        self.ops.remove(address);
+        self.voices.remove(address);
+        self.members.remove(address)
+    }
+
+    /// The current flag modes, for example `+nt`.
+    pub fn mode_string(&self) -> String {
+        format!("+{}", self.modes.iter().collect::<String>())
+    }
+
+    /// Whether the member at `address` may speak in the channel.
+    pub fn can_speak(&self, address: &str) -> bool {
+        if !self.members.contains(address) && self.modes.contains(&'n') {
+            return false;
+        }
+        !self.modes.contains(&'m') || self.ops.contains(address) || self.voices.contains(address)
+    }
+
+    /// The status prefixes of the member at `address`, for example `@+`.
+    /// Without `multi_prefix`, only the highest one is given.
+    pub fn prefixes(&self, address: &str, multi_prefix: bool) -> String {
+        let prefixes = [('@', &self.ops), ('+', &self.voices)]
+            .into_iter()
+            .filter(|(_, members)| members.contains(address))
+            .map(|(prefix, _)| prefix);
+        if multi_prefix {
+            prefixes.collect()
+        } else {
+            prefixes.take(1).collect()
+        }
+    }
+}
+
+/// The channels on the server, found by name as the server's [`NameRules`] compare them.
+/// Each channel keeps the name it was created with.
+#[derive(Debug, Default)]
+pub struct Channels {
+    rules: NameRules,
+    /// Channels by their casefolded name.
+    channels: HashMap<String, (Channel, ChannelInfo)>,
+}
+
+impl Channels {
+    pub fn new(rules: NameRules) -> Self {
+        Channels {
+            rules,
+            channels: HashMap::new(),
+        }
+    }
+
+    pub fn get(&self, channel: &Channel) -> Option<&ChannelInfo> {
+        self.channels
+            .get(&self.rules.fold(&channel.0))
+            .map(|(_, info)| info)
+    }
+
+    pub fn get_mut(&mut self, channel: &Channel) -> Option<&mut ChannelInfo> {
+        self.channels
+            .get_mut(&self.rules.fold(&channel.0))
+            .map(|(_, info)| info)
+    }
+
+    /// The channel as it was created, along with its information.
+    pub fn get_key_value(&self, channel: &Channel) -> Option<(&Channel, &ChannelInfo)> {
+        self.channels
+            .get(&self.rules.fold(&channel.0))
+            .map(|(channel, info)| (channel, info))
+    }
+
+    pub fn contains(&self, channel: &Channel) -> bool {
+        self.channels.contains_key(&self.rules.fold(&channel.0))
+    }
+
+    /// The channel, created with no members if it doesn't exist yet.
+    pub fn get_or_create(&mut self, channel: &Channel) -> &mut ChannelInfo {
+        let (_, info) = self
+            .channels
+            .entry(self.rules.fold(&channel.0))
+            .or_insert_with(|| (channel.clone(), ChannelInfo::default()));
+        info
+    }
+
+    pub fn remove(&mut self, channel: &Channel) -> Option<ChannelInfo> {
+        self.channels
+            .remove(&self.rules.fold(&channel.0))
+            .map(|(_, info)| info)
+    }
+
+    /// Every channel, by the name it was created with.
+    pub fn iter(&self) -> impl Iterator<Item = (&Channel, &ChannelInfo)> {
+        self.channels
+            .values()
+            .map(|(channel, info)| (channel, info))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &Channel> {
+        self.channels.values().map(|(channel, _)| channel)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = &ChannelInfo> {
+        self.channels.values().map(|(_, info)| info)
+    }
+
+    pub fn values_mut(&mut self) -> impl Iterator<Item = &mut ChannelInfo> {
+        self.channels.values_mut().map(|(_, info)| info)
+    }
+
+    pub fn retain(&mut self, mut keep: impl FnMut(&Channel, &mut ChannelInfo) -> bool) {
+        self.channels
+            .retain(|_, (channel, info)| keep(channel, info));
+    }
+
+    pub fn clear(&mut self) {
+        self.channels.clear();
+    }
+}
+
+/// Who uses each nickname, so that users can be found by nickname, and nicknames that look
+/// alike spotted, without going through every user.
+#[derive(Debug, Default)]
+pub struct Nicks {
+    rules: NameRules,
+    /// Addresses by casefolded nickname.
+    addresses: HashMap<String, String>,
+    /// Addresses by the skeleton of their nickname, see [`NameRules::skeleton`].
+    skeletons: HashMap<String, String>,
+}
+
+impl Nicks {
+    pub fn new(rules: NameRules) -> Self {
+        Nicks {
+            rules,
+            ..Nicks::default()
+        }
+    }
+
+    /// The address of the user using `nick`, if anyone is.
+    pub fn address_of(&self, nick: &str) -> Option<&String> {
+        self.addresses.get(&self.rules.fold(nick))
+    }
+
+    /// The address of a user whose nickname could be mistaken for `nick`, if anyone's could.
+    pub fn confusable(&self, nick: &str) -> Option<&String> {
+        self.skeletons.get(&self.rules.skeleton(nick))
+    }
+
+    /// Records that the user at `address` now uses `nick`, instead of `old` if they had one.
+    pub fn insert(&mut self, address: &str, old: Option<&Nick>, nick: &Nick) {
+        if let Some(old) = old {
+            self.remove(address, old);
+        }
+        self.addresses
+            .insert(self.rules.fold(&nick.0), address.to_string());
+        self.skeletons
+            .insert(self.rules.skeleton(&nick.0), address.to_string());
+    }
+
+    /// Forgets that the user at `address` uses `nick`.
+    pub fn remove(&mut self, address: &str, nick: &Nick) {
+        for (index, key) in [
+            (&mut self.addresses, self.rules.fold(&nick.0)),
+            (&mut self.skeletons, self.rules.skeleton(&nick.0)),
+        ] {
+            if index.get(&key).is_some_and(|user| user == address) {
+                index.remove(&key);
+            }
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.addresses.clear();
+        self.skeletons.clear();
+    }
+}
+
+/// Whether `value` matches the wildcard `mask`, exactly as written; see [`NameRules::mask_matches`]
+/// to ignore case.
+/// In a mask, `*` matches any number of characters and `?` matches exactly one.
+pub fn mask_matches(mask: &str, value: &str) -> bool {
+    let mask = mask.chars().collect::<Vec<_>>();
+    let value = value.chars().collect::<Vec<_>>();
+    // Backtrack to just after the last `*` whenever a match fails.
+    let (mut m, mut v) = (0, 0);
+    let mut star = None;
+    while v < value.len() {
+        match mask.get(m) {
+            Some('*') => {
+                star = Some((m, v));
+                m += 1;
+            }
+            Some(c) if *c == '?' || *c == value[v] => {
+                m += 1;
+                v += 1;
+            }
+            _ => match star {
+                Some((star_m, star_v)) => {
+                    m = star_m + 1;
+                    v = star_v + 1;
+                    star = Some((star_m, star_v + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    mask[m..].iter().all(|c| *c == '*')
+}
+
+/// A random identifier, for example to tell messages or batches apart.
+pub fn unique_id() -> String {
+    let mut bytes = [0u8; 8];
+    getrandom::getrandom(&mut bytes).expect("failed to gather randomness for an identifier");
+    bytes.iter().map(|b| format!("{b:02x}")).collect()
 }
 /// This is the name of your server, all messages originating from
-/// the server should be listed as from this name.
+/// the server should be listed as from this name, unless another is given to
+/// [`ServerBuilder::server_name`](crate::server::ServerBuilder::server_name).
 pub const SERVER_NAME: &str = "iris-server";
 
+impl ErrorType {
+    /// The numeric this error is sent as.
+    pub fn numeric(self) -> Numeric {
+        match self {
+            ErrorType::NoNickNameGiven => Numeric::ErrNoNicknameGiven,
+            ErrorType::ErroneousNickname => Numeric::ErrErroneusNickname,
+            ErrorType::NickCollision => Numeric::ErrNickCollision,
+            ErrorType::InvalidCapCmd => Numeric::ErrInvalidCapCmd,
+            ErrorType::NoRecipient => Numeric::ErrNoRecipient,
+            ErrorType::NoTextToSend => Numeric::ErrNoTextToSend,
+            ErrorType::NoOrigin => Numeric::ErrNoOrigin,
+            ErrorType::UnknownCommand => Numeric::ErrUnknownCommand,
+            ErrorType::NeedMoreParams => Numeric::ErrNeedMoreParams,
+            ErrorType::NoSuchNick => Numeric::ErrNoSuchNick,
+            ErrorType::NoSuchChannel => Numeric::ErrNoSuchChannel,
+            ErrorType::CannotSendToChan => Numeric::ErrCannotSendToChan,
+            ErrorType::UserNotInChannel => Numeric::ErrUserNotInChannel,
+            ErrorType::NotOnChannel => Numeric::ErrNotOnChannel,
+            ErrorType::UnknownMode => Numeric::ErrUnknownMode,
+            ErrorType::ChanOPrivsNeeded => Numeric::ErrChanOPrivsNeeded,
+            ErrorType::NotRegistered => Numeric::ErrNotRegistered,
+            ErrorType::AlreadyRegistered => Numeric::ErrAlreadyRegistered,
+            ErrorType::InvalidUsername => Numeric::ErrInvalidUsername,
+            ErrorType::BadChanName => Numeric::ErrBadChanName,
+            ErrorType::PasswdMismatch => Numeric::ErrPasswdMismatch,
+            ErrorType::YoureBannedCreep => Numeric::ErrYoureBannedCreep,
+            ErrorType::NoPrivileges => Numeric::ErrNoPrivileges,
+            ErrorType::UsersDontMatch => Numeric::ErrUsersDontMatch,
+        }
+    }
+}
+
+/// An error along with the parameters naming its cause, before it is addressed to a user.
+/// For example `ERR_NOSUCHNICK` names the nick that could not be found.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CommandError {
+    pub error: ErrorType,
+    pub params: Vec<String>,
+}
+
+impl ErrorType {
+    /// This error, caused by `params`.
+    pub fn about<P: ToString>(self, params: impl IntoIterator<Item = P>) -> CommandError {
+        CommandError {
+            error: self,
+            params: params.into_iter().map(|p| p.to_string()).collect(),
+        }
+    }
+}
+
+impl From<ErrorType> for CommandError {
+    fn from(error: ErrorType) -> Self {
+        CommandError {
+            error,
+            params: vec![],
+        }
+    }
+}
+
 impl std::fmt::Display for ErrorType {
+    /// The text of the error, for example `No such nick/channel`.
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
-        match *self {
-            ErrorType::NoNickNameGiven => {
-                write!(fmt, ":{SERVER_NAME} 431 :No nickname given.")
-            }
-            ErrorType::ErroneousNickname => {
-                // Typo is same as in RFC1459
-                write!(fmt, ":{SERVER_NAME} 432 :Erroneus nickname")
-            }
-            ErrorType::NoRecipient => {
-                write!(fmt, ":{SERVER_NAME} 411 :No recipient given")
-            }
-            ErrorType::NoTextToSend => {
-                write!(fmt, ":{SERVER_NAME} 412 :No text to send")
-            }
-            ErrorType::NoOrigin => {
-                write!(fmt, ":{SERVER_NAME} 409 :No origin specified")
-            }
-            ErrorType::UnknownCommand => {
-                write!(fmt, ":{SERVER_NAME} 421 :Unknown command")
-            }
-            ErrorType::NeedMoreParams => {
-                write!(fmt, ":{SERVER_NAME} 461 :Not enough parameters")
-            }
-            ErrorType::NoSuchNick => {
-                write!(fmt, ":{SERVER_NAME} 401 :No such nick/channel")
-            }
-            ErrorType::NoSuchChannel => {
-                write!(fmt, ":{SERVER_NAME} 403 :No such channel")
-            }
-            ErrorType::NickCollision => {
-                write!(fmt, ":{SERVER_NAME} 436 :Nickname collision")
-            }
+        write!(fmt, "{}", self.numeric().default_text().unwrap_or_default())
+    }
+}
+
+impl TryFrom<u16> for ErrorType {
+    type Error = ();
+
+    /// Looks up an error by its numeric, for example `401` for `NoSuchNick`.
+    fn try_from(value: u16) -> Result<Self, Self::Error> {
+        match value {
+            431 => Ok(ErrorType::NoNickNameGiven),
+            432 => Ok(ErrorType::ErroneousNickname),
+            436 => Ok(ErrorType::NickCollision),
+            410 => Ok(ErrorType::InvalidCapCmd),
+            411 => Ok(ErrorType::NoRecipient),
+            412 => Ok(ErrorType::NoTextToSend),
+            409 => Ok(ErrorType::NoOrigin),
+            421 => Ok(ErrorType::UnknownCommand),
+            461 => Ok(ErrorType::NeedMoreParams),
+            401 => Ok(ErrorType::NoSuchNick),
+            403 => Ok(ErrorType::NoSuchChannel),
+            404 => Ok(ErrorType::CannotSendToChan),
+            441 => Ok(ErrorType::UserNotInChannel),
+            442 => Ok(ErrorType::NotOnChannel),
+            472 => Ok(ErrorType::UnknownMode),
+            482 => Ok(ErrorType::ChanOPrivsNeeded),
+            451 => Ok(ErrorType::NotRegistered),
+            462 => Ok(ErrorType::AlreadyRegistered),
+            468 => Ok(ErrorType::InvalidUsername),
+            479 => Ok(ErrorType::BadChanName),
+            464 => Ok(ErrorType::PasswdMismatch),
+            465 => Ok(ErrorType::YoureBannedCreep),
+            481 => Ok(ErrorType::NoPrivileges),
+            502 => Ok(ErrorType::UsersDontMatch),
+            _ => Err(()),
         }
     }
 }
 
-/// Given an IRC command, this will split it up into component parts.
-/// Particularly, the prefix (optionally), then all space-separated args,
-/// then (optionally) the final argument.
-fn split_command(cmd: &str) -> Vec<&str> {
-    let stripped = cmd.strip_suffix("\r\n").unwrap_or(cmd);
+/// Splits the message tags (without their `@`) and prefix (without its `:`) off the front of a message.
+fn split_tags_and_prefix(message: &str) -> (Option<&str>, Option<&str>, &str) {
+    let (tags, rest) = match message.strip_prefix('@') {
+        Some(tagged) => match tagged.split_once(' ') {
+            Some((tags, rest)) => (Some(tags), rest.trim_start_matches(' ')),
+            None => (Some(tagged), ""),
+        },
+        None => (None, message),
+    };
+    let (prefix, rest) = match rest.strip_prefix(':') {
+        Some(prefixed) => match prefixed.split_once(' ') {
+            Some((prefix, rest)) => (Some(prefix), rest.trim_start_matches(' ')),
+            None => (Some(prefixed), ""),
+        },
+        None => (None, rest),
+    };
+    (tags, prefix, rest)
+}
+
+/// A message borrowed from the line it was read from, so parsing it allocates nothing.
+/// The owned [`Message`] is only built when asked for, with [`MessageRef::to_message`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MessageRef<'a> {
+    /// The message tags, without their `@` and still escaped.
+    pub tags: Option<&'a str>,
+    /// The prefix, without its `:`.
+    pub prefix: Option<&'a str>,
+    pub command: &'a str,
+    /// The space-separated args after the command.
+    middle: &'a str,
+    /// The final argument, which starts at the first ` :` and is kept exactly as sent.
+    trailing: Option<&'a str>,
+    /// Everything after the command, exactly as sent.
+    pub args: &'a str,
+}
+
+impl<'a> MessageRef<'a> {
+    /// Splits a line into its parts, without checking that the command is one we know.
+    pub fn parse(line: &'a str) -> Self {
+        let (tags, prefix, rest) = split_tags_and_prefix(line);
+        let stripped = rest.strip_suffix("\r\n").unwrap_or(rest);
+        let (middle, trailing) = match stripped.split_once(" :") {
+            Some((before, after)) => (before, Some(after)),
+            None => (stripped, None),
+        };
+        let (command, middle) = middle.split_once(' ').unwrap_or((middle, ""));
+        let args = stripped.split_once(' ').map_or("", |(_, args)| args);
+        MessageRef {
+            tags,
+            prefix,
+            command,
+            middle,
+            trailing,
+            args,
+        }
+    }
 
-    match stripped.split_once(':') {
-        Some((before, after)) => {
-            let mut cmd_vec = before.split(' ').collect::<Vec<_>>();
+    /// The args after the command, in order.
+    pub fn params(&self) -> impl Iterator<Item = &'a str> + Clone + 'a {
+        self.middle
+            .split(' ')
+            .filter(|arg| !arg.is_empty())
+            .chain(self.trailing)
+    }
 
-            if cmd_vec.last() == Some(&"") {
-                let _ = cmd_vec.pop();
-            }
+    /// The arg at `index`, counting from 0 after the command.
+    pub fn param(&self, index: usize) -> Option<&'a str> {
+        self.params().nth(index)
+    }
+
+    /// Builds the owned message, checking its arguments.
+    /// An error names the command, or the argument, that it is about.
+    pub fn to_message(&self) -> Result<Message, CommandError> {
+        self.build_message().map_err(|error| match error {
+            ErrorType::NeedMoreParams | ErrorType::UnknownCommand => error.about([self.command]),
+            ErrorType::ErroneousNickname
+            | ErrorType::NoSuchChannel
+            | ErrorType::InvalidUsername => error.about(self.param(0)),
+            error => error.into(),
+        })
+    }
+
+    fn build_message(&self) -> Result<Message, ErrorType> {
+        // Commands are case-insensitive.
+        match self.command.to_ascii_uppercase().as_str() {
+            "PING" => Ok(Message::Ping(
+                self.params().last().ok_or(ErrorType::NoOrigin)?.to_string(),
+            )),
+            "PONG" => Ok(Message::Pong(
+                self.params().last().ok_or(ErrorType::NoOrigin)?.to_string(),
+            )),
+            "PRIVMSG" => Ok(Message::PrivMsg(PrivMsg::try_from(self)?)),
+            "NOTICE" => Ok(Message::Notice(NoticeMsg::try_from(self)?)),
+            "USER" => Ok(Message::User(UserMsg::try_from(self)?)),
+            "NICK" => Ok(Message::Nick(NickMsg::try_from(self)?)),
+            "JOIN" => Ok(Message::Join(JoinMsg::try_from(self)?)),
+            "PART" => Ok(Message::Part(PartMsg::try_from(self)?)),
+            "TOPIC" => Ok(Message::Topic(TopicMsg::try_from(self)?)),
+            "MODE" => Ok(Message::Mode(ModeMsg::try_from(self)?)),
+            "AWAY" => Ok(Message::Away(AwayMsg::try_from(self)?)),
+            "CAP" => Ok(Message::Cap(CapMsg::try_from(self)?)),
+            "NAMES" => Ok(Message::Names(NamesMsg::try_from(self)?)),
+            "WHO" => Ok(Message::Who(WhoMsg::try_from(self)?)),
+            "CHATHISTORY" => Ok(Message::ChatHistory(ChatHistoryMsg::try_from(self)?)),
+            "QUIT" => Ok(Message::Quit(QuitMsg::try_from(self)?)),
+            "OPER" => Ok(Message::Oper(OperMsg::try_from(self)?)),
+            "KLINE" => Ok(Message::KLine(BanMsg::try_from(self)?)),
+            "DLINE" => Ok(Message::DLine(BanMsg::try_from(self)?)),
+            "UNKLINE" => Ok(Message::UnKLine(
+                self.param(0).ok_or(ErrorType::NeedMoreParams)?.to_string(),
+            )),
+            "UNDLINE" => Ok(Message::UnDLine(
+                self.param(0).ok_or(ErrorType::NeedMoreParams)?.to_string(),
+            )),
+            "STATS" => Ok(Message::Stats(
+                self.param(0).ok_or(ErrorType::NeedMoreParams)?.to_string(),
+            )),
+            // `WHOIS <server> <nick>` asks a particular server, which is always this one.
+            "WHOIS" => Ok(Message::Whois(Nick(
+                self.params()
+                    .last()
+                    .ok_or(ErrorType::NoNickNameGiven)?
+                    .to_string(),
+            ))),
+            _ => Err(ErrorType::UnknownCommand),
+        }
+    }
+}
 
-            cmd_vec.push(after.trim_start());
-            cmd_vec
+/// Writes a command and its arguments as one line, ending in `\r\n`.
+/// A `trailing` argument is always written after a `:`; without one, the last of `args`
+/// only gets a `:` when it would otherwise be misread (it is empty, has a space, or starts with `:`).
+pub(crate) fn write_command(
+    fmt: &mut std::fmt::Formatter<'_>,
+    command: &str,
+    args: &[&str],
+    trailing: Option<&str>,
+) -> Result<(), std::fmt::Error> {
+    write!(fmt, "{command}")?;
+    match (args.split_last(), trailing) {
+        (Some((last, middle)), None) => {
+            for arg in middle {
+                write!(fmt, " {arg}")?;
+            }
+            if last.is_empty() || last.contains(' ') || last.starts_with(':') {
+                write!(fmt, " :{last}")?;
+            } else {
+                write!(fmt, " {last}")?;
+            }
+        }
+        (_, trailing) => {
+            for arg in args {
+                write!(fmt, " {arg}")?;
+            }
+            if let Some(trailing) = trailing {
+                write!(fmt, " :{trailing}")?;
+            }
         }
-        None => stripped.split(' ').collect::<Vec<_>>(),
     }
+    write!(fmt, "\r\n")
+}
+
+/// Writes a KLINE or DLINE, with its duration first if it has one.
+fn write_ban(
+    fmt: &mut std::fmt::Formatter<'_>,
+    command: &str,
+    ban: &BanMsg,
+) -> Result<(), std::fmt::Error> {
+    let minutes = ban.minutes.map(|minutes| minutes.to_string());
+    let args = minutes
+        .iter()
+        .map(String::as_str)
+        .chain([ban.mask.as_str()])
+        .collect::<Vec<_>>();
+    write_command(fmt, command, &args, ban.reason.as_deref())
 }
 
 /// A person or channel to whom a command is addressed.
@@ -116,19 +637,220 @@ impl std::fmt::Display for Target {
     }
 }
 
-/// A nickname.
+/// Which characters count as the same letter in different case, when comparing nicknames and channels.
+/// The server's choice is advertised to clients as `CASEMAPPING` in `RPL_ISUPPORT`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum CaseMapping {
+    /// Only `A-Z` and `a-z`.
+    Ascii,
+    /// As `Ascii`, and `[]\~` are the uppercase of `{}|^`.
+    #[default]
+    Rfc1459,
+    /// As `Rfc1459`, except for `~` and `^`.
+    StrictRfc1459,
+}
+
+impl CaseMapping {
+    /// The lowercase form of `value`.
+    pub fn fold(self, value: &str) -> String {
+        value
+            .chars()
+            .map(|c| match (self, c) {
+                (_, 'A'..='Z') => c.to_ascii_lowercase(),
+                (CaseMapping::Ascii, _) => c,
+                (_, '[') => '{',
+                (_, ']') => '}',
+                (_, '\\') => '|',
+                (CaseMapping::Rfc1459, '~') => '^',
+                _ => c,
+            })
+            .collect()
+    }
+}
+
+impl std::fmt::Display for CaseMapping {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            CaseMapping::Ascii => write!(fmt, "ascii"),
+            CaseMapping::Rfc1459 => write!(fmt, "rfc1459"),
+            CaseMapping::StrictRfc1459 => write!(fmt, "strict-rfc1459"),
+        }
+    }
+}
+
+impl FromStr for CaseMapping {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        [
+            CaseMapping::Ascii,
+            CaseMapping::Rfc1459,
+            CaseMapping::StrictRfc1459,
+        ]
+        .into_iter()
+        .find(|mapping| mapping.to_string() == value)
+        .ok_or_else(|| format!("unknown casemapping {value}"))
+    }
+}
+
+/// Characters besides letters and digits that nicknames and channel names may contain.
+const SPECIAL: &str = "[]\\`_^{|}~";
+
+/// Which characters nicknames and channel names may contain.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NamePolicy {
+    /// The ASCII characters RFC 2812 allows.
+    #[default]
+    Ascii,
+    /// Letters and digits of any script, normalized to NFC.
+    /// Names mixing scripts in ways that could pass for another name are refused.
+    Utf8,
+}
+
+impl NamePolicy {
+    /// The form a name is kept in, if it only uses characters this policy allows.
+    pub fn normalize(self, value: String) -> Option<String> {
+        match self {
+            NamePolicy::Ascii => value.is_ascii().then_some(value),
+            NamePolicy::Utf8 => {
+                let value = value.nfc().collect::<String>();
+                // Only letters and digits count towards the scripts a name mixes.
+                let letters = value
+                    .chars()
+                    .filter(|c| c.is_alphanumeric())
+                    .collect::<String>();
+                let allowed = value
+                    .chars()
+                    .all(|c| c.is_ascii() || c.identifier_allowed())
+                    && letters
+                        .as_str()
+                        .check_restriction_level(RestrictionLevel::HighlyRestrictive);
+                allowed.then_some(value)
+            }
+        }
+    }
+}
+
+impl std::fmt::Display for NamePolicy {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            NamePolicy::Ascii => write!(fmt, "ascii"),
+            NamePolicy::Utf8 => write!(fmt, "utf8"),
+        }
+    }
+}
+
+impl FromStr for NamePolicy {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        [NamePolicy::Ascii, NamePolicy::Utf8]
+            .into_iter()
+            .find(|policy| policy.to_string() == value)
+            .ok_or_else(|| format!("unknown name policy {value}"))
+    }
+}
+
+/// How a server compares and checks nicknames and channel names.
+/// Each server keeps its own, so servers in the same process can differ.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NameRules {
+    pub casemapping: CaseMapping,
+    pub policy: NamePolicy,
+    /// The longest nickname allowed, in characters, advertised as `NICKLEN`.
+    pub nick_len: usize,
+    /// The longest channel name allowed, in characters and including the `#`, advertised as `CHANNELLEN`.
+    pub channel_len: usize,
+}
+
+impl Default for NameRules {
+    fn default() -> Self {
+        NameRules {
+            casemapping: CaseMapping::Rfc1459,
+            policy: NamePolicy::Ascii,
+            nick_len: 9,
+            channel_len: 200,
+        }
+    }
+}
+
+impl NameRules {
+    /// The lowercase form of `value`, which names that are the same share.
+    /// UTF-8 names are also normalized, and letters outside ASCII lowercased.
+    pub fn fold(&self, value: &str) -> String {
+        let folded = self.casemapping.fold(value);
+        match self.policy {
+            NamePolicy::Ascii => folded,
+            NamePolicy::Utf8 => folded.nfc().flat_map(char::to_lowercase).collect(),
+        }
+    }
+
+    /// Whether two nicknames or channel names are the same.
+    pub fn same(&self, a: &str, b: &str) -> bool {
+        self.fold(a) == self.fold(b)
+    }
+
+    /// The form of `value` that names which look alike share,
+    /// for example `pay` and `рау` written in Cyrillic.
+    /// See the confusable detection of Unicode Technical Standard #39.
+    pub fn skeleton(&self, value: &str) -> String {
+        match self.policy {
+            NamePolicy::Ascii => self.fold(value),
+            NamePolicy::Utf8 => {
+                let skeleton = unicode_security::skeleton(&self.fold(value)).collect::<String>();
+                self.fold(&skeleton)
+            }
+        }
+    }
+
+    /// Whether two nicknames or channel names could be mistaken for each other.
+    pub fn confusable(&self, a: &str, b: &str) -> bool {
+        self.skeleton(a) == self.skeleton(b)
+    }
+
+    /// Whether `value` matches the wildcard `mask`, ignoring case.
+    pub fn mask_matches(&self, mask: &str, value: &str) -> bool {
+        mask_matches(&self.fold(mask), &self.fold(value))
+    }
+
+    /// The nickname as it is kept, if the policy allows its characters and it isn't too long.
+    pub fn nick(&self, nick: &Nick) -> Result<Nick, ErrorType> {
+        self.policy
+            .normalize(nick.0.clone())
+            .filter(|nick| nick.chars().count() <= self.nick_len)
+            .map(Nick)
+            .ok_or(ErrorType::ErroneousNickname)
+    }
+
+    /// The channel name as it is kept, if the policy allows its characters and it isn't too long.
+    pub fn channel(&self, channel: &Channel) -> Result<Channel, ErrorType> {
+        self.policy
+            .normalize(channel.0.clone())
+            .filter(|channel| channel.chars().count() <= self.channel_len)
+            .map(Channel)
+            .ok_or(ErrorType::NoSuchChannel)
+    }
+}
+
+/// A nickname, kept in the casing it was chosen with.
+/// Comparing two compares them exactly; the server compares them with its [`NameRules`].
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Nick(pub String);
 
 impl TryFrom<String> for Nick {
     type Error = ErrorType;
 
+    /// Nicknames start with a letter or a special character, and may also contain digits and `-`.
+    /// Which letters are allowed, and how long a nickname may be, is up to [`NameRules::nick`].
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        let value = value;
-        if (1..10).contains(&value.len())
-            && value.is_ascii()
-            && value.chars().next().unwrap_or('!').is_alphabetic()
-            && value.chars().all(char::is_alphanumeric)
+        let value = value.nfc().collect::<String>();
+        if value
+            .chars()
+            .next()
+            .is_some_and(|c| c.is_alphabetic() || SPECIAL.contains(c))
+            && value
+                .chars()
+                .all(|c| c.is_alphanumeric() || c == '-' || SPECIAL.contains(c))
         {
             Ok(Nick(value))
         } else {
@@ -144,17 +866,24 @@ impl std::fmt::Display for Nick {
 }
 
 /// An IRC channel.
+/// Like nicknames, channel names are compared exactly, and by the server with its [`NameRules`].
 #[derive(Debug, Clone, PartialEq, Eq, Hash)]
 pub struct Channel(pub String);
 
 impl TryFrom<String> for Channel {
     type Error = ErrorType;
 
+    /// Channel names start with `#`, followed by an RFC 2812 `chanstring`: anything but spaces,
+    /// commas, colons and control characters. Slashes aren't allowed either, so that a channel
+    /// name never looks like a path.
+    /// Which letters are allowed, and how long a name may be, is up to [`NameRules::channel`].
     fn try_from(value: String) -> Result<Self, Self::Error> {
-        if (1..200).contains(&value.len())
-            && value.chars().next().unwrap_or('!') == '#'
-            && value.is_ascii()
-            && value[1..].chars().all(char::is_alphanumeric)
+        let value = value.nfc().collect::<String>();
+        if value.chars().count() >= 2
+            && value.starts_with('#')
+            && value
+                .chars()
+                .all(|c| !c.is_whitespace() && !c.is_control() && !",:/".contains(c))
         {
             Ok(Channel(value))
         } else {
@@ -169,6 +898,86 @@ impl std::fmt::Display for Channel {
     }
 }
 
+/// Where a message comes from: a server, or a user as `nick!username@host`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Prefix {
+    Server(String),
+    User {
+        nick: Nick,
+        username: Option<String>,
+        host: Option<String>,
+    },
+}
+
+impl Prefix {
+    /// The prefix of messages sent by the server called `name`.
+    pub fn server(name: &str) -> Self {
+        Prefix::Server(name.to_string())
+    }
+
+    /// Parses a prefix without its leading `:`, sent by or through the server called `server_name`.
+    pub fn parse(value: &str, server_name: &str) -> Self {
+        if value == server_name {
+            return Prefix::server(server_name);
+        }
+        Prefix::from(value)
+    }
+
+    /// The server name, or the user's nick.
+    pub fn name(&self) -> &str {
+        match self {
+            Prefix::Server(name) => name,
+            Prefix::User { nick, .. } => &nick.0,
+        }
+    }
+}
+
+impl From<&str> for Prefix {
+    /// Parses a prefix without its leading `:`.
+    /// Anything with a `.` and no `!` or `@` is taken to be a server, as nicknames cannot contain dots.
+    /// Server names without a dot are only told apart by [`Prefix::parse`].
+    fn from(value: &str) -> Self {
+        if value.contains('.') && !value.contains(['!', '@']) {
+            return Prefix::Server(value.to_string());
+        }
+        let (rest, host) = match value.split_once('@') {
+            Some((rest, host)) => (rest, Some(host.to_string())),
+            None => (value, None),
+        };
+        let (nick, username) = match rest.split_once('!') {
+            Some((nick, username)) => (nick, Some(username.to_string())),
+            None => (rest, None),
+        };
+        Prefix::User {
+            nick: Nick(nick.to_string()),
+            username,
+            host,
+        }
+    }
+}
+
+impl std::fmt::Display for Prefix {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Prefix::Server(name) => write!(fmt, "{name}"),
+            Prefix::User {
+                nick,
+                username,
+                host,
+            } => {
+                write!(fmt, "{nick}")?;
+                if let Some(username) = username {
+                    write!(fmt, "!{username}")?;
+                }
+                if let Some(host) = host {
+                    write!(fmt, "@{host}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
 /// A message to set the nickname.
 /// For example: `NICK tfpk\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -176,15 +985,14 @@ pub struct NickMsg {
     pub nick: Nick,
 }
 
-impl TryFrom<Vec<String>> for NickMsg {
+impl TryFrom<&MessageRef<'_>> for NickMsg {
     type Error = ErrorType;
 
-    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+    fn try_from(value: &MessageRef<'_>) -> Result<Self, Self::Error> {
         value
-            .into_iter()
-            .nth(1)
+            .param(0)
             .ok_or(ErrorType::NoNickNameGiven)
-            .and_then(|value| Nick::try_from(value))
+            .and_then(|nick| Nick::try_from(nick.to_string()))
             .map(|nick| NickMsg { nick })
     }
 }
@@ -196,15 +1004,14 @@ pub struct JoinMsg {
     pub channel: Channel,
 }
 
-impl TryFrom<Vec<String>> for JoinMsg {
+impl TryFrom<&MessageRef<'_>> for JoinMsg {
     type Error = ErrorType;
 
-    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+    fn try_from(value: &MessageRef<'_>) -> Result<Self, Self::Error> {
         value
-            .into_iter()
-            .nth(1)
+            .param(0)
             .ok_or(ErrorType::NeedMoreParams)
-            .and_then(|value| Channel::try_from(value))
+            .and_then(|channel| Channel::try_from(channel.to_string()))
             .map(|channel| JoinMsg { channel })
     }
 }
@@ -216,78 +1023,416 @@ pub struct PartMsg {
     pub channel: Channel,
 }
 
-impl TryFrom<Vec<String>> for PartMsg {
+impl TryFrom<&MessageRef<'_>> for PartMsg {
     type Error = ErrorType;
 
-    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
+    fn try_from(value: &MessageRef<'_>) -> Result<Self, Self::Error> {
         value
-            .into_iter()
-            .nth(1)
+            .param(0)
             .ok_or(ErrorType::NeedMoreParams)
-            .and_then(|value| Channel::try_from(value))
+            .and_then(|channel| Channel::try_from(channel.to_string()))
             .map(|channel| PartMsg { channel })
     }
 }
 
-/// A message to register a new user.
-// For example: `USER ignored ignored ignored :Thomas Kunc\r\n`
+/// A message to view or change the topic of a channel.
+/// For example: `TOPIC #channel :Welcome!\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct UserMsg {
-    pub real_name: String,
+pub struct TopicMsg {
+    pub channel: Channel,
+    pub topic: Option<String>,
 }
 
-impl TryFrom<Vec<String>> for UserMsg {
+impl TryFrom<&MessageRef<'_>> for TopicMsg {
     type Error = ErrorType;
 
-    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
-        value
-            .into_iter()
-            .nth(4)
-            .ok_or(ErrorType::NeedMoreParams)
-            .map(|real_name| UserMsg { real_name })
+    fn try_from(value: &MessageRef<'_>) -> Result<Self, Self::Error> {
+        let mut params = value.params();
+        Ok(TopicMsg {
+            channel: params
+                .next()
+                .ok_or(ErrorType::NeedMoreParams)
+                .and_then(|channel| Channel::try_from(channel.to_string()))?,
+            topic: params.next().map(str::to_string),
+        })
     }
 }
 
-/// A private message.
-/// For example: `PRIVMSG tom :Hi Tom, how are you?\r\n`
+/// A message to view or change modes.
+/// For example: `MODE #channel +ov tom tom\r\n`
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct PrivMsg {
+pub struct ModeMsg {
     pub target: Target,
-    pub message: String,
+    pub modes: Option<String>,
+    pub args: Vec<String>,
 }
 
-impl TryFrom<Vec<String>> for PrivMsg {
+impl TryFrom<&MessageRef<'_>> for ModeMsg {
     type Error = ErrorType;
 
-    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
-        Ok(PrivMsg {
-            target: Target::from(value.get(1).ok_or(ErrorType::NoRecipient)?.to_string()),
-            // skip(2) here skips the PRIVMSG instruction and target.
-            message: value
-                .into_iter()
-                .skip(2)
-                .last()
-                .ok_or(ErrorType::NoTextToSend)?,
+    fn try_from(value: &MessageRef<'_>) -> Result<Self, Self::Error> {
+        let mut params = value.params();
+        Ok(ModeMsg {
+            target: Target::from(params.next().ok_or(ErrorType::NeedMoreParams)?.to_string()),
+            modes: params.next().map(str::to_string),
+            args: params.map(str::to_string).collect(),
         })
     }
 }
 
-/// The last message a user will send before leaving.
-/// For example: `QUIT :Leaving now!`
+/// A single mode being set or unset, for example the `+o tom` in `MODE #channel +o tom`.
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct QuitMsg {
-    pub message: Option<String>,
+pub struct ModeChange {
+    pub add: bool,
+    pub mode: char,
+    pub arg: Option<String>,
 }
 
-impl TryFrom<Vec<String>> for QuitMsg {
-    type Error = ErrorType;
+impl ModeMsg {
+    /// Modes which take a nickname as an argument.
+    pub const MEMBER_MODES: [char; 2] = ['o', 'v'];
 
-    fn try_from(value: Vec<String>) -> Result<Self, Self::Error> {
-        Ok(QuitMsg {
-            // skip(1) here skips the QUIT instruction.
-            message: value.into_iter().skip(1).last(),
-        })
-    }
+    /// Splits the requested channel modes into individual changes, pairing up their arguments.
+    /// An unknown mode character, or a missing argument, is an error.
+    pub fn changes(&self) -> Result<Vec<ModeChange>, CommandError> {
+        let mut args = self.args.iter();
+        let mut add = true;
+        let mut changes = vec![];
+        for mode in self.modes.iter().flat_map(|m| m.chars()) {
+            match mode {
+                '+' => add = true,
+                '-' => add = false,
+                m if ModeMsg::MEMBER_MODES.contains(&m) => changes.push(ModeChange {
+                    add,
+                    mode,
+                    arg: Some(
+                        args.next()
+                            .ok_or_else(|| ErrorType::NeedMoreParams.about(["MODE"]))?
+                            .to_string(),
+                    ),
+                }),
+                m if ChannelInfo::FLAG_MODES.contains(&m) => changes.push(ModeChange {
+                    add,
+                    mode,
+                    arg: None,
+                }),
+                m => return Err(ErrorType::UnknownMode.about([m])),
+            }
+        }
+        Ok(changes)
+    }
+}
+
+/// A message to register a new user.
+// For example: `USER ignored ignored ignored :Thomas Kunc\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UserMsg {
+    pub username: String,
+    pub real_name: String,
+}
+
+impl TryFrom<&MessageRef<'_>> for UserMsg {
+    type Error = ErrorType;
+
+    /// The username may not contain `@`.
+    /// The second and third fields are ignored, whether they are RFC 2812's mode and unused
+    /// field or RFC 1459's hostname and servername.
+    fn try_from(value: &MessageRef<'_>) -> Result<Self, Self::Error> {
+        let username = value.param(0).ok_or(ErrorType::NeedMoreParams)?;
+        let real_name = value.param(3).ok_or(ErrorType::NeedMoreParams)?;
+        if username.contains(['@', '\0']) {
+            return Err(ErrorType::InvalidUsername);
+        }
+        Ok(UserMsg {
+            username: username.to_string(),
+            real_name: real_name.to_string(),
+        })
+    }
+}
+
+/// A message to set or clear an away message.
+/// For example: `AWAY :Gone to lunch\r\n`, or `AWAY\r\n` to come back.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AwayMsg {
+    pub message: Option<String>,
+}
+
+impl TryFrom<&MessageRef<'_>> for AwayMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: &MessageRef<'_>) -> Result<Self, Self::Error> {
+        Ok(AwayMsg {
+            // An empty message means "back".
+            message: value
+                .params()
+                .last()
+                .filter(|m| !m.is_empty())
+                .map(str::to_string),
+        })
+    }
+}
+
+/// An IRCv3 capability the server supports.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Capability {
+    AccountNotify,
+    AwayNotify,
+    ExtendedJoin,
+    ChgHost,
+    MultiPrefix,
+    UserhostInNames,
+    Batch,
+    ServerTime,
+    MessageTags,
+    ChatHistory,
+    LabeledResponse,
+}
+
+impl Capability {
+    /// Every capability, in the order they are advertised.
+    pub const ALL: [Capability; 11] = [
+        Capability::AccountNotify,
+        Capability::AwayNotify,
+        Capability::ExtendedJoin,
+        Capability::ChgHost,
+        Capability::MultiPrefix,
+        Capability::UserhostInNames,
+        Capability::Batch,
+        Capability::ServerTime,
+        Capability::MessageTags,
+        Capability::ChatHistory,
+        Capability::LabeledResponse,
+    ];
+}
+
+impl TryFrom<&str> for Capability {
+    type Error = ();
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        Capability::ALL
+            .into_iter()
+            .find(|cap| cap.to_string() == value)
+            .ok_or(())
+    }
+}
+
+impl std::fmt::Display for Capability {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Capability::AccountNotify => write!(fmt, "account-notify"),
+            Capability::AwayNotify => write!(fmt, "away-notify"),
+            Capability::ExtendedJoin => write!(fmt, "extended-join"),
+            Capability::ChgHost => write!(fmt, "chghost"),
+            Capability::MultiPrefix => write!(fmt, "multi-prefix"),
+            Capability::UserhostInNames => write!(fmt, "userhost-in-names"),
+            Capability::Batch => write!(fmt, "batch"),
+            Capability::ServerTime => write!(fmt, "server-time"),
+            Capability::MessageTags => write!(fmt, "message-tags"),
+            Capability::ChatHistory => write!(fmt, "draft/chathistory"),
+            Capability::LabeledResponse => write!(fmt, "labeled-response"),
+        }
+    }
+}
+
+/// A message to list who is in a channel, or in every channel if none is given.
+/// For example: `NAMES #channel\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamesMsg {
+    pub channel: Option<Channel>,
+}
+
+impl TryFrom<&MessageRef<'_>> for NamesMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: &MessageRef<'_>) -> Result<Self, Self::Error> {
+        Ok(NamesMsg {
+            channel: value
+                .param(0)
+                .map(|channel| Channel::try_from(channel.to_string()))
+                .transpose()?,
+        })
+    }
+}
+
+/// A message to list users matching a mask, or the members of a channel.
+/// For example: `WHO #channel\r\n` or `WHO tom*\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoMsg {
+    pub mask: String,
+}
+
+impl TryFrom<&MessageRef<'_>> for WhoMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: &MessageRef<'_>) -> Result<Self, Self::Error> {
+        value
+            .param(0)
+            .ok_or(ErrorType::NeedMoreParams)
+            .map(|mask| WhoMsg {
+                mask: mask.to_string(),
+            })
+    }
+}
+
+/// A request for stored messages, left for the history module to interpret.
+/// For example: `CHATHISTORY LATEST #channel * 50\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatHistoryMsg {
+    pub subcommand: String,
+    pub args: Vec<String>,
+}
+
+impl TryFrom<&MessageRef<'_>> for ChatHistoryMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: &MessageRef<'_>) -> Result<Self, Self::Error> {
+        let mut params = value.params();
+        Ok(ChatHistoryMsg {
+            subcommand: params
+                .next()
+                .ok_or(ErrorType::NeedMoreParams)?
+                .to_ascii_uppercase(),
+            args: params.map(str::to_string).collect(),
+        })
+    }
+}
+
+/// A capability negotiation message.
+/// For example: `CAP REQ :away-notify extended-join\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapMsg {
+    pub subcommand: String,
+    pub args: Option<String>,
+}
+
+impl TryFrom<&MessageRef<'_>> for CapMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: &MessageRef<'_>) -> Result<Self, Self::Error> {
+        let mut params = value.params();
+        Ok(CapMsg {
+            subcommand: params
+                .next()
+                .ok_or(ErrorType::NeedMoreParams)?
+                .to_ascii_uppercase(),
+            args: params.next().map(str::to_string),
+        })
+    }
+}
+
+/// A private message.
+/// For example: `PRIVMSG tom :Hi Tom, how are you?\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PrivMsg {
+    pub target: Target,
+    pub message: String,
+}
+
+impl TryFrom<&MessageRef<'_>> for PrivMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: &MessageRef<'_>) -> Result<Self, Self::Error> {
+        Ok(PrivMsg {
+            target: Target::from(value.param(0).ok_or(ErrorType::NoRecipient)?.to_string()),
+            // skip(1) here skips the target.
+            message: value
+                .params()
+                .skip(1)
+                .last()
+                .ok_or(ErrorType::NoTextToSend)?
+                .to_string(),
+        })
+    }
+}
+
+/// A notice, which must never be automatically replied to.
+/// For example: `NOTICE tom :This nickname is registered\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoticeMsg {
+    pub target: Target,
+    pub message: String,
+}
+
+impl TryFrom<&MessageRef<'_>> for NoticeMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: &MessageRef<'_>) -> Result<Self, Self::Error> {
+        let PrivMsg { target, message } = PrivMsg::try_from(value)?;
+        Ok(NoticeMsg { target, message })
+    }
+}
+
+/// The last message a user will send before leaving.
+/// For example: `QUIT :Leaving now!`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QuitMsg {
+    pub message: Option<String>,
+}
+
+impl TryFrom<&MessageRef<'_>> for QuitMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: &MessageRef<'_>) -> Result<Self, Self::Error> {
+        Ok(QuitMsg {
+            message: value.params().last().map(str::to_string),
+        })
+    }
+}
+
+/// A request to become an IRC operator.
+/// For example: `OPER alice hunter2\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperMsg {
+    pub name: String,
+    pub password: String,
+}
+
+impl TryFrom<&MessageRef<'_>> for OperMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: &MessageRef<'_>) -> Result<Self, Self::Error> {
+        match (value.param(0), value.param(1)) {
+            (Some(name), Some(password)) => Ok(OperMsg {
+                name: name.to_string(),
+                password: password.to_string(),
+            }),
+            _ => Err(ErrorType::NeedMoreParams),
+        }
+    }
+}
+
+/// An IRC operator banning a mask from the server with KLINE or DLINE, for some minutes or for good.
+/// For example: `KLINE 60 *@spam.example :Spamming\r\n`, or `DLINE 10.0.0.0/8\r\n`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BanMsg {
+    pub minutes: Option<u64>,
+    pub mask: String,
+    pub reason: Option<String>,
+}
+
+impl TryFrom<&MessageRef<'_>> for BanMsg {
+    type Error = ErrorType;
+
+    fn try_from(value: &MessageRef<'_>) -> Result<Self, Self::Error> {
+        let params = value.params().collect::<Vec<_>>();
+        // A leading number is the duration, unless it is the only argument.
+        let (minutes, rest) = match params[..] {
+            [first, ref rest @ ..]
+                if !rest.is_empty() && first.bytes().all(|b| b.is_ascii_digit()) =>
+            {
+                (first.parse().ok(), rest)
+            }
+            ref rest => (None, rest),
+        };
+        match rest {
+            [mask, reason @ ..] => Ok(BanMsg {
+                minutes,
+                mask: mask.to_string(),
+                reason: reason.first().map(|r| r.to_string()),
+            }),
+            [] => Err(ErrorType::NeedMoreParams),
+        }
+    }
 }
 
 /// A list of every possible message that can be sent.
@@ -296,10 +1441,81 @@ pub enum Message {
     Nick(NickMsg),
     User(UserMsg),
     PrivMsg(PrivMsg),
+    Notice(NoticeMsg),
     Ping(String),
+    Pong(String),
     Join(JoinMsg),
     Part(PartMsg),
+    Topic(TopicMsg),
+    Mode(ModeMsg),
+    Away(AwayMsg),
+    Cap(CapMsg),
+    Names(NamesMsg),
+    Who(WhoMsg),
+    ChatHistory(ChatHistoryMsg),
     Quit(QuitMsg),
+    Oper(OperMsg),
+    KLine(BanMsg),
+    DLine(BanMsg),
+    UnKLine(String),
+    UnDLine(String),
+    Stats(String),
+    Whois(Nick),
+}
+
+impl std::fmt::Display for Message {
+    /// Renders the message the way a client would send it.
+    /// Parsing the rendered line gives back an equal message, for any message the parser can produce.
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        match self {
+            Message::Nick(m) => write_command(fmt, "NICK", &[&m.nick.0], None),
+            Message::User(m) => {
+                write_command(fmt, "USER", &[&m.username, "0", "*"], Some(&m.real_name))
+            }
+            Message::PrivMsg(m) => {
+                write_command(fmt, "PRIVMSG", &[&m.target.to_string()], Some(&m.message))
+            }
+            Message::Notice(m) => {
+                write_command(fmt, "NOTICE", &[&m.target.to_string()], Some(&m.message))
+            }
+            Message::Ping(origin) => write_command(fmt, "PING", &[], Some(origin)),
+            Message::Pong(origin) => write_command(fmt, "PONG", &[], Some(origin)),
+            Message::Join(m) => write_command(fmt, "JOIN", &[&m.channel.0], None),
+            Message::Part(m) => write_command(fmt, "PART", &[&m.channel.0], None),
+            Message::Topic(m) => write_command(fmt, "TOPIC", &[&m.channel.0], m.topic.as_deref()),
+            Message::Mode(m) => {
+                let target = m.target.to_string();
+                let args = [target.as_str()]
+                    .into_iter()
+                    .chain(m.modes.as_deref())
+                    .chain(m.args.iter().map(String::as_str))
+                    .collect::<Vec<_>>();
+                write_command(fmt, "MODE", &args, None)
+            }
+            Message::Away(m) => write_command(fmt, "AWAY", &[], m.message.as_deref()),
+            Message::Cap(m) => write_command(fmt, "CAP", &[&m.subcommand], m.args.as_deref()),
+            Message::Names(m) => {
+                let channel = m.channel.iter().map(|c| c.0.as_str()).collect::<Vec<_>>();
+                write_command(fmt, "NAMES", &channel, None)
+            }
+            Message::Who(m) => write_command(fmt, "WHO", &[&m.mask], None),
+            Message::ChatHistory(m) => {
+                let args = [m.subcommand.as_str()]
+                    .into_iter()
+                    .chain(m.args.iter().map(String::as_str))
+                    .collect::<Vec<_>>();
+                write_command(fmt, "CHATHISTORY", &args, None)
+            }
+            Message::Quit(m) => write_command(fmt, "QUIT", &[], m.message.as_deref()),
+            Message::Oper(m) => write_command(fmt, "OPER", &[&m.name, &m.password], None),
+            Message::KLine(m) => write_ban(fmt, "KLINE", m),
+            Message::DLine(m) => write_ban(fmt, "DLINE", m),
+            Message::UnKLine(mask) => write_command(fmt, "UNKLINE", &[mask], None),
+            Message::UnDLine(mask) => write_command(fmt, "UNDLINE", &[mask], None),
+            Message::Stats(query) => write_command(fmt, "STATS", &[query], None),
+            Message::Whois(nick) => write_command(fmt, "WHOIS", &[&nick.0], None),
+        }
+    }
 }
 
 /// To parse a message, construct this struct.
@@ -307,45 +1523,30 @@ pub enum Message {
 pub struct UnparsedMessage<'a> {
     pub sender_nick: Nick,
     pub message: &'a str,
+    /// The name of the server the message was sent to, so a prefix naming it is known for one.
+    pub server_name: &'a str,
 }
 
 /// After parsing an `UnparsedMessage`, this struct will be created.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct ParsedMessage {
     pub sender_nick: Nick,
+    /// The prefix the client sent, if any. It is never trusted to say who sent the message.
+    pub prefix: Option<Prefix>,
     pub message: Message,
 }
 
 impl<'a> TryFrom<UnparsedMessage<'a>> for ParsedMessage {
-    type Error = ErrorType;
+    type Error = CommandError;
     fn try_from(value: UnparsedMessage<'a>) -> Result<Self, Self::Error> {
-        let command = split_command(value.message)
-            .into_iter()
-            .map(str::to_string)
-            .collect::<Vec<_>>();
-
-        let message = match command[0].as_str() {
-            "PING" => Ok(Message::Ping(
-                // Skip here ignores the "PING".
-                command
-                    .iter()
-                    .skip(1)
-                    .last()
-                    .ok_or(ErrorType::NoOrigin)?
-                    .to_string(),
-            )),
-            "PRIVMSG" => Ok(Message::PrivMsg(PrivMsg::try_from(command)?)),
-            "USER" => Ok(Message::User(UserMsg::try_from(command)?)),
-            "NICK" => Ok(Message::Nick(NickMsg::try_from(command)?)),
-            "JOIN" => Ok(Message::Join(JoinMsg::try_from(command)?)),
-            "PART" => Ok(Message::Part(PartMsg::try_from(command)?)),
-            "QUIT" => Ok(Message::Quit(QuitMsg::try_from(command)?)),
-            _ => Err(ErrorType::UnknownCommand),
-        }?;
-
+        // No command looks at client message tags yet, so they are dropped.
+        let message = MessageRef::parse(value.message);
         Ok(ParsedMessage {
             sender_nick: value.sender_nick,
-            message,
+            prefix: message
+                .prefix
+                .map(|prefix| Prefix::parse(prefix, value.server_name)),
+            message: message.to_message()?,
         })
     }
 }
@@ -353,25 +1554,216 @@ impl<'a> TryFrom<UnparsedMessage<'a>> for ParsedMessage {
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PrivReply {
     pub message: PrivMsg,
-    pub sender_nick: Nick,
+    pub sender: Prefix,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NoticeReply {
+    pub message: NoticeMsg,
+    pub sender: Prefix,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NickReply {
+    pub message: NickMsg,
+    pub sender: Prefix,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct JoinReply {
     pub message: JoinMsg,
-    pub sender_nick: Nick,
+    pub sender: Prefix,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct PartReply {
     pub message: PartMsg,
-    pub sender_nick: Nick,
+    pub sender: Prefix,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicReply {
+    pub message: TopicMsg,
+    pub sender: Prefix,
+}
+
+/// The topic of a channel, as sent to a single user (`RPL_TOPIC` or `RPL_NOTOPIC`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TopicIsReply {
+    pub target_nick: Nick,
+    pub channel: Channel,
+    pub topic: Option<String>,
+}
+
+/// A user has set or cleared their away message, sent to those with `away-notify`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AwayReply {
+    pub message: AwayMsg,
+    pub sender: Prefix,
+}
+
+/// Confirms a change of away status to the user who sent `AWAY` (`RPL_NOWAWAY` or `RPL_UNAWAY`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AwayStatusReply {
+    pub target_nick: Nick,
+    pub away: bool,
+}
+
+/// Tells a user that the person they messaged is away (`RPL_AWAY`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IsAwayReply {
+    pub target_nick: Nick,
+    pub away_nick: Nick,
+    pub message: String,
+}
+
+/// A user has logged in to or out of an account, sent to those with `account-notify`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AccountReply {
+    pub account: Option<String>,
+    pub sender: Prefix,
+}
+
+/// A user's username or host has changed, sent to those with `chghost`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChgHostReply {
+    pub username: String,
+    pub host: String,
+    pub sender: Prefix,
+}
+
+/// A JOIN sent to those with `extended-join`, including the joining user's account and real name.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExtendedJoinReply {
+    pub message: JoinMsg,
+    pub account: Option<String>,
+    pub real_name: String,
+    pub sender: Prefix,
+}
+
+/// A reply to `CAP`, for example `:iris-server CAP tom ACK :away-notify`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapReply {
+    /// The user's nick, or `*` if they haven't chosen one yet.
+    pub target: String,
+    pub subcommand: String,
+    pub caps: String,
+}
+
+/// Some of the members of a channel (`RPL_NAMREPLY`), each with their status prefixes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct NamesReply {
+    pub target_nick: Nick,
+    pub channel: Channel,
+    pub secret: bool,
+    pub names: Vec<String>,
+}
+
+/// The end of a NAMES listing (`RPL_ENDOFNAMES`), for a channel or `*`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndOfNamesReply {
+    pub target_nick: Nick,
+    pub channel: String,
+}
+
+/// A single user matching a WHO query (`RPL_WHOREPLY`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WhoReply {
+    pub target_nick: Nick,
+    /// The channel the user was found through, if any.
+    pub channel: Option<Channel>,
+    pub username: String,
+    pub host: String,
+    pub nick: Nick,
+    pub away: bool,
+    /// Status prefixes in `channel`, for example `@`.
+    pub prefixes: String,
+    pub real_name: String,
+}
+
+/// The end of a WHO listing (`RPL_ENDOFWHO`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EndOfWhoReply {
+    pub target_nick: Nick,
+    pub mask: String,
+}
+
+/// A reply carrying IRCv3 message tags, for example `@time=2023-11-14T22:13:20.123Z :tom PRIVMSG ...`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TaggedReply {
+    pub tags: Vec<(String, String)>,
+    pub reply: Box<Reply>,
+}
+
+/// The start of a batch of replies, for example `BATCH +1a2b chathistory #channel`.
+/// Replies in the batch are tagged with its id, and it is closed by `Reply::BatchEnd`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BatchStartReply {
+    pub id: String,
+    pub kind: String,
+    pub params: Vec<String>,
+}
+
+/// An IRCv3 standard reply reporting a failed command,
+/// for example `FAIL CHATHISTORY INVALID_TARGET LATEST #channel :Messages could not be retrieved`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FailReply {
+    pub command: String,
+    pub code: String,
+    pub context: Vec<String>,
+    pub description: String,
+}
+
+/// A conversation with stored messages, and the time of its latest one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChatHistoryTargetsReply {
+    pub target: Target,
+    /// Milliseconds since the unix epoch.
+    pub time: u64,
+}
+
+/// A change of modes, for example `:tom MODE #channel +o ann`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModeReply {
+    pub target: Target,
+    pub modes: String,
+    pub args: Vec<String>,
+    pub sender: Prefix,
+}
+
+impl ModeReply {
+    /// Combines `changes` into a single reply, for example `+o-v tom ann`.
+    pub fn from_changes(target: Target, changes: &[ModeChange], sender: Prefix) -> Self {
+        let mut modes = String::new();
+        let mut add = None;
+        for change in changes {
+            if add != Some(change.add) {
+                modes.push(if change.add { '+' } else { '-' });
+                add = Some(change.add);
+            }
+            modes.push(change.mode);
+        }
+        ModeReply {
+            target,
+            modes,
+            args: changes.iter().filter_map(|c| c.arg.clone()).collect(),
+            sender,
+        }
+    }
+}
+
+/// The modes of a channel, as sent to a single user (`RPL_CHANNELMODEIS`).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChannelModeIsReply {
+    pub target_nick: Nick,
+    pub channel: Channel,
+    pub modes: String,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct QuitReply {
     pub message: QuitMsg,
-    pub sender_nick: Nick,
+    pub sender: Prefix,
 }
 
 #[derive(Debug, Clone, PartialEq, Eq)]
@@ -380,57 +1772,672 @@ pub struct WelcomeReply {
     pub message: String,
 }
 
+/// Features of the server clients need to know about (`RPL_ISUPPORT`), for example `CASEMAPPING=rfc1459`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ISupportReply {
+    pub target_nick: Nick,
+    pub tokens: Vec<String>,
+}
+
+/// An error sent to a single user, for example `:iris-server 403 tom #haku :No such channel`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErrorReply {
+    /// The user's nick, or `*` if they haven't chosen one yet.
+    pub target: String,
+    pub error: ErrorType,
+    /// What the error is about, for example the channel that does not exist.
+    pub params: Vec<String>,
+}
+
 /// Every possible reply to a message.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Reply {
+    Ping(String),
     Pong(String),
     Welcome(WelcomeReply),
+    ISupport(ISupportReply),
     PrivMsg(PrivReply),
+    Notice(NoticeReply),
+    Nick(NickReply),
     Join(JoinReply),
     Part(PartReply),
-    Error(ErrorType),
+    Topic(TopicReply),
+    TopicIs(TopicIsReply),
+    Mode(ModeReply),
+    ChannelModeIs(ChannelModeIsReply),
+    Away(AwayReply),
+    AwayStatus(AwayStatusReply),
+    IsAway(IsAwayReply),
+    Account(AccountReply),
+    ChgHost(ChgHostReply),
+    ExtendedJoin(ExtendedJoinReply),
+    Cap(CapReply),
+    Names(NamesReply),
+    EndOfNames(EndOfNamesReply),
+    Who(WhoReply),
+    EndOfWho(EndOfWhoReply),
+    Tagged(TaggedReply),
+    BatchStart(BatchStartReply),
+    BatchEnd(String),
+    Fail(FailReply),
+    Ack,
+    ChatHistoryTargets(ChatHistoryTargetsReply),
+    Numeric(NumericReply),
+    Error(ErrorReply),
     Quit(QuitReply),
 }
 
+impl Reply {
+    /// The error `error`, sent to `target`.
+    pub fn error(target: impl std::fmt::Display, error: impl Into<CommandError>) -> Reply {
+        let CommandError { error, params } = error.into();
+        Reply::Error(ErrorReply {
+            target: target.to_string(),
+            error,
+            params,
+        })
+    }
+
+    /// The numeric this reply is sent as, if it is one.
+    pub fn numeric(&self) -> Option<NumericReply> {
+        let reply = match self {
+            Reply::Welcome(r) => {
+                NumericReply::new(Numeric::RplWelcome, &r.target_nick, vec![]).with_text(&r.message)
+            }
+            Reply::ISupport(r) => {
+                NumericReply::new(Numeric::RplISupport, &r.target_nick, r.tokens.clone())
+            }
+            Reply::TopicIs(r) => {
+                let channel = vec![r.channel.to_string()];
+                match &r.topic {
+                    Some(topic) => NumericReply::new(Numeric::RplTopic, &r.target_nick, channel)
+                        .with_text(topic),
+                    None => NumericReply::new(Numeric::RplNoTopic, &r.target_nick, channel),
+                }
+            }
+            Reply::ChannelModeIs(r) => NumericReply::new(
+                Numeric::RplChannelModeIs,
+                &r.target_nick,
+                vec![r.channel.to_string(), r.modes.clone()],
+            ),
+            Reply::AwayStatus(r) => {
+                let numeric = if r.away {
+                    Numeric::RplNowAway
+                } else {
+                    Numeric::RplUnAway
+                };
+                NumericReply::new(numeric, &r.target_nick, vec![])
+            }
+            Reply::IsAway(r) => NumericReply::new(
+                Numeric::RplAway,
+                &r.target_nick,
+                vec![r.away_nick.to_string()],
+            )
+            .with_text(&r.message),
+            Reply::Names(r) => {
+                let symbol = if r.secret { "@" } else { "=" };
+                NumericReply::new(
+                    Numeric::RplNamReply,
+                    &r.target_nick,
+                    vec![symbol.to_string(), r.channel.to_string()],
+                )
+                .with_text(r.names.join(" "))
+            }
+            Reply::EndOfNames(r) => NumericReply::new(
+                Numeric::RplEndOfNames,
+                &r.target_nick,
+                vec![r.channel.clone()],
+            ),
+            Reply::Who(r) => {
+                let channel = r
+                    .channel
+                    .as_ref()
+                    .map_or("*".to_string(), |c| c.to_string());
+                let here = if r.away { 'G' } else { 'H' };
+                NumericReply::new(
+                    Numeric::RplWhoReply,
+                    &r.target_nick,
+                    vec![
+                        channel,
+                        r.username.clone(),
+                        r.host.clone(),
+                        SERVER_NAME.to_string(),
+                        r.nick.to_string(),
+                        format!("{here}{}", r.prefixes),
+                    ],
+                )
+                .with_text(format!("0 {}", r.real_name))
+            }
+            Reply::EndOfWho(r) => {
+                NumericReply::new(Numeric::RplEndOfWho, &r.target_nick, vec![r.mask.clone()])
+            }
+            Reply::Numeric(r) => r.clone(),
+            Reply::Error(r) => NumericReply::new(r.error.numeric(), &r.target, r.params.clone()),
+            _ => return None,
+        };
+        Some(reply)
+    }
+
+    /// This reply with `tags` added in front of any it already has.
+    pub fn with_tags(self, mut tags: Vec<(String, String)>) -> Reply {
+        if tags.is_empty() {
+            return self;
+        }
+        match self {
+            Reply::Tagged(tagged) => {
+                tags.extend(tagged.tags);
+                Reply::Tagged(TaggedReply {
+                    tags,
+                    reply: tagged.reply,
+                })
+            }
+            reply => Reply::Tagged(TaggedReply {
+                tags,
+                reply: Box::new(reply),
+            }),
+        }
+    }
+}
+
+/// The `label` tag of a message from a client, for example `abc` in `@label=abc PING :x`.
+pub fn message_label(message: &str) -> Option<String> {
+    let (tags, _, _) = split_tags_and_prefix(message);
+    tags?
+        .split(';')
+        .find_map(|tag| tag.strip_prefix("label="))
+        .filter(|label| !label.is_empty())
+        .map(unescape_tag_value)
+}
+
+/// Labels the lines sent in response to a request carrying `label`.
+/// No lines are acknowledged with `ACK`, a single line is tagged, and anything longer
+/// is wrapped in a `labeled-response` batch. Lines already in a batch of their own stay in it.
+/// For a client without `batch`, longer responses are sent as they are, unlabeled.
+pub fn labeled_response(label: &str, lines: Vec<String>, batch: bool) -> Vec<String> {
+    let label_tag = vec![("label".to_string(), label.to_string())];
+    let lines = lines
+        .iter()
+        .flat_map(|message| message.split_inclusive('\n'))
+        .map(str::to_string)
+        .collect::<Vec<_>>();
+    match &lines[..] {
+        [] => vec![Reply::Ack.with_tags(label_tag).to_string()],
+        [line] => vec![add_tags(line, &label_tag)],
+        _ if !batch => lines,
+        lines => {
+            let id = unique_id();
+            let batch_tag = vec![("batch".to_string(), id.clone())];
+            let start = Reply::BatchStart(BatchStartReply {
+                id: id.clone(),
+                kind: "labeled-response".to_string(),
+                params: vec![],
+            });
+            let mut labeled = vec![start.with_tags(label_tag).to_string()];
+            labeled.extend(lines.iter().map(|line| {
+                let batched = line.strip_prefix('@').is_some_and(|tagged| {
+                    tagged
+                        .split(' ')
+                        .next()
+                        .unwrap_or_default()
+                        .split(';')
+                        .any(|tag| tag.starts_with("batch="))
+                });
+                if batched {
+                    line.clone()
+                } else {
+                    add_tags(line, &batch_tag)
+                }
+            }));
+            labeled.push(Reply::BatchEnd(id).to_string());
+            labeled
+        }
+    }
+}
+
+/// Adds `tags` to an already formatted line, in front of any it has.
+fn add_tags(line: &str, tags: &[(String, String)]) -> String {
+    match line.strip_prefix('@') {
+        Some(tagged) => format!("@{};{tagged}", format_tags(tags)),
+        None => format!("@{} {line}", format_tags(tags)),
+    }
+}
+
+fn format_tags(tags: &[(String, String)]) -> String {
+    tags.iter()
+        .map(|(key, value)| match value.as_str() {
+            "" => key.clone(),
+            value => format!("{key}={}", escape_tag_value(value)),
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Undoes `escape_tag_value`.
+fn unescape_tag_value(value: &str) -> String {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\\' => match chars.next() {
+                Some(':') => unescaped.push(';'),
+                Some('s') => unescaped.push(' '),
+                Some('r') => unescaped.push('\r'),
+                Some('n') => unescaped.push('\n'),
+                Some(c) => unescaped.push(c),
+                None => {}
+            },
+            c => unescaped.push(c),
+        }
+    }
+    unescaped
+}
+
+/// Escapes a message tag value, so it cannot end the tags early.
+fn escape_tag_value(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            ';' => escaped.push_str("\\:"),
+            ' ' => escaped.push_str("\\s"),
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
 impl std::fmt::Display for Reply {
     fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        if let Some(numeric) = self.numeric() {
+            return write!(fmt, "{numeric}");
+        }
         match self {
+            Reply::Ping(p) => write!(fmt, "PING :{p}\r\n"),
             Reply::Pong(p) => write!(fmt, "PONG :{p}\r\n"),
-            Reply::Welcome(r) => {
-                let nick = &r.target_nick;
-                let message = &r.message;
-                write!(fmt, ":{SERVER_NAME} 001 {nick} :{message}\r\n")
-            }
             Reply::PrivMsg(r) => {
                 let nick = &r.message.target;
                 let message = &r.message.message;
-                let from = &r.sender_nick;
+                let from = &r.sender;
                 write!(fmt, ":{from} PRIVMSG {nick} :{message}\r\n")
             }
-            Reply::Error(e) => {
-                write!(fmt, ":{SERVER_NAME} {e}\r\n")
+            Reply::Notice(r) => {
+                let nick = &r.message.target;
+                let message = &r.message.message;
+                let from = &r.sender;
+                write!(fmt, ":{from} NOTICE {nick} :{message}\r\n")
+            }
+            Reply::Nick(r) => {
+                let sender = &r.sender;
+                let nick = &r.message.nick;
+                write!(fmt, ":{sender} NICK {nick}\r\n")
             }
             Reply::Join(r) => {
-                let sender = &r.sender_nick;
+                let sender = &r.sender;
                 let channel = &r.message.channel;
                 write!(fmt, ":{sender} JOIN {channel}\r\n")
             }
             Reply::Part(r) => {
-                let sender = &r.sender_nick;
+                let sender = &r.sender;
                 let channel = &r.message.channel;
                 write!(fmt, ":{sender} PART {channel}\r\n")
             }
+            Reply::Topic(r) => {
+                let sender = &r.sender;
+                let channel = &r.message.channel;
+                let topic = r.message.topic.as_deref().unwrap_or_default();
+                write!(fmt, ":{sender} TOPIC {channel} :{topic}\r\n")
+            }
+            Reply::Mode(r) => {
+                let sender = &r.sender;
+                let target = &r.target;
+                let modes = &r.modes;
+                write!(fmt, ":{sender} MODE {target} {modes}")?;
+                for arg in &r.args {
+                    write!(fmt, " {arg}")?;
+                }
+                write!(fmt, "\r\n")
+            }
+            Reply::Away(r) => {
+                let sender = &r.sender;
+                match &r.message.message {
+                    Some(message) => write!(fmt, ":{sender} AWAY :{message}\r\n"),
+                    None => write!(fmt, ":{sender} AWAY\r\n"),
+                }
+            }
+            Reply::Account(r) => {
+                let sender = &r.sender;
+                let account = r.account.as_deref().unwrap_or("*");
+                write!(fmt, ":{sender} ACCOUNT {account}\r\n")
+            }
+            Reply::ChgHost(r) => {
+                let sender = &r.sender;
+                let username = &r.username;
+                let host = &r.host;
+                write!(fmt, ":{sender} CHGHOST {username} {host}\r\n")
+            }
+            Reply::ExtendedJoin(r) => {
+                let sender = &r.sender;
+                let channel = &r.message.channel;
+                let account = r.account.as_deref().unwrap_or("*");
+                let real_name = &r.real_name;
+                write!(fmt, ":{sender} JOIN {channel} {account} :{real_name}\r\n")
+            }
+            Reply::Tagged(r) => write!(fmt, "@{} {}", format_tags(&r.tags), r.reply),
+            Reply::BatchStart(r) => {
+                let id = &r.id;
+                let kind = &r.kind;
+                write!(fmt, ":{SERVER_NAME} BATCH +{id} {kind}")?;
+                for param in &r.params {
+                    write!(fmt, " {param}")?;
+                }
+                write!(fmt, "\r\n")
+            }
+            Reply::BatchEnd(id) => write!(fmt, ":{SERVER_NAME} BATCH -{id}\r\n"),
+            Reply::Ack => write!(fmt, ":{SERVER_NAME} ACK\r\n"),
+            Reply::Fail(r) => {
+                let command = &r.command;
+                let code = &r.code;
+                write!(fmt, ":{SERVER_NAME} FAIL {command} {code}")?;
+                for context in &r.context {
+                    write!(fmt, " {context}")?;
+                }
+                write!(fmt, " :{}\r\n", r.description)
+            }
+            Reply::ChatHistoryTargets(r) => {
+                let target = &r.target;
+                let time = format_timestamp(r.time);
+                write!(
+                    fmt,
+                    ":{SERVER_NAME} CHATHISTORY TARGETS {target} timestamp={time}\r\n"
+                )
+            }
+            Reply::Cap(r) => {
+                let target = &r.target;
+                let subcommand = &r.subcommand;
+                let caps = &r.caps;
+                write!(fmt, ":{SERVER_NAME} CAP {target} {subcommand} :{caps}\r\n")
+            }
             Reply::Quit(r) => {
-                let sender = &r.sender_nick.to_string();
-                let message = &r.message.message.as_ref().unwrap_or(sender);
+                let sender = &r.sender;
+                let message = r.message.message.as_deref().unwrap_or(sender.name());
                 write!(fmt, ":{sender} QUIT :{message}\r\n")
             }
+            Reply::Welcome(_)
+            | Reply::ISupport(_)
+            | Reply::TopicIs(_)
+            | Reply::ChannelModeIs(_)
+            | Reply::AwayStatus(_)
+            | Reply::IsAway(_)
+            | Reply::Names(_)
+            | Reply::EndOfNames(_)
+            | Reply::Who(_)
+            | Reply::EndOfWho(_)
+            | Reply::Numeric(_)
+            | Reply::Error(_) => unreachable!("numeric replies are written above"),
+        }
+    }
+}
+
+/// A line from the server that does not decode into any [`Reply`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UnknownReply(pub String);
+
+impl std::fmt::Display for UnknownReply {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter<'_>) -> Result<(), std::fmt::Error> {
+        write!(fmt, "Unrecognised reply: {}", self.0)
+    }
+}
+
+impl FromStr for Reply {
+    type Err = UnknownReply;
+
+    /// Decodes a line sent by the server, with or without its line ending.
+    /// Text the server fills in itself, like the description of a numeric, is not kept.
+    fn from_str(line: &str) -> Result<Self, Self::Err> {
+        let unknown = || UnknownReply(line.to_string());
+        let trimmed = line.trim_end_matches(['\r', '\n']);
+        let message = MessageRef::parse(trimmed);
+        if let Some(tags) = message.tags {
+            let tags = tags
+                .split(';')
+                .map(|tag| match tag.split_once('=') {
+                    Some((key, value)) => (key.to_string(), unescape_tag_value(value)),
+                    None => (tag.to_string(), String::new()),
+                })
+                .collect();
+            // Tags never contain a space, so the rest of the line starts after the first.
+            let (_, untagged) = trimmed.split_once(' ').unwrap_or_default();
+            return untagged
+                .parse()
+                .map(|reply| {
+                    Reply::Tagged(TaggedReply {
+                        tags,
+                        reply: Box::new(reply),
+                    })
+                })
+                .map_err(|_| unknown());
         }
+
+        let prefix = message.prefix;
+        let command = std::iter::once(message.command)
+            .chain(message.params())
+            .collect::<Vec<_>>();
+        let sender = || prefix.map(Prefix::from).ok_or_else(unknown);
+        let nick = |nick: &str| Nick(nick.to_string());
+        let channel = |channel: &str| Channel(channel.to_string());
+        let account = |account: &str| Some(account.to_string()).filter(|a| a != "*");
+        let strings = |args: &[&str]| args.iter().map(|arg| arg.to_string()).collect();
+        let reply = match command[..] {
+            ["PING", token] => Reply::Ping(token.to_string()),
+            ["PONG", origin] => Reply::Pong(origin.to_string()),
+            ["001", target, message] => Reply::Welcome(WelcomeReply {
+                target_nick: nick(target),
+                message: message.to_string(),
+            }),
+            ["005", target, ref tokens @ .., _] => Reply::ISupport(ISupportReply {
+                target_nick: nick(target),
+                tokens: strings(tokens),
+            }),
+            ["PRIVMSG", target, message] => Reply::PrivMsg(PrivReply {
+                message: PrivMsg {
+                    target: Target::from(target.to_string()),
+                    message: message.to_string(),
+                },
+                sender: sender()?,
+            }),
+            ["NOTICE", target, message] => Reply::Notice(NoticeReply {
+                message: NoticeMsg {
+                    target: Target::from(target.to_string()),
+                    message: message.to_string(),
+                },
+                sender: sender()?,
+            }),
+            ["NICK", new_nick] => Reply::Nick(NickReply {
+                message: NickMsg {
+                    nick: nick(new_nick),
+                },
+                sender: sender()?,
+            }),
+            ["JOIN", joined] => Reply::Join(JoinReply {
+                message: JoinMsg {
+                    channel: channel(joined),
+                },
+                sender: sender()?,
+            }),
+            ["JOIN", joined, joined_account, real_name] => Reply::ExtendedJoin(ExtendedJoinReply {
+                message: JoinMsg {
+                    channel: channel(joined),
+                },
+                account: account(joined_account),
+                real_name: real_name.to_string(),
+                sender: sender()?,
+            }),
+            ["PART", parted] => Reply::Part(PartReply {
+                message: PartMsg {
+                    channel: channel(parted),
+                },
+                sender: sender()?,
+            }),
+            ["TOPIC", topic_channel, topic] => Reply::Topic(TopicReply {
+                message: TopicMsg {
+                    channel: channel(topic_channel),
+                    topic: Some(topic.to_string()),
+                },
+                sender: sender()?,
+            }),
+            ["331", target, topic_channel, _] => Reply::TopicIs(TopicIsReply {
+                target_nick: nick(target),
+                channel: channel(topic_channel),
+                topic: None,
+            }),
+            ["332", target, topic_channel, topic] => Reply::TopicIs(TopicIsReply {
+                target_nick: nick(target),
+                channel: channel(topic_channel),
+                topic: Some(topic.to_string()),
+            }),
+            ["MODE", target, modes, ref args @ ..] => Reply::Mode(ModeReply {
+                target: Target::from(target.to_string()),
+                modes: modes.to_string(),
+                args: strings(args),
+                sender: sender()?,
+            }),
+            ["324", target, mode_channel, modes] => Reply::ChannelModeIs(ChannelModeIsReply {
+                target_nick: nick(target),
+                channel: channel(mode_channel),
+                modes: modes.to_string(),
+            }),
+            ["AWAY", ref message @ ..] if message.len() <= 1 => Reply::Away(AwayReply {
+                message: AwayMsg {
+                    message: message.first().map(|m| m.to_string()),
+                },
+                sender: sender()?,
+            }),
+            [numeric @ ("305" | "306"), target, _] => Reply::AwayStatus(AwayStatusReply {
+                target_nick: nick(target),
+                away: numeric == "306",
+            }),
+            ["301", target, away, message] => Reply::IsAway(IsAwayReply {
+                target_nick: nick(target),
+                away_nick: nick(away),
+                message: message.to_string(),
+            }),
+            ["ACCOUNT", new_account] => Reply::Account(AccountReply {
+                account: account(new_account),
+                sender: sender()?,
+            }),
+            ["CHGHOST", username, host] => Reply::ChgHost(ChgHostReply {
+                username: username.to_string(),
+                host: host.to_string(),
+                sender: sender()?,
+            }),
+            ["CAP", target, subcommand, caps] => Reply::Cap(CapReply {
+                target: target.to_string(),
+                subcommand: subcommand.to_string(),
+                caps: caps.to_string(),
+            }),
+            ["353", target, symbol, names_channel, names] => Reply::Names(NamesReply {
+                target_nick: nick(target),
+                channel: channel(names_channel),
+                secret: symbol == "@",
+                names: names.split_whitespace().map(str::to_string).collect(),
+            }),
+            ["366", target, names_channel, _] => Reply::EndOfNames(EndOfNamesReply {
+                target_nick: nick(target),
+                channel: names_channel.to_string(),
+            }),
+            ["352", target, who_channel, username, host, _, who, flags, hops_and_name] => {
+                let (away, prefixes) = match flags.split_at_checked(1) {
+                    Some(("H", prefixes)) => (false, prefixes),
+                    Some(("G", prefixes)) => (true, prefixes),
+                    _ => return Err(unknown()),
+                };
+                Reply::Who(WhoReply {
+                    target_nick: nick(target),
+                    channel: Some(who_channel).filter(|c| *c != "*").map(channel),
+                    username: username.to_string(),
+                    host: host.to_string(),
+                    nick: nick(who),
+                    away,
+                    prefixes: prefixes.to_string(),
+                    real_name: hops_and_name
+                        .split_once(' ')
+                        .map_or("", |(_, name)| name)
+                        .to_string(),
+                })
+            }
+            ["315", target, mask, _] => Reply::EndOfWho(EndOfWhoReply {
+                target_nick: nick(target),
+                mask: mask.to_string(),
+            }),
+            ["BATCH", id] => Reply::BatchEnd(id.strip_prefix('-').ok_or_else(unknown)?.to_string()),
+            ["BATCH", id, kind, ref params @ ..] => Reply::BatchStart(BatchStartReply {
+                id: id.strip_prefix('+').ok_or_else(unknown)?.to_string(),
+                kind: kind.to_string(),
+                params: strings(params),
+            }),
+            ["ACK"] => Reply::Ack,
+            ["FAIL", failed, code, ref context @ .., description] => Reply::Fail(FailReply {
+                command: failed.to_string(),
+                code: code.to_string(),
+                context: strings(context),
+                description: description.to_string(),
+            }),
+            ["CHATHISTORY", "TARGETS", target, timestamp] => {
+                Reply::ChatHistoryTargets(ChatHistoryTargetsReply {
+                    target: Target::from(target.to_string()),
+                    time: timestamp
+                        .strip_prefix("timestamp=")
+                        .and_then(parse_timestamp)
+                        .ok_or_else(unknown)?,
+                })
+            }
+            ["QUIT", message] => Reply::Quit(QuitReply {
+                message: QuitMsg {
+                    message: Some(message.to_string()),
+                },
+                sender: sender()?,
+            }),
+            [code, target, ref params @ ..] => {
+                let code = code.parse::<u16>().map_err(|_| unknown())?;
+                if let Ok(error) = ErrorType::try_from(code) {
+                    // The last parameter is the text of the error.
+                    let params = params.split_last().map_or(&[][..], |(_, params)| params);
+                    return Ok(Reply::error(target, error.about(params)));
+                }
+                let numeric = Numeric::try_from(code).map_err(|_| unknown())?;
+                let mut params: Vec<String> = strings(params);
+                let text = if numeric.has_text() {
+                    params.pop()
+                } else {
+                    None
+                };
+                Reply::Numeric(NumericReply {
+                    numeric,
+                    target: target.to_string(),
+                    params,
+                    text,
+                })
+            }
+            _ => return Err(unknown()),
+        };
+        Ok(reply)
+    }
+}
+
+impl TryFrom<&str> for Reply {
+    type Error = UnknownReply;
+
+    fn try_from(value: &str) -> Result<Self, UnknownReply> {
+        value.parse()
     }
 }
 
+#[cfg(test)]
 mod tests {
-    #[allow(unused_imports)]
+    use proptest::prelude::*;
+
     use super::*;
 
     #[test]
@@ -438,7 +2445,8 @@ mod tests {
         assert_eq!(
             ParsedMessage::try_from(UnparsedMessage {
                 message: "PING :host-name with space\r\n",
-                sender_nick: Nick("Person".to_string())
+                sender_nick: Nick("Person".to_string()),
+                server_name: SERVER_NAME,
             })
             .unwrap()
             .message,
@@ -446,12 +2454,32 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_pong() {
+        let pong = |message| {
+            ParsedMessage::try_from(UnparsedMessage {
+                message,
+                sender_nick: Nick("Person".to_string()),
+                server_name: SERVER_NAME,
+            })
+            .map(|parsed| parsed.message)
+            .map_err(|e| e.error)
+        };
+        assert_eq!(pong("PONG :1f\r\n"), Ok(Message::Pong("1f".to_string())));
+        assert_eq!(
+            pong("PONG iris-server :1f\r\n"),
+            Ok(Message::Pong("1f".to_string()))
+        );
+        assert_eq!(pong("PONG\r\n"), Err(ErrorType::NoOrigin));
+    }
+
     #[test]
     fn test_privmsg() {
         assert_eq!(
             ParsedMessage::try_from(UnparsedMessage {
                 message: "PRIVMSG tom :Hi Tom, how are you?\r\n",
-                sender_nick: Nick("Person".to_string())
+                sender_nick: Nick("Person".to_string()),
+                server_name: SERVER_NAME,
             })
             .unwrap()
             .message,
@@ -467,7 +2495,8 @@ mod tests {
         assert_eq!(
             ParsedMessage::try_from(UnparsedMessage {
                 message: "NICK tfpk\r\n",
-                sender_nick: Nick("Person".to_string())
+                sender_nick: Nick("Person".to_string()),
+                server_name: SERVER_NAME,
             })
             .unwrap()
             .message,
@@ -477,10 +2506,681 @@ mod tests {
         );
         assert_eq!(
             ParsedMessage::try_from(UnparsedMessage {
-                message: "NICK tfpkasdfasdfasdf\r\n",
-                sender_nick: Nick("Person".to_string())
+                message: "NICK 9tfpk\r\n",
+                sender_nick: Nick("Person".to_string()),
+                server_name: SERVER_NAME,
             }),
+            Err(ErrorType::ErroneousNickname.about(["9tfpk"]))
+        );
+    }
+
+    #[test]
+    fn test_user() {
+        assert_eq!(
+            MessageRef::parse("user tom 0 * :Thomas Kunc").to_message(),
+            Ok(Message::User(UserMsg {
+                username: "tom".to_string(),
+                real_name: "Thomas Kunc".to_string()
+            }))
+        );
+        assert_eq!(
+            MessageRef::parse("USER t@m 0 * :Thomas Kunc").to_message(),
+            Err(ErrorType::InvalidUsername.about(["t@m"]))
+        );
+        assert_eq!(
+            MessageRef::parse("USER tom localhost irc.example.com :Thomas Kunc").to_message(),
+            Ok(Message::User(UserMsg {
+                username: "tom".to_string(),
+                real_name: "Thomas Kunc".to_string(),
+            }))
+        );
+        assert_eq!(
+            MessageRef::parse("USER tom 0 *").to_message(),
+            Err(ErrorType::NeedMoreParams.about(["USER"]))
+        );
+    }
+
+    #[test]
+    fn test_mode_changes() {
+        let message = ParsedMessage::try_from(UnparsedMessage {
+            message: "MODE #haku +nt-o+v tom ann\r\n",
+            sender_nick: Nick("Person".to_string()),
+            server_name: SERVER_NAME,
+        })
+        .unwrap()
+        .message;
+        let Message::Mode(mode) = message else {
+            panic!("expected a MODE message");
+        };
+        let changes = mode.changes().unwrap();
+        assert_eq!(changes.len(), 4);
+        assert_eq!(
+            changes[2],
+            ModeChange {
+                add: false,
+                mode: 'o',
+                arg: Some("tom".to_string())
+            }
+        );
+        let reply = Reply::Mode(ModeReply::from_changes(
+            mode.target,
+            &changes,
+            Prefix::from("Person!p@127.0.0.1"),
+        ));
+        assert_eq!(
+            reply.to_string(),
+            ":Person!p@127.0.0.1 MODE #haku +nt-o+v tom ann\r\n"
+        );
+        assert_eq!(
+            ModeMsg {
+                target: Target::Channel(Channel("#haku".to_string())),
+                modes: Some("+x".to_string()),
+                args: vec![]
+            }
+            .changes(),
+            Err(ErrorType::UnknownMode.about(['x']))
+        );
+    }
+
+    #[test]
+    fn test_mask_matches() {
+        assert!(mask_matches("*", ""));
+        assert!(!mask_matches("tom*", "Tommy"));
+        assert!(NameRules::default().mask_matches("tom*", "Tommy"));
+        assert!(mask_matches("t?m", "tim"));
+        assert!(mask_matches(
+            "*!*@*.example.com",
+            "tom!tom@host.example.com"
+        ));
+        assert!(!mask_matches("*!*@*.example.com", "tom!tom@example.com"));
+        assert!(!mask_matches("tom", "tommy"));
+        assert!(mask_matches("a*b*c", "aXXbYYbZc"));
+    }
+
+    #[test]
+    fn test_prefixes() {
+        let mut info = ChannelInfo::default();
+        info.members.insert("a".to_string());
+        info.ops.insert("a".to_string());
+        info.voices.insert("a".to_string());
+        assert_eq!(info.prefixes("a", true), "@+");
+        assert_eq!(info.prefixes("a", false), "@");
+        info.ops.clear();
+        assert_eq!(info.prefixes("a", false), "+");
+    }
+
+    #[test]
+    fn test_tags() {
+        assert_eq!(
+            ParsedMessage::try_from(UnparsedMessage {
+                message: "@label=a;b=c\\s:d NOTICE #haku :hi all\r\n",
+                sender_nick: Nick("Person".to_string()),
+                server_name: SERVER_NAME,
+            })
+            .unwrap()
+            .message,
+            Message::Notice(NoticeMsg {
+                target: Target::Channel(Channel("#haku".to_string())),
+                message: "hi all".to_string()
+            })
+        );
+        let reply = Reply::Pong("iris".to_string())
+            .with_tags(vec![("time".to_string(), "now".to_string())])
+            .with_tags(vec![
+                ("batch".to_string(), "1".to_string()),
+                ("note".to_string(), "a b;c\\".to_string()),
+            ]);
+        assert_eq!(
+            reply.to_string(),
+            "@batch=1;note=a\\sb\\:c\\\\;time=now PONG :iris\r\n"
+        );
+    }
+
+    #[test]
+    fn test_labeled_response() {
+        assert_eq!(
+            message_label("@time=x;label=a\\sb PING :x").as_deref(),
+            Some("a b")
+        );
+        assert_eq!(message_label("PING :x"), None);
+        assert_eq!(
+            labeled_response("l", vec![], true),
+            ["@label=l :iris-server ACK\r\n"]
+        );
+        assert_eq!(
+            labeled_response("l", vec!["@time=now PONG :x\r\n".to_string()], true),
+            ["@label=l;time=now PONG :x\r\n"]
+        );
+        let lines = labeled_response(
+            "l",
+            vec![
+                ":iris-server BATCH +inner chathistory #haku\r\n".to_string(),
+                "@batch=inner :tom PRIVMSG #haku :hi\r\n".to_string(),
+                ":iris-server BATCH -inner\r\n".to_string(),
+            ],
+            true,
+        );
+        let id = lines[0]
+            .strip_prefix("@label=l :iris-server BATCH +")
+            .and_then(|rest| rest.strip_suffix(" labeled-response\r\n"))
+            .unwrap();
+        assert_eq!(
+            lines[1..],
+            [
+                format!("@batch={id} :iris-server BATCH +inner chathistory #haku\r\n"),
+                "@batch=inner :tom PRIVMSG #haku :hi\r\n".to_string(),
+                format!("@batch={id} :iris-server BATCH -inner\r\n"),
+                format!(":iris-server BATCH -{id}\r\n"),
+            ]
+        );
+        // Without batch, only a single line can carry the label.
+        assert_eq!(
+            labeled_response("l", vec!["PONG :x\r\n".to_string()], false),
+            ["@label=l PONG :x\r\n"]
+        );
+        assert_eq!(
+            labeled_response(
+                "l",
+                vec!["NOTICE a :b\r\n".to_string(), "NOTICE a :c\r\n".to_string()],
+                false
+            ),
+            ["NOTICE a :b\r\n", "NOTICE a :c\r\n"]
+        );
+    }
+
+    #[test]
+    fn test_nicks() {
+        let mut nicks = Nicks::new(NameRules {
+            policy: NamePolicy::Utf8,
+            ..NameRules::default()
+        });
+        nicks.insert("127.0.0.1:5000", None, &Nick("Pay".to_string()));
+        assert_eq!(
+            nicks.address_of("pay").map(String::as_str),
+            Some("127.0.0.1:5000")
+        );
+        assert_eq!(
+            nicks.confusable("рау").map(String::as_str),
+            Some("127.0.0.1:5000")
+        );
+        assert_eq!(nicks.address_of("рау"), None);
+        nicks.insert(
+            "127.0.0.1:5000",
+            Some(&Nick("Pay".to_string())),
+            &Nick("payee".to_string()),
+        );
+        assert_eq!(nicks.address_of("pay"), None);
+        assert_eq!(nicks.confusable("pay"), None);
+        // Someone else's entry isn't forgotten along with a nickname they took over.
+        nicks.insert("127.0.0.1:5001", None, &Nick("PAYEE".to_string()));
+        nicks.remove("127.0.0.1:5000", &Nick("payee".to_string()));
+        assert_eq!(
+            nicks.address_of("payee").map(String::as_str),
+            Some("127.0.0.1:5001")
+        );
+    }
+
+    #[test]
+    fn test_casemapping() {
+        assert_eq!(CaseMapping::Rfc1459.fold("Ab[]\\~"), "ab{}|^");
+        assert_eq!(CaseMapping::StrictRfc1459.fold("Ab[]\\~"), "ab{}|~");
+        assert_eq!(CaseMapping::Ascii.fold("Ab[]\\~"), "ab[]\\~");
+        assert_eq!("strict-rfc1459".parse(), Ok(CaseMapping::StrictRfc1459));
+        assert!("utf8".parse::<CaseMapping>().is_err());
+
+        let rules = NameRules::default();
+        assert!(rules.same("Alice[m]", "alice{M}"));
+        let ascii = NameRules {
+            casemapping: CaseMapping::Ascii,
+            ..rules
+        };
+        assert!(!ascii.same("Alice[m]", "alice{M}"));
+        assert!(rules.mask_matches("ALICE{*", "alice[m]"));
+        assert!(Nick::try_from("-dash".to_string()).is_err());
+
+        // Names keep the casing they were chosen with, and are looked up without it.
+        let mut channels = Channels::new(rules);
+        channels.get_or_create(&Channel("#Haku".to_string()));
+        assert!(channels.contains(&Channel("#hAKU".to_string())));
+        assert_eq!(
+            channels.keys().collect::<Vec<_>>(),
+            [&Channel("#Haku".to_string())]
+        );
+        assert!(channels.remove(&Channel("#HAKU".to_string())).is_some());
+    }
+
+    #[test]
+    fn test_name_policy() {
+        assert_eq!(NamePolicy::Ascii.normalize("élise".to_string()), None);
+        assert_eq!(
+            NamePolicy::Utf8.normalize("e\u{301}lise".to_string()),
+            Some("élise".to_string())
+        );
+        assert_eq!(
+            NamePolicy::Utf8.normalize("#日本語-chat".to_string()),
+            Some("#日本語-chat".to_string())
+        );
+        // A Cyrillic `а` among Latin letters.
+        assert_eq!(NamePolicy::Utf8.normalize("p\u{430}ypal".to_string()), None);
+        assert_eq!("utf8".parse(), Ok(NamePolicy::Utf8));
+
+        let ascii = NameRules::default();
+        let utf8 = NameRules {
+            policy: NamePolicy::Utf8,
+            ..ascii
+        };
+        assert_eq!(utf8.fold("ÉLISE"), "élise");
+        assert!(utf8.confusable("\u{440}\u{430}\u{443}", "pay"));
+        assert!(!ascii.confusable("\u{440}\u{430}\u{443}", "pay"));
+        let nick = |nick: &str| Nick::try_from(nick.to_string()).unwrap();
+        assert!(ascii.nick(&nick("élise")).is_err());
+        assert_eq!(utf8.nick(&nick("élise")), Ok(nick("élise")));
+        assert!(ascii.nick(&nick("nine_char")).is_ok());
+        assert_eq!(
+            ascii.nick(&nick("ten_chars_")),
             Err(ErrorType::ErroneousNickname)
         );
+        let short = NameRules {
+            channel_len: 4,
+            ..ascii
+        };
+        let haku = Channel::try_from("#haku".to_string()).unwrap();
+        assert!(ascii.channel(&haku).is_ok());
+        assert_eq!(short.channel(&haku), Err(ErrorType::NoSuchChannel));
+        assert!(Channel::try_from("#a.b&c".to_string()).is_ok());
+        assert!(Channel::try_from("#a,b".to_string()).is_err());
+        assert!(Channel::try_from("#../../etc".to_string()).is_err());
+        assert!(Channel::try_from("#a/b".to_string()).is_err());
+    }
+
+    #[test]
+    fn test_prefix() {
+        let parsed = ParsedMessage::try_from(UnparsedMessage {
+            message: "@label=x :tom!tfpk@127.0.0.1 PRIVMSG ann :Hi: Ann\r\n",
+            sender_nick: Nick("tom".to_string()),
+            server_name: SERVER_NAME,
+        })
+        .unwrap();
+        assert_eq!(
+            parsed.prefix,
+            Some(Prefix::User {
+                nick: Nick("tom".to_string()),
+                username: Some("tfpk".to_string()),
+                host: Some("127.0.0.1".to_string())
+            })
+        );
+        assert_eq!(
+            parsed.message,
+            Message::PrivMsg(PrivMsg {
+                target: Target::User(Nick("ann".to_string())),
+                message: "Hi: Ann".to_string()
+            })
+        );
+        assert_eq!(
+            Prefix::from("irc.example.com"),
+            Prefix::Server("irc.example.com".to_string())
+        );
+        assert_eq!(
+            Prefix::parse(SERVER_NAME, SERVER_NAME),
+            Prefix::server(SERVER_NAME)
+        );
+        assert_eq!(Prefix::parse("haku", "haku"), Prefix::server("haku"));
+        assert!(matches!(
+            Prefix::parse("haku", SERVER_NAME),
+            Prefix::User { .. }
+        ));
+        for prefix in ["tom", "tom@host", "tom!tfpk@user/tom"] {
+            assert_eq!(Prefix::from(prefix).to_string(), prefix);
+        }
+    }
+
+    #[test]
+    fn test_message_ref() {
+        let message = MessageRef::parse("@label=x :tom MODE  #haku +ov tom :ann b\r\n");
+        assert_eq!(message.tags, Some("label=x"));
+        assert_eq!(message.prefix, Some("tom"));
+        assert_eq!(message.command, "MODE");
+        assert_eq!(
+            message.params().collect::<Vec<_>>(),
+            ["#haku", "+ov", "tom", "ann b"]
+        );
+        assert_eq!(message.param(3), Some("ann b"));
+        assert_eq!(message.param(4), None);
+        assert_eq!(message.args, " #haku +ov tom :ann b");
+        assert_eq!(MessageRef::parse("PING").args, "");
+        assert_eq!(
+            MessageRef::parse("FOO bar").to_message(),
+            Err(ErrorType::UnknownCommand.about(["FOO"]))
+        );
+    }
+
+    #[test]
+    fn test_render() {
+        let render = |message: Message| message.to_string();
+        assert_eq!(
+            render(Message::PrivMsg(PrivMsg {
+                target: Target::Channel(Channel("#haku".to_string())),
+                message: "hi".to_string()
+            })),
+            "PRIVMSG #haku :hi\r\n"
+        );
+        assert_eq!(
+            render(Message::Mode(ModeMsg {
+                target: Target::Channel(Channel("#haku".to_string())),
+                modes: Some("+ov".to_string()),
+                args: vec!["tom".to_string(), "ann".to_string()]
+            })),
+            "MODE #haku +ov tom ann\r\n"
+        );
+        assert_eq!(
+            render(Message::Who(WhoMsg {
+                mask: ":odd mask".to_string()
+            })),
+            "WHO ::odd mask\r\n"
+        );
+        assert_eq!(
+            render(Message::Names(NamesMsg { channel: None })),
+            "NAMES\r\n"
+        );
+        assert_eq!(
+            render(Message::Quit(QuitMsg {
+                message: Some(String::new())
+            })),
+            "QUIT :\r\n"
+        );
+    }
+
+    #[test]
+    fn test_parse_reply() {
+        let tom = || Nick("tom".to_string());
+        let haku = || Channel("#haku".to_string());
+        let user = || Prefix::from("ann!ann@127.0.0.1");
+        let replies = vec![
+            Reply::Ping("1f".to_string()),
+            Reply::Pong("x y".to_string()),
+            Reply::Welcome(WelcomeReply {
+                target_nick: tom(),
+                message: "Hi tom!".to_string(),
+            }),
+            Reply::ISupport(ISupportReply {
+                target_nick: tom(),
+                tokens: vec![
+                    "CASEMAPPING=ascii".to_string(),
+                    "CHATHISTORY=100".to_string(),
+                ],
+            }),
+            Reply::Notice(NoticeReply {
+                message: NoticeMsg {
+                    target: Target::Channel(haku()),
+                    message: "Hi: all".to_string(),
+                },
+                sender: user(),
+            }),
+            Reply::Nick(NickReply {
+                message: NickMsg { nick: tom() },
+                sender: user(),
+            }),
+            Reply::Part(PartReply {
+                message: PartMsg { channel: haku() },
+                sender: user(),
+            }),
+            Reply::Topic(TopicReply {
+                message: TopicMsg {
+                    channel: haku(),
+                    topic: Some("Welcome".to_string()),
+                },
+                sender: user(),
+            }),
+            Reply::TopicIs(TopicIsReply {
+                target_nick: tom(),
+                channel: haku(),
+                topic: None,
+            }),
+            Reply::Mode(ModeReply {
+                target: Target::Channel(haku()),
+                modes: "+o-v".to_string(),
+                args: vec!["tom".to_string(), "ann".to_string()],
+                sender: Prefix::from("ChanServ!ChanServ@iris-server"),
+            }),
+            Reply::ChannelModeIs(ChannelModeIsReply {
+                target_nick: tom(),
+                channel: haku(),
+                modes: "+nt".to_string(),
+            }),
+            Reply::Away(AwayReply {
+                message: AwayMsg { message: None },
+                sender: user(),
+            }),
+            Reply::AwayStatus(AwayStatusReply {
+                target_nick: tom(),
+                away: true,
+            }),
+            Reply::IsAway(IsAwayReply {
+                target_nick: tom(),
+                away_nick: Nick("ann".to_string()),
+                message: "lunch".to_string(),
+            }),
+            Reply::Account(AccountReply {
+                account: None,
+                sender: user(),
+            }),
+            Reply::ChgHost(ChgHostReply {
+                username: "ann".to_string(),
+                host: "user/ann".to_string(),
+                sender: user(),
+            }),
+            Reply::ExtendedJoin(ExtendedJoinReply {
+                message: JoinMsg { channel: haku() },
+                account: Some("ann".to_string()),
+                real_name: "Ann Example".to_string(),
+                sender: user(),
+            }),
+            Reply::Cap(CapReply {
+                target: "*".to_string(),
+                subcommand: "LS".to_string(),
+                caps: "batch server-time".to_string(),
+            }),
+            Reply::Names(NamesReply {
+                target_nick: tom(),
+                channel: haku(),
+                secret: true,
+                names: vec!["@ann".to_string(), "tom".to_string()],
+            }),
+            Reply::EndOfNames(EndOfNamesReply {
+                target_nick: tom(),
+                channel: "*".to_string(),
+            }),
+            Reply::Who(WhoReply {
+                target_nick: tom(),
+                channel: Some(haku()),
+                username: "ann".to_string(),
+                host: "127.0.0.1".to_string(),
+                nick: Nick("ann".to_string()),
+                away: true,
+                prefixes: "@+".to_string(),
+                real_name: "Ann Example".to_string(),
+            }),
+            Reply::EndOfWho(EndOfWhoReply {
+                target_nick: tom(),
+                mask: "a*".to_string(),
+            }),
+            Reply::Ack.with_tags(vec![
+                ("label".to_string(), "a b;c".to_string()),
+                ("draft/flag".to_string(), String::new()),
+            ]),
+            Reply::BatchStart(BatchStartReply {
+                id: "1a".to_string(),
+                kind: "chathistory".to_string(),
+                params: vec!["#haku".to_string()],
+            }),
+            Reply::BatchEnd("1a".to_string()),
+            Reply::Fail(FailReply {
+                command: "CHATHISTORY".to_string(),
+                code: "INVALID_TARGET".to_string(),
+                context: vec!["LATEST".to_string(), "#haku".to_string()],
+                description: "Messages could not be retrieved".to_string(),
+            }),
+            Reply::ChatHistoryTargets(ChatHistoryTargetsReply {
+                target: Target::User(Nick("ann".to_string())),
+                time: 1_700_000_000_123,
+            }),
+            Reply::Numeric(
+                NumericReply::new(
+                    Numeric::RplList,
+                    "tom",
+                    vec!["#haku".to_string(), "2".to_string()],
+                )
+                .with_text("Welcome to haku"),
+            ),
+            Reply::Numeric(NumericReply::new(Numeric::RplListEnd, "tom", vec![])),
+            Reply::error("*", ErrorType::NoSuchNick.about(["ann"])),
+            Reply::error("tom", ErrorType::UserNotInChannel.about(["ann", "#haku"])),
+            Reply::error("tom", ErrorType::NoTextToSend),
+            Reply::Quit(QuitReply {
+                message: QuitMsg {
+                    message: Some("Bye".to_string()),
+                },
+                sender: user(),
+            }),
+        ];
+        for reply in replies {
+            assert_eq!(reply.to_string().parse(), Ok(reply));
+        }
+
+        assert_eq!(
+            Reply::try_from(":nick1!ignored@127.0.0.1 PRIVMSG nick2 :How are you?"),
+            Ok(Reply::PrivMsg(PrivReply {
+                message: PrivMsg {
+                    target: Target::User(Nick("nick2".to_string())),
+                    message: "How are you?".to_string()
+                },
+                sender: Prefix::from("nick1!ignored@127.0.0.1")
+            }))
+        );
+        assert_eq!(
+            ":iris-server 482 tom #haku :You're not channel operator\n".parse(),
+            Ok(Reply::error(
+                "tom",
+                ErrorType::ChanOPrivsNeeded.about(["#haku"])
+            ))
+        );
+        assert!(Reply::from_str("PRIVMSG nick2 :no sender").is_err());
+        assert!(Reply::from_str(":iris-server 999 :Unknown").is_err());
+    }
+
+    fn nick() -> impl Strategy<Value = Nick> {
+        "[A-Za-z\\[\\]`_^{|}][A-Za-z0-9\\-\\[\\]`_^{|}]{0,8}".prop_map(Nick)
+    }
+
+    fn channel() -> impl Strategy<Value = Channel> {
+        "#[A-Za-z0-9\\-_]{1,20}".prop_map(Channel)
+    }
+
+    fn target() -> impl Strategy<Value = Target> {
+        prop_oneof![
+            nick().prop_map(Target::User),
+            channel().prop_map(Target::Channel)
+        ]
+    }
+
+    /// A middle argument: never empty, and without spaces or a leading `:`.
+    fn word() -> impl Strategy<Value = String> {
+        "[!-9;-~][!-~]{0,11}"
+    }
+
+    /// Free text, which can be anything but a line ending.
+    fn username() -> impl Strategy<Value = String> {
+        "[!-9;-?A-~][!-?A-~]{0,11}"
+    }
+
+    fn text() -> impl Strategy<Value = String> {
+        "[^\r\n\0]{0,40}"
+    }
+
+    fn ban() -> impl Strategy<Value = BanMsg> {
+        (
+            proptest::option::of(0..100_000u64),
+            "[a-z*?]{1,8}@[a-z0-9.*]{1,12}",
+            proptest::option::of(text()),
+        )
+            .prop_map(|(minutes, mask, reason)| BanMsg {
+                minutes,
+                mask,
+                reason,
+            })
+    }
+
+    fn message() -> impl Strategy<Value = Message> {
+        let mode = (target(), proptest::option::of("[+-][a-z]{1,4}"))
+            .prop_flat_map(|(target, modes)| {
+                let max_args = if modes.is_some() { 4 } else { 0 };
+                (
+                    Just(target),
+                    Just(modes),
+                    proptest::collection::vec(word(), 0..=max_args),
+                )
+            })
+            .prop_map(|(target, modes, args)| {
+                Message::Mode(ModeMsg {
+                    target,
+                    modes,
+                    args,
+                })
+            });
+        prop_oneof![
+            nick().prop_map(|nick| Message::Nick(NickMsg { nick })),
+            (username(), text()).prop_map(|(username, real_name)| Message::User(UserMsg {
+                username,
+                real_name
+            })),
+            (target(), text())
+                .prop_map(|(target, message)| Message::PrivMsg(PrivMsg { target, message })),
+            (target(), text())
+                .prop_map(|(target, message)| Message::Notice(NoticeMsg { target, message })),
+            text().prop_map(Message::Ping),
+            text().prop_map(Message::Pong),
+            channel().prop_map(|channel| Message::Join(JoinMsg { channel })),
+            channel().prop_map(|channel| Message::Part(PartMsg { channel })),
+            (channel(), proptest::option::of(text()))
+                .prop_map(|(channel, topic)| Message::Topic(TopicMsg { channel, topic })),
+            mode,
+            proptest::option::of("[^\r\n\0]{1,40}")
+                .prop_map(|message| Message::Away(AwayMsg { message })),
+            ("[A-Z]{1,4}", proptest::option::of(text()))
+                .prop_map(|(subcommand, args)| { Message::Cap(CapMsg { subcommand, args }) }),
+            proptest::option::of(channel())
+                .prop_map(|channel| Message::Names(NamesMsg { channel })),
+            text().prop_map(|mask| Message::Who(WhoMsg { mask })),
+            (
+                "[A-Z]{1,8}",
+                proptest::collection::vec(word(), 0..4),
+                proptest::option::of(text())
+            )
+                .prop_map(|(subcommand, mut args, last)| {
+                    args.extend(last);
+                    Message::ChatHistory(ChatHistoryMsg { subcommand, args })
+                }),
+            proptest::option::of(text()).prop_map(|message| Message::Quit(QuitMsg { message })),
+            (word(), word()).prop_map(|(name, password)| Message::Oper(OperMsg { name, password })),
+            ban().prop_map(Message::KLine),
+            ban().prop_map(Message::DLine),
+            "[a-z*?]{1,8}@[a-z0-9.*]{1,12}".prop_map(Message::UnKLine),
+            "[0-9]{1,3}\\.[0-9./]{1,11}".prop_map(Message::UnDLine),
+            "[a-zA-Z]".prop_map(Message::Stats),
+            nick().prop_map(Message::Whois),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_render_round_trip(message in message()) {
+            let rendered = message.to_string();
+            let parsed = ParsedMessage::try_from(UnparsedMessage {
+                message: &rendered,
+                sender_nick: Nick("Person".to_string()),
+                server_name: SERVER_NAME,
+            });
+            prop_assert_eq!(parsed.map(|p| p.message), Ok(message), "{:?}", rendered);
+        }
     }
 }
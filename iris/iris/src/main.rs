@@ -1,20 +1,17 @@
 use clap::Parser;
 use iris_lib::{
-    connect::{ConnectionError, ConnectionManager},
-    plugin::{create_plugin, parse_plugin},
-    types::{
-        Channel, ErrorType, JoinMsg, JoinReply, Message, MyMessage, Nick, ParsedMessage, PartMsg,
-        PartReply, PrivReply, QuitReply, Reply, Target, ThreadInfo, UnparsedMessage, WelcomeReply,
-        SERVER_NAME,
-    },
+    connect::ConnectionClass,
+    flood::FloodPolicy,
+    history::Retention,
+    server::Server,
+    types::{CaseMapping, NamePolicy, SERVER_NAME},
 };
-use log::{debug, error, info};
-use std::{
-    collections::{HashMap, HashSet},
-    net::IpAddr,
-    sync::mpsc::{self},
-    thread::{self},
+use log::{info, warn};
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
 };
+use std::{net::IpAddr, path::PathBuf, time::Duration};
 
 #[derive(Parser)]
 struct Arguments {
@@ -23,6 +20,124 @@ struct Arguments {
 
     #[clap(default_value = "6991")]
     port: u16,
+
+    /// Also accept clients over TLS on this port, which needs --tls-cert and --tls-key.
+    #[clap(long, requires_all = ["tls_cert", "tls_key"])]
+    tls_port: Option<u16>,
+
+    /// PEM file with the server's certificate chain, leaf first. Reloaded on SIGHUP.
+    #[clap(long)]
+    tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the server's certificate. Reloaded on SIGHUP.
+    #[clap(long)]
+    tls_key: Option<PathBuf>,
+
+    /// Directory where services keep their persistent data.
+    #[clap(long, default_value = "iris-data")]
+    data_dir: PathBuf,
+
+    /// Seconds an unidentified user may hold a registered nickname before being renamed.
+    #[clap(long, default_value = "30")]
+    nick_grace: u64,
+
+    /// How nicknames and channel names are compared: ascii, rfc1459 or strict-rfc1459.
+    #[clap(long, default_value = "rfc1459")]
+    casemapping: CaseMapping,
+
+    /// Which characters nicknames and channel names may contain: ascii, or utf8 for any script.
+    #[clap(long, default_value = "ascii")]
+    names: NamePolicy,
+
+    /// The longest nickname allowed, in characters.
+    #[clap(long, default_value = "9")]
+    nick_len: usize,
+
+    /// The longest channel name allowed, in characters.
+    #[clap(long, default_value = "200")]
+    channel_len: usize,
+
+    /// Messages kept for CHATHISTORY in each channel, 0 to keep none.
+    #[clap(long, default_value = "500")]
+    channel_history: usize,
+
+    /// Messages kept for CHATHISTORY between each pair of logged in users, 0 to keep none.
+    #[clap(long, default_value = "100")]
+    direct_history: usize,
+
+    /// Bytes that may wait to be sent to a client before it is disconnected.
+    #[clap(long, default_value = "1048576")]
+    sendq: usize,
+
+    /// Seconds a registered client may send nothing before it is sent a PING.
+    #[clap(long, default_value = "120")]
+    ping_frequency: u64,
+
+    /// Seconds a client has to answer a PING before it is disconnected.
+    #[clap(long, default_value = "60")]
+    ping_timeout: u64,
+
+    /// Seconds a client has to register before it is disconnected.
+    #[clap(long, default_value = "30")]
+    registration_timeout: u64,
+
+    /// Points a client may spend at once before being slowed down; most commands cost one.
+    #[clap(long, default_value = "10")]
+    flood_burst: u32,
+
+    /// Milliseconds each point a client spends takes to earn back.
+    #[clap(long, default_value = "500")]
+    flood_penalty: u64,
+
+    /// Bytes of commands that may be held back before a client is disconnected for flooding.
+    #[clap(long, default_value = "8192")]
+    flood_recvq: usize,
+
+    /// NickServ accounts never slowed down for flooding, e.g. bots. May be given more than once.
+    #[clap(long)]
+    flood_exempt: Vec<String>,
+
+    /// Connections allowed from a single IP address.
+    #[clap(long, default_value = "10")]
+    max_per_ip: usize,
+
+    /// Connections allowed from a single network: a /24 for IPv4, or a /64 for IPv6.
+    #[clap(long, default_value = "50")]
+    max_per_cidr: usize,
+
+    /// Connections allowed from a single IP address within the throttle window.
+    #[clap(long, default_value = "10")]
+    throttle: usize,
+
+    /// Seconds over which reconnects are counted for throttling.
+    #[clap(long, default_value = "10")]
+    throttle_window: u64,
+
+    /// An IRC operator, as NAME:PASSWORD for the OPER command. May be given more than once.
+    #[clap(long, value_parser = parse_oper)]
+    oper: Vec<(String, String)>,
+
+    /// An IRC operator who needs no password, as NAME:FINGERPRINT with the SHA-256 fingerprint
+    /// of their client certificate. May be given more than once.
+    #[clap(long, value_parser = parse_oper)]
+    oper_certfp: Vec<(String, String)>,
+
+    /// Threads serving client connections, by default one per CPU core.
+    #[clap(long)]
+    worker_threads: Option<usize>,
+
+    /// Seconds to wait for clients to be disconnected on SIGINT or SIGTERM before exiting anyway.
+    #[clap(long, default_value = "5")]
+    drain_timeout: u64,
+}
+
+fn parse_oper(oper: &str) -> Result<(String, String), String> {
+    match oper.split_once(':') {
+        Some((name, password)) if !name.is_empty() && !password.is_empty() => {
+            Ok((name.to_string(), password.to_string()))
+        }
+        _ => Err("expected NAME:PASSWORD or NAME:FINGERPRINT".to_string()),
+    }
 }
 
 fn sever(arguments: Arguments) {
@@ -30,305 +145,85 @@ fn sever(arguments: Arguments) {
         "Launching {} at {}:{}",
         SERVER_NAME, arguments.ip_address, arguments.port
     );
-    let mut connection_manager = ConnectionManager::launch(arguments.ip_address, arguments.port);
-    let (sender, receiver) = mpsc::channel::<(String, MyMessage)>(); //String for IP address + port
-    {
-        let sender = sender.clone(); // for create_plugin to work under borrow checker in the match statement
-        thread::spawn(move || {
-            let mut my_map: HashMap<String, ThreadInfo> = HashMap::new();
-            let mut channels: HashMap<Channel, HashSet<String>> = HashMap::new();
-            loop {
-                debug!("User Info: {:?}", my_map);
-                debug!("Channels: {:?}", channels);
-                let message = receiver.recv().unwrap();
-                let address = message.0;
-                let request = message.1;
-                match request {
-                    MyMessage::Request(request) => {
-                        let ThreadInfo {
-                            conn_write,
-                            nick,
-                            full_name,
-                        } = my_map.get_mut(&address).unwrap();
-                        let request = UnparsedMessage {
-                            sender_nick: match &nick {
-                                Some(nick) => nick.clone(),
-                                None => Nick("".to_string()),
-                            },
-                            message: &request,
-                        };
-                        if nick.is_none() && full_name.is_none() {
-                            let parsed_message = ParsedMessage::try_from(request);
-                            match parsed_message {
-                                Ok(request) => match request.message {
-                                    Message::Nick(name) => {
-                                        if my_map
-                                            .values()
-                                            .into_iter()
-                                            .any(|e| e.nick == Some(name.nick.clone()))
-                                        {
-                                            let conn_write =
-                                                &mut my_map.get_mut(&address).unwrap().conn_write;
-                                            conn_write
-                                                .write_message(&format!(
-                                                    "{}\n",
-                                                    ErrorType::NickCollision
-                                                ))
-                                                .unwrap();
-                                        } else {
-                                            let nick = &mut my_map.get_mut(&address).unwrap().nick;
-                                            *nick = Some(name.nick);
-                                        }
-                                    }
-                                    Message::Quit(_) => {
-                                        my_map.remove(&address).unwrap();
-                                    }
-                                    _ => {}
-                                },
-                                Err(e) => conn_write.write_message(&format!("{}\n", e)).unwrap(),
-                            }
-                        } else if nick.is_some() && full_name.is_none() {
-                            let parsed_message = ParsedMessage::try_from(request);
-                            match parsed_message {
-                                Ok(request) => match request.message {
-                                    Message::User(name) => {
-                                        let reply = Reply::Welcome(WelcomeReply {
-                                            target_nick: nick.as_ref().unwrap().clone(),
-                                            message: format!(
-                                                "Hi {}, welcome to IRC",
-                                                name.real_name
-                                            ),
-                                        });
-                                        conn_write.write_message(&reply.to_string()).unwrap();
-                                        *full_name = Some(name.real_name);
-                                    }
-                                    Message::Quit(_) => {
-                                        my_map.remove(&address).unwrap();
-                                    }
-                                    _ => {}
-                                },
-                                Err(e) => {
-                                    conn_write.write_message(&format!("{}\n", e)).unwrap();
-                                }
-                            }
-                        } else if nick.is_some() && full_name.is_some() {
-                            let parsed_message = ParsedMessage::try_from(request);
-                            match parsed_message {
-                                Ok(request) => match request.message {
-                                    Message::PrivMsg(msg) => match msg.target.clone() {
-                                        Target::Channel(target) => {
-                                            if !channels.contains_key(&target) {
-                                                conn_write
-                                                    .write_message(&format!(
-                                                        "{}\n",
-                                                        ErrorType::NoSuchChannel
-                                                    ))
-                                                    .unwrap();
-                                            } else {
-                                                let reply = Reply::PrivMsg(PrivReply {
-                                                    message: msg,
-                                                    sender_nick: nick.as_ref().unwrap().clone(),
-                                                });
-                                                let members = channels.get(&target).unwrap();
-                                                for member in members {
-                                                    let conn_write = &mut my_map
-                                                        .get_mut(member)
-                                                        .unwrap()
-                                                        .conn_write;
-                                                    conn_write
-                                                        .write_message(&reply.to_string())
-                                                        .unwrap();
-                                                }
-                                            }
-                                        }
-                                        Target::User(target) => {
-                                            if my_map
-                                                .values()
-                                                .any(|e| e.nick == Some(target.clone()))
-                                            {
-                                                let ThreadInfo {
-                                                    conn_write: _,
-                                                    nick,
-                                                    full_name: _,
-                                                } = my_map.get_mut(&address).unwrap();
-                                                let reply = Reply::PrivMsg(PrivReply {
-                                                    message: msg,
-                                                    sender_nick: nick.as_ref().unwrap().clone(),
-                                                });
-                                                let conn_write = &mut my_map
-                                                    .values_mut()
-                                                    .find(|e| e.nick == Some(target.clone()))
-                                                    .unwrap()
-                                                    .conn_write;
-                                                conn_write
-                                                    .write_message(&reply.to_string())
-                                                    .unwrap();
-                                            } else {
-                                                let conn_write = &mut my_map
-                                                    .get_mut(&address)
-                                                    .unwrap()
-                                                    .conn_write;
-                                                conn_write
-                                                    .write_message(&format!(
-                                                        "{}\n",
-                                                        ErrorType::NoSuchNick
-                                                    ))
-                                                    .unwrap()
-                                            }
-                                        }
-                                    },
-                                    Message::Ping(msg) => {
-                                        let reply = Reply::Pong(msg);
-                                        conn_write.write_message(&reply.to_string()).unwrap();
-                                    }
-                                    Message::Join(msg) => {
-                                        let reply = Reply::Join(JoinReply {
-                                            message: JoinMsg {
-                                                channel: msg.channel.clone(),
-                                            },
-                                            sender_nick: nick.as_ref().unwrap().clone(),
-                                        });
-                                        channels
-                                            .entry(msg.channel.clone())
-                                            .and_modify(|c| {
-                                                c.insert(address.clone());
-                                            })
-                                            .or_insert({
-                                                let mut initial = HashSet::new();
-                                                initial.insert(address.clone());
-                                                initial
-                                            });
-                                        let members = channels.get(&msg.channel).unwrap();
-                                        for member in members {
-                                            let conn_write =
-                                                &mut my_map.get_mut(member).unwrap().conn_write;
-                                            conn_write.write_message(&reply.to_string()).unwrap();
-                                        }
-                                    }
-                                    Message::Part(msg) => {
-                                        if !channels.contains_key(&msg.channel) {
-                                            conn_write
-                                                .write_message(&format!(
-                                                    "{}\n",
-                                                    ErrorType::NoSuchChannel
-                                                ))
-                                                .unwrap();
-                                        } else if channels
-                                            .get_mut(&msg.channel)
-                                            .unwrap()
-                                            .remove(&address)
-                                        {
-                                            let reply = Reply::Part(PartReply {
-                                                message: PartMsg {
-                                                    channel: msg.channel.clone(),
-                                                },
-                                                sender_nick: nick.as_ref().unwrap().clone(),
-                                            });
-                                            let members = channels.get(&msg.channel).unwrap();
-                                            for member in members {
-                                                let conn_write =
-                                                    &mut my_map.get_mut(member).unwrap().conn_write;
-                                                conn_write
-                                                    .write_message(&reply.to_string())
-                                                    .unwrap();
-                                            }
-                                        }
-                                    }
-                                    Message::Quit(msg) => {
-                                        let reply = Reply::Quit(QuitReply {
-                                            message: msg,
-                                            sender_nick: nick.as_ref().unwrap().clone(),
-                                        });
-                                        for (_, members) in channels
-                                            .iter_mut()
-                                            .filter(|(_, v)| v.contains(&address))
-                                        {
-                                            members.remove(&address);
-                                            for member in members.iter() {
-                                                let conn_write =
-                                                    &mut my_map.get_mut(member).unwrap().conn_write;
-                                                conn_write
-                                                    .write_message(&reply.to_string())
-                                                    .unwrap();
-                                            }
-                                        }
-                                        my_map.remove(&address).unwrap();
-                                    }
-                                    _ => {}
-                                },
-                                Err(e) => {
-                                    conn_write.write_message(&format!("{}\n", e)).unwrap();
-                                }
-                            }
-                        }
-                    }
-                    MyMessage::Est(ip, conn_write) => {
-                        my_map.insert(
-                            ip,
-                            ThreadInfo {
-                                conn_write,
-                                nick: None,
-                                full_name: None,
-                            },
-                        );
-                    }
-                    MyMessage::Plugin(plugin) => {
-                        let plugin_function = create_plugin(
-                            plugin,
-                            address,
-                            sender.clone(),
-                            &mut channels,
-                            &mut my_map,
-                        );
-                        plugin_function();
-                    }
-                }
+    let mut signals =
+        Signals::new([SIGINT, SIGTERM, SIGHUP]).expect("failed to register signal handlers");
+    let mut builder = Server::builder().listen((arguments.ip_address, arguments.port));
+    if let Some(threads) = arguments.worker_threads {
+        builder = builder.worker_threads(threads);
+    }
+    for account in arguments.flood_exempt {
+        builder = builder.flood_exempt(account);
+    }
+    for (name, password) in arguments.oper {
+        builder = builder.oper(name, password);
+    }
+    for (name, fingerprint) in arguments.oper_certfp {
+        builder = builder.oper_certfp(name, fingerprint);
+    }
+    if let (Some(cert), Some(key)) = (arguments.tls_cert, arguments.tls_key) {
+        builder = builder.tls(cert, key);
+    }
+    if let Some(port) = arguments.tls_port {
+        builder = builder.listen_tls((arguments.ip_address, port));
+    }
+    let server = builder
+        .data_dir(arguments.data_dir)
+        .nick_grace(Duration::from_secs(arguments.nick_grace))
+        .casemapping(arguments.casemapping)
+        .names(arguments.names)
+        .name_lengths(arguments.nick_len, arguments.channel_len)
+        .sendq(arguments.sendq)
+        .flood(FloodPolicy {
+            burst: arguments.flood_burst,
+            penalty: Duration::from_millis(arguments.flood_penalty),
+            recvq: arguments.flood_recvq,
+        })
+        .connection_class(ConnectionClass {
+            hosts: "*".to_string(),
+            ping_frequency: Duration::from_secs(arguments.ping_frequency),
+            ping_timeout: Duration::from_secs(arguments.ping_timeout),
+            registration_timeout: Duration::from_secs(arguments.registration_timeout),
+            max_per_ip: arguments.max_per_ip,
+            max_per_cidr: arguments.max_per_cidr,
+            throttle: arguments.throttle,
+            throttle_window: Duration::from_secs(arguments.throttle_window),
+            ..ConnectionClass::default()
+        })
+        .retention(Retention {
+            channel: arguments.channel_history,
+            direct: arguments.direct_history,
+        })
+        .build()
+        .unwrap_or_else(|e| {
+            panic!(
+                "failed to start {SERVER_NAME} at {}:{}: {e}",
+                arguments.ip_address, arguments.port
+            )
+        })
+        .spawn();
+    for signal in signals.forever() {
+        if signal == SIGHUP {
+            match server.reload_tls() {
+                Ok(()) => info!("Reloaded the TLS certificate and key"),
+                Err(e) => warn!("Failed to reload the TLS certificate and key: {e}"),
             }
-        });
-    }
-    loop {
-        let (mut conn_read, conn_write) = connection_manager.accept_new_connection();
-        info!("New connection from {}", conn_read.id());
-        let sender = sender.clone();
-        sender
-            .send((conn_read.id(), MyMessage::Est(conn_read.id(), conn_write)))
-            .unwrap();
-        {
-            thread::spawn(move || loop {
-                let message = match conn_read.read_message() {
-                    Ok(message) => message,
-                    Err(ConnectionError::ConnectionLost | ConnectionError::ConnectionClosed) => {
-                        sender
-                            .send((conn_read.id(), MyMessage::Request("QUIT".to_string())))
-                            .unwrap();
-                        debug!("Lost connection from {}.", conn_read.id());
-                        break;
-                    }
-                    Err(e) => {
-                        error!("{}", e);
-                        error!("Invalid message received... ignoring message.");
-                        continue;
-                    }
-                };
-                if message == "QUIT" || message.starts_with("QUIT ") {
-                    sender
-                        .send((conn_read.id(), MyMessage::Request(message)))
-                        .unwrap();
-                    break;
-                } else if message.starts_with("PLUGIN") {
-                    let rest: String = message.split("PLUGIN ").skip(1).collect();
-                    if let Some(plugin) = parse_plugin(rest) {
-                        sender
-                            .send((conn_read.id(), MyMessage::Plugin(plugin)))
-                            .unwrap();
-                    }
-                } else {
-                    sender
-                        .send((conn_read.id(), MyMessage::Request(message)))
-                        .unwrap();
-                }
-            });
+            continue;
         }
+        info!("Received signal {signal}, shutting down");
+        break;
+    }
+    let stats = server.queue_stats();
+    info!(
+        "Send queues peaked at {} bytes, {} clients exceeded their SendQ",
+        stats.peak_bytes(),
+        stats.sendq_exceeded()
+    );
+    server.shutdown();
+    if !server.join_timeout(Duration::from_secs(arguments.drain_timeout)) {
+        warn!(
+            "Clients were still being disconnected after {}s, exiting anyway",
+            arguments.drain_timeout
+        );
     }
 }
 
@@ -340,31 +235,56 @@ fn main() {
 
 #[cfg(test)]
 mod tests {
-    use crate::{sever, Arguments};
     use bufstream::BufStream;
+    use iris_lib::{
+        connect::ConnectionClass,
+        flood::FloodPolicy,
+        history::Retention,
+        server::{Server, ServerHandle},
+        tls::fingerprint,
+        types::{CaseMapping, NamePolicy, Nick, Prefix, PrivMsg, PrivReply, Reply, Target},
+    };
+    use rcgen::CertifiedKey;
+    use rustls::{
+        crypto::ring,
+        pki_types::{PrivateKeyDer, ServerName},
+        ClientConfig, ClientConnection, RootCertStore, StreamOwned,
+    };
     use serial_test::serial;
     use std::{
         io::{BufRead, Write},
-        net::{IpAddr, Ipv4Addr, TcpStream},
-        thread::{self, sleep},
+        net::{Ipv4Addr, SocketAddr, TcpStream},
+        path::Path,
+        sync::Arc,
+        thread::sleep,
         time::Duration,
     };
-    fn spawn() {
-        let arguments = Arguments {
-            ip_address: IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-            port: 6991,
-        };
-        {
-            thread::spawn(move || sever(arguments));
-        }
-        sleep(Duration::from_millis(200));
+
+    type TlsStream = BufStream<StreamOwned<ClientConnection, TcpStream>>;
+    fn spawn() -> ServerHandle {
+        Server::builder()
+            .listen((Ipv4Addr::LOCALHOST, 0))
+            .nick_grace(Duration::from_secs(1))
+            .flood(FloodPolicy {
+                burst: 1000,
+                ..FloodPolicy::default()
+            })
+            .casemapping(CaseMapping::Rfc1459)
+            .names(NamePolicy::Ascii)
+            .password_rounds(1000)
+            .retention(Retention {
+                channel: 10,
+                direct: 10,
+            })
+            .build()
+            .expect("failed to start the server")
+            .spawn()
     }
 
-    fn setup() -> (TcpStream, BufStream<TcpStream>) {
-        let ip_address = IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1));
-        let port = 6991;
-        let stream_read = TcpStream::connect((ip_address, 6991))
-            .expect(&format!("failed to connect to {ip_address}:{port}"));
+    fn setup(server: &ServerHandle) -> (TcpStream, BufStream<TcpStream>) {
+        let address = server.local_addr();
+        let stream_read = TcpStream::connect(address)
+            .unwrap_or_else(|_| panic!("failed to connect to {address}"));
         let stream_write = stream_read.try_clone().expect("failed to clone connection");
         let stream_read = BufStream::new(stream_read);
         (stream_write, stream_read)
@@ -383,6 +303,54 @@ mod tests {
         buf
     }
 
+    /// Writes a fresh self-signed certificate for `localhost` and its key to `dir`.
+    fn write_cert(dir: &Path) -> CertifiedKey {
+        let generated = rcgen::generate_simple_self_signed(vec!["localhost".to_string()]).unwrap();
+        std::fs::write(dir.join("cert.pem"), generated.cert.pem()).unwrap();
+        std::fs::write(dir.join("key.pem"), generated.key_pair.serialize_pem()).unwrap();
+        generated
+    }
+
+    /// Connects over TLS, trusting only the `server` certificate and presenting `client`, if any.
+    fn setup_tls(
+        address: SocketAddr,
+        server: &CertifiedKey,
+        client: Option<&CertifiedKey>,
+    ) -> TlsStream {
+        let mut roots = RootCertStore::empty();
+        roots.add(server.cert.der().clone()).unwrap();
+        let config = ClientConfig::builder_with_provider(Arc::new(ring::default_provider()))
+            .with_safe_default_protocol_versions()
+            .unwrap()
+            .with_root_certificates(roots);
+        let config = match client {
+            Some(client) => config
+                .with_client_auth_cert(
+                    vec![client.cert.der().clone()],
+                    PrivateKeyDer::Pkcs8(client.key_pair.serialize_der().into()),
+                )
+                .unwrap(),
+            None => config.with_no_client_auth(),
+        };
+        let name = ServerName::try_from("localhost").unwrap();
+        let connection = ClientConnection::new(Arc::new(config), name).unwrap();
+        let socket = TcpStream::connect(address).unwrap();
+        BufStream::new(StreamOwned::new(connection, socket))
+    }
+
+    fn command_tls(stream: &mut TlsStream, command: &str) {
+        stream
+            .write_all(format!("{}\r\n", command).as_bytes())
+            .and_then(|_| stream.flush())
+            .unwrap();
+    }
+
+    fn receive_tls(stream: &mut TlsStream) -> String {
+        let mut buf = String::new();
+        stream.read_line(&mut buf).unwrap();
+        buf
+    }
+
     fn register_user(
         nick: &str,
         stream_write: &mut TcpStream,
@@ -397,25 +365,32 @@ mod tests {
             &format!(":iris-server 001 {} :Hi {}, welcome to IRC", nick, nick),
             receive(stream_read).trim()
         );
+        assert_eq!(
+            &format!(
+                ":iris-server 005 {} CASEMAPPING=rfc1459 CHANNELLEN=200 CHATHISTORY=100 NICKLEN=9 :are supported by this server",
+                nick
+            ),
+            receive(stream_read).trim()
+        );
     }
 
     #[test]
     #[serial]
     fn single_client_registration() {
-        spawn();
-        let (mut stream_write, mut stream_read) = setup();
+        let server = spawn();
+        let (mut stream_write, mut stream_read) = setup(&server);
         register_user("nick", &mut stream_write, &mut stream_read);
     }
 
     #[test]
     #[serial]
     fn single_client_msg_self() {
-        spawn();
-        let (mut stream_write, mut stream_read) = setup();
+        let server = spawn();
+        let (mut stream_write, mut stream_read) = setup(&server);
         register_user("nick", &mut stream_write, &mut stream_read);
         command(&mut stream_write, "PRIVMSG nick :How are you?");
         assert_eq!(
-            ":nick PRIVMSG nick :How are you?",
+            ":nick!ignored@127.0.0.1 PRIVMSG nick :How are you?",
             receive(&mut stream_read).trim()
         );
     }
@@ -423,8 +398,8 @@ mod tests {
     #[test]
     #[serial]
     fn single_client_ping() {
-        spawn();
-        let (mut stream_write, mut stream_read) = setup();
+        let server = spawn();
+        let (mut stream_write, mut stream_read) = setup(&server);
         register_user("nick", &mut stream_write, &mut stream_read);
         command(&mut stream_write, "PING :Hello, world!");
         assert_eq!("PONG :Hello, world!", receive(&mut stream_read).trim());
@@ -433,8 +408,8 @@ mod tests {
     #[test]
     #[serial]
     fn single_client_quit() {
-        spawn();
-        let (mut stream_write, mut stream_read) = setup();
+        let server = spawn();
+        let (mut stream_write, mut stream_read) = setup(&server);
         register_user("nick", &mut stream_write, &mut stream_read);
         command(&mut stream_write, "QUIT");
     }
@@ -442,76 +417,395 @@ mod tests {
     #[test]
     #[serial]
     fn single_client_channel1() {
-        spawn();
-        let (mut stream_write, mut stream_read) = setup();
+        let server = spawn();
+        let (mut stream_write, mut stream_read) = setup(&server);
         register_user("nick", &mut stream_write, &mut stream_read);
         command(&mut stream_write, "JOIN #haku");
-        assert_eq!(":nick JOIN #haku", receive(&mut stream_read).trim());
+        assert_eq!(
+            ":nick!ignored@127.0.0.1 JOIN #haku",
+            receive(&mut stream_read).trim()
+        );
         command(&mut stream_write, "PART #haku");
     }
     #[test]
     #[serial]
     fn single_client_channel2() {
-        spawn();
-        let (mut stream_write, mut stream_read) = setup();
+        let server = spawn();
+        let (mut stream_write, mut stream_read) = setup(&server);
         register_user("nick", &mut stream_write, &mut stream_read);
         command(&mut stream_write, "JOIN #haku");
-        assert_eq!(":nick JOIN #haku", receive(&mut stream_read).trim());
+        assert_eq!(
+            ":nick!ignored@127.0.0.1 JOIN #haku",
+            receive(&mut stream_read).trim()
+        );
         command(&mut stream_write, "PRIVMSG #haku Hello,world!");
         assert_eq!(
-            ":nick PRIVMSG #haku :Hello,world!",
+            ":nick!ignored@127.0.0.1 PRIVMSG #haku :Hello,world!",
+            receive(&mut stream_read).trim()
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn server_shutdown() {
+        let server = spawn();
+        assert_ne!(0, server.local_addr().port());
+        let (mut stream_write, mut stream_read) = setup(&server);
+        register_user("nick", &mut stream_write, &mut stream_read);
+        assert!(server.queue_stats().peak_bytes() > 0);
+        server.shutdown();
+        assert_eq!(
+            "ERROR :Closing Link (server shutting down)",
+            receive(&mut stream_read).trim()
+        );
+        assert_eq!("", receive(&mut stream_read));
+        assert!(server.join_timeout(Duration::from_secs(1)));
+    }
+
+    #[test]
+    #[serial]
+    fn dropped_sockets_during_broadcast() {
+        let server = spawn();
+        let (mut stream_write, mut stream_read) = setup(&server);
+        register_user("talker", &mut stream_write, &mut stream_read);
+        command(&mut stream_write, "JOIN #haku");
+        receive(&mut stream_read);
+        let mut others = Vec::new();
+        for i in 0..8 {
+            let (mut other_write, mut other_read) = setup(&server);
+            register_user(&format!("gone{i}"), &mut other_write, &mut other_read);
+            command(&mut other_write, "JOIN #haku");
+            receive(&mut other_read);
+            receive(&mut stream_read);
+            others.push(other_write);
+        }
+        // Kill every other member's socket while the channel is being written to.
+        for (i, other) in others.into_iter().enumerate() {
+            command(&mut stream_write, &format!("PRIVMSG #haku :message {i}"));
+            other.shutdown(std::net::Shutdown::Both).unwrap();
+        }
+        for i in 0..100 {
+            command(&mut stream_write, &format!("PRIVMSG #haku :after {i}"));
+        }
+        command(&mut stream_write, "PING :alive");
+        let mut quits = 0;
+        loop {
+            let line = receive(&mut stream_read);
+            if line.trim() == "PONG :alive" {
+                break;
+            }
+            if line.contains(" QUIT ") {
+                quits += 1;
+            }
+        }
+        while quits < 8 {
+            assert!(receive(&mut stream_read).contains(" QUIT "));
+            quits += 1;
+        }
+        command(&mut stream_write, "NAMES #haku");
+        assert_eq!(
+            ":iris-server 353 talker = #haku :@talker",
             receive(&mut stream_read).trim()
         );
+        let (mut stream_write2, mut stream_read2) = setup(&server);
+        register_user("newcomer", &mut stream_write2, &mut stream_read2);
+    }
+
+    #[test]
+    #[serial]
+    fn ping_and_registration_timeouts() {
+        let server = Server::builder()
+            .listen((Ipv4Addr::LOCALHOST, 0))
+            .connection_class(ConnectionClass {
+                hosts: "127.0.0.*".to_string(),
+                ping_frequency: Duration::from_secs(1),
+                ping_timeout: Duration::from_secs(2),
+                registration_timeout: Duration::from_secs(2),
+                ..ConnectionClass::default()
+            })
+            .build()
+            .expect("failed to start the server")
+            .spawn();
+        let (mut stream_write1, mut stream_read1) = setup(&server);
+        register_user("nick1", &mut stream_write1, &mut stream_read1);
+        let (mut stream_write2, mut stream_read2) = setup(&server);
+        register_user("nick2", &mut stream_write2, &mut stream_read2);
+        let (_stream_write3, mut stream_read3) = setup(&server);
+        assert_eq!("PING :iris-server", receive(&mut stream_read1).trim());
+        assert_eq!("PING :iris-server", receive(&mut stream_read2).trim());
+        command(&mut stream_write1, "PONG :iris-server");
+        assert_eq!(
+            "ERROR :Closing Link (Registration timeout)",
+            receive(&mut stream_read3).trim()
+        );
+        assert_eq!("", receive(&mut stream_read3));
+        assert_eq!(
+            "ERROR :Closing Link (Ping timeout)",
+            receive(&mut stream_read2).trim()
+        );
+        assert_eq!("", receive(&mut stream_read2));
+        // The first client answered, so it is just pinged again.
+        assert_eq!("PING :iris-server", receive(&mut stream_read1).trim());
+        command(&mut stream_write1, "PING :still here");
+        assert_eq!("PONG :still here", receive(&mut stream_read1).trim());
+    }
+
+    #[test]
+    #[serial]
+    fn flood_protection() {
+        let server = Server::builder()
+            .listen((Ipv4Addr::LOCALHOST, 0))
+            .flood(FloodPolicy {
+                burst: 5,
+                penalty: Duration::from_millis(100),
+                recvq: 400,
+            })
+            .flood_exempt("botty")
+            .password_rounds(1000)
+            .build()
+            .expect("failed to start the server")
+            .spawn();
+        let (mut stream_write1, mut stream_read1) = setup(&server);
+        register_user("nick1", &mut stream_write1, &mut stream_read1);
+        let (mut stream_write2, mut stream_read2) = setup(&server);
+        register_user("botty", &mut stream_write2, &mut stream_read2);
+        command(&mut stream_write2, "PRIVMSG NickServ :REGISTER hunter2");
+        receive(&mut stream_read2);
+        // Past its burst, a client is slowed down rather than dropped.
+        let start = std::time::Instant::now();
+        for i in 0..10 {
+            command(&mut stream_write1, &format!("PING :{i}"));
+        }
+        for i in 0..10 {
+            assert_eq!(format!("PONG :{i}"), receive(&mut stream_read1).trim());
+        }
+        assert!(start.elapsed() >= Duration::from_millis(400));
+        // An exempt account can send as much as it likes.
+        let burst = (0..100)
+            .map(|i| format!("PING :{i}\r\n"))
+            .collect::<String>();
+        stream_write2.write_all(burst.as_bytes()).unwrap();
+        for i in 0..100 {
+            assert_eq!(format!("PONG :{i}"), receive(&mut stream_read2).trim());
+        }
+        // Everyone else is disconnected once too much is held back.
+        stream_write1.write_all(burst.as_bytes()).unwrap();
+        let mut line = receive(&mut stream_read1);
+        while line.starts_with("PONG") {
+            line = receive(&mut stream_read1);
+        }
+        assert_eq!("ERROR :Closing Link (Excess Flood)", line.trim());
+    }
+
+    #[test]
+    #[serial]
+    fn tls_listener() {
+        let dir = std::env::temp_dir().join(format!("iris-tls-listener-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let server_cert = write_cert(&dir);
+        let server = Server::builder()
+            .listen((Ipv4Addr::LOCALHOST, 0))
+            .listen_tls((Ipv4Addr::LOCALHOST, 0))
+            .tls(dir.join("cert.pem"), dir.join("key.pem"))
+            .build()
+            .expect("failed to start the server")
+            .spawn();
+        let tls_address = server.local_addrs()[1];
+        // Plaintext clients are still welcome on the other listener.
+        let (mut stream_write1, mut stream_read1) = setup(&server);
+        register_user("nick1", &mut stream_write1, &mut stream_read1);
+        let client_cert = rcgen::generate_simple_self_signed(vec!["nick2".to_string()]).unwrap();
+        let mut stream2 = setup_tls(tls_address, &server_cert, Some(&client_cert));
+        command_tls(&mut stream2, "NICK nick2");
+        command_tls(&mut stream2, "USER ignored ignored ignored nick2");
+        assert_eq!(
+            ":iris-server 001 nick2 :Hi nick2, welcome to IRC",
+            receive_tls(&mut stream2).trim()
+        );
+        receive_tls(&mut stream2);
+        command_tls(&mut stream2, "MODE nick2");
+        assert_eq!(
+            ":iris-server 221 nick2 +Z",
+            receive_tls(&mut stream2).trim()
+        );
+        command_tls(&mut stream2, "WHOIS nick2");
+        let whois = (0..5)
+            .map(|_| receive_tls(&mut stream2).trim().to_string())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            whois[2..],
+            [
+                ":iris-server 671 nick2 nick2 :is using a secure connection".to_string(),
+                format!(
+                    ":iris-server 276 nick2 nick2 :has client certificate fingerprint {}",
+                    fingerprint(client_cert.cert.der())
+                ),
+                ":iris-server 318 nick2 nick2 :End of /WHOIS list".to_string(),
+            ]
+        );
+        // A renewed certificate is presented to the clients connecting after a reload.
+        let renewed = write_cert(&dir);
+        server.reload_tls().unwrap();
+        let mut stream3 = setup_tls(tls_address, &renewed, None);
+        command_tls(&mut stream3, "NICK nick3");
+        command_tls(&mut stream3, "USER ignored ignored ignored nick3");
+        assert_eq!(
+            ":iris-server 001 nick3 :Hi nick3, welcome to IRC",
+            receive_tls(&mut stream3).trim()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[serial]
+    fn connection_limits_and_bans() {
+        let server = Server::builder()
+            .listen((Ipv4Addr::LOCALHOST, 0))
+            .connection_class(ConnectionClass {
+                max_per_ip: 2,
+                ..ConnectionClass::default()
+            })
+            .oper("admin", "hunter2")
+            .build()
+            .expect("failed to start the server")
+            .spawn();
+        let (mut stream_write1, mut stream_read1) = setup(&server);
+        register_user("nick1", &mut stream_write1, &mut stream_read1);
+        let (mut stream_write2, mut stream_read2) = setup(&server);
+        register_user("nick2", &mut stream_write2, &mut stream_read2);
+        let (_, mut stream_read3) = setup(&server);
+        assert_eq!(
+            "ERROR :Closing Link (Too many connections from your host)",
+            receive(&mut stream_read3).trim()
+        );
+        command(&mut stream_write1, "OPER admin hunter2");
+        assert_eq!(
+            ":iris-server 381 nick1 :You are now an IRC operator",
+            receive(&mut stream_read1).trim()
+        );
+        command(&mut stream_write1, "KLINE 1 ignored@127.0.0.1 :Testing");
+        assert_eq!(
+            "ERROR :Closing Link (K-lined: Testing)",
+            receive(&mut stream_read2).trim()
+        );
+        // The operator is caught by their own K-line too.
+        assert_eq!(
+            ":iris-server NOTICE nick1 :Added K-line for ignored@127.0.0.1.",
+            receive(&mut stream_read1).trim()
+        );
+        assert_eq!(
+            "ERROR :Closing Link (K-lined: Testing)",
+            receive(&mut stream_read1).trim()
+        );
     }
 
     #[test]
     #[serial]
     fn multi_client_registration() {
-        spawn();
-        let (mut stream_write1, mut stream_read1) = setup();
+        let server = spawn();
+        let (mut stream_write1, mut stream_read1) = setup(&server);
         register_user("nick1", &mut stream_write1, &mut stream_read1);
-        let (mut stream_write2, mut stream_read2) = setup();
+        let (mut stream_write2, mut stream_read2) = setup(&server);
         register_user("nick2", &mut stream_write2, &mut stream_read2);
     }
 
+    #[test]
+    #[serial]
+    fn registration_errors() {
+        let server = spawn();
+        let (mut stream_write, mut stream_read) = setup(&server);
+        command(&mut stream_write, "JOIN #haku");
+        assert_eq!(
+            ":iris-server 451 * :You have not registered",
+            receive(&mut stream_read).trim()
+        );
+        command(&mut stream_write, "user ignored 0 * :Olive O");
+        command(&mut stream_write, "FROB");
+        assert_eq!(
+            ":iris-server 451 * :You have not registered",
+            receive(&mut stream_read).trim()
+        );
+        command(&mut stream_write, "nick olive");
+        assert_eq!(
+            ":iris-server 001 olive :Hi Olive O, welcome to IRC",
+            receive(&mut stream_read).trim()
+        );
+        receive(&mut stream_read);
+        command(&mut stream_write, "USER olive 0 * :Olive O");
+        assert_eq!(
+            ":iris-server 462 olive :You may not reregister",
+            receive(&mut stream_read).trim()
+        );
+    }
+
     #[test]
     #[serial]
     fn multi_client_msg() {
-        spawn();
-        let (mut stream_write1, mut stream_read1) = setup();
+        let server = spawn();
+        let (mut stream_write1, mut stream_read1) = setup(&server);
         register_user("nick1", &mut stream_write1, &mut stream_read1);
-        let (mut stream_write2, mut stream_read2) = setup();
+        let (mut stream_write2, mut stream_read2) = setup(&server);
         register_user("nick2", &mut stream_write2, &mut stream_read2);
         command(&mut stream_write1, "PRIVMSG nick2 :How are you?");
         assert_eq!(
-            ":nick1 PRIVMSG nick2 :How are you?",
+            ":nick1!ignored@127.0.0.1 PRIVMSG nick2 :How are you?",
             receive(&mut stream_read2).trim()
         );
+        command(&mut stream_write2, "PRIVMSG nick1 :Good, you?");
+        assert_eq!(
+            receive(&mut stream_read1).parse(),
+            Ok(Reply::PrivMsg(PrivReply {
+                message: PrivMsg {
+                    target: Target::User(Nick("nick1".to_string())),
+                    message: "Good, you?".to_string()
+                },
+                sender: Prefix::from("nick2!ignored@127.0.0.1")
+            }))
+        );
+        command(&mut stream_write1, "PRIVMSG nick3 :Anyone there?");
+        assert_eq!(
+            ":iris-server 401 nick1 nick3 :No such nick/channel",
+            receive(&mut stream_read1).trim()
+        );
+        command(&mut stream_write1, "FROB #haku");
+        assert_eq!(
+            ":iris-server 421 nick1 FROB :Unknown command",
+            receive(&mut stream_read1).trim()
+        );
     }
 
     #[test]
     #[serial]
     fn multi_client_channel() {
-        spawn();
-        let (mut stream_write1, mut stream_read1) = setup();
+        let server = spawn();
+        let (mut stream_write1, mut stream_read1) = setup(&server);
         register_user("nick1", &mut stream_write1, &mut stream_read1);
-        let (mut stream_write2, mut stream_read2) = setup();
+        let (mut stream_write2, mut stream_read2) = setup(&server);
         register_user("nick2", &mut stream_write2, &mut stream_read2);
 
         command(&mut stream_write1, "JOIN #haku");
-        assert_eq!(":nick1 JOIN #haku", receive(&mut stream_read1).trim());
+        assert_eq!(
+            ":nick1!ignored@127.0.0.1 JOIN #haku",
+            receive(&mut stream_read1).trim()
+        );
 
         command(&mut stream_write2, "JOIN #haku");
-        assert_eq!(":nick2 JOIN #haku", receive(&mut stream_read1).trim());
-        assert_eq!(":nick2 JOIN #haku", receive(&mut stream_read2).trim());
+        assert_eq!(
+            ":nick2!ignored@127.0.0.1 JOIN #haku",
+            receive(&mut stream_read1).trim()
+        );
+        assert_eq!(
+            ":nick2!ignored@127.0.0.1 JOIN #haku",
+            receive(&mut stream_read2).trim()
+        );
 
         command(&mut stream_write1, "PRIVMSG #haku Hello,world!");
         assert_eq!(
-            ":nick1 PRIVMSG #haku :Hello,world!",
+            ":nick1!ignored@127.0.0.1 PRIVMSG #haku :Hello,world!",
             receive(&mut stream_read1).trim()
         );
         assert_eq!(
-            ":nick1 PRIVMSG #haku :Hello,world!",
+            ":nick1!ignored@127.0.0.1 PRIVMSG #haku :Hello,world!",
             receive(&mut stream_read2).trim()
         );
     }
@@ -519,20 +813,506 @@ mod tests {
     #[test]
     #[serial]
     fn multi_client_quit() {
-        spawn();
-        let (mut stream_write1, mut stream_read1) = setup();
+        let server = spawn();
+        let (mut stream_write1, mut stream_read1) = setup(&server);
         register_user("nick1", &mut stream_write1, &mut stream_read1);
-        let (mut stream_write2, mut stream_read2) = setup();
+        let (mut stream_write2, mut stream_read2) = setup(&server);
         register_user("nick2", &mut stream_write2, &mut stream_read2);
 
         command(&mut stream_write1, "JOIN #haku");
-        assert_eq!(":nick1 JOIN #haku", receive(&mut stream_read1).trim());
+        assert_eq!(
+            ":nick1!ignored@127.0.0.1 JOIN #haku",
+            receive(&mut stream_read1).trim()
+        );
 
         command(&mut stream_write2, "JOIN #haku");
-        assert_eq!(":nick2 JOIN #haku", receive(&mut stream_read1).trim());
-        assert_eq!(":nick2 JOIN #haku", receive(&mut stream_read2).trim());
+        assert_eq!(
+            ":nick2!ignored@127.0.0.1 JOIN #haku",
+            receive(&mut stream_read1).trim()
+        );
+        assert_eq!(
+            ":nick2!ignored@127.0.0.1 JOIN #haku",
+            receive(&mut stream_read2).trim()
+        );
+
+        command(&mut stream_write1, "QUIT");
+        assert_eq!(
+            ":nick1!ignored@127.0.0.1 QUIT :nick1",
+            receive(&mut stream_read2).trim()
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn nickserv_register_identify() {
+        let server = spawn();
+        let (mut stream_write1, mut stream_read1) = setup(&server);
+        register_user("alice", &mut stream_write1, &mut stream_read1);
+        command(&mut stream_write1, "PRIVMSG NickServ :REGISTER hunter2");
+        assert_eq!(
+            ":NickServ!NickServ@iris-server NOTICE alice :Nickname alice registered. You are now identified.",
+            receive(&mut stream_read1).trim()
+        );
+        let (mut stream_write2, mut stream_read2) = setup(&server);
+        register_user("bob", &mut stream_write2, &mut stream_read2);
+        command(&mut stream_write2, "PRIVMSG NickServ :IDENTIFY alice wrong");
+        assert_eq!(
+            ":NickServ!NickServ@iris-server NOTICE bob :Invalid password.",
+            receive(&mut stream_read2).trim()
+        );
+        command(&mut stream_write2, "PRIVMSG NickServ :GHOST alice hunter2");
+        assert_eq!(
+            "ERROR :Closing Link (GHOST command used by bob)",
+            receive(&mut stream_read1).trim()
+        );
+        assert_eq!(
+            ":NickServ!NickServ@iris-server NOTICE bob :alice has been ghosted.",
+            receive(&mut stream_read2).trim()
+        );
+        command(&mut stream_write2, "NICK alice");
+        assert_eq!(
+            ":bob!ignored@127.0.0.1 NICK alice",
+            receive(&mut stream_read2).trim()
+        );
+        assert!(receive(&mut stream_read2).contains("This nickname is registered."));
+        command(&mut stream_write2, "PRIVMSG NickServ :IDENTIFY hunter2");
+        assert_eq!(
+            ":NickServ!NickServ@iris-server NOTICE alice :You are now identified for alice.",
+            receive(&mut stream_read2).trim()
+        );
+        command(&mut stream_write2, "PRIVMSG NickServ :DROP hunter2");
+        assert_eq!(
+            ":NickServ!NickServ@iris-server NOTICE alice :Account alice has been dropped.",
+            receive(&mut stream_read2).trim()
+        );
+    }
 
+    #[test]
+    #[serial]
+    fn nickserv_enforce() {
+        let server = spawn();
+        let (mut stream_write1, mut stream_read1) = setup(&server);
+        register_user("carol", &mut stream_write1, &mut stream_read1);
+        command(&mut stream_write1, "PRIVMSG NickServ :REGISTER secret");
+        receive(&mut stream_read1);
+        command(&mut stream_write1, "NICK dave");
+        assert_eq!(
+            ":carol!ignored@user/carol NICK dave",
+            receive(&mut stream_read1).trim()
+        );
+        command(&mut stream_write1, "NICK carol");
+        assert_eq!(
+            ":dave!ignored@user/carol NICK carol",
+            receive(&mut stream_read1).trim()
+        );
+        // Still identified, so no warning is sent.
+        command(&mut stream_write1, "PING :identified");
+        assert_eq!("PONG :identified", receive(&mut stream_read1).trim());
         command(&mut stream_write1, "QUIT");
-        assert_eq!(":nick1 QUIT :nick1", receive(&mut stream_read2).trim());
+        sleep(Duration::from_millis(100));
+
+        let (mut stream_write2, mut stream_read2) = setup(&server);
+        register_user("carol", &mut stream_write2, &mut stream_read2);
+        assert!(receive(&mut stream_read2).contains("This nickname is registered."));
+        let rename = receive(&mut stream_read2);
+        assert!(
+            rename.starts_with(":carol!ignored@127.0.0.1 NICK Guest"),
+            "{rename}"
+        );
+        assert!(receive(&mut stream_read2).contains("You did not identify in time"));
+    }
+
+    #[test]
+    #[serial]
+    fn chanserv_register_reapply() {
+        let server = spawn();
+        let (mut stream_write1, mut stream_read1) = setup(&server);
+        register_user("erin", &mut stream_write1, &mut stream_read1);
+        command(&mut stream_write1, "PRIVMSG NickServ :REGISTER pw1");
+        receive(&mut stream_read1);
+        let (mut stream_write2, mut stream_read2) = setup(&server);
+        register_user("frank", &mut stream_write2, &mut stream_read2);
+        command(&mut stream_write2, "PRIVMSG NickServ :REGISTER pw2");
+        receive(&mut stream_read2);
+
+        command(&mut stream_write1, "JOIN #team");
+        assert_eq!(
+            ":erin!ignored@user/erin JOIN #team",
+            receive(&mut stream_read1).trim()
+        );
+        command(&mut stream_write1, "TOPIC #team :Team stuff");
+        assert_eq!(
+            ":erin!ignored@user/erin TOPIC #team :Team stuff",
+            receive(&mut stream_read1).trim()
+        );
+        command(&mut stream_write1, "MODE #team +t");
+        assert_eq!(
+            ":erin!ignored@user/erin MODE #team +t",
+            receive(&mut stream_read1).trim()
+        );
+        command(&mut stream_write1, "PRIVMSG ChanServ :REGISTER #team");
+        assert_eq!(
+            ":ChanServ!ChanServ@iris-server NOTICE erin :Channel #team is now registered to erin.",
+            receive(&mut stream_read1).trim()
+        );
+        command(
+            &mut stream_write1,
+            "PRIVMSG ChanServ :ACCESS #team ADD frank voice",
+        );
+        assert_eq!(
+            ":ChanServ!ChanServ@iris-server NOTICE erin :frank now has voice access to #team.",
+            receive(&mut stream_read1).trim()
+        );
+        command(&mut stream_write1, "PART #team");
+        command(&mut stream_write1, "PING :parted");
+        assert_eq!("PONG :parted", receive(&mut stream_read1).trim());
+
+        command(&mut stream_write2, "JOIN #team");
+        assert_eq!(
+            ":frank!ignored@user/frank JOIN #team",
+            receive(&mut stream_read2).trim()
+        );
+        assert_eq!(
+            ":iris-server 332 frank #team :Team stuff",
+            receive(&mut stream_read2).trim()
+        );
+        assert_eq!(
+            ":ChanServ!ChanServ@iris-server MODE #team +v frank",
+            receive(&mut stream_read2).trim()
+        );
+        command(&mut stream_write2, "MODE #team");
+        assert_eq!(
+            ":iris-server 324 frank #team +t",
+            receive(&mut stream_read2).trim()
+        );
+        command(&mut stream_write2, "TOPIC #team :Mine now");
+        assert_eq!(
+            ":iris-server 482 frank #team :You're not channel operator",
+            receive(&mut stream_read2).trim()
+        );
+
+        command(&mut stream_write1, "JOIN #team");
+        assert_eq!(
+            ":erin!ignored@user/erin JOIN #team",
+            receive(&mut stream_read2).trim()
+        );
+        assert_eq!(
+            ":ChanServ!ChanServ@iris-server MODE #team +o erin",
+            receive(&mut stream_read2).trim()
+        );
+        assert_eq!(
+            ":erin!ignored@user/erin JOIN #team",
+            receive(&mut stream_read1).trim()
+        );
+        assert_eq!(
+            ":iris-server 332 erin #team :Team stuff",
+            receive(&mut stream_read1).trim()
+        );
+        assert_eq!(
+            ":ChanServ!ChanServ@iris-server MODE #team +o erin",
+            receive(&mut stream_read1).trim()
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn capability_notifications() {
+        let server = spawn();
+        let (mut stream_write1, mut stream_read1) = setup(&server);
+        command(&mut stream_write1, "CAP LS 302");
+        command(&mut stream_write1, "NICK gina");
+        command(&mut stream_write1, "USER gina 0 * :Gina G");
+        assert_eq!(
+            ":iris-server CAP * LS :account-notify away-notify extended-join chghost multi-prefix userhost-in-names batch server-time message-tags draft/chathistory labeled-response",
+            receive(&mut stream_read1).trim()
+        );
+        command(&mut stream_write1, "CAP REQ :account-notify bogus");
+        assert_eq!(
+            ":iris-server CAP gina NAK :account-notify bogus",
+            receive(&mut stream_read1).trim()
+        );
+        command(
+            &mut stream_write1,
+            "CAP REQ :account-notify away-notify extended-join chghost",
+        );
+        assert_eq!(
+            ":iris-server CAP gina ACK :account-notify away-notify extended-join chghost",
+            receive(&mut stream_read1).trim()
+        );
+        command(&mut stream_write1, "CAP END");
+        assert_eq!(
+            ":iris-server 001 gina :Hi Gina G, welcome to IRC",
+            receive(&mut stream_read1).trim()
+        );
+        receive(&mut stream_read1);
+        command(&mut stream_write1, "JOIN #caps");
+        assert_eq!(
+            ":gina!gina@127.0.0.1 JOIN #caps * :Gina G",
+            receive(&mut stream_read1).trim()
+        );
+
+        let (mut stream_write2, mut stream_read2) = setup(&server);
+        register_user("hank", &mut stream_write2, &mut stream_read2);
+        command(&mut stream_write2, "AWAY :lunch");
+        assert_eq!(
+            ":iris-server 306 hank :You have been marked as being away",
+            receive(&mut stream_read2).trim()
+        );
+        command(&mut stream_write2, "JOIN #caps");
+        assert_eq!(
+            ":hank!ignored@127.0.0.1 JOIN #caps",
+            receive(&mut stream_read2).trim()
+        );
+        assert_eq!(
+            ":hank!ignored@127.0.0.1 JOIN #caps * :hank",
+            receive(&mut stream_read1).trim()
+        );
+        assert_eq!(
+            ":hank!ignored@127.0.0.1 AWAY :lunch",
+            receive(&mut stream_read1).trim()
+        );
+
+        command(&mut stream_write2, "PRIVMSG NickServ :REGISTER pw");
+        receive(&mut stream_read2);
+        assert_eq!(
+            ":hank!ignored@127.0.0.1 ACCOUNT hank",
+            receive(&mut stream_read1).trim()
+        );
+        assert_eq!(
+            ":hank!ignored@127.0.0.1 CHGHOST ignored user/hank",
+            receive(&mut stream_read1).trim()
+        );
+
+        command(&mut stream_write1, "PRIVMSG hank :are you there?");
+        assert_eq!(
+            ":iris-server 301 gina hank :lunch",
+            receive(&mut stream_read1).trim()
+        );
+        assert_eq!(
+            ":gina!gina@127.0.0.1 PRIVMSG hank :are you there?",
+            receive(&mut stream_read2).trim()
+        );
+        command(&mut stream_write2, "AWAY");
+        assert_eq!(
+            ":iris-server 305 hank :You are no longer marked as being away",
+            receive(&mut stream_read2).trim()
+        );
+        assert_eq!(
+            ":hank!ignored@user/hank AWAY",
+            receive(&mut stream_read1).trim()
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn names_and_who() {
+        let server = spawn();
+        let (mut stream_write1, mut stream_read1) = setup(&server);
+        register_user("ivan", &mut stream_write1, &mut stream_read1);
+        let (mut stream_write2, mut stream_read2) = setup(&server);
+        command(
+            &mut stream_write2,
+            "CAP REQ :multi-prefix userhost-in-names",
+        );
+        command(&mut stream_write2, "NICK judy");
+        command(&mut stream_write2, "USER judy 0 * :Judy J");
+        command(&mut stream_write2, "CAP END");
+        assert_eq!(
+            ":iris-server CAP * ACK :multi-prefix userhost-in-names",
+            receive(&mut stream_read2).trim()
+        );
+        receive(&mut stream_read2);
+        receive(&mut stream_read2);
+
+        command(&mut stream_write1, "JOIN #names");
+        receive(&mut stream_read1);
+        command(&mut stream_write2, "JOIN #names");
+        receive(&mut stream_read1);
+        receive(&mut stream_read2);
+        command(&mut stream_write1, "MODE #names +v ivan");
+        receive(&mut stream_read1);
+        receive(&mut stream_read2);
+
+        command(&mut stream_write1, "NAMES #names");
+        assert_eq!(
+            ":iris-server 353 ivan = #names :@ivan judy",
+            receive(&mut stream_read1).trim()
+        );
+        assert_eq!(
+            ":iris-server 366 ivan #names :End of /NAMES list",
+            receive(&mut stream_read1).trim()
+        );
+        command(&mut stream_write2, "NAMES #names");
+        assert_eq!(
+            ":iris-server 353 judy = #names :@+ivan!ignored@127.0.0.1 judy!judy@127.0.0.1",
+            receive(&mut stream_read2).trim()
+        );
+        receive(&mut stream_read2);
+
+        command(&mut stream_write2, "WHO #names");
+        assert_eq!(
+            ":iris-server 352 judy #names ignored 127.0.0.1 iris-server ivan H@+ :0 ivan",
+            receive(&mut stream_read2).trim()
+        );
+        assert_eq!(
+            ":iris-server 352 judy #names judy 127.0.0.1 iris-server judy H :0 Judy J",
+            receive(&mut stream_read2).trim()
+        );
+        assert_eq!(
+            ":iris-server 315 judy #names :End of WHO list",
+            receive(&mut stream_read2).trim()
+        );
+        command(&mut stream_write1, "WHO j*");
+        assert_eq!(
+            ":iris-server 352 ivan * judy 127.0.0.1 iris-server judy H :0 Judy J",
+            receive(&mut stream_read1).trim()
+        );
+        assert_eq!(
+            ":iris-server 315 ivan j* :End of WHO list",
+            receive(&mut stream_read1).trim()
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn chathistory_replay() {
+        let server = spawn();
+        let (mut stream_write1, mut stream_read1) = setup(&server);
+        register_user("kate", &mut stream_write1, &mut stream_read1);
+        command(&mut stream_write1, "JOIN #haku");
+        receive(&mut stream_read1);
+        command(&mut stream_write1, "PRIVMSG #haku :anyone here?");
+        receive(&mut stream_read1);
+
+        let (mut stream_write2, mut stream_read2) = setup(&server);
+        command(&mut stream_write2, "CAP REQ :batch draft/chathistory");
+        command(&mut stream_write2, "NICK leo");
+        command(&mut stream_write2, "USER leo 0 * :Leo L");
+        command(&mut stream_write2, "CAP END");
+        receive(&mut stream_read2);
+        receive(&mut stream_read2);
+        receive(&mut stream_read2);
+        command(&mut stream_write2, "CHATHISTORY LATEST #haku * 10");
+        assert_eq!(
+            ":iris-server FAIL CHATHISTORY INVALID_TARGET LATEST #haku :Messages could not be retrieved",
+            receive(&mut stream_read2).trim()
+        );
+        command(&mut stream_write2, "JOIN #haku");
+        receive(&mut stream_read1);
+        receive(&mut stream_read2);
+        command(&mut stream_write2, "NOTICE #haku :late, sorry");
+        assert_eq!(
+            ":leo!leo@127.0.0.1 NOTICE #haku :late, sorry",
+            receive(&mut stream_read1).trim()
+        );
+        receive(&mut stream_read2);
+
+        command(&mut stream_write2, "CHATHISTORY LATEST #haku * 10");
+        let start = receive(&mut stream_read2);
+        let id = start
+            .trim()
+            .strip_prefix(":iris-server BATCH +")
+            .and_then(|rest| rest.strip_suffix(" chathistory #haku"))
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            format!("@batch={id} :kate!ignored@127.0.0.1 PRIVMSG #haku :anyone here?"),
+            receive(&mut stream_read2).trim()
+        );
+        assert_eq!(
+            format!("@batch={id} :leo!leo@127.0.0.1 NOTICE #haku :late, sorry"),
+            receive(&mut stream_read2).trim()
+        );
+        assert_eq!(
+            format!(":iris-server BATCH -{id}"),
+            receive(&mut stream_read2).trim()
+        );
+        command(&mut stream_write2, "CHATHISTORY FORGET #haku");
+        assert_eq!(
+            ":iris-server FAIL CHATHISTORY UNKNOWN_COMMAND FORGET :Unknown subcommand",
+            receive(&mut stream_read2).trim()
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn labeled_responses() {
+        let server = spawn();
+        let (mut stream_write1, mut stream_read1) = setup(&server);
+        register_user("mona", &mut stream_write1, &mut stream_read1);
+        let (mut stream_write2, mut stream_read2) = setup(&server);
+        command(&mut stream_write2, "CAP REQ :batch labeled-response");
+        command(&mut stream_write2, "NICK ned");
+        command(&mut stream_write2, "USER ned 0 * :Ned N");
+        command(&mut stream_write2, "CAP END");
+        receive(&mut stream_read2);
+        receive(&mut stream_read2);
+        receive(&mut stream_read2);
+
+        command(&mut stream_write2, "@label=p1 PING :check");
+        assert_eq!("@label=p1 PONG :check", receive(&mut stream_read2).trim());
+        command(&mut stream_write2, "@label=m1 PRIVMSG mona :hello");
+        assert_eq!(
+            "@label=m1 :iris-server ACK",
+            receive(&mut stream_read2).trim()
+        );
+        assert_eq!(
+            ":ned!ned@127.0.0.1 PRIVMSG mona :hello",
+            receive(&mut stream_read1).trim()
+        );
+        command(&mut stream_write1, "@label=ignored PING :plain");
+        assert_eq!("PONG :plain", receive(&mut stream_read1).trim());
+
+        command(&mut stream_write2, "JOIN #labels");
+        receive(&mut stream_read2);
+        command(&mut stream_write2, "@label=n1 NAMES #labels");
+        let start = receive(&mut stream_read2);
+        let id = start
+            .trim()
+            .strip_prefix("@label=n1 :iris-server BATCH +")
+            .and_then(|rest| rest.strip_suffix(" labeled-response"))
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            format!("@batch={id} :iris-server 353 ned = #labels :@ned"),
+            receive(&mut stream_read2).trim()
+        );
+        assert_eq!(
+            format!("@batch={id} :iris-server 366 ned #labels :End of /NAMES list"),
+            receive(&mut stream_read2).trim()
+        );
+        assert_eq!(
+            format!(":iris-server BATCH -{id}"),
+            receive(&mut stream_read2).trim()
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn casemapped_names() {
+        let server = spawn();
+        let (mut stream_write1, mut stream_read1) = setup(&server);
+        register_user("Olga[1]", &mut stream_write1, &mut stream_read1);
+        let (mut stream_write2, mut stream_read2) = setup(&server);
+        command(&mut stream_write2, "NICK olga{1}");
+        assert_eq!(
+            ":iris-server 436 * olga{1} :Nickname collision",
+            receive(&mut stream_read2).trim()
+        );
+        register_user("pete", &mut stream_write2, &mut stream_read2);
+
+        command(&mut stream_write1, "JOIN #Case");
+        receive(&mut stream_read1);
+        command(&mut stream_write2, "JOIN #CASE");
+        assert_eq!(
+            ":pete!ignored@127.0.0.1 JOIN #CASE",
+            receive(&mut stream_read1).trim()
+        );
+        receive(&mut stream_read2);
+        command(&mut stream_write2, "PRIVMSG OLGA{1} :found you");
+        assert_eq!(
+            ":pete!ignored@127.0.0.1 PRIVMSG OLGA{1} :found you",
+            receive(&mut stream_read1).trim()
+        );
     }
 }